@@ -12,49 +12,275 @@ extern crate proc_macro;
 extern crate proc_macro2;
 #[macro_use]
 extern crate quote;
+#[macro_use]
 extern crate syn;
 
+use std::collections::HashMap;
+
 use proc_macro::TokenStream as StdTokenStream;
 use proc_macro2::{Span, TokenStream};
-use syn::{DeriveInput, Ident, Lit, Meta};
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, GenericParam, Ident, Lit, Meta, NestedMeta};
 
 /// Macros 1.1 implementation of `#[derive(Job)]`
 ///
-/// This macro supports several attributes:
+/// The trait can be derived for structs and enums. The variants of an enum are sent as the same
+/// job: they share its attributes, and are executed by its handler. Generic types are supported
+/// as long as their type parameters implement `TypeName`: each instantiation is a job of its own,
+/// whose name includes the names of its type parameters.
 ///
-/// * `job_name`: a unique ID for the job.
-///   e.g: `#[job_name = "batch-rs:send-confirmation-email"]`
-///   **default value**: The derived struct name
-/// * `job_exchange`: the exchange this job will be published to.
-///   e.g: `#[job_exchange = "batch.example"]`
+/// This macro supports several attributes, given as `#[job(name = value, ...)]`:
+///
+/// * `name`: a unique ID for the job. The names of generic jobs must include each of their
+///   type parameters, between braces.
+///   e.g: `#[job(name = "batch-rs:send-confirmation-email")]`, `#[job(name = "process-{T}")]`
+///   **default value**: The name of the type deriving `Job`, followed by the names of its type
+///   parameters between angle brackets if it's generic
+/// * `exchange`: the exchange this job will be published to, which must be declared by the
+///   client (see `ClientBuilder::exchanges`). Invalid exchange names are rejected at compile time.
+///   e.g: `#[job(exchange = "batch.example")]`
 ///   **default value**: `""`
-/// * `job_routing_key`: the routing key associated to the job.
-///   e.g: `#[job_routing_key = "mailer"]`
-/// * `job_timeout`: Number of seconds available for the job to execute. If the time limit is
+/// * `routing_key`: the routing key associated to the job, at most 255 bytes long. Workers
+///   check it against the bindings of their queues (see `WorkerBuilder::deny_unroutable_jobs`).
+///   e.g: `#[job(routing_key = "mailer")]`
+/// * `timeout`: Number of seconds available for the job to execute. If the time limit is
 ///   exceeded, the job's process is killed and the job is marked as failed.
-///   e.g: `#[job_timeout = "120"]`
+///   e.g: `#[job(timeout = 120)]`
 ///   **default value**: `900` (15 minutes)
-/// * `job_retries`: Number of times the job should be retried in case of error.
-///   e.g: `#[job_retries = "5"]`
+/// * `retries`: Number of times the job should be retried in case of error.
+///   e.g: `#[job(retries = 5)]`
 ///   **default value**: `2`
-/// * `job_priority`: The priority associated to the job
-///   e.g: `#[job_priority = "critical"]`
+/// * `retry_backoff`: How long to wait before retrying the job after a failure, one of
+///   `immediate`, `fixed(<duration>)` or `exponential(<base>, <max>)`, where durations are
+///   integers suffixed by a unit (`ms`, `s`, `m`, `h` or `d`).
+///   e.g: `#[job(retry_backoff = "exponential(2s, 10m)")]`
+///   **default value**: `"immediate"`
+/// * `failure_policy`: What the worker does with the job when it fails, one of `retry`,
+///   `requeue`, `reject`, `dead-letter` or `drop` (see `FailurePolicy`).
+///   e.g: `#[job(failure_policy = "dead-letter")]`
+///   **default value**: none, the worker's default policy is used
+/// * `priority`: The priority associated to the job
+///   e.g: `#[job(priority = "critical")]`
 ///   **default value**: `"normal"`
+/// * `delay`: Number of seconds to wait before the job is delivered to a worker.
+///   e.g: `#[job(delay = 300)]`
+///   **default value**: none, the job is delivered as soon as possible
+/// * `expires`: Number of seconds after which the job is dropped instead of executed, counted
+///   from the time it was sent.
+///   e.g: `#[job(expires = 3600)]`
+///   **default value**: none, the job never expires
+/// * `cron`: A cron expression describing when the job is published by a `Scheduler`.
+///   e.g: `#[job(cron = "0 3 * * *")]`
+///   **default value**: none, the job isn't periodic
+/// * `timezone`: The name of the timezone the `cron` expression is evaluated in, from the IANA
+///   database. Occurrences follow the wall clock across DST changes.
+///   e.g: `#[job(timezone = "Europe/Paris")]`
+///   **default value**: none, the scheduler's timezone is used (UTC unless changed)
+/// * `jitter`: Maximum number of seconds by which each occurrence of the `cron` schedule is
+///   delayed, so that jobs sharing a schedule aren't all published at the same instant.
+///   e.g: `#[job(jitter = 30)]`
+///   **default value**: none, the scheduler's jitter is used (none unless changed)
+/// * `codec`: The codec used to serialize the job, one of `json`, `msgpack` or `cbor` (the
+///   latter two require the matching feature of the `batch` crate).
+///   e.g: `#[job(codec = "msgpack")]`
+///   **default value**: none, the client's default codec is used
+/// * `unique_for`: Number of seconds during which sending an identical job (see
+///   `Job::unique_key`) is a no-op.
+///   e.g: `#[job(unique_for = 600)]`
+///   **default value**: none, the job isn't unique
+/// * `rate_limit`: The maximum number of jobs of this type executed per second (`s`), minute
+///   (`min`), hour (`h`) or day (`d`).
+///   e.g: `#[job(rate_limit = "100/min")]`
+///   **default value**: none, the job isn't rate limited
+/// * `concurrency`: The maximum number of jobs of this type executing at the same time.
+///   e.g: `#[job(concurrency = 2)]`
+///   **default value**: none, the job is only limited by the concurrency of the worker
+/// * `version`: The version of the schema of the job's payload, to be bumped when jobs sent
+///   before a change of the struct can't be deserialized anymore (see `Job::version`).
+///   e.g: `#[job(version = 3)]`
+///   **default value**: `1`
+/// * `migrate`: The path of the function building the job from the payload of a job sent
+///   with another version, with the signature of `Job::migrate`. The payload is a
+///   `serde_json::Value`, so the crate deriving `Job` must depend on `serde_json`.
+///   e.g: `#[job(migrate = "SendEmail::migrate")]`
+///   **default value**: none, the payload is deserialized as is
+/// * `register`: Whether to register the job in the registry of the `discovery` module, from
+///   which workers register the handlers of the jobs implementing `Perform`. Generic jobs are
+///   never registered.
+///   e.g: `#[job(register = false)]`
+///   **default value**: `true`
+///
+/// The attributes can be split across several `#[job(...)]` lists, but each of them can only be
+/// given once. They can also be given one at a time as `#[job_name = "value"]`, in which case
+/// every value is a string, e.g: `#[job_timeout = "120"]`.
+///
+/// Invalid attributes are reported as compile errors pointing at the attribute, along with a
+/// suggestion when an attribute name looks misspelled.
 #[proc_macro_derive(
-    Job, attributes(job_name, job_exchange, job_routing_key, job_timeout, job_retries, job_priority)
+    Job,
+    attributes(
+        job, job_name, job_exchange, job_routing_key, job_timeout, job_retries, job_retry_backoff, job_priority,
+        job_delay, job_expires, job_cron, job_timezone, job_jitter, job_codec, job_unique_for,
+        job_rate_limit, job_concurrency, job_version, job_migrate, job_failure_policy, job_register
+    )
 )]
 pub fn task_derive(input: StdTokenStream) -> StdTokenStream {
     let input: DeriveInput = syn::parse(input.into()).unwrap();
-    let job_name = get_derive_name_attr(&input);
-    let job_exchange = get_derive_exchange_attr(&input);
-    let job_routing_key = get_derive_routing_key_attr(&input);
-    let job_timeout = get_derive_timeout_attr(&input);
-    let job_retries = get_derive_retries_attr(&input);
-    let job_priority = get_derive_priority_attr(&input);
+    let expanded = match expand(&input) {
+        Ok(expanded) => expanded,
+        Err(errors) => errors.iter().map(Error::to_compile_error).collect(),
+    };
+    expanded.into()
+}
+
+/// The attributes supported by the derive, along with the type of their value.
+const ATTRIBUTES: &[(&str, Kind)] = &[
+    ("name", Kind::Str),
+    ("exchange", Kind::Str),
+    ("routing_key", Kind::Str),
+    ("timeout", Kind::Int),
+    ("retries", Kind::Int),
+    ("retry_backoff", Kind::Str),
+    ("priority", Kind::Str),
+    ("delay", Kind::Int),
+    ("expires", Kind::Int),
+    ("cron", Kind::Str),
+    ("timezone", Kind::Str),
+    ("jitter", Kind::Int),
+    ("codec", Kind::Str),
+    ("unique_for", Kind::Int),
+    ("rate_limit", Kind::Str),
+    ("concurrency", Kind::Int),
+    ("version", Kind::Int),
+    ("migrate", Kind::Str),
+    ("failure_policy", Kind::Str),
+    ("register", Kind::Bool),
+];
+
+/// The type of the value of an attribute in its `#[job(...)]` form.
+///
+/// Values are always strings in the `#[job_xxx = "..."]` form.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+}
+
+/// An invalid use of the derive, reported as a compile error pointing at `span`.
+struct Error {
+    span: Span,
+    message: String,
+}
+
+impl Error {
+    fn new<M>(span: Span, message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        Error {
+            span,
+            message: message.into(),
+        }
+    }
+
+    fn to_compile_error(&self) -> TokenStream {
+        let message = &self.message;
+        quote_spanned! { self.span=>
+            compile_error!(#message);
+        }
+    }
+}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// The value of an attribute, along with the span of its literal.
+#[derive(Clone)]
+struct Attr {
+    value: String,
+    span: Span,
+}
+
+impl Attr {
+    /// Parse the value of this attribute, reporting `expected` if it can't be parsed.
+    fn parse<T>(&self, expected: &str) -> Result<T>
+    where
+        T: ::std::str::FromStr,
+    {
+        self.value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| self.error(format!("expected {}, found {:?}", expected, self.value)))
+    }
+
+    fn error<M>(&self, message: M) -> Error
+    where
+        M: Into<String>,
+    {
+        Error::new(self.span, message)
+    }
+}
+
+fn expand(input: &DeriveInput) -> ::std::result::Result<TokenStream, Vec<Error>> {
+    let mut errors = Vec::new();
+    let attrs = Attributes::parse(input, &mut errors);
+    if let Data::Union(ref data) = input.data {
+        errors.push(Error::new(
+            data.union_token.span,
+            "Job can only be derived for structs and enums",
+        ));
+    }
+    let type_params = get_type_params(input);
+    let job_exchange = check(&mut errors, get_derive_exchange_attr(&attrs));
+    let job_routing_key = check(&mut errors, get_derive_routing_key_attr(input, &attrs));
+    let job_timeout = check(&mut errors, get_derive_timeout_attr(&attrs));
+    let job_retries = check(&mut errors, get_derive_retries_attr(&attrs));
+    let job_retry_backoff = check(&mut errors, get_derive_retry_backoff_attr(&attrs));
+    let job_failure_policy = check(&mut errors, get_derive_failure_policy_attr(&attrs));
+    let job_priority = check(&mut errors, get_derive_priority_attr(&attrs));
+    let job_delay = check(&mut errors, get_derive_delay_attr(&attrs));
+    let job_expires = check(&mut errors, get_derive_expires_attr(&attrs));
+    let job_cron = check(&mut errors, get_derive_cron_attr(&attrs));
+    let job_timezone = check(&mut errors, get_derive_timezone_attr(&attrs));
+    let job_jitter = check(&mut errors, get_derive_jitter_attr(&attrs));
+    let job_codec = check(&mut errors, get_derive_codec_attr(&attrs));
+    let job_unique_for = check(&mut errors, get_derive_unique_for_attr(&attrs));
+    let job_rate_limit = check(&mut errors, get_derive_rate_limit_attr(&attrs));
+    let job_concurrency = check(&mut errors, get_derive_concurrency_attr(&attrs));
+    let job_version = check(&mut errors, get_derive_version_attr(&attrs));
+    let job_migrate = check(&mut errors, get_derive_migrate_attr(&attrs));
+    let job_register = check(&mut errors, get_derive_register_attr(input, &attrs));
+    // The names of generic jobs are built once per instantiation, as statics can't be generic.
+    let (job_name_static, job_name) = if type_params.is_empty() {
+        let job_name = check(&mut errors, get_derive_name_attr(input, &attrs));
+        let job_name_static = quote! {
+            lazy_static! {
+                static ref _BATCH_JOB_NAME: String = #job_name.replace("::", ".");
+            }
+        };
+        (job_name_static, quote! { _BATCH_JOB_NAME.as_ref() })
+    } else {
+        let job_name = get_derive_generic_name_attr(input, &attrs, &type_params);
+        let job_name = check(&mut errors, job_name);
+        (quote! {}, quote! { _batch::__job_name::<Self, _>(|| #job_name) })
+    };
+    if !errors.is_empty() {
+        return Err(errors);
+    }
     let name = &input.ident;
     let impl_block_name = gen_derive_impl_block_name(name.to_string());
+    let mut generics = input.generics.clone();
+    if !type_params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        for param in &type_params {
+            where_clause
+                .predicates
+                .push(parse_quote!(#param: _batch::TypeName + 'static));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
-    let expanded = quote! {
+    Ok(quote! {
         #[allow(non_upper_case_globals)]
         const #impl_block_name: () =
         {
@@ -64,13 +290,11 @@ pub fn task_derive(input: StdTokenStream) -> StdTokenStream {
             use ::std::option::Option;
             use ::std::time::Duration;
 
-            lazy_static! {
-                static ref _BATCH_JOB_NAME: String = #job_name.replace("::", ".");
-            }
+            #job_name_static
 
-            impl _batch::Job for #name {
+            impl #impl_generics _batch::Job for #name #ty_generics #where_clause {
                 fn name() -> &'static str {
-                    _BATCH_JOB_NAME.as_ref()
+                    #job_name
                 }
 
                 fn exchange() -> &'static str {
@@ -89,115 +313,744 @@ pub fn task_derive(input: StdTokenStream) -> StdTokenStream {
                     #job_retries
                 }
 
+                fn retry_strategy() -> _batch::RetryStrategy {
+                    #job_retry_backoff
+                }
+
+                fn failure_policy() -> Option<_batch::FailurePolicy> {
+                    #job_failure_policy
+                }
+
                 fn priority() -> _batch::Priority {
                     #job_priority
                 }
+
+                fn delay() -> Option<Duration> {
+                    #job_delay
+                }
+
+                fn expires() -> Option<Duration> {
+                    #job_expires
+                }
+
+                fn cron() -> Option<&'static str> {
+                    #job_cron
+                }
+
+                fn timezone() -> Option<&'static str> {
+                    #job_timezone
+                }
+
+                fn jitter() -> Option<Duration> {
+                    #job_jitter
+                }
+
+                fn codec() -> Option<_batch::Codec> {
+                    #job_codec
+                }
+
+                fn unique_for() -> Option<Duration> {
+                    #job_unique_for
+                }
+
+                fn rate_limit() -> Option<_batch::RateLimit> {
+                    #job_rate_limit
+                }
+
+                fn concurrency() -> Option<u32> {
+                    #job_concurrency
+                }
+
+                fn version() -> u32 {
+                    #job_version
+                }
+
+                #job_migrate
+            }
+
+            #job_register
+        };
+    })
+}
+
+/// Return the tokens of the given result, recording its error if it failed.
+fn check(errors: &mut Vec<Error>, result: Result<TokenStream>) -> TokenStream {
+    match result {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            errors.push(e);
+            quote! {}
+        }
+    }
+}
+
+/// The attributes of the type deriving `Job`, given either as `#[job(name = value, ...)]` or as
+/// `#[job_name = "value"]`.
+struct Attributes {
+    values: HashMap<&'static str, Attr>,
+}
+
+impl Attributes {
+    /// Parse the attributes of the given type, recording the invalid ones in `errors`.
+    fn parse(input: &DeriveInput, errors: &mut Vec<Error>) -> Self {
+        let mut attributes = Attributes {
+            values: HashMap::new(),
+        };
+        for attr in &input.attrs {
+            let meta = match attr.interpret_meta() {
+                Some(meta) => meta,
+                None => continue,
+            };
+            match meta {
+                Meta::List(ref list) if list.ident == "job" => {
+                    for nested in &list.nested {
+                        match *nested {
+                            NestedMeta::Meta(Meta::NameValue(ref nv)) => {
+                                attributes.insert(errors, &nv.ident, &nv.lit, false)
+                            }
+                            ref nested => errors.push(Error::new(
+                                nested.span(),
+                                "expected `name = value`, e.g: #[job(timeout = 120)]",
+                            )),
+                        }
+                    }
+                }
+                Meta::Word(ref ident) if ident == "job" => errors.push(Error::new(
+                    ident.span(),
+                    "expected a list of attributes, e.g: #[job(routing_key = \"emails\")]",
+                )),
+                Meta::NameValue(ref nv) if nv.ident == "job" => errors.push(Error::new(
+                    nv.ident.span(),
+                    "expected a list of attributes, e.g: #[job(routing_key = \"emails\")]",
+                )),
+                Meta::NameValue(ref nv) if nv.ident.to_string().starts_with("job_") => {
+                    attributes.insert(errors, &nv.ident, &nv.lit, true)
+                }
+                Meta::Word(ref ident) => check_legacy_name(errors, ident),
+                Meta::List(ref list) => check_legacy_name(errors, &list.ident),
+                _ => {}
             }
+        }
+        attributes
+    }
+
+    /// Record the attribute with the given name and value, `job_` prefixed if `legacy`.
+    fn insert(&mut self, errors: &mut Vec<Error>, ident: &Ident, lit: &Lit, legacy: bool) {
+        let name = ident.to_string();
+        let name = if legacy { &name["job_".len()..] } else { &name[..] };
+        let (name, kind) = match ATTRIBUTES.iter().find(|&&(known, _)| known == name) {
+            Some(&(name, kind)) => (name, kind),
+            None => {
+                errors.push(unknown_attribute(ident, legacy));
+                return;
+            }
+        };
+        let value = match (lit, kind, legacy) {
+            (&Lit::Str(ref lit), Kind::Str, _) | (&Lit::Str(ref lit), _, true) => lit.value(),
+            (&Lit::Int(ref lit), Kind::Int, false) => lit.value().to_string(),
+            (&Lit::Bool(ref lit), Kind::Bool, false) => lit.value.to_string(),
+            _ => {
+                let message = match (kind, legacy) {
+                    (_, true) => format!("expected a string, e.g: #[job_{} = \"...\"]", name),
+                    (Kind::Str, false) => {
+                        format!("expected a string, e.g: #[job({} = \"...\")]", name)
+                    }
+                    (Kind::Int, false) => {
+                        format!("expected an integer, e.g: #[job({} = 5)]", name)
+                    }
+                    (Kind::Bool, false) => {
+                        format!("expected a boolean, e.g: #[job({} = false)]", name)
+                    }
+                };
+                errors.push(Error::new(lit.span(), message));
+                return;
+            }
+        };
+        if self.values.contains_key(name) {
+            let message = format!("duplicate attribute `{}`, it can only be given once", name);
+            errors.push(Error::new(ident.span(), message));
+            return;
+        }
+        let attr = Attr {
+            value,
+            span: lit.span(),
         };
+        self.values.insert(name, attr);
+    }
+
+    /// Return the attribute with the given name, without its `job_` prefix.
+    fn get(&self, name: &str) -> Option<Attr> {
+        self.values.get(name).cloned()
+    }
+}
+
+/// Report attributes looking like misspelled `job_*` attributes, or like `job_*` attributes
+/// missing their value.
+fn check_legacy_name(errors: &mut Vec<Error>, ident: &Ident) {
+    let name = ident.to_string();
+    if !name.starts_with("job_") {
+        return;
+    }
+    if ATTRIBUTES.iter().any(|&(known, _)| known == &name["job_".len()..]) {
+        let message = format!("expected a value, e.g: #[{} = \"...\"]", name);
+        errors.push(Error::new(ident.span(), message));
+    } else {
+        errors.push(unknown_attribute(ident, true));
+    }
+}
+
+/// Return the error reporting the given unknown attribute, `job_` prefixed if `legacy`.
+fn unknown_attribute(ident: &Ident, legacy: bool) -> Error {
+    let prefix = if legacy { "job_" } else { "" };
+    let name = ident.to_string();
+    let message = match suggest(&name[prefix.len()..]) {
+        Some(suggestion) => format!(
+            "unknown attribute `{}`, did you mean `{}{}`?",
+            name, prefix, suggestion
+        ),
+        None => {
+            let known = ATTRIBUTES.iter().map(|&(known, _)| format!("{}{}", prefix, known));
+            format!(
+                "unknown attribute `{}`, expected one of: {}",
+                name,
+                known.collect::<Vec<_>>().join(", ")
+            )
+        }
     };
-    expanded.into()
+    Error::new(ident.span(), message)
+}
+
+/// Return the supported attribute closest to the given unknown one, if any is close enough.
+fn suggest(name: &str) -> Option<&'static str> {
+    ATTRIBUTES
+        .iter()
+        .map(|&(attr, _)| (distance(name, attr), attr))
+        .filter(|&(distance, _)| distance <= 3)
+        .min()
+        .map(|(_, attr)| attr)
 }
 
-fn get_derive_name_attr(input: &DeriveInput) -> TokenStream {
-    if let Some(raw) = get_str_attr_by_name(&input.attrs, "job_name") {
-        quote! { #raw }
+/// Return the Levenshtein distance between the given strings.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + if ca == *cb { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn get_derive_name_attr(input: &DeriveInput, attrs: &Attributes) -> Result<TokenStream> {
+    if let Some(attr) = attrs.get("name") {
+        let raw = attr.value;
+        Ok(quote! { #raw })
     } else {
         let name = input.ident.to_string();
-        quote! {
+        Ok(quote! {
             concat!(concat!(module_path!(), "::"), #name)
+        })
+    }
+}
+
+fn get_derive_generic_name_attr(
+    input: &DeriveInput,
+    attrs: &Attributes,
+    params: &[Ident],
+) -> Result<TokenStream> {
+    let attr = match attrs.get("name") {
+        Some(attr) => attr,
+        None => {
+            let name = input.ident.to_string();
+            return Ok(quote! {
+                format!(
+                    "{}<{}>",
+                    concat!(concat!(module_path!(), "::"), #name),
+                    [#(<#params as _batch::TypeName>::type_name()),*].join(", ")
+                )
+            });
         }
+    };
+    let mut format = String::new();
+    let mut args = Vec::new();
+    let mut used = Vec::new();
+    let mut rest = attr.value.as_str();
+    while let Some(open) = rest.find('{') {
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => return Err(attr.error("invalid job name, a placeholder isn't closed")),
+        };
+        let placeholder = rest[open + 1..close].trim();
+        let param = match params.iter().find(|param| param.to_string() == placeholder) {
+            Some(param) => param,
+            None => {
+                let params = params.iter().map(|param| format!("{{{}}}", param));
+                let message = format!(
+                    "invalid job name, {{{}}} isn't a type parameter, expected one of: {}",
+                    placeholder,
+                    params.collect::<Vec<_>>().join(", ")
+                );
+                return Err(attr.error(message));
+            }
+        };
+        format.push_str(&rest[..open]);
+        format.push_str("{}");
+        args.push(quote! { <#param as _batch::TypeName>::type_name() });
+        used.push(param.to_string());
+        rest = &rest[close + 1..];
+    }
+    format.push_str(rest);
+    if format.replace("{}", "").contains('}') {
+        return Err(attr.error("invalid job name, braces are reserved for type parameters"));
     }
+    if let Some(param) = params.iter().find(|param| !used.contains(&param.to_string())) {
+        let message = format!(
+            "invalid job name, it must include {{{}}} so that the instantiations of the job have different names",
+            param
+        );
+        return Err(attr.error(message));
+    }
+    Ok(quote! { format!(#format, #(#args),*) })
+}
+
+/// Returns the type parameters of the type deriving `Job`.
+fn get_type_params(input: &DeriveInput) -> Vec<Ident> {
+    input
+        .generics
+        .params
+        .iter()
+        .filter_map(|param| match *param {
+            GenericParam::Type(ref param) => Some(param.ident.clone()),
+            _ => None,
+        })
+        .collect()
 }
 
-fn get_derive_exchange_attr(input: &DeriveInput) -> TokenStream {
-    let attr = {
-        let raw = get_str_attr_by_name(&input.attrs, "job_exchange");
-        raw.unwrap_or_else(|| "".to_string())
+fn get_derive_exchange_attr(attrs: &Attributes) -> Result<TokenStream> {
+    let attr = match attrs.get("exchange") {
+        Some(attr) => attr,
+        None => return Ok(quote! { "" }),
     };
-    quote! { #attr }
+    // AMQP restricts exchange names to 127 letters, digits, hyphens, underscores, periods and
+    // colons, catch typos here rather than when the broker rejects the first publish.
+    let valid = |c: char| c.is_ascii_alphanumeric() || "-_.:".contains(c);
+    if attr.value.len() > 127 || !attr.value.chars().all(valid) {
+        return Err(attr.error(format!(
+            "invalid exchange name {:?}, must be at most 127 letters, digits, '-', '_', '.' or ':'",
+            attr.value
+        )));
+    }
+    if attr.value.starts_with("amq.") {
+        return Err(attr.error(format!(
+            "invalid exchange name {:?}, names starting with \"amq.\" are reserved by the broker",
+            attr.value
+        )));
+    }
+    let value = attr.value;
+    Ok(quote! { #value })
 }
 
-fn get_derive_routing_key_attr(input: &DeriveInput) -> TokenStream {
-    let attr = {
-        let raw = get_str_attr_by_name(&input.attrs, "job_routing_key");
-        raw.expect("job_routing_key is a mandatory attribute when deriving Job")
+fn get_derive_routing_key_attr(input: &DeriveInput, attrs: &Attributes) -> Result<TokenStream> {
+    let attr = match attrs.get("routing_key") {
+        Some(attr) => attr,
+        None => {
+            return Err(Error::new(
+                input.ident.span(),
+                "missing routing key, add e.g: #[job(routing_key = \"emails\")]",
+            ))
+        }
     };
-    quote! { #attr }
+    if attr.value.len() > 255 {
+        return Err(attr.error(format!(
+            "invalid routing key {:?}, must be at most 255 bytes long",
+            attr.value
+        )));
+    }
+    let value = attr.value;
+    Ok(quote! { #value })
 }
 
-fn get_derive_timeout_attr(input: &DeriveInput) -> TokenStream {
-    let attr = {
-        let raw = get_str_attr_by_name(&input.attrs, "job_timeout");
-        raw.unwrap_or_else(|| "900".to_string())
+fn get_derive_timeout_attr(attrs: &Attributes) -> Result<TokenStream> {
+    let timeout = match attrs.get("timeout") {
+        Some(attr) => attr.parse::<u64>("a number of seconds, e.g: \"120\"")?,
+        None => 900,
     };
-    let timeout = attr.parse::<u64>()
-        .expect("Couldn't parse timeout as an unsigned integer");
-    quote! {
+    Ok(quote! {
         Option::Some(Duration::from_secs(#timeout))
-    }
+    })
 }
 
-fn get_derive_retries_attr(input: &DeriveInput) -> TokenStream {
-    let attr = {
-        let raw = get_str_attr_by_name(&input.attrs, "job_retries");
-        raw.unwrap_or_else(|| "2".to_string())
+fn get_derive_retries_attr(attrs: &Attributes) -> Result<TokenStream> {
+    let retries = match attrs.get("retries") {
+        Some(attr) => attr.parse::<u32>("a number of retries, e.g: \"5\"")?,
+        None => 2,
     };
-    let retries = attr.parse::<u32>()
-        .expect("Couldn't parse retries as an unsigned integer");
-    quote! {
+    Ok(quote! {
         #retries
-    }
+    })
 }
 
-fn get_derive_priority_attr(input: &DeriveInput) -> TokenStream {
-    let attr = {
-        let raw = get_str_attr_by_name(&input.attrs, "job_priority");
-        raw.unwrap_or_else(|| "normal".to_string())
+fn get_derive_retry_backoff_attr(attrs: &Attributes) -> Result<TokenStream> {
+    let attr = match attrs.get("retry_backoff") {
+        Some(attr) => attr,
+        None => return Ok(quote! { _batch::RetryStrategy::Immediate }),
+    };
+    let invalid = || {
+        attr.error(format!(
+            "invalid retry backoff {:?}, expected one of: immediate, fixed(<duration>), exponential(<base>, <max>)",
+            attr.value
+        ))
+    };
+    let raw = attr.value.trim();
+    if raw == "immediate" {
+        return Ok(quote! { _batch::RetryStrategy::Immediate });
+    }
+    let open = raw.find('(');
+    let (kind, args) = match open {
+        Some(open) if raw.ends_with(')') => (&raw[..open], &raw[open + 1..raw.len() - 1]),
+        _ => return Err(invalid()),
     };
-    match attr.to_lowercase().as_ref() {
-        "trivial" => quote! { _batch::Priority::Trivial },
-        "low" => quote! { _batch::Priority::Low },
-        "normal" => quote! { _batch::Priority::Normal },
-        "high" => quote! { _batch::Priority::High },
-        "critical" => quote! { _batch::Priority::Critical },
+    let args = args
+        .split(',')
+        .map(|arg| parse_duration_millis(&attr, arg.trim()))
+        .collect::<Result<Vec<_>>>()?;
+    match (kind.trim(), args.as_slice()) {
+        ("fixed", &[delay]) => Ok(quote! {
+            _batch::RetryStrategy::fixed(Duration::from_millis(#delay))
+        }),
+        ("exponential", &[base, max]) => Ok(quote! {
+            _batch::RetryStrategy::exponential(
+                Duration::from_millis(#base),
+                Duration::from_millis(#max),
+            )
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a duration such as `30s` or `10m` given in the given attribute, returning its value in
+/// milliseconds.
+fn parse_duration_millis(attr: &Attr, raw: &str) -> Result<u64> {
+    let split = raw
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| raw.len());
+    let (value, unit) = raw.split_at(split);
+    let value = value.parse::<u64>().map_err(|_| {
+        attr.error(format!(
+            "invalid duration {:?}, expected an unsigned integer followed by a unit, e.g: \"30s\"",
+            raw
+        ))
+    })?;
+    let factor = match unit.trim() {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60 * 1_000,
+        "h" => 60 * 60 * 1_000,
+        "d" => 24 * 60 * 60 * 1_000,
         _ => {
-            panic!("Invalid priority, must be one of: trivial, low, normal, high, critical.");
+            return Err(attr.error(format!(
+                "invalid duration unit in {:?}, expected one of: ms, s, m, h, d",
+                raw
+            )))
         }
+    };
+    Ok(value * factor)
+}
+
+fn get_derive_priority_attr(attrs: &Attributes) -> Result<TokenStream> {
+    let attr = match attrs.get("priority") {
+        Some(attr) => attr,
+        None => return Ok(quote! { _batch::Priority::Normal }),
+    };
+    match attr.value.to_lowercase().as_ref() {
+        "trivial" => Ok(quote! { _batch::Priority::Trivial }),
+        "low" => Ok(quote! { _batch::Priority::Low }),
+        "normal" => Ok(quote! { _batch::Priority::Normal }),
+        "high" => Ok(quote! { _batch::Priority::High }),
+        "critical" => Ok(quote! { _batch::Priority::Critical }),
+        _ => Err(attr.error(format!(
+            "invalid priority {:?}, expected one of: trivial, low, normal, high, critical",
+            attr.value
+        ))),
     }
 }
 
-fn gen_derive_impl_block_name(name: String) -> TokenStream {
-    let ident = Ident::new(&format!("_IMPL_BATCH_JOB_FOR_{}", name), Span::call_site());
-    quote! { #ident }
+fn get_derive_delay_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("delay") {
+        Some(attr) => {
+            let delay = attr.parse::<u64>("a number of seconds, e.g: \"300\"")?;
+            Ok(quote! {
+                Option::Some(Duration::from_secs(#delay))
+            })
+        }
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_expires_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("expires") {
+        Some(attr) => {
+            let expires = attr.parse::<u64>("a number of seconds, e.g: \"3600\"")?;
+            Ok(quote! {
+                Option::Some(Duration::from_secs(#expires))
+            })
+        }
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_cron_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("cron") {
+        Some(attr) => {
+            let fields = attr.value.split_whitespace().count();
+            if fields < 5 || fields > 7 {
+                return Err(attr.error(format!(
+                    "invalid cron expression {:?}, expected 5 to 7 fields, e.g: \"0 3 * * *\"",
+                    attr.value
+                )));
+            }
+            let value = attr.value;
+            Ok(quote! {
+                Option::Some(#value)
+            })
+        }
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_timezone_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("timezone") {
+        Some(attr) => {
+            if attr.value.is_empty() || attr.value.contains(char::is_whitespace) {
+                return Err(attr.error(format!(
+                    "invalid timezone {:?}, expected the name of a timezone, e.g: \"Europe/Paris\"",
+                    attr.value
+                )));
+            }
+            let value = attr.value;
+            Ok(quote! {
+                Option::Some(#value)
+            })
+        }
+        None => Ok(quote! { Option::None }),
+    }
 }
 
-/// Gets the string value of an attribute by its name.
-fn get_str_attr_by_name(haystack: &[syn::Attribute], needle: &str) -> Option<String> {
-    let attr = get_raw_attr_by_name(haystack, needle);
-    attr.and_then(|attr| {
-        if let Lit::Str(literal) = attr {
-            Some(literal.value())
-        } else {
-            None
+fn get_derive_jitter_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("jitter") {
+        Some(attr) => {
+            let jitter = attr.parse::<u64>("a number of seconds, e.g: \"30\"")?;
+            Ok(quote! {
+                Option::Some(Duration::from_secs(#jitter))
+            })
         }
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_codec_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("codec") {
+        Some(attr) => match attr.value.to_lowercase().as_ref() {
+            "json" => Ok(quote! { Option::Some(_batch::Codec::Json) }),
+            "msgpack" => Ok(quote! { Option::Some(_batch::Codec::MessagePack) }),
+            "cbor" => Ok(quote! { Option::Some(_batch::Codec::Cbor) }),
+            _ => Err(attr.error(format!(
+                "invalid codec {:?}, expected one of: json, msgpack, cbor",
+                attr.value
+            ))),
+        },
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_failure_policy_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("failure_policy") {
+        Some(attr) => match attr.value.as_str() {
+            "retry" => Ok(quote! { Option::Some(_batch::FailurePolicy::Retry) }),
+            "requeue" => Ok(quote! { Option::Some(_batch::FailurePolicy::Requeue) }),
+            "reject" => Ok(quote! { Option::Some(_batch::FailurePolicy::Reject) }),
+            "dead-letter" => Ok(quote! { Option::Some(_batch::FailurePolicy::DeadLetter) }),
+            "drop" => Ok(quote! { Option::Some(_batch::FailurePolicy::Drop) }),
+            _ => Err(attr.error(format!(
+                "invalid failure policy {:?}, expected one of: retry, requeue, reject, dead-letter, drop",
+                attr.value
+            ))),
+        },
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_unique_for_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("unique_for") {
+        Some(attr) => {
+            let unique_for = attr.parse::<u64>("a number of seconds, e.g: \"600\"")?;
+            Ok(quote! {
+                Option::Some(Duration::from_secs(#unique_for))
+            })
+        }
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_rate_limit_attr(attrs: &Attributes) -> Result<TokenStream> {
+    let attr = match attrs.get("rate_limit") {
+        Some(attr) => attr,
+        None => return Ok(quote! { Option::None }),
+    };
+    let invalid = || {
+        attr.error(format!(
+            "invalid rate limit {:?}, expected <amount>/<unit> with unit one of: s, min, h, d, e.g: \"100/min\"",
+            attr.value
+        ))
+    };
+    let mut parts = attr.value.splitn(2, '/');
+    let amount = match parts.next().and_then(|amount| amount.trim().parse::<u32>().ok()) {
+        Some(amount) if amount > 0 => amount,
+        _ => return Err(invalid()),
+    };
+    let period: u64 = match parts.next().map(|unit| unit.trim()) {
+        Some("s") | Some("sec") | Some("second") => 1,
+        Some("m") | Some("min") | Some("minute") => 60,
+        Some("h") | Some("hour") => 60 * 60,
+        Some("d") | Some("day") => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(quote! {
+        Option::Some(_batch::RateLimit::new(#amount, Duration::from_secs(#period)))
     })
 }
 
-/// Gets the raw value of an attribute by its name.
-fn get_raw_attr_by_name(haystack: &[syn::Attribute], needle: &str) -> Option<Lit> {
-    for attr in haystack {
-        let meta = match attr.interpret_meta() {
-            Some(meta) => meta,
-            None => continue,
-        };
-        let nv = match meta {
-            Meta::NameValue(nv) => nv,
-            _ => continue,
-        };
-        if nv.ident != needle {
-            continue;
+fn get_derive_concurrency_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("concurrency") {
+        Some(attr) => {
+            let concurrency = attr.parse::<u32>("a number of jobs, e.g: \"2\"")?;
+            if concurrency == 0 {
+                return Err(attr.error("invalid concurrency, must be at least 1"));
+            }
+            Ok(quote! {
+                Option::Some(#concurrency)
+            })
+        }
+        None => Ok(quote! { Option::None }),
+    }
+}
+
+fn get_derive_version_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("version") {
+        Some(attr) => {
+            let version = attr.parse::<u32>("a version number, e.g: \"2\"")?;
+            if version == 0 {
+                return Err(attr.error("invalid version, must be at least 1"));
+            }
+            Ok(quote! { #version })
         }
-        return Some(nv.lit.clone());
+        None => Ok(quote! { 1 }),
+    }
+}
+
+fn get_derive_migrate_attr(attrs: &Attributes) -> Result<TokenStream> {
+    match attrs.get("migrate") {
+        Some(attr) => {
+            let migrate = syn::parse_str::<syn::Path>(&attr.value).map_err(|_| {
+                attr.error(format!(
+                    "invalid migration {:?}, expected the path of a function, e.g: \"SendEmail::migrate\"",
+                    attr.value
+                ))
+            })?;
+            Ok(quote! {
+                fn migrate(
+                    version: u32,
+                    raw: ::serde_json::Value,
+                ) -> ::std::result::Result<Self, _batch::Error> {
+                    #migrate(version, raw)
+                }
+            })
+        }
+        None => Ok(quote! {}),
+    }
+}
+
+fn get_derive_register_attr(input: &DeriveInput, attrs: &Attributes) -> Result<TokenStream> {
+    let register = match attrs.get("register") {
+        Some(attr) => attr.parse::<bool>("true or false")?,
+        None => true,
+    };
+    // Statics can't be generic, so the registry can't hold the instantiations of generic jobs.
+    if !register || !get_type_params(input).is_empty() {
+        return Ok(quote! {});
+    }
+    let name = &input.ident;
+    // The handler of the job is only registered if it implements `Perform`, the `install`
+    // method of `Performs` returning it being picked over the one of `Jobs` in that case.
+    Ok(quote! {
+        #[allow(unused_imports)]
+        use _batch::discovery::{Jobs as _BatchJobs, Performs as _BatchPerforms};
+
+        _batch::__register_job!(_batch::discovery::Registration::new::<#name>(
+            (&_batch::discovery::Tag::<#name>::new()).install()
+        ));
+    })
+}
+
+fn gen_derive_impl_block_name(name: String) -> TokenStream {
+    let ident = Ident::new(&format!("_IMPL_BATCH_JOB_FOR_{}", name), Span::call_site());
+    quote! { #ident }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(suggest("timeotu"), Some("timeout"));
+        assert_eq!(suggest("routing_kye"), Some("routing_key"));
+        assert_eq!(suggest("frobnicate"), None);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    fn parse(input: &str) -> (Attributes, Vec<Error>) {
+        let input = syn::parse_str::<DeriveInput>(input).unwrap();
+        let mut errors = Vec::new();
+        let attrs = Attributes::parse(&input, &mut errors);
+        (attrs, errors)
+    }
+
+    #[test]
+    fn parse_attributes() {
+        let (attrs, errors) = parse(
+            r#"
+            #[job(routing_key = "emails", timeout = 120)]
+            #[job_retries = "3"]
+            struct SendEmail;
+            "#,
+        );
+        assert!(errors.is_empty());
+        assert_eq!(attrs.get("routing_key").unwrap().value, "emails");
+        assert_eq!(attrs.get("timeout").unwrap().value, "120");
+        assert_eq!(attrs.get("retries").unwrap().value, "3");
+        assert!(attrs.get("priority").is_none());
+
+        let (_, errors) = parse(
+            r#"
+            #[job(timeout = "120", retries = 3, retries = 4, priorty = "high")]
+            #[job_timeout = "60"]
+            struct SendEmail;
+            "#,
+        );
+        let errors = errors.into_iter().map(|e| e.message).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "expected an integer, e.g: #[job(timeout = 5)]",
+                "duplicate attribute `retries`, it can only be given once",
+                "unknown attribute `priorty`, did you mean `priority`?",
+            ]
+        );
     }
-    None
 }
@@ -0,0 +1,518 @@
+//! A web dashboard for the batch crate.
+//!
+//! The dashboard is a small HTTP server showing the queues of a `Client`'s broker along with the
+//! number of jobs waiting in each of them, the workers that are alive or dead and the jobs they
+//! are running, and the jobs that exhausted their retries. Dead jobs can be sent again or removed
+//! from the dashboard.
+//!
+//! Workers are listed from their heartbeats, given to the dashboard as a `monitor::Workers`:
+//! only the workers enabling `WorkerBuilder::heartbeats` are shown. Queues and dead jobs are only
+//! shown if the broker supports inspecting them (see `Client::queues` and `Client::dead_letters`).
+//!
+//! Besides the HTML page served on `/`, the dashboard exposes a JSON API:
+//!
+//! * `GET /api/queues`: the queues, with their number of pending and dead jobs.
+//! * `GET /api/workers`: the last heartbeat of the workers that are alive and dead.
+//! * `GET /api/queues/{queue}/dead`: the dead jobs of a queue, oldest first.
+//! * `POST /api/queues/{queue}/dead/{id}/retry`: send a dead job back to its original queue.
+//! * `POST /api/queues/{queue}/dead/{id}/delete`: remove a dead job.
+//! * `GET /api/jobs/{id}/attempts`: the attempts at executing a job, oldest first, when the
+//!   dashboard was given the `AuditSink` of the workers with `Dashboard::audit_sink`.
+//!
+//! The dashboard doesn't authenticate its users: it should only be exposed on trusted networks.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate batch_dashboard;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use batch::monitor::Workers;
+//! use batch::{queue, Client, ConnectionBuilder};
+//! use batch_dashboard::Dashboard;
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let connection = ConnectionBuilder::new("amqp://localhost/%2f");
+//!     let client = Client::builder()
+//!         .connection(connection.clone())
+//!         .queues(vec![queue("emails")])
+//!         .build();
+//!     let task = client
+//!         .join(Workers::connect(&connection))
+//!         .map_err(|e| eprintln!("Couldn't connect to RabbitMQ: {}", e))
+//!         .and_then(|(client, workers)| {
+//!             let addr = "127.0.0.1:8080".parse().unwrap();
+//!             Dashboard::new(client)
+//!                 .workers(workers)
+//!                 .serve(&addr)
+//!                 .map_err(|e| eprintln!("Couldn't serve the dashboard: {}", e))
+//!         });
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/batch-dashboard/0.1.0")]
+#![deny(missing_debug_implementations)]
+#![deny(missing_docs)]
+
+extern crate batch;
+extern crate chrono;
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate uuid;
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use batch::audit::AuditSink;
+use batch::monitor::{Heartbeat, Workers};
+use batch::{Client, DeadJob, Error, Failure, Properties};
+use chrono::{DateTime, Utc};
+use futures::{future, Future};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use uuid::Uuid;
+
+/// The page served on `/`, rendering the JSON API.
+const INDEX: &str = include_str!("index.html");
+
+/// Type of the responses of the dashboard.
+type Reply = Box<Future<Item = Response<Body>, Error = Error> + Send>;
+
+/// The state of a queue, as returned by `GET /api/queues`.
+#[derive(Debug, Serialize)]
+struct QueueSummary {
+    name: String,
+    pending: u64,
+    dead: Option<usize>,
+}
+
+/// The workers known to the dashboard, as returned by `GET /api/workers`.
+#[derive(Debug, Serialize)]
+struct WorkersSummary {
+    alive: Vec<Heartbeat>,
+    dead: Vec<Heartbeat>,
+}
+
+/// A dead job, as returned by `GET /api/queues/{queue}/dead`.
+///
+/// The payload is decoded as UTF-8 so that it can be displayed, invalid sequences (e.g: in
+/// compressed payloads) are replaced.
+#[derive(Debug, Serialize)]
+struct DeadJobView<'a> {
+    queue: &'a str,
+    properties: &'a Properties,
+    payload: Cow<'a, str>,
+    failure: &'a Failure,
+    died_at: &'a DateTime<Utc>,
+}
+
+impl<'a> From<&'a DeadJob> for DeadJobView<'a> {
+    fn from(job: &'a DeadJob) -> Self {
+        DeadJobView {
+            queue: &job.queue,
+            properties: &job.properties,
+            payload: String::from_utf8_lossy(&job.payload),
+            failure: &job.failure,
+            died_at: &job.died_at,
+        }
+    }
+}
+
+/// An action applied to a dead job.
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Retry,
+    Delete,
+}
+
+/// A web dashboard showing the state of the queues and workers of a `Client`'s broker.
+#[derive(Clone, Debug)]
+pub struct Dashboard {
+    client: Client,
+    workers: Option<Workers>,
+    audit: Option<Arc<AuditSink>>,
+}
+
+impl Dashboard {
+    /// Create a new `Dashboard` inspecting the broker of the given `Client`.
+    pub fn new(client: Client) -> Self {
+        Dashboard {
+            client,
+            workers: None,
+            audit: None,
+        }
+    }
+
+    /// Set the `Workers` listed by the dashboard.
+    ///
+    /// By default, no worker is listed.
+    pub fn workers(mut self, workers: Workers) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Set the `AuditSink` the attempts at executing jobs are read from.
+    ///
+    /// This should be the sink given to the workers with `WorkerBuilder::audit_sink`. By default,
+    /// the history of jobs isn't available.
+    pub fn audit_sink<S>(mut self, sink: S) -> Self
+    where
+        S: AuditSink + 'static,
+    {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Return a `Future` serving the dashboard over HTTP on the given address.
+    ///
+    /// The returned `Future` only resolves if the server fails.
+    pub fn serve(self, addr: &SocketAddr) -> Box<Future<Item = (), Error = hyper::Error> + Send> {
+        let builder = match Server::try_bind(addr) {
+            Ok(builder) => builder,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        info!("Serving dashboard on http://{}/", addr);
+        let server = builder.serve(move || {
+            let dashboard = self.clone();
+            service_fn(move |request| dashboard.handle(&request))
+        });
+        Box::new(server)
+    }
+
+    /// Route the given request to its handler.
+    fn handle(
+        &self,
+        request: &Request<Body>,
+    ) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        debug!("{} {}", request.method(), request.uri());
+        let segments = request
+            .uri()
+            .path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+        let reply: Reply = match (request.method(), segments.as_slice()) {
+            (&Method::GET, &[]) => Box::new(future::ok(html(INDEX))),
+            (&Method::GET, &["api", "queues"]) => self.queues(),
+            (&Method::GET, &["api", "workers"]) => self.list_workers(),
+            (&Method::GET, &["api", "queues", queue, "dead"]) => self.dead(queue),
+            (&Method::GET, &["api", "jobs", id, "attempts"]) => self.attempts(id),
+            (&Method::POST, &["api", "queues", queue, "dead", id, "retry"]) => {
+                self.apply(queue, id, Action::Retry)
+            }
+            (&Method::POST, &["api", "queues", queue, "dead", id, "delete"]) => {
+                self.apply(queue, id, Action::Delete)
+            }
+            _ => Box::new(future::ok(error(StatusCode::NOT_FOUND, "Not found"))),
+        };
+        let task = reply.or_else(|e| {
+            error!("Couldn't handle dashboard request: {}", e);
+            Ok(error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))
+        });
+        Box::new(task)
+    }
+
+    /// List the queues of the broker, with their number of pending and dead jobs.
+    fn queues(&self) -> Reply {
+        let queues = match self.client.queues() {
+            Some(queues) => queues,
+            None => return Box::new(future::ok(json(&Vec::<QueueSummary>::new()))),
+        };
+        let dead_letters = self.client.dead_letters();
+        let task = queues
+            .list()
+            .and_then(move |queues| {
+                let tasks = queues
+                    .into_iter()
+                    .map(|queue| {
+                        let dead: Box<Future<Item = _, Error = Error> + Send> = match dead_letters {
+                            Some(ref dead_letters) => Box::new(
+                                dead_letters.list(&queue.name).map(|jobs| Some(jobs.len())),
+                            ),
+                            None => Box::new(future::ok(None)),
+                        };
+                        dead.map(move |dead| QueueSummary {
+                            name: queue.name,
+                            pending: queue.pending,
+                            dead,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                future::join_all(tasks)
+            })
+            .map(|summaries| json(&summaries));
+        Box::new(task)
+    }
+
+    /// List the workers that are alive and dead.
+    fn list_workers(&self) -> Reply {
+        let workers = match self.workers {
+            Some(ref workers) => workers,
+            None => {
+                let summary = WorkersSummary {
+                    alive: Vec::new(),
+                    dead: Vec::new(),
+                };
+                return Box::new(future::ok(json(&summary)));
+            }
+        };
+        let task = workers
+            .list()
+            .join(workers.dead())
+            .map(|(alive, dead)| json(&WorkersSummary { alive, dead }));
+        Box::new(task)
+    }
+
+    /// List the dead jobs of the given queue.
+    fn dead(&self, queue: &str) -> Reply {
+        let dead_letters = match self.client.dead_letters() {
+            Some(dead_letters) => dead_letters,
+            None => return Box::new(future::ok(unsupported())),
+        };
+        let task = dead_letters.list(queue).map(|jobs| {
+            let jobs = jobs.iter().map(DeadJobView::from).collect::<Vec<_>>();
+            json(&jobs)
+        });
+        Box::new(task)
+    }
+
+    /// List the attempts at executing the job with the given ID.
+    fn attempts(&self, id: &str) -> Reply {
+        let audit = match self.audit {
+            Some(ref audit) => audit,
+            None => {
+                let message = "The history of jobs isn't available, see Dashboard::audit_sink";
+                return Box::new(future::ok(error(StatusCode::NOT_FOUND, message)));
+            }
+        };
+        let id = match id.parse::<Uuid>() {
+            Ok(id) => id,
+            Err(_) => {
+                return Box::new(future::ok(error(StatusCode::BAD_REQUEST, "Invalid job ID")))
+            }
+        };
+        let task = audit.history(id).map(|attempts| json(&attempts));
+        Box::new(task)
+    }
+
+    /// Apply the given action to the dead job with the given ID.
+    fn apply(&self, queue: &str, id: &str, action: Action) -> Reply {
+        let dead_letters = match self.client.dead_letters() {
+            Some(dead_letters) => dead_letters,
+            None => return Box::new(future::ok(unsupported())),
+        };
+        let id = match id.parse::<Uuid>() {
+            Ok(id) => id,
+            Err(_) => {
+                return Box::new(future::ok(error(StatusCode::BAD_REQUEST, "Invalid job ID")))
+            }
+        };
+        info!(
+            "[{}] Applying {:?} to dead job from the dashboard",
+            id, action
+        );
+        let task = match action {
+            Action::Retry => dead_letters.requeue(queue, id),
+            Action::Delete => dead_letters.remove(queue, id),
+        };
+        let task = task.map(|found| {
+            if found {
+                empty(StatusCode::NO_CONTENT)
+            } else {
+                error(StatusCode::NOT_FOUND, "Dead job not found")
+            }
+        });
+        Box::new(task)
+    }
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn html(body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response
+}
+
+fn json<T>(value: &T) -> Response<Body>
+where
+    T: serde::Serialize,
+{
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut response = Response::new(Body::from(body));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(e) => {
+            error!("Couldn't serialize dashboard response: {}", e);
+            empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    #[derive(Serialize)]
+    struct Message<'a> {
+        error: &'a str,
+    }
+
+    let mut response = json(&Message { error: message });
+    *response.status_mut() = status;
+    response
+}
+
+fn unsupported() -> Response<Body> {
+    error(
+        StatusCode::NOT_FOUND,
+        "The broker doesn't support dead-letter queues",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+
+    use batch::audit::{Attempt, JsonLines};
+    use batch::{memory, queue, Broker, Codec, Priority, Status};
+    use futures::Stream;
+
+    fn request(dashboard: &Dashboard, method: Method, uri: &str) -> (StatusCode, Vec<u8>) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = dashboard.handle(&request).wait().unwrap();
+        let status = response.status();
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, body.to_vec())
+    }
+
+    fn properties() -> Properties {
+        Properties {
+            id: Uuid::new_v4(),
+            task: "job".into(),
+            exchange: "batch.tests".into(),
+            routing_key: "dashboard".into(),
+            priority: Priority::Normal,
+            timeout: None,
+            delay: None,
+            retries: 0,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: None,
+            expires: None,
+            origin: None,
+            codec: Codec::Json,
+            compression: None,
+            version: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn dead_jobs() {
+        let connection = memory::Connection::new(vec![
+            queue("tests.dashboard").bind("batch.tests", "dashboard")
+        ]);
+        let dashboard = Dashboard::new(Client::new(connection.clone()));
+        let properties = properties();
+        connection.publish(b"{}", &properties).wait().unwrap();
+        let delivery = connection
+            .consume(1)
+            .wait()
+            .unwrap()
+            .wait()
+            .next()
+            .unwrap()
+            .unwrap();
+        delivery.dead_letter(Failure::Error).wait().unwrap();
+
+        let (status, body) = request(&dashboard, Method::GET, "/api/queues");
+        assert_eq!(status, StatusCode::OK);
+        let queues: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(queues[0]["pending"], 0);
+        assert_eq!(queues[0]["dead"], 1);
+
+        let (status, body) = request(&dashboard, Method::GET, "/api/queues/tests.dashboard/dead");
+        assert_eq!(status, StatusCode::OK);
+        let jobs: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(jobs[0]["payload"], "{}");
+        assert_eq!(jobs[0]["failure"], "Error");
+
+        let retry = format!("/api/queues/tests.dashboard/dead/{}/retry", properties.id);
+        let (status, _) = request(&dashboard, Method::POST, &retry);
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(connection.len("tests.dashboard"), 1);
+        let (status, _) = request(&dashboard, Method::POST, &retry);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let invalid = "/api/queues/tests.dashboard/dead/nope/delete";
+        let (status, _) = request(&dashboard, Method::POST, invalid);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn attempts() {
+        let connection = memory::Connection::new(vec![queue("tests.dashboard")]);
+        let dashboard = Dashboard::new(Client::new(connection.clone()));
+        let id = Uuid::new_v4();
+        let uri = format!("/api/jobs/{}/attempts", id);
+        let (status, _) = request(&dashboard, Method::GET, &uri);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let path = env::temp_dir().join(format!("batch-dashboard-{}.jsonl", id));
+        let sink = JsonLines::new(path.clone());
+        let now = Utc::now();
+        let attempt = Attempt {
+            id,
+            task: "job".into(),
+            queue: Some("tests.dashboard".into()),
+            attempt: 1,
+            worker: Uuid::new_v4(),
+            hostname: None,
+            pid: 42,
+            started_at: now,
+            finished_at: now,
+            duration: Default::default(),
+            status: Status::Success,
+            error: None,
+        };
+        sink.record(&attempt).wait().unwrap();
+        let dashboard = dashboard.audit_sink(sink);
+        let (status, body) = request(&dashboard, Method::GET, &uri);
+        assert_eq!(status, StatusCode::OK);
+        let attempts: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(attempts[0]["pid"], 42);
+        assert_eq!(attempts[0]["status"], "Success");
+        fs::remove_file(&path).unwrap();
+    }
+}
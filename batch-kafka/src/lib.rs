@@ -0,0 +1,451 @@
+//! An Apache Kafka broker for the batch crate.
+//!
+//! Each queue consumed by a worker is a Kafka topic, and the routing key of a job is used as the
+//! name of the topic it is sent to. Exchanges are ignored. Workers sharing the same group ID join
+//! the same consumer group, the partitions of the topics being balanced between them.
+//!
+//! Offsets are only committed once jobs are acknowledged or rejected, so that the jobs of a worker
+//! that died are consumed again by the other members of its group. Since jobs don't necessarily
+//! complete in order, the committed offset of a partition never goes past its oldest job still
+//! being processed: other jobs of the partition may be executed twice if the worker dies.
+//!
+//! Failed jobs aren't sent back to their topic: each queue is paired with a retry topic, named
+//! after it with the `.retry` suffix, which receives the retries of its jobs as well as its
+//! delayed jobs. Retry topics are consumed by a separate consumer group (the group ID followed by
+//! `.retry`), which waits for jobs to be due before handing them to the worker, without holding
+//! back the jobs of the queues themselves. Both topics of a queue must exist, unless the Kafka
+//! cluster creates topics automatically.
+//!
+//! Priorities are not supported by this broker.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate batch_kafka;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use batch::Client;
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let task = batch_kafka::Connection::open("localhost:9092", "batch-workers", vec!["emails"])
+//!         .map(Client::new)
+//!         .map(|_client| {
+//!             // Send jobs using the client
+//!         })
+//!         .map_err(|e| eprintln!("Couldn't connect to Kafka: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/batch-kafka/0.1.0")]
+#![deny(missing_debug_implementations)]
+#![deny(missing_docs)]
+
+extern crate batch;
+extern crate failure;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate rdkafka;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+
+mod offsets;
+
+use std::cmp;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use batch::{Broker, Deliveries, Delivery as BatchDelivery, Error, Properties};
+use futures::sync::mpsc;
+use futures::{future, Future, Sink, Stream};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{Message as KafkaMessage, Offset, TopicPartitionList};
+
+use offsets::Offsets;
+
+/// The suffix of the topics receiving the retries and the delayed jobs of a queue.
+const RETRY_SUFFIX: &str = ".retry";
+
+/// The number of milliseconds a consumer waits for messages before checking whether it should
+/// stop.
+const POLL_TIMEOUT: u64 = 1_000;
+
+/// The number of milliseconds the producer waits for room in its queue when sending a job.
+const SEND_TIMEOUT: i64 = 5_000;
+
+/// The maximum time allowed between two polls of the retry consumer, which stops polling while
+/// waiting for a job to be due (24 hours, the maximum allowed by Kafka).
+const RETRY_POLL_INTERVAL: u64 = 24 * 60 * 60 * 1_000;
+
+/// The representation of a job stored in Kafka.
+#[derive(Serialize, Deserialize)]
+struct Message {
+    properties: Properties,
+    payload: Vec<u8>,
+    /// The time at which a delayed job is due, in milliseconds since the UNIX epoch.
+    #[serde(default)]
+    due_at: Option<u64>,
+}
+
+/// A `Broker` implementation backed by Apache Kafka.
+pub struct Connection {
+    brokers: String,
+    group: String,
+    queues: Vec<String>,
+    producer: FutureProducer,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Connection {{ brokers: {:?} group: {:?} queues: {:?} }}",
+            self.brokers, self.group, self.queues
+        )
+    }
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1_000 + u64::from(now.subsec_millis())
+}
+
+/// Return the topic storing the retries and the delayed jobs of the given queue.
+fn retry_topic(queue: &str) -> String {
+    format!("{}{}", queue, RETRY_SUFFIX)
+}
+
+impl Connection {
+    /// Create a new connection to the Kafka cluster reachable through the given comma-separated
+    /// list of brokers.
+    ///
+    /// The given queues are the ones jobs will be consumed from when this connection is used by
+    /// a `Worker`, as a member of the given consumer group.
+    pub fn open<Q, S>(
+        brokers: &str,
+        group: &str,
+        queues: Q,
+    ) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        Q: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create::<FutureProducer>();
+        let task = future::result(producer.map_err(Error::broker)).map({
+            let brokers = brokers.to_string();
+            let group = group.to_string();
+            let queues = queues.into_iter().map(Into::into).collect();
+            move |producer| Connection {
+                brokers,
+                group,
+                queues,
+                producer,
+            }
+        });
+        Box::new(task)
+    }
+
+    /// Create a consumer of the given topics, as a member of the given group.
+    fn subscribe(
+        &self,
+        group: &str,
+        topics: &[String],
+        poll_interval: Option<u64>,
+    ) -> StdResult<Arc<BaseConsumer>, Error> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest");
+        if let Some(poll_interval) = poll_interval {
+            config.set("max.poll.interval.ms", &poll_interval.to_string());
+        }
+        let consumer = config.create::<BaseConsumer>().map_err(Error::broker)?;
+        let topics = topics.iter().map(String::as_str).collect::<Vec<_>>();
+        consumer.subscribe(&topics).map_err(Error::broker)?;
+        Ok(Arc::new(consumer))
+    }
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let message = Message {
+            properties: properties.clone(),
+            payload: payload.to_vec(),
+            due_at: properties.delay.map(|delay| {
+                now_millis() + delay.as_secs() * 1_000 + u64::from(delay.subsec_millis())
+            }),
+        };
+        let body = match serde_json::to_vec(&message) {
+            Ok(body) => body,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let topic = if properties.retries > 0 || properties.delay.is_some() {
+            retry_topic(&properties.routing_key)
+        } else {
+            properties.routing_key.clone()
+        };
+        let id = properties.id;
+        let key = id.to_string();
+        trace!("Sending job {} to {:?}", id, topic);
+        let record = FutureRecord::to(&topic).key(&key).payload(&body);
+        let task = self
+            .producer
+            .send(record, SEND_TIMEOUT)
+            .then(move |result| match result {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err((e, _))) => Err(Error::broker(e)),
+                Err(_) => Err(Error::broker(failure::err_msg(format!(
+                    "The delivery of job {} was canceled",
+                    id
+                )))),
+            });
+        Box::new(task)
+    }
+
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let retry_topics = self
+            .queues
+            .iter()
+            .map(|queue| retry_topic(queue))
+            .collect::<Vec<_>>();
+        let consumers = self
+            .subscribe(&self.group, &self.queues, None)
+            .and_then(|consumer| {
+                let retry_group = format!("{}{}", self.group, RETRY_SUFFIX);
+                let retry_consumer =
+                    self.subscribe(&retry_group, &retry_topics, Some(RETRY_POLL_INTERVAL))?;
+                Ok((consumer, retry_consumer))
+            });
+        let (consumer, retry_consumer) = match consumers {
+            Ok(consumers) => consumers,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (sender, receiver) = mpsc::channel(usize::from(cmp::max(1, prefetch)));
+        let stopped = Arc::new(AtomicBool::new(false));
+        for (consumer, delayed) in vec![(consumer, false), (retry_consumer, true)] {
+            let poller = Poller {
+                consumer,
+                offsets: Arc::new(Mutex::new(Offsets::default())),
+                delayed,
+                stopped: Arc::clone(&stopped),
+            };
+            let sender = sender.clone();
+            thread::Builder::new()
+                .name("batch-kafka".into())
+                .spawn(move || poller.run(sender))
+                .expect("couldn't spawn the Kafka consumer thread");
+        }
+        // Stop the consumer threads once the stream of deliveries is dropped.
+        let guard = Guard(stopped);
+        let stream = receiver
+            .map(move |delivery| {
+                let _ = &guard;
+                delivery
+            })
+            .map_err(|_| Error::broker(failure::err_msg("The Kafka consumers stopped")));
+        Box::new(future::ok(Box::new(stream) as Deliveries))
+    }
+}
+
+/// Sets the given flag when dropped.
+struct Guard(Arc<AtomicBool>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Polls a consumer on a dedicated thread, forwarding the jobs it receives.
+struct Poller {
+    consumer: Arc<BaseConsumer>,
+    offsets: Arc<Mutex<Offsets>>,
+    delayed: bool,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Poller {
+    fn run(self, mut sender: mpsc::Sender<Box<BatchDelivery>>) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let message = match self.consumer.poll(Duration::from_millis(POLL_TIMEOUT)) {
+                Some(Ok(message)) => message.detach(),
+                Some(Err(e)) => {
+                    error!("Couldn't receive a job from Kafka: {}", e);
+                    continue;
+                }
+                None => continue,
+            };
+            let topic = message.topic().to_string();
+            let queue = if self.delayed {
+                topic.trim_right_matches(RETRY_SUFFIX).to_string()
+            } else {
+                topic.clone()
+            };
+            let (partition, offset) = (message.partition(), message.offset());
+            self.offsets
+                .lock()
+                .unwrap()
+                .start(&topic, partition, offset);
+            let parsed = message
+                .payload()
+                .ok_or_else(|| failure::err_msg("empty message"))
+                .and_then(|payload| serde_json::from_slice::<Message>(payload).map_err(Into::into));
+            let parsed = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    error!("Couldn't parse job received from {:?}: {}", topic, e);
+                    // Commit past the message so that it isn't consumed again.
+                    if let Err(e) = commit(&self.consumer, &self.offsets, &topic, partition, offset)
+                    {
+                        error!("Couldn't commit offset of {:?}: {}", topic, e);
+                    }
+                    continue;
+                }
+            };
+            let delivery = Delivery {
+                message: parsed,
+                queue,
+                topic,
+                partition,
+                offset,
+                consumer: Arc::clone(&self.consumer),
+                offsets: Arc::clone(&self.offsets),
+            };
+            if self.delayed && !self.wait(&delivery.message) {
+                break;
+            }
+            sender = match sender.send(Box::new(delivery)).wait() {
+                Ok(sender) => sender,
+                Err(_) => break,
+            };
+        }
+        trace!("Stopping Kafka consumer");
+    }
+
+    /// Wait for the given job to be due, unless the consumer is stopped first.
+    fn wait(&self, message: &Message) -> bool {
+        let due_at = match message.due_at {
+            Some(due_at) => due_at,
+            None => return true,
+        };
+        loop {
+            if self.stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+            let now = now_millis();
+            if now >= due_at {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(cmp::min(due_at - now, POLL_TIMEOUT)));
+        }
+    }
+}
+
+/// A job received from Kafka.
+pub struct Delivery {
+    message: Message,
+    queue: String,
+    topic: String,
+    partition: i32,
+    offset: i64,
+    consumer: Arc<BaseConsumer>,
+    offsets: Arc<Mutex<Offsets>>,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ topic: {:?} partition: {} offset: {} properties: {:?} }}",
+            self.topic, self.partition, self.offset, self.message.properties
+        )
+    }
+}
+
+impl Delivery {
+    /// Commit the offset of this job's partition, once it is processed.
+    fn commit(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let result = commit(
+            &self.consumer,
+            &self.offsets,
+            &self.topic,
+            self.partition,
+            self.offset,
+        );
+        Box::new(future::result(result))
+    }
+}
+
+/// Record that the message at the given offset was processed, committing the offset of its
+/// partition if possible.
+fn commit(
+    consumer: &BaseConsumer,
+    offsets: &Mutex<Offsets>,
+    topic: &str,
+    partition: i32,
+    offset: i64,
+) -> StdResult<(), Error> {
+    let next = match offsets.lock().unwrap().complete(topic, partition, offset) {
+        Some(next) => next,
+        None => return Ok(()),
+    };
+    trace!(
+        "Committing offset {} of {:?} partition {}",
+        next,
+        topic,
+        partition
+    );
+    let mut list = TopicPartitionList::new();
+    list.add_partition_offset(topic, partition, Offset::Offset(next));
+    consumer
+        .commit(&list, CommitMode::Async)
+        .map_err(Error::broker)
+}
+
+impl BatchDelivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.message.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.message.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        Some(&self.queue)
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Committing acked job {}", self.message.properties.id);
+        self.commit()
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Committing rejected job {}", self.message.properties.id);
+        self.commit()
+    }
+}
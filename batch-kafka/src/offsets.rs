@@ -0,0 +1,80 @@
+//! Tracking of the offsets to commit.
+//!
+//! Jobs consumed from a partition don't necessarily complete in order: the offset committed for a
+//! partition is the one of the oldest job still being processed, or the one following the last
+//! processed job if there is none, so that no job is skipped when the partition is consumed again.
+
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Default)]
+struct Partition {
+    pending: BTreeSet<i64>,
+    next: i64,
+}
+
+/// The offsets of the jobs being processed, per partition.
+#[derive(Default)]
+pub struct Offsets {
+    partitions: HashMap<(String, i32), Partition>,
+}
+
+impl Offsets {
+    /// Record that the job at the given offset is being processed.
+    pub fn start(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partitions
+            .entry((topic.to_string(), partition))
+            .or_insert_with(Partition::default)
+            .pending
+            .insert(offset);
+    }
+
+    /// Record that the job at the given offset was processed.
+    ///
+    /// Returns the offset to commit for its partition, or `None` if an older job of the partition
+    /// is still being processed.
+    pub fn complete(&mut self, topic: &str, partition: i32, offset: i64) -> Option<i64> {
+        let partition = self.partitions.get_mut(&(topic.to_string(), partition))?;
+        let oldest = *partition.pending.iter().next()?;
+        partition.pending.remove(&offset);
+        partition.next = cmp::max(partition.next, offset + 1);
+        if offset != oldest {
+            return None;
+        }
+        Some(
+            partition
+                .pending
+                .iter()
+                .next()
+                .cloned()
+                .unwrap_or(partition.next),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut offsets = Offsets::default();
+        offsets.start("emails", 0, 3);
+        offsets.start("emails", 0, 4);
+        assert_eq!(offsets.complete("emails", 0, 3), Some(4));
+        assert_eq!(offsets.complete("emails", 0, 4), Some(5));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut offsets = Offsets::default();
+        offsets.start("emails", 0, 3);
+        offsets.start("emails", 0, 4);
+        offsets.start("emails", 0, 5);
+        offsets.start("emails", 1, 7);
+        assert_eq!(offsets.complete("emails", 0, 5), None);
+        assert_eq!(offsets.complete("emails", 1, 7), Some(8));
+        assert_eq!(offsets.complete("emails", 0, 3), Some(4));
+        assert_eq!(offsets.complete("emails", 0, 4), Some(6));
+    }
+}
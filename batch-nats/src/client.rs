@@ -0,0 +1,420 @@
+//! A minimal client for the core NATS protocol.
+//!
+//! Only what the broker needs is implemented: publishing, subscribing and requests. Messages
+//! carrying headers are received so that the status of JetStream control messages can be read,
+//! but their other headers are discarded. The client doesn't reconnect: once the connection is
+//! lost, every subscription ends.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use serde_json;
+use tokio;
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+use uuid::Uuid;
+
+/// A message received from NATS.
+#[derive(Debug, PartialEq)]
+pub struct Msg {
+    /// The subject the message was published to.
+    pub subject: String,
+    /// The subject replies to this message should be published to.
+    pub reply: Option<String>,
+    /// The status code of a control message, e.g: 404 when no JetStream message is available.
+    pub status: Option<u16>,
+    /// The payload of the message.
+    pub payload: Vec<u8>,
+}
+
+/// The credentials sent when connecting to NATS.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Credentials {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pass: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+}
+
+/// The options of the `CONNECT` operation.
+#[derive(Serialize)]
+struct Connect<'a> {
+    verbose: bool,
+    pedantic: bool,
+    lang: &'a str,
+    version: &'a str,
+    protocol: u8,
+    headers: bool,
+    no_responders: bool,
+    #[serde(flatten)]
+    credentials: &'a Credentials,
+}
+
+/// An operation sent by the server.
+#[derive(Debug, PartialEq)]
+enum ServerOp {
+    Info,
+    Msg(u64, Msg),
+    Ping,
+    Pong,
+    Ok,
+    Err(String),
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid NATS protocol line {:?}", line),
+    )
+}
+
+/// Return the status code of a message from its headers, e.g: `NATS/1.0 404 No Messages`.
+fn status(headers: &[u8]) -> Option<u16> {
+    let headers = String::from_utf8_lossy(headers);
+    let line = headers.lines().next()?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// The codec of the NATS protocol, decoding server operations and encoding raw client
+/// operations.
+struct Codec;
+
+impl Decoder for Codec {
+    type Item = ServerOp;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<ServerOp>, io::Error> {
+        let end = match buf.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let line = String::from_utf8_lossy(&buf[..end]).into_owned();
+        let mut parts = line.split_whitespace();
+        let op = parts.next().unwrap_or("").to_ascii_uppercase();
+        let op = match op.as_str() {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            // HMSG <subject> <sid> [reply-to] <#header bytes> <#total bytes>
+            "MSG" | "HMSG" => {
+                let args = parts.collect::<Vec<_>>();
+                let sizes = if op == "HMSG" { 2 } else { 1 };
+                if args.len() != 2 + sizes && args.len() != 3 + sizes {
+                    return Err(invalid(&line));
+                }
+                let parse = |arg: &str| arg.parse::<usize>().map_err(|_| invalid(&line));
+                let total = parse(args[args.len() - 1])?;
+                let header_len = if sizes == 2 {
+                    parse(args[args.len() - 2])?
+                } else {
+                    0
+                };
+                if header_len > total {
+                    return Err(invalid(&line));
+                }
+                if buf.len() < end + 2 + total + 2 {
+                    return Ok(None);
+                }
+                let sid = args[1].parse::<u64>().map_err(|_| invalid(&line))?;
+                buf.split_to(end + 2);
+                let data = buf.split_to(total + 2);
+                let msg = Msg {
+                    subject: args[0].to_string(),
+                    reply: if args.len() == 3 + sizes {
+                        Some(args[2].to_string())
+                    } else {
+                        None
+                    },
+                    status: if sizes == 2 {
+                        status(&data[..header_len])
+                    } else {
+                        None
+                    },
+                    payload: data[header_len..total].to_vec(),
+                };
+                return Ok(Some(ServerOp::Msg(sid, msg)));
+            }
+            "INFO" => ServerOp::Info,
+            "PING" => ServerOp::Ping,
+            "PONG" => ServerOp::Pong,
+            "+OK" => ServerOp::Ok,
+            "-ERR" => ServerOp::Err(line[4..].trim().to_string()),
+            _ => return Err(invalid(&line)),
+        };
+        buf.split_to(end + 2);
+        Ok(Some(op))
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, op: Vec<u8>, buf: &mut BytesMut) -> Result<(), io::Error> {
+        buf.extend_from_slice(&op);
+        Ok(())
+    }
+}
+
+struct Shared {
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    subscriptions: Mutex<HashMap<u64, mpsc::UnboundedSender<Msg>>>,
+    next_sid: AtomicUsize,
+}
+
+impl Shared {
+    fn send(&self, op: Vec<u8>) -> Result<(), io::Error> {
+        self.sender.unbounded_send(op).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection to NATS was lost",
+            )
+        })
+    }
+
+    fn dispatch(&self, op: ServerOp) {
+        match op {
+            ServerOp::Ping => {
+                let _ = self.send(b"PONG\r\n".to_vec());
+            }
+            ServerOp::Msg(sid, msg) => {
+                let mut subscriptions = self.subscriptions.lock().unwrap();
+                let delivered = subscriptions.get(&sid).map_or(false, |subscription| {
+                    subscription.unbounded_send(msg).is_ok()
+                });
+                if !delivered {
+                    subscriptions.remove(&sid);
+                }
+            }
+            ServerOp::Err(e) => error!("NATS server error: {}", e),
+            ServerOp::Info | ServerOp::Pong | ServerOp::Ok => {}
+        }
+    }
+}
+
+/// A connection to a NATS server.
+#[derive(Clone)]
+pub struct Client {
+    shared: Arc<Shared>,
+}
+
+impl Client {
+    /// Connect to the NATS server at the given address.
+    ///
+    /// Must be called from within a Tokio runtime, the connection being driven by tasks spawned
+    /// on it.
+    pub fn connect(
+        addr: &SocketAddr,
+        credentials: Credentials,
+    ) -> Box<Future<Item = Self, Error = io::Error> + Send> {
+        let task = TcpStream::connect(addr)
+            .and_then(|stream| Framed::new(stream, Codec).into_future().map_err(|(e, _)| e))
+            .and_then(move |(op, framed)| {
+                if op != Some(ServerOp::Info) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the NATS server didn't send its INFO",
+                    ));
+                }
+                let connect = serde_json::to_string(&Connect {
+                    verbose: false,
+                    pedantic: false,
+                    lang: "rust",
+                    version: env!("CARGO_PKG_VERSION"),
+                    protocol: 1,
+                    headers: true,
+                    no_responders: true,
+                    credentials: &credentials,
+                })?;
+                let (sink, stream) = framed.split();
+                let (sender, receiver) = mpsc::unbounded();
+                let shared = Arc::new(Shared {
+                    sender,
+                    subscriptions: Mutex::new(HashMap::new()),
+                    next_sid: AtomicUsize::new(1),
+                });
+                shared.send(format!("CONNECT {}\r\nPING\r\n", connect).into_bytes())?;
+                let receiver = receiver.map_err(|_| io::Error::new(io::ErrorKind::Other, "closed"));
+                tokio::spawn(
+                    sink.send_all(receiver)
+                        .map(|_| ())
+                        .map_err(|e| error!("Couldn't write to NATS: {}", e)),
+                );
+                let reader = Arc::clone(&shared);
+                tokio::spawn(
+                    stream
+                        .for_each(move |op| {
+                            reader.dispatch(op);
+                            Ok(())
+                        })
+                        .map_err(|e| error!("Lost the connection to NATS: {}", e))
+                        .then(move |_| {
+                            // Dropping the senders ends every subscription.
+                            reader.subscriptions.lock().unwrap().clear();
+                            Ok(())
+                        }),
+                );
+                Ok(Client { shared })
+            });
+        Box::new(task)
+    }
+
+    /// Publish the given payload to the given subject.
+    pub fn publish(
+        &self,
+        subject: &str,
+        reply: Option<&str>,
+        payload: &[u8],
+    ) -> Result<(), io::Error> {
+        let mut op = match reply {
+            Some(reply) => format!("PUB {} {} {}\r\n", subject, reply, payload.len()),
+            None => format!("PUB {} {}\r\n", subject, payload.len()),
+        }
+        .into_bytes();
+        op.extend_from_slice(payload);
+        op.extend_from_slice(b"\r\n");
+        self.shared.send(op)
+    }
+
+    /// Subscribe to the given subject, returning the ID of the subscription and the stream of its
+    /// messages.
+    pub fn subscribe(
+        &self,
+        subject: &str,
+    ) -> Result<(u64, mpsc::UnboundedReceiver<Msg>), io::Error> {
+        let sid = self.shared.next_sid.fetch_add(1, Ordering::Relaxed) as u64;
+        let (sender, receiver) = mpsc::unbounded();
+        self.shared
+            .subscriptions
+            .lock()
+            .unwrap()
+            .insert(sid, sender);
+        self.shared
+            .send(format!("SUB {} {}\r\n", subject, sid).into_bytes())?;
+        Ok((sid, receiver))
+    }
+
+    /// End the given subscription.
+    pub fn unsubscribe(&self, sid: u64) -> Result<(), io::Error> {
+        self.shared.subscriptions.lock().unwrap().remove(&sid);
+        self.shared.send(format!("UNSUB {}\r\n", sid).into_bytes())
+    }
+
+    /// Return a new unique subject to receive replies on.
+    pub fn inbox() -> String {
+        format!("_INBOX.{}", Uuid::new_v4().simple())
+    }
+
+    /// Publish the given payload to the given subject, and wait at most `timeout` for a reply.
+    pub fn request(
+        &self,
+        subject: &str,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Box<Future<Item = Msg, Error = io::Error> + Send> {
+        let inbox = Client::inbox();
+        let sent = self.subscribe(&inbox).and_then(|(sid, replies)| {
+            self.shared
+                .send(format!("UNSUB {} 1\r\n", sid).into_bytes())?;
+            self.publish(subject, Some(&inbox), payload)?;
+            Ok((sid, replies))
+        });
+        let (sid, replies) = match sent {
+            Ok(sent) => sent,
+            Err(e) => return Box::new(::futures::future::err(e)),
+        };
+        let subject = subject.to_string();
+        let shared = Arc::clone(&self.shared);
+        let reply = replies.into_future().then(move |result| match result {
+            Ok((Some(msg), _)) => Ok(msg),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection to NATS was lost",
+            )),
+        });
+        let task = Timeout::new(reply, timeout).then(move |result| {
+            shared.subscriptions.lock().unwrap().remove(&sid);
+            result.map_err(|e| {
+                e.into_inner().unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no reply to the request sent to {:?}", subject),
+                    )
+                })
+            })
+        });
+        Box::new(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(raw: &[u8]) -> Vec<ServerOp> {
+        let mut buf = BytesMut::from(raw);
+        let mut ops = Vec::new();
+        while let Some(op) = Codec.decode(&mut buf).unwrap() {
+            ops.push(op);
+        }
+        assert!(buf.is_empty());
+        ops
+    }
+
+    #[test]
+    fn decode_ops() {
+        let ops = decode(b"INFO {\"server_id\":\"test\"}\r\nPING\r\n+OK\r\n-ERR 'Unknown Protocol Operation'\r\n");
+        let expected = vec![
+            ServerOp::Info,
+            ServerOp::Ping,
+            ServerOp::Ok,
+            ServerOp::Err("'Unknown Protocol Operation'".into()),
+        ];
+        assert_eq!(ops, expected);
+    }
+
+    #[test]
+    fn decode_messages() {
+        let ops = decode(
+            b"MSG batch.emails 1 $JS.ACK.BATCH.emails.1.2.2.0.0 5\r\nhello\r\n\
+              HMSG _INBOX.abc 2 28 28\r\nNATS/1.0 404 No Messages\r\n\r\n\r\n",
+        );
+        let expected = vec![
+            ServerOp::Msg(
+                1,
+                Msg {
+                    subject: "batch.emails".into(),
+                    reply: Some("$JS.ACK.BATCH.emails.1.2.2.0.0".into()),
+                    status: None,
+                    payload: b"hello".to_vec(),
+                },
+            ),
+            ServerOp::Msg(
+                2,
+                Msg {
+                    subject: "_INBOX.abc".into(),
+                    reply: None,
+                    status: Some(404),
+                    payload: Vec::new(),
+                },
+            ),
+        ];
+        assert_eq!(ops, expected);
+    }
+
+    #[test]
+    fn partial_message() {
+        let mut buf = BytesMut::from(&b"MSG batch.emails 1 5\r\nhel"[..]);
+        assert!(Codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"lo\r\n");
+        assert!(Codec.decode(&mut buf).unwrap().is_some());
+    }
+}
@@ -0,0 +1,588 @@
+//! A NATS JetStream broker for the batch crate.
+//!
+//! Jobs are stored in a single JetStream stream named `BATCH`, created when opening a connection,
+//! with the work queue retention policy: a job is removed from the stream once it has been
+//! acknowledged. The routing key of a job is appended to the `batch.` prefix to form the subject
+//! it is published to, and each queue consumed by a worker is a durable pull consumer of the
+//! stream, named after the queue and filtering on the matching subject. Exchanges are ignored.
+//!
+//! Retries are driven by the redelivery of messages: a job that isn't acknowledged within the
+//! consumer's ack wait (5 minutes by default, see `Connection::ack_wait`), e.g: because its worker
+//! died, is delivered again. The number of times a message was delivered is accounted for in the
+//! job's retries, so that these redeliveries are bounded by the job's `retries` value. The ack
+//! wait applies to every job of a queue, it must therefore be longer than the `timeout` of the
+//! jobs sent to it, unless their handler calls `Context::touch` to reset it.
+//!
+//! Delayed jobs are published right away and handed back to JetStream with a delay when they are
+//! received before they are due.
+//!
+//! Priorities are not supported by this broker, and the connection isn't restored if it is lost.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate batch_nats;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use batch::Client;
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let task = batch_nats::Connection::open("nats://localhost:4222", vec!["emails"])
+//!         .map(Client::new)
+//!         .map(|_client| {
+//!             // Send jobs using the client
+//!         })
+//!         .map_err(|e| eprintln!("Couldn't connect to NATS: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/batch-nats/0.1.0")]
+#![deny(missing_debug_implementations)]
+#![deny(missing_docs)]
+
+extern crate batch;
+extern crate bytes;
+extern crate failure;
+extern crate futures;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio;
+extern crate uuid;
+
+mod client;
+
+use std::cmp;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::result::Result as StdResult;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use batch::{Broker, Deliveries, Delivery as BatchDelivery, Error, Lease, Properties};
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use serde_json::Value;
+
+use client::{Client, Credentials, Msg};
+
+/// The name of the stream jobs are stored in.
+const STREAM: &str = "BATCH";
+
+/// The prefix of the subjects jobs are published to.
+const SUBJECT_PREFIX: &str = "batch.";
+
+/// The default number of seconds a consumer waits for a job to be acknowledged before delivering
+/// it again.
+const DEFAULT_ACK_WAIT: u64 = 5 * 60;
+
+/// The number of seconds a pull request waits for messages.
+const PULL_EXPIRES: u64 = 5;
+
+/// The number of seconds to wait for the reply of the JetStream API.
+const API_TIMEOUT: u64 = 5;
+
+/// The error code returned by JetStream when a stream already exists with another configuration.
+const STREAM_NAME_IN_USE: u64 = 10058;
+
+/// The representation of a job stored in JetStream.
+#[derive(Serialize, Deserialize)]
+struct Message {
+    properties: Properties,
+    payload: Vec<u8>,
+    /// The time at which a delayed job is due, in milliseconds since the UNIX epoch.
+    #[serde(default)]
+    due_at: Option<u64>,
+}
+
+/// A `Broker` implementation backed by NATS JetStream.
+pub struct Connection {
+    client: Client,
+    queues: Vec<String>,
+    ack_wait: Duration,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Connection {{ queues: {:?} ack_wait: {:?} }}",
+            self.queues, self.ack_wait
+        )
+    }
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1_000 + u64::from(now.subsec_millis())
+}
+
+fn to_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+/// Parse an URL of the form `nats://[user:password@|token@]host[:port]`.
+fn parse_url(url: &str) -> StdResult<(SocketAddr, Credentials), Error> {
+    let invalid = || Error::broker(failure::err_msg(format!("Invalid NATS URL {:?}", url)));
+    let rest = if url.starts_with("nats://") {
+        &url["nats://".len()..]
+    } else {
+        url
+    };
+    let rest = rest.trim_right_matches('/');
+    let mut credentials = Credentials::default();
+    let host = match rest.rfind('@') {
+        Some(at) => {
+            let userinfo = &rest[..at];
+            match userinfo.find(':') {
+                Some(colon) => {
+                    credentials.user = Some(userinfo[..colon].to_string());
+                    credentials.pass = Some(userinfo[colon + 1..].to_string());
+                }
+                None => credentials.auth_token = Some(userinfo.to_string()),
+            }
+            &rest[at + 1..]
+        }
+        None => rest,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:4222", host)
+    };
+    let addr = host
+        .to_socket_addrs()
+        .map_err(Error::broker)?
+        .next()
+        .ok_or_else(invalid)?;
+    Ok((addr, credentials))
+}
+
+/// Return the subject jobs sent with the given routing key are published to.
+fn subject(routing_key: &str) -> String {
+    format!("{}{}", SUBJECT_PREFIX, routing_key)
+}
+
+/// Return the name of the durable consumer of the given queue.
+///
+/// Consumer names can't contain the characters used as wildcards or separators in subjects.
+fn durable(queue: &str) -> String {
+    queue
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Return the number of times the message with the given reply subject was delivered.
+///
+/// The reply subject of a JetStream message is of the form
+/// `$JS.ACK.<stream>.<consumer>.<delivered>.<stream seq>.<consumer seq>.<timestamp>.<pending>`,
+/// possibly with a domain and an account hash inserted after `$JS.ACK`.
+fn delivered_count(reply: &str) -> Option<u32> {
+    let tokens = reply.split('.').collect::<Vec<_>>();
+    let index = match tokens.len() {
+        9 => 4,
+        len if len >= 11 => 6,
+        _ => return None,
+    };
+    tokens[index].parse().ok()
+}
+
+/// Call the given endpoint of the JetStream API.
+///
+/// Fails if JetStream isn't enabled or if the API returned an error, unless its code is one of
+/// the `allowed` ones.
+fn api(
+    client: &Client,
+    subject: &str,
+    request: &Value,
+    allowed: &'static [u64],
+) -> Box<Future<Item = Value, Error = Error> + Send> {
+    let request = match serde_json::to_vec(request) {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::err(Error::broker(e))),
+    };
+    let subject = subject.to_string();
+    let task = client
+        .request(&subject, &request, Duration::from_secs(API_TIMEOUT))
+        .map_err(Error::broker)
+        .and_then(move |reply| -> StdResult<Value, Error> {
+            if reply.status == Some(503) {
+                return Err(Error::broker(failure::err_msg(format!(
+                    "No responder for {:?}, is JetStream enabled?",
+                    subject
+                ))));
+            }
+            let response: Value = serde_json::from_slice(&reply.payload).map_err(Error::broker)?;
+            if let Some(error) = response.get("error") {
+                let code = error["err_code"].as_u64().unwrap_or(0);
+                if !allowed.contains(&code) {
+                    return Err(Error::broker(failure::err_msg(format!(
+                        "JetStream error on {:?}: {}",
+                        subject, error["description"]
+                    ))));
+                }
+            }
+            Ok(response)
+        });
+    Box::new(task)
+}
+
+impl Connection {
+    /// Create a new connection to the NATS server at the given URL.
+    ///
+    /// The given queues are the ones jobs will be consumed from when this connection is used by
+    /// a `Worker`. The stream storing the jobs is created if it doesn't exist.
+    pub fn open<Q, S>(url: &str, queues: Q) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        Q: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let (addr, credentials) = match parse_url(url) {
+            Ok(parsed) => parsed,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let queues = queues.into_iter().map(Into::into).collect::<Vec<_>>();
+        let task = Client::connect(&addr, credentials)
+            .map_err(Error::broker)
+            .and_then(|client| {
+                trace!("Creating JetStream stream {:?}", STREAM);
+                let config = json!({
+                    "name": STREAM,
+                    "subjects": [format!("{}>", SUBJECT_PREFIX)],
+                    "retention": "workqueue",
+                    "storage": "file",
+                });
+                let subject = format!("$JS.API.STREAM.CREATE.{}", STREAM);
+                api(&client, &subject, &config, &[STREAM_NAME_IN_USE]).map(move |_| client)
+            })
+            .map(move |client| Connection {
+                client,
+                queues,
+                ack_wait: Duration::from_secs(DEFAULT_ACK_WAIT),
+            });
+        Box::new(task)
+    }
+
+    /// Set the duration consumers wait for a job to be acknowledged before delivering it again.
+    ///
+    /// It must be longer than the timeout of the jobs consumed through this connection. The ack
+    /// wait of an existing consumer can't be changed: it must be deleted first.
+    pub fn ack_wait(mut self, ack_wait: Duration) -> Self {
+        self.ack_wait = ack_wait;
+        self
+    }
+
+    /// Create the durable consumer of the given queue, if it doesn't exist.
+    fn create_consumer(&self, queue: &str) -> Box<Future<Item = Value, Error = Error> + Send> {
+        let name = durable(queue);
+        trace!("Creating JetStream consumer {:?}", name);
+        let request = json!({
+            "stream_name": STREAM,
+            "config": {
+                "durable_name": name,
+                "deliver_policy": "all",
+                "ack_policy": "explicit",
+                "ack_wait": to_nanos(self.ack_wait),
+                "filter_subject": subject(queue),
+            },
+        });
+        let subject = format!("$JS.API.CONSUMER.DURABLE.CREATE.{}.{}", STREAM, name);
+        api(&self.client, &subject, &request, &[])
+    }
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let message = Message {
+            properties: properties.clone(),
+            payload: payload.to_vec(),
+            due_at: properties.delay.map(|delay| {
+                now_millis() + delay.as_secs() * 1_000 + u64::from(delay.subsec_millis())
+            }),
+        };
+        let body = match serde_json::to_vec(&message) {
+            Ok(body) => body,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let subject = subject(&properties.routing_key);
+        let id = properties.id;
+        trace!("Sending job {} to {:?}", id, subject);
+        let task = self
+            .client
+            .request(&subject, &body, Duration::from_secs(API_TIMEOUT))
+            .map_err(Error::broker)
+            .and_then(move |reply| -> StdResult<(), Error> {
+                if reply.status == Some(503) {
+                    return Err(Error::broker(failure::err_msg(format!(
+                        "No JetStream stream stores the subject {:?}",
+                        subject
+                    ))));
+                }
+                let ack: Value = serde_json::from_slice(&reply.payload).map_err(Error::broker)?;
+                if let Some(error) = ack.get("error") {
+                    return Err(Error::broker(failure::err_msg(format!(
+                        "JetStream didn't store job {}: {}",
+                        id, error["description"]
+                    ))));
+                }
+                Ok(())
+            });
+        Box::new(task)
+    }
+
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let client = self.client.clone();
+        let queues = self.queues.clone();
+        let ack_wait = self.ack_wait;
+        let batch = cmp::max(1, prefetch);
+        let consumers = self
+            .queues
+            .iter()
+            .map(|queue| self.create_consumer(queue))
+            .collect::<Vec<_>>();
+        let task = future::join_all(consumers)
+            .and_then(move |_| -> StdResult<_, Error> {
+                let inbox = Client::inbox();
+                let (_, replies) = client.subscribe(&inbox).map_err(Error::broker)?;
+                Ok((client, inbox, replies))
+            })
+            .map(move |(client, inbox, replies)| -> Deliveries {
+                let stream = stream::unfold((replies, 0usize), move |(replies, index)| {
+                    if queues.is_empty() {
+                        return None;
+                    }
+                    let queue = queues[index % queues.len()].clone();
+                    let next = format!("$JS.API.CONSUMER.MSG.NEXT.{}.{}", STREAM, durable(&queue));
+                    let request = json!({
+                        "batch": batch,
+                        "expires": to_nanos(Duration::from_secs(PULL_EXPIRES)),
+                    })
+                    .to_string();
+                    if let Err(e) = client.publish(&next, Some(&inbox), request.as_bytes()) {
+                        return Some(Box::new(future::err(Error::broker(e)))
+                            as Box<Future<Item = _, Error = _> + Send>);
+                    }
+                    let client = client.clone();
+                    let task =
+                        future::loop_fn((replies, Vec::new()), move |(replies, mut msgs)| {
+                            replies.into_future().then(move |result| match result {
+                                Ok((Some(msg), replies)) => {
+                                    match msg.status {
+                                        Some(404) | Some(408) => {
+                                            return Ok(Loop::Break((replies, msgs)))
+                                        }
+                                        Some(status) => {
+                                            warn!("Pull request ended with status {}", status);
+                                            return Ok(Loop::Break((replies, msgs)));
+                                        }
+                                        None => msgs.push(msg),
+                                    }
+                                    if msgs.len() >= usize::from(batch) {
+                                        Ok(Loop::Break((replies, msgs)))
+                                    } else {
+                                        Ok(Loop::Continue((replies, msgs)))
+                                    }
+                                }
+                                _ => Err(Error::broker(failure::err_msg(
+                                    "The connection to NATS was lost",
+                                ))),
+                            })
+                        })
+                        .map(move |(replies, msgs)| {
+                            let deliveries = msgs
+                                .into_iter()
+                                .filter_map(|msg| {
+                                    Delivery::new(msg, queue.clone(), ack_wait, client.clone())
+                                })
+                                .map(|delivery| Box::new(delivery) as Box<BatchDelivery>)
+                                .collect::<Vec<_>>();
+                            (
+                                stream::iter_ok::<_, Error>(deliveries),
+                                (replies, index + 1),
+                            )
+                        });
+                    Some(Box::new(task) as Box<Future<Item = _, Error = _> + Send>)
+                })
+                .flatten();
+                Box::new(stream)
+            });
+        Box::new(task)
+    }
+}
+
+/// A job received from JetStream.
+pub struct Delivery {
+    message: Message,
+    queue: String,
+    reply: String,
+    client: Client,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ queue: {:?} properties: {:?} }}",
+            self.queue, self.message.properties
+        )
+    }
+}
+
+impl Delivery {
+    /// Create a delivery from the given message, unless it is invalid or not due yet.
+    fn new(msg: Msg, queue: String, ack_wait: Duration, client: Client) -> Option<Self> {
+        let reply = msg.reply?;
+        let mut message = match serde_json::from_slice::<Message>(&msg.payload) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Couldn't parse job received from {:?}: {}", queue, e);
+                // Terminate the message so that it isn't delivered again.
+                let _ = client.publish(&reply, None, b"+TERM");
+                return None;
+            }
+        };
+        if let Some(due_at) = message.due_at {
+            let now = now_millis();
+            if due_at > now {
+                trace!("Job {} isn't due yet, delaying it", message.properties.id);
+                let nak = json!({ "delay": (due_at - now) * 1_000_000 });
+                let nak = format!("-NAK {}", nak);
+                if let Err(e) = client.publish(&reply, None, nak.as_bytes()) {
+                    error!("Couldn't delay job {}: {}", message.properties.id, e);
+                }
+                return None;
+            }
+        }
+        // The early delivery of a delayed job doesn't count as a retry.
+        let early = if message.due_at.is_some() { 1 } else { 0 };
+        let redeliveries = delivered_count(&reply)
+            .unwrap_or(1)
+            .saturating_sub(1 + early);
+        message.properties.retries = cmp::max(message.properties.retries, redeliveries);
+        if message
+            .properties
+            .timeout
+            .map_or(false, |timeout| timeout >= ack_wait)
+        {
+            warn!(
+                "The timeout of job {} exceeds the ack wait of queue {:?}, it may run twice",
+                message.properties.id, queue
+            );
+        }
+        Some(Delivery {
+            message,
+            queue,
+            reply,
+            client,
+        })
+    }
+
+    /// Send the given acknowledgement for the JetStream message of this job.
+    fn respond(self, ack: &[u8]) -> Box<Future<Item = (), Error = Error> + Send> {
+        let result = self.client.publish(&self.reply, None, ack);
+        Box::new(future::result(result.map_err(Error::broker)))
+    }
+}
+
+impl BatchDelivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.message.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.message.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        Some(&self.queue)
+    }
+
+    fn lease(&self) -> Option<Box<Lease>> {
+        Some(Box::new(AckWait {
+            reply: self.reply.clone(),
+            client: self.client.clone(),
+        }))
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Acknowledging job {}", self.message.properties.id);
+        self.respond(b"+ACK")
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Terminating rejected job {}", self.message.properties.id);
+        self.respond(b"+TERM")
+    }
+}
+
+/// The ack wait of a job received from JetStream, reset while its handler calls
+/// `Context::touch`.
+///
+/// JetStream resets the ack wait of a message when told that it is in progress, the duration of
+/// the extension is thus the ack wait of the consumer.
+struct AckWait {
+    reply: String,
+    client: Client,
+}
+
+impl fmt::Debug for AckWait {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "AckWait {{ reply: {:?} }}", self.reply)
+    }
+}
+
+impl Lease for AckWait {
+    fn extend(&self, _duration: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        let result = self.client.publish(&self.reply, None, b"+WPI");
+        Box::new(future::result(result.map_err(Error::broker)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delivered() {
+        assert_eq!(
+            delivered_count("$JS.ACK.BATCH.emails.3.42.7.1530000000000000000.0"),
+            Some(3)
+        );
+        assert_eq!(
+            delivered_count(
+                "$JS.ACK.hub.ACCOUNTHASH.BATCH.emails.2.42.7.1530000000000000000.0.token"
+            ),
+            Some(2)
+        );
+        assert_eq!(delivered_count("_INBOX.abc"), None);
+    }
+
+    #[test]
+    fn durable_names() {
+        assert_eq!(durable("emails"), "emails");
+        assert_eq!(durable("emails.high priority"), "emails_high_priority");
+    }
+}
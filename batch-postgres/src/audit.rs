@@ -0,0 +1,125 @@
+//! An audit log of the attempts at executing jobs, stored in PostgreSQL.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use batch::audit::{Attempt, AuditSink};
+use batch::{Error, Status};
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use serde_json;
+use uuid::Uuid;
+
+use {connect, to_seconds, Pool};
+
+/// The statements creating the table storing the attempts and its index.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS batch_attempts (
+    seq BIGSERIAL PRIMARY KEY,
+    id TEXT NOT NULL,
+    task TEXT NOT NULL,
+    worker TEXT NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    duration DOUBLE PRECISION NOT NULL,
+    success BOOLEAN NOT NULL,
+    attempt TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS batch_attempts_id ON batch_attempts (id, seq);
+";
+
+/// The statement storing an attempt.
+const INSERT: &str = "
+INSERT INTO batch_attempts (id, task, worker, started_at, duration, success, attempt)
+VALUES ($1, $2, $3, to_timestamp($4), $5, $6, $7)
+";
+
+/// The statement fetching the attempts of a job.
+const HISTORY: &str = "SELECT seq, attempt FROM batch_attempts WHERE id = $1 ORDER BY seq";
+
+/// An `AuditSink` implementation backed by a PostgreSQL table.
+///
+/// Attempts are stored in the `batch_attempts` table as JSON documents, along with the columns
+/// most useful to query them with SQL: the ID and name of the job, the worker that executed it,
+/// when it started, how long it took and whether it succeeded.
+pub struct AuditLog {
+    pool: Pool,
+    executor: CpuPool,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "AuditLog {{ table: \"batch_attempts\" }}")
+    }
+}
+
+impl AuditLog {
+    /// Create a new audit log stored in the PostgreSQL database at the given URL.
+    ///
+    /// The table storing the attempts is created if it doesn't exist.
+    pub fn open(url: &str) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let task = connect(url, "batch_attempts", SCHEMA)
+            .map(|(pool, executor)| AuditLog { pool, executor });
+        Box::new(task)
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&self, attempt: &Attempt) -> Box<Future<Item = (), Error = Error> + Send> {
+        let serialized = match serde_json::to_string(attempt) {
+            Ok(serialized) => serialized,
+            Err(e) => return Box::new(future::err(Error::audit(e))),
+        };
+        let pool = self.pool.clone();
+        let id = attempt.id.to_string();
+        let task = attempt.task.clone();
+        let worker = attempt.worker.to_string();
+        let started_at = attempt.started_at.timestamp() as f64
+            + f64::from(attempt.started_at.timestamp_subsec_micros()) / 1e6;
+        let duration = to_seconds(attempt.duration);
+        let success = attempt.status == Status::Success;
+        let insert = move || -> StdResult<(), Error> {
+            pool.get()
+                .map_err(Error::audit)?
+                .execute(
+                    INSERT,
+                    &[
+                        &id,
+                        &task,
+                        &worker,
+                        &started_at,
+                        &duration,
+                        &success,
+                        &serialized,
+                    ],
+                )
+                .map_err(Error::audit)?;
+            Ok(())
+        };
+        Box::new(self.executor.spawn_fn(insert))
+    }
+
+    fn history(&self, id: Uuid) -> Box<Future<Item = Vec<Attempt>, Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let id = id.to_string();
+        let task = self.executor.spawn_fn(move || -> StdResult<_, Error> {
+            let conn = pool.get().map_err(Error::audit)?;
+            let rows = conn.query(HISTORY, &[&id]).map_err(Error::audit)?;
+            let attempts = rows
+                .iter()
+                .filter_map(|row| {
+                    let seq: i64 = row.get(0);
+                    let attempt: String = row.get(1);
+                    match serde_json::from_str::<Attempt>(&attempt) {
+                        Ok(attempt) => Some(attempt),
+                        Err(e) => {
+                            error!("Couldn't parse attempt {} of job {}: {}", seq, id, e);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            Ok(attempts)
+        });
+        Box::new(task)
+    }
+}
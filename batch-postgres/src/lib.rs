@@ -0,0 +1,442 @@
+//! A PostgreSQL broker for the batch crate.
+//!
+//! Jobs are stored in the `batch_jobs` table, created when opening a connection if it doesn't
+//! exist. Each queue consumed by a worker is the set of rows whose `queue` column matches its
+//! name, which is the routing key of the jobs. Exchanges are ignored.
+//!
+//! Workers poll the table for jobs using `SELECT ... FOR UPDATE SKIP LOCKED`, so that concurrent
+//! workers never fetch the same job, taking the priority of jobs into account: the most urgent
+//! jobs are fetched first, and jobs of the same priority in the order they are due. Delayed jobs
+//! are stored along with the time at which they are due and aren't fetched before.
+//!
+//! A fetched job is locked for the duration of its `timeout` (or 5 minutes if it has none), plus
+//! a grace period, and is fetched again once its lock expired if it wasn't acknowledged or
+//! rejected in the meantime, e.g: because its worker died. The number of times a job was fetched
+//! is accounted for in the job's retries, so that these redeliveries are bounded by the job's
+//! `retries` value. Handlers running longer than their lock can keep their job by calling
+//! `Context::touch`, see `WorkerBuilder::lease_duration`.
+//!
+//! `Outbox` stores jobs in the `batch_outbox` table within the transactions of the application,
+//! to be published by a `batch::Relay` once they are committed, whichever broker is used.
+//! `AuditLog` records the attempts at executing jobs in the `batch_attempts` table, see
+//! `batch::audit`. `Schedule` stores the jobs sent with `Client::send_at` in the
+//! `batch_scheduled` table until a `batch::Dispatcher` publishes them, so that they survive
+//! restarts.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate batch_postgres;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use batch::Client;
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let task = batch_postgres::Connection::open("postgres://localhost/batch", vec!["emails"])
+//!         .map(Client::new)
+//!         .map(|_client| {
+//!             // Send jobs using the client
+//!         })
+//!         .map_err(|e| eprintln!("Couldn't connect to PostgreSQL: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/batch-postgres/0.1.0")]
+#![deny(missing_debug_implementations)]
+#![deny(missing_docs)]
+
+extern crate batch;
+extern crate chrono;
+extern crate failure;
+extern crate futures;
+extern crate futures_cpupool;
+#[macro_use]
+extern crate log;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate serde_json;
+extern crate tokio_timer;
+extern crate uuid;
+
+use std::cmp;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::time::{Duration, Instant};
+
+use batch::{
+    Broker, Deliveries, Delivery as BatchDelivery, Error, Lease, Priority, Properties,
+};
+use futures::{future, stream, Future, Stream};
+use futures_cpupool::CpuPool;
+use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+use tokio_timer::Delay;
+
+mod audit;
+mod outbox;
+mod schedule;
+
+pub use audit::AuditLog;
+pub use outbox::Outbox;
+pub use schedule::Schedule;
+
+type Pool = r2d2::Pool<PostgresConnectionManager>;
+
+/// The statements creating the table storing the jobs and its index.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS batch_jobs (
+    seq BIGSERIAL PRIMARY KEY,
+    queue TEXT NOT NULL,
+    priority SMALLINT NOT NULL,
+    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    timeout DOUBLE PRECISION,
+    locked_until TIMESTAMPTZ,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    properties TEXT NOT NULL,
+    payload BYTEA NOT NULL
+);
+CREATE INDEX IF NOT EXISTS batch_jobs_fetch ON batch_jobs (queue, priority DESC, run_at, seq);
+";
+
+/// The statement storing a job.
+const INSERT: &str = "
+INSERT INTO batch_jobs (queue, priority, run_at, timeout, properties, payload)
+VALUES ($1, $2, now() + make_interval(secs => $3), $4, $5, $6)
+";
+
+/// The statement fetching and locking the next jobs of the given queues.
+const FETCH: &str = "
+UPDATE batch_jobs
+SET locked_until = now() + make_interval(secs => COALESCE(timeout, $3) + $4),
+    attempts = attempts + 1
+WHERE seq IN (
+    SELECT seq FROM batch_jobs
+    WHERE queue = ANY($1)
+      AND run_at <= now()
+      AND (locked_until IS NULL OR locked_until < now())
+    ORDER BY priority DESC, run_at, seq
+    LIMIT $2
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING seq, queue, attempts, properties, payload
+";
+
+/// The statement deleting a job.
+const DELETE: &str = "DELETE FROM batch_jobs WHERE seq = $1";
+
+/// The statement extending the lock of a job.
+const EXTEND: &str = "
+UPDATE batch_jobs
+SET locked_until = now() + make_interval(secs => $2)
+WHERE seq = $1
+";
+
+/// The number of seconds a job without timeout is locked for once fetched.
+const DEFAULT_LOCK: f64 = 5.0 * 60.0;
+
+/// The number of seconds added to a job's timeout when locking it.
+const LOCK_GRACE: f64 = 30.0;
+
+/// The default number of milliseconds to wait before polling again queues without due jobs.
+const DEFAULT_POLL_INTERVAL: u64 = 1_000;
+
+/// The maximum number of connections to PostgreSQL.
+const POOL_SIZE: u32 = 4;
+
+fn to_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn to_i16(priority: Priority) -> i16 {
+    match priority {
+        Priority::Trivial => 0,
+        Priority::Low => 1,
+        Priority::Normal => 2,
+        Priority::High => 3,
+        Priority::Critical => 4,
+    }
+}
+
+/// A `Broker` implementation backed by a PostgreSQL table.
+pub struct Connection {
+    pool: Pool,
+    executor: CpuPool,
+    queues: Vec<String>,
+    poll_interval: Duration,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Connection {{ queues: {:?} poll_interval: {:?} }}",
+            self.queues, self.poll_interval
+        )
+    }
+}
+
+impl Connection {
+    /// Create a new connection to the PostgreSQL database at the given URL.
+    ///
+    /// The given queues are the ones jobs will be consumed from when this connection is used by
+    /// a `Worker`. The table storing the jobs is created if it doesn't exist.
+    pub fn open<Q, S>(url: &str, queues: Q) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        Q: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let queues = queues.into_iter().map(Into::into).collect::<Vec<_>>();
+        let task = connect(url, "batch_jobs", SCHEMA).map(|(pool, executor)| Connection {
+            pool,
+            executor,
+            queues,
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL),
+        });
+        Box::new(task)
+    }
+
+    /// Set the duration to wait before polling again queues without due jobs.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+}
+
+/// Create a pool of connections to the database at the given URL, along with the executor running
+/// the queries, then create the given table with the given statements if it doesn't exist.
+fn connect(
+    url: &str,
+    table: &'static str,
+    schema: &'static str,
+) -> Box<Future<Item = (Pool, CpuPool), Error = Error> + Send> {
+    let manager = match PostgresConnectionManager::new(url, TlsMode::None) {
+        Ok(manager) => manager,
+        Err(e) => return Box::new(future::err(Error::broker(e))),
+    };
+    let executor = CpuPool::new(POOL_SIZE as usize);
+    let handle = executor.clone();
+    let task = handle.spawn_fn(move || -> StdResult<_, Error> {
+        let pool = r2d2::Pool::builder()
+            .max_size(POOL_SIZE)
+            .build(manager)
+            .map_err(Error::broker)?;
+        trace!("Creating the {} table", table);
+        pool.get()
+            .map_err(Error::broker)?
+            .batch_execute(schema)
+            .map_err(Error::broker)?;
+        Ok((pool, executor))
+    });
+    Box::new(task)
+}
+
+/// Fetch and lock at most `limit` due jobs of the given queues.
+fn fetch(
+    pool: &Pool,
+    executor: &CpuPool,
+    queues: &[String],
+    limit: u16,
+) -> StdResult<Vec<Delivery>, Error> {
+    let conn = pool.get().map_err(Error::broker)?;
+    let rows = conn
+        .query(
+            FETCH,
+            &[&queues, &i64::from(limit), &DEFAULT_LOCK, &LOCK_GRACE],
+        )
+        .map_err(Error::broker)?;
+    let deliveries = rows
+        .iter()
+        .filter_map(|row| {
+            let seq: i64 = row.get(0);
+            let queue: String = row.get(1);
+            let attempts: i32 = row.get(2);
+            let properties: String = row.get(3);
+            let mut properties = match serde_json::from_str::<Properties>(&properties) {
+                Ok(properties) => properties,
+                Err(e) => {
+                    error!("Couldn't parse job {} of {:?}: {}", seq, queue, e);
+                    return None;
+                }
+            };
+            let redeliveries = cmp::max(attempts, 1) as u32 - 1;
+            properties.retries = cmp::max(properties.retries, redeliveries);
+            Some(Delivery {
+                seq,
+                queue,
+                properties,
+                payload: row.get(4),
+                pool: pool.clone(),
+                executor: executor.clone(),
+            })
+        })
+        .collect();
+    Ok(deliveries)
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let serialized = match serde_json::to_string(properties) {
+            Ok(serialized) => serialized,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let pool = self.pool.clone();
+        let payload = payload.to_vec();
+        let queue = properties.routing_key.clone();
+        let priority = to_i16(properties.priority);
+        let delay = properties.delay.map_or(0.0, to_seconds);
+        let timeout = properties.timeout.map(to_seconds);
+        let id = properties.id;
+        let task = self.executor.spawn_fn(move || -> StdResult<(), Error> {
+            trace!("Storing job {} in {:?}", id, queue);
+            pool.get()
+                .map_err(Error::broker)?
+                .execute(
+                    INSERT,
+                    &[&queue, &priority, &delay, &timeout, &serialized, &payload],
+                )
+                .map_err(Error::broker)?;
+            Ok(())
+        });
+        Box::new(task)
+    }
+
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let executor = self.executor.clone();
+        let queues = self.queues.clone();
+        let poll_interval = self.poll_interval;
+        let limit = cmp::max(1, prefetch);
+        let stream = stream::unfold(false, move |wait| {
+            if queues.is_empty() {
+                return None;
+            }
+            let pool = pool.clone();
+            let queues = queues.clone();
+            let executor = executor.clone();
+            let delay: Box<Future<Item = (), Error = Error> + Send> = if wait {
+                Box::new(Delay::new(Instant::now() + poll_interval).map_err(Error::broker))
+            } else {
+                Box::new(future::ok(()))
+            };
+            let task = delay
+                .and_then(move |_| {
+                    let fetcher = executor.clone();
+                    fetcher.spawn_fn(move || fetch(&pool, &executor, &queues, limit))
+                })
+                .map(move |deliveries| {
+                    let wait = deliveries.is_empty();
+                    (stream::iter_ok::<_, Error>(deliveries), wait)
+                });
+            Some(task)
+        })
+        .flatten()
+        .map(|delivery| Box::new(delivery) as Box<BatchDelivery>);
+        Box::new(future::ok(Box::new(stream) as Deliveries))
+    }
+}
+
+/// A job fetched from PostgreSQL.
+pub struct Delivery {
+    seq: i64,
+    queue: String,
+    properties: Properties,
+    payload: Vec<u8>,
+    pool: Pool,
+    executor: CpuPool,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ seq: {} queue: {:?} properties: {:?} }}",
+            self.seq, self.queue, self.properties
+        )
+    }
+}
+
+impl Delivery {
+    /// Delete this job from its table.
+    fn delete(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let pool = self.pool;
+        let seq = self.seq;
+        let delete = move || -> StdResult<(), Error> {
+            pool.get()
+                .map_err(Error::broker)?
+                .execute(DELETE, &[&seq])
+                .map_err(Error::broker)?;
+            Ok(())
+        };
+        Box::new(self.executor.spawn_fn(delete))
+    }
+}
+
+impl BatchDelivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        Some(&self.queue)
+    }
+
+    fn lease(&self) -> Option<Box<Lease>> {
+        Some(Box::new(Lock {
+            seq: self.seq,
+            pool: self.pool.clone(),
+            executor: self.executor.clone(),
+        }))
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Deleting acked job {}", self.properties.id);
+        self.delete()
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Deleting rejected job {}", self.properties.id);
+        self.delete()
+    }
+}
+
+/// The lock of a job fetched from PostgreSQL, extended while its handler calls `Context::touch`.
+struct Lock {
+    seq: i64,
+    pool: Pool,
+    executor: CpuPool,
+}
+
+impl fmt::Debug for Lock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Lock {{ seq: {} }}", self.seq)
+    }
+}
+
+impl Lease for Lock {
+    fn extend(&self, duration: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let seq = self.seq;
+        let extend = move || -> StdResult<(), Error> {
+            pool.get()
+                .map_err(Error::broker)?
+                .execute(EXTEND, &[&seq, &to_seconds(duration)])
+                .map_err(Error::broker)?;
+            Ok(())
+        };
+        Box::new(self.executor.spawn_fn(extend))
+    }
+}
@@ -0,0 +1,146 @@
+//! A transactional outbox stored in PostgreSQL.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use batch::{Error, Outbox as BatchOutbox, OutboxEntry, Properties};
+use futures::Future;
+use futures_cpupool::CpuPool;
+use r2d2_postgres::postgres::GenericConnection;
+use serde_json;
+
+use {connect, Pool};
+
+/// The statements creating the table storing the outbox and its index.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS batch_outbox (
+    seq BIGSERIAL PRIMARY KEY,
+    id TEXT NOT NULL,
+    properties TEXT NOT NULL,
+    payload BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS batch_outbox_pending ON batch_outbox (seq) WHERE sent_at IS NULL;
+";
+
+/// The statement storing an entry.
+const INSERT: &str = "INSERT INTO batch_outbox (id, properties, payload) VALUES ($1, $2, $3)";
+
+/// The statement fetching the entries not sent yet.
+const PENDING: &str = "
+SELECT seq, properties, payload FROM batch_outbox
+WHERE sent_at IS NULL
+ORDER BY seq
+LIMIT $1
+";
+
+/// The statement marking an entry as sent.
+const MARK_SENT: &str = "UPDATE batch_outbox SET sent_at = now() WHERE id = $1";
+
+/// An `Outbox` implementation backed by a PostgreSQL table.
+///
+/// Entries are stored in the `batch_outbox` table with `Outbox::insert`, usually within the
+/// transaction writing the data the job is about, and are published by a `batch::Relay`. Entries
+/// marked sent are kept along with the date they were sent at, and can be deleted once they are
+/// no longer useful, e.g: `DELETE FROM batch_outbox WHERE sent_at < now() - interval '7 days'`.
+pub struct Outbox {
+    pool: Pool,
+    executor: CpuPool,
+}
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Outbox {{ table: \"batch_outbox\" }}")
+    }
+}
+
+impl Outbox {
+    /// Create a new outbox stored in the PostgreSQL database at the given URL.
+    ///
+    /// The table storing the entries is created if it doesn't exist.
+    pub fn open(url: &str) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let task = connect(url, "batch_outbox", SCHEMA)
+            .map(|(pool, executor)| Outbox { pool, executor });
+        Box::new(task)
+    }
+
+    /// Store the given entry using the given connection or transaction.
+    ///
+    /// The entry is only published once the transaction it was stored in is committed, and
+    /// never if it is rolled back.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate batch;
+    /// # extern crate batch_postgres;
+    /// # extern crate r2d2_postgres;
+    /// # use batch::{Error, OutboxEntry};
+    /// # use r2d2_postgres::postgres::Connection;
+    /// #
+    /// fn sign_up(conn: &Connection, email: OutboxEntry) -> Result<(), Error> {
+    ///     let transaction = conn.transaction().map_err(Error::broker)?;
+    ///     // Store the user using the transaction, then the job sending them an email
+    ///     batch_postgres::Outbox::insert(&transaction, &email)?;
+    ///     transaction.commit().map_err(Error::broker)?;
+    ///     Ok(())
+    /// }
+    /// # fn main() {}
+    /// ```
+    pub fn insert<C>(conn: &C, entry: &OutboxEntry) -> StdResult<(), Error>
+    where
+        C: GenericConnection,
+    {
+        let properties = serde_json::to_string(&entry.properties).map_err(Error::broker)?;
+        let id = entry.properties.id.to_string();
+        trace!("Storing job {} in the outbox", id);
+        conn.execute(INSERT, &[&id, &properties, &entry.payload])
+            .map_err(Error::broker)?;
+        Ok(())
+    }
+}
+
+impl BatchOutbox for Outbox {
+    fn pending(&self, limit: usize) -> Box<Future<Item = Vec<OutboxEntry>, Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let limit = limit as i64;
+        let task = self.executor.spawn_fn(move || -> StdResult<_, Error> {
+            let conn = pool.get().map_err(Error::broker)?;
+            let rows = conn.query(PENDING, &[&limit]).map_err(Error::broker)?;
+            let entries = rows
+                .iter()
+                .filter_map(|row| {
+                    let seq: i64 = row.get(0);
+                    let properties: String = row.get(1);
+                    match serde_json::from_str::<Properties>(&properties) {
+                        Ok(properties) => Some(OutboxEntry {
+                            payload: row.get(2),
+                            properties,
+                        }),
+                        Err(e) => {
+                            error!("Couldn't parse entry {} of the outbox: {}", seq, e);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            Ok(entries)
+        });
+        Box::new(task)
+    }
+
+    fn mark_sent(&self, entry: &OutboxEntry) -> Box<Future<Item = (), Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let id = entry.properties.id.to_string();
+        let task = self.executor.spawn_fn(move || -> StdResult<(), Error> {
+            trace!("Marking job {} of the outbox as sent", id);
+            pool.get()
+                .map_err(Error::broker)?
+                .execute(MARK_SENT, &[&id])
+                .map_err(Error::broker)?;
+            Ok(())
+        });
+        Box::new(task)
+    }
+}
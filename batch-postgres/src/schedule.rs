@@ -0,0 +1,154 @@
+//! A store of scheduled jobs in PostgreSQL.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use batch::{Error, ScheduleStore, ScheduledJob};
+use chrono::{DateTime, Utc};
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use serde_json;
+use uuid::Uuid;
+
+use {connect, Pool};
+
+/// The statements creating the table storing the scheduled jobs and its index.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS batch_scheduled (
+    id TEXT PRIMARY KEY,
+    due_at TIMESTAMPTZ NOT NULL,
+    job TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS batch_scheduled_due ON batch_scheduled (due_at);
+";
+
+/// The statement storing a job, replacing the job with the same ID if any.
+const INSERT: &str = "
+INSERT INTO batch_scheduled (id, due_at, job) VALUES ($1, to_timestamp($2), $3)
+ON CONFLICT (id) DO UPDATE SET due_at = EXCLUDED.due_at, job = EXCLUDED.job
+";
+
+/// The statement fetching every scheduled job.
+const PENDING: &str = "SELECT id, job FROM batch_scheduled ORDER BY due_at, id";
+
+/// The statement fetching the jobs that are due.
+const DUE: &str = "
+SELECT id, job FROM batch_scheduled
+WHERE due_at <= to_timestamp($1)
+ORDER BY due_at, id
+LIMIT $2
+";
+
+/// The statement removing a job.
+const DELETE: &str = "DELETE FROM batch_scheduled WHERE id = $1";
+
+/// A `ScheduleStore` implementation backed by a PostgreSQL table.
+///
+/// Jobs sent with `Client::send_at` are stored in the `batch_scheduled` table as JSON documents,
+/// along with the date they are due at, until a `batch::Dispatcher` publishes them.
+pub struct Schedule {
+    pool: Pool,
+    executor: CpuPool,
+}
+
+impl fmt::Debug for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Schedule {{ table: \"batch_scheduled\" }}")
+    }
+}
+
+impl Schedule {
+    /// Create a new store of scheduled jobs in the PostgreSQL database at the given URL.
+    ///
+    /// The table storing the jobs is created if it doesn't exist.
+    pub fn open(url: &str) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let task = connect(url, "batch_scheduled", SCHEMA)
+            .map(|(pool, executor)| Schedule { pool, executor });
+        Box::new(task)
+    }
+
+    /// Return the jobs returned by the given statement and parameters.
+    fn select(
+        &self,
+        statement: &'static str,
+        now: Option<(f64, i64)>,
+    ) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let task = self.executor.spawn_fn(move || -> StdResult<_, Error> {
+            let conn = pool.get().map_err(Error::broker)?;
+            let rows = match now {
+                Some((now, limit)) => conn.query(statement, &[&now, &limit]),
+                None => conn.query(statement, &[]),
+            };
+            let rows = rows.map_err(Error::broker)?;
+            let jobs = rows
+                .iter()
+                .filter_map(|row| {
+                    let id: String = row.get(0);
+                    let job: String = row.get(1);
+                    match serde_json::from_str::<ScheduledJob>(&job) {
+                        Ok(job) => Some(job),
+                        Err(e) => {
+                            error!("Couldn't parse scheduled job {}: {}", id, e);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            Ok(jobs)
+        });
+        Box::new(task)
+    }
+}
+
+/// Return the number of seconds since the epoch of the given date.
+fn to_timestamp(date: DateTime<Utc>) -> f64 {
+    date.timestamp() as f64 + f64::from(date.timestamp_subsec_micros()) / 1e6
+}
+
+impl ScheduleStore for Schedule {
+    fn schedule(&self, job: &ScheduledJob) -> Box<Future<Item = (), Error = Error> + Send> {
+        let serialized = match serde_json::to_string(job) {
+            Ok(serialized) => serialized,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let pool = self.pool.clone();
+        let id = job.properties.id.to_string();
+        let due = to_timestamp(job.due);
+        let task = self.executor.spawn_fn(move || -> StdResult<(), Error> {
+            trace!("Scheduling job {}", id);
+            pool.get()
+                .map_err(Error::broker)?
+                .execute(INSERT, &[&id, &due, &serialized])
+                .map_err(Error::broker)?;
+            Ok(())
+        });
+        Box::new(task)
+    }
+
+    fn pending(&self) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send> {
+        self.select(PENDING, None)
+    }
+
+    fn due(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send> {
+        self.select(DUE, Some((to_timestamp(now), limit as i64)))
+    }
+
+    fn remove(&self, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let pool = self.pool.clone();
+        let id = id.to_string();
+        let task = self.executor.spawn_fn(move || -> StdResult<bool, Error> {
+            trace!("Removing scheduled job {}", id);
+            let removed = pool.get()
+                .map_err(Error::broker)?
+                .execute(DELETE, &[&id])
+                .map_err(Error::broker)?;
+            Ok(removed > 0)
+        });
+        Box::new(task)
+    }
+}
@@ -0,0 +1,1299 @@
+//! A Redis broker for the batch crate.
+//!
+//! Jobs are stored as JSON documents in Redis lists, one list per queue. The routing key of a job
+//! is used as the name of the queue it is pushed to, exchanges are ignored. When consumed, a job
+//! is atomically moved to a processing list (using `BRPOPLPUSH`) where it stays until it is
+//! acknowledged or rejected.
+//!
+//! Each job of a processing list is leased until its `timeout` elapsed, plus a grace period (or
+//! for 15 minutes if it has no timeout): the deadlines are stored in a sorted set per queue, and
+//! are extended while the job's handler calls `Context::touch`. Consumers push the jobs whose
+//! lease expired back to their queue, so that the jobs of a crashed worker are delivered again.
+//! When a consumer starts, the jobs of the processing lists without a lease (e.g: because a
+//! worker crashed right after receiving them) are leased from then on. Jobs that can't be parsed
+//! are moved to the dead-letter list of their queue as is.
+//!
+//! Delayed jobs are stored in a sorted set per queue, scored by the time they are due at, and are
+//! moved to their queue by consumers once they are due.
+//!
+//! Priorities are not supported by this broker: jobs are consumed in the order they were sent.
+//!
+//! The `Connection` also implements `StatusStore`: the status of each job is stored in a hash,
+//! expiring a week after its last update. It implements `workflow::Barrier` as well, storing the
+//! members of each chord that succeeded in a set expiring a week after its last update.
+//!
+//! Finally, the `Connection` implements `RateLimiter` and `Semaphore`, allowing workers to share
+//! their rate and concurrency limits: each token bucket is stored in a hash, and the holders of
+//! each semaphore in a sorted set scored by the expiration of their lease, both updated
+//! atomically by Lua scripts. It implements `SeenStore` too, recording each job executed by
+//! the workers deduplicating jobs in a key expiring at the end of their window.
+//!
+//! Jobs that exhausted their retries are pushed to a dead-letter list per queue, which can be
+//! inspected through the `DeadLetterQueue` implementation of the `Connection`. The jobs waiting
+//! in each queue can be counted and peeked at through its `QueueInspector` implementation.
+//!
+//! Alternatively, jobs can be stored in Sidekiq's format, using its key names, so that existing
+//! Sidekiq queues can be drained by Batch workers, see
+//! [`Connection::sidekiq`](struct.Connection.html#method.sidekiq).
+//!
+//! This crate also provides [`Lock`](struct.Lock.html), allowing multiple `Scheduler` instances
+//! to coordinate through Redis.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate batch_redis;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use batch::Client;
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let task = batch_redis::Connection::open("redis://127.0.0.1/", vec!["emails"])
+//!         .map(Client::new)
+//!         .map(|_client| {
+//!             // Send jobs using the client
+//!         })
+//!         .map_err(|e| eprintln!("Couldn't connect to Redis: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/batch-redis/0.1.0")]
+#![deny(missing_debug_implementations)]
+#![deny(missing_docs)]
+
+extern crate batch;
+extern crate chrono;
+extern crate failure;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate redis;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate uuid;
+
+mod sidekiq;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use batch::scheduler::Lock as SchedulerLock;
+use batch::workflow::Barrier;
+use batch::{
+    Broker, DeadJob, DeadLetterQueue, Deliveries, Delivery as BatchDelivery, Error, Failure, Lease,
+    Panic, PendingJob, ProgressReport, Properties, QueueInspector, RateLimit, RateLimiter,
+    SeenStore, Semaphore, Status, StatusReport, StatusStore,
+};
+use chrono::{DateTime, Utc};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use redis::async::{Connection as RedisConnection, SharedConnection};
+use redis::RedisError;
+use uuid::Uuid;
+
+/// The prefix of the lists storing pending jobs.
+const QUEUE_PREFIX: &str = "batch:queue:";
+
+/// The prefix of the lists storing jobs being executed.
+const PROCESSING_PREFIX: &str = "batch:processing:";
+
+/// The prefix of the sorted sets storing delayed jobs.
+const DELAYED_PREFIX: &str = "batch:delayed:";
+
+/// The prefix of the sorted sets storing the deadline of the lease of jobs being executed.
+const LEASE_PREFIX: &str = "batch:leases:";
+
+/// The number of seconds added to a job's timeout to get the duration of its lease.
+const LEASE_GRACE: u64 = 30;
+
+/// The number of seconds jobs without a timeout are leased for.
+const DEFAULT_LEASE: u64 = 15 * 60;
+
+/// The prefix of the lists storing the jobs that exhausted their retries.
+const DEAD_PREFIX: &str = "batch:dead:";
+
+/// The prefix of the hashes storing the status of jobs.
+const STATUS_PREFIX: &str = "batch:status:";
+
+/// The number of seconds the status of a job is kept after its last update (one week).
+const STATUS_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// The prefix of the sets storing the members of chords that succeeded.
+const CHORD_PREFIX: &str = "batch:chord:";
+
+/// The prefix of the hashes storing the token buckets of rate limits.
+const RATE_LIMIT_PREFIX: &str = "batch:rate:";
+
+/// Take a token from the bucket stored at `KEYS[1]`, holding up to `ARGV[1]` tokens refilled
+/// over `ARGV[2]` milliseconds. Returns 0 if a token was taken, or the number of milliseconds to
+/// wait for the next token.
+const RATE_LIMIT_SCRIPT: &str = r#"
+redis.replicate_commands()
+local capacity = tonumber(ARGV[1])
+local period = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or capacity
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * capacity / period)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) * period / capacity)
+end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], period)
+return wait
+"#;
+
+/// The prefix of the sorted sets storing the holders of semaphores.
+const SEMAPHORE_PREFIX: &str = "batch:semaphore:";
+
+/// The prefix of the keys recording the jobs executed by workers.
+const SEEN_PREFIX: &str = "batch:seen:";
+
+/// Take one of the `ARGV[1]` permits of the semaphore stored at `KEYS[1]` for the holder
+/// `ARGV[2]`, with a lease of `ARGV[3]` milliseconds. Returns 1 if a permit was taken.
+const SEMAPHORE_SCRIPT: &str = r#"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now)
+local held = redis.call('ZSCORE', KEYS[1], ARGV[2])
+if not held and redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('ZADD', KEYS[1], now + tonumber(ARGV[3]), ARGV[2])
+local last = redis.call('ZRANGE', KEYS[1], -1, -1, 'WITHSCORES')
+redis.call('PEXPIREAT', KEYS[1], last[2])
+return 1
+"#;
+
+/// The maximum number of due delayed jobs moved to their queue at once.
+const PROMOTE_BATCH: usize = 100;
+
+/// The number of seconds a consumer blocks waiting for a job before polling the next queue.
+const POLL_TIMEOUT: u64 = 1;
+
+/// The representation of a job stored in Redis.
+#[derive(Serialize, Deserialize)]
+struct Message {
+    properties: Properties,
+    payload: Vec<u8>,
+}
+
+/// Deserialize a job stored in Redis, in Sidekiq's format if `sidekiq` is true.
+fn parse(raw: &[u8], sidekiq: bool) -> StdResult<Message, serde_json::Error> {
+    if sidekiq {
+        sidekiq::decode(raw)
+    } else {
+        serde_json::from_slice(raw)
+    }
+}
+
+fn queue_key(queue: &str) -> String {
+    format!("{}{}", QUEUE_PREFIX, queue)
+}
+
+fn processing_key(queue: &str) -> String {
+    format!("{}{}", PROCESSING_PREFIX, queue)
+}
+
+fn lease_key(queue: &str) -> String {
+    format!("{}{}", LEASE_PREFIX, queue)
+}
+
+fn delayed_key(queue: &str) -> String {
+    format!("{}{}", DELAYED_PREFIX, queue)
+}
+
+fn dead_key(queue: &str) -> String {
+    format!("{}{}", DEAD_PREFIX, queue)
+}
+
+fn status_key(id: &Uuid) -> String {
+    format!("{}{}", STATUS_PREFIX, id)
+}
+
+fn chord_key(id: &Uuid) -> String {
+    format!("{}{}", CHORD_PREFIX, id)
+}
+
+fn rate_limit_key(key: &str) -> String {
+    format!("{}{}", RATE_LIMIT_PREFIX, key)
+}
+
+fn semaphore_key(key: &str) -> String {
+    format!("{}{}", SEMAPHORE_PREFIX, key)
+}
+
+fn seen_key(id: &Uuid) -> String {
+    format!("{}{}", SEEN_PREFIX, id)
+}
+
+/// Return the number of milliseconds elapsed since the UNIX epoch.
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1_000 + u64::from(now.subsec_nanos() / 1_000_000)
+}
+
+/// Move the jobs of the given sorted set whose score is at most `max` to their queue.
+///
+/// `target` returns the list each job is pushed to, along with the job to push, or `None` if the
+/// job can't be pushed. Each job is pushed by the consumer that successfully removed it from the
+/// sorted set, so that concurrent consumers never push the same job twice.
+fn promote<F>(
+    conn: RedisConnection,
+    key: String,
+    max: String,
+    target: F,
+) -> Box<Future<Item = RedisConnection, Error = RedisError> + Send>
+where
+    F: Fn(Vec<u8>) -> Option<(String, Vec<u8>)> + Send + 'static,
+{
+    let task = redis::cmd("ZRANGEBYSCORE")
+        .arg(&key)
+        .arg("-inf")
+        .arg(max)
+        .arg("LIMIT")
+        .arg(0)
+        .arg(PROMOTE_BATCH)
+        .query_async::<_, Vec<Vec<u8>>>(conn)
+        .and_then(move |(conn, due)| {
+            future::loop_fn((conn, due.into_iter()), move |(conn, mut due)| {
+                let raw = match due.next() {
+                    Some(raw) => raw,
+                    None => {
+                        return Box::new(future::ok(future::Loop::Break(conn)))
+                            as Box<Future<Item = _, Error = RedisError> + Send>
+                    }
+                };
+                let (list, job) = match target(raw.clone()) {
+                    Some(target) => target,
+                    None => return Box::new(future::ok(future::Loop::Continue((conn, due)))),
+                };
+                let task = redis::cmd("ZREM")
+                    .arg(&key)
+                    .arg(raw)
+                    .query_async::<_, u32>(conn)
+                    .and_then(move |(conn, removed)| {
+                        let task: Box<Future<Item = _, Error = RedisError> + Send> = if removed == 1
+                        {
+                            trace!("Moving due delayed job to {:?}", list);
+                            let task = redis::cmd("LPUSH")
+                                .arg(list)
+                                .arg(job)
+                                .query_async::<_, ()>(conn)
+                                .map(|(conn, _)| conn);
+                            Box::new(task)
+                        } else {
+                            Box::new(future::ok(conn))
+                        };
+                        task.map(move |conn| future::Loop::Continue((conn, due)))
+                    });
+                Box::new(task)
+            })
+        });
+    Box::new(task)
+}
+
+/// Push the jobs of the processing list of the given queue whose lease expired back to `list`.
+///
+/// Each job is pushed by the consumer that successfully removed it from the processing list, so
+/// that concurrent consumers never push the same job twice.
+fn recover(
+    conn: RedisConnection,
+    queue: String,
+    list: String,
+) -> Box<Future<Item = RedisConnection, Error = RedisError> + Send> {
+    let task = redis::cmd("ZRANGEBYSCORE")
+        .arg(lease_key(&queue))
+        .arg("-inf")
+        .arg(now_millis())
+        .arg("LIMIT")
+        .arg(0)
+        .arg(PROMOTE_BATCH)
+        .query_async::<_, Vec<Vec<u8>>>(conn)
+        .and_then(move |(conn, expired)| {
+            future::loop_fn((conn, expired.into_iter()), move |(conn, mut expired)| {
+                let raw = match expired.next() {
+                    Some(raw) => raw,
+                    None => {
+                        return Box::new(future::ok(future::Loop::Break(conn)))
+                            as Box<Future<Item = _, Error = RedisError> + Send>
+                    }
+                };
+                let leases = lease_key(&queue);
+                let list = list.clone();
+                let task = redis::cmd("LREM")
+                    .arg(processing_key(&queue))
+                    .arg(1)
+                    .arg(&raw)
+                    .query_async::<_, u32>(conn)
+                    .and_then(move |(conn, removed)| {
+                        let mut pipe = redis::pipe();
+                        pipe.cmd("ZREM").arg(leases).arg(&raw).ignore();
+                        if removed == 1 {
+                            warn!("Lease of job expired, pushing it back to {:?}", list);
+                            pipe.cmd("RPUSH").arg(list).arg(raw).ignore();
+                        }
+                        pipe.query_async::<_, ()>(conn)
+                            .map(move |(conn, _)| future::Loop::Continue((conn, expired)))
+                    });
+                Box::new(task)
+            })
+        });
+    Box::new(task)
+}
+
+/// Lease the jobs of the processing lists of the given queues that have no lease, e.g: because
+/// a worker crashed between receiving them and leasing them.
+fn adopt(
+    conn: RedisConnection,
+    queues: Arc<Vec<String>>,
+) -> Box<Future<Item = RedisConnection, Error = RedisError> + Send> {
+    let deadline = now_millis() + DEFAULT_LEASE * 1_000;
+    let task = future::loop_fn((conn, 0), move |(conn, index)| {
+        let queue = match queues.get(index) {
+            Some(queue) => queue.clone(),
+            None => {
+                return Box::new(future::ok(future::Loop::Break(conn)))
+                    as Box<Future<Item = _, Error = RedisError> + Send>
+            }
+        };
+        let task = redis::cmd("LRANGE")
+            .arg(processing_key(&queue))
+            .arg(0)
+            .arg(-1)
+            .query_async::<_, Vec<Vec<u8>>>(conn)
+            .and_then(move |(conn, raws)| {
+                let task: Box<Future<Item = _, Error = RedisError> + Send> = if raws.is_empty() {
+                    Box::new(future::ok(conn))
+                } else {
+                    let mut cmd = redis::cmd("ZADD");
+                    cmd.arg(lease_key(&queue)).arg("NX");
+                    for raw in raws {
+                        cmd.arg(deadline).arg(raw);
+                    }
+                    Box::new(cmd.query_async::<_, ()>(conn).map(|(conn, _)| conn))
+                };
+                task.map(move |conn| future::Loop::Continue((conn, index + 1)))
+            });
+        Box::new(task)
+    });
+    Box::new(task)
+}
+
+/// Move the scheduled jobs and the jobs waiting to be retried that are due to their queue, in
+/// Sidekiq's format.
+fn promote_sidekiq(
+    conn: RedisConnection,
+    keys: sidekiq::Keys,
+) -> Box<Future<Item = RedisConnection, Error = RedisError> + Send> {
+    let now = sidekiq::to_seconds(now_millis());
+    let retry = keys.retry();
+    let schedule = keys.schedule();
+    let target = move |raw: Vec<u8>| match sidekiq::enqueue(&raw, now) {
+        Ok((queue, job)) => Some((keys.queue(&queue), job)),
+        Err(e) => {
+            error!("Couldn't parse due Sidekiq job: {}", e);
+            None
+        }
+    };
+    let task = promote(conn, schedule, now.to_string(), target.clone())
+        .and_then(move |conn| promote(conn, retry, now.to_string(), target));
+    Box::new(task)
+}
+
+/// A `Broker` implementation backed by Redis.
+#[derive(Clone)]
+pub struct Connection {
+    client: redis::Client,
+    shared: Arc<Mutex<SharedConnection>>,
+    queues: Vec<String>,
+    sidekiq: Option<sidekiq::Keys>,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Connection {{ queues: {:?} sidekiq: {:?} }}",
+            self.queues, self.sidekiq
+        )
+    }
+}
+
+impl Connection {
+    /// Open a new connection to the Redis server at the given URL.
+    ///
+    /// The given queues are the ones jobs will be consumed from when this connection is used by
+    /// a `Worker`.
+    pub fn open<Q, S>(url: &str, queues: Q) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        Q: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let queues = queues.into_iter().map(Into::into).collect::<Vec<_>>();
+        let task = redis::Client::open(url)
+            .map_err(Error::broker)
+            .into_future()
+            .and_then(|client| {
+                trace!("Opening shared Redis connection");
+                client
+                    .get_shared_async_connection()
+                    .map_err(Error::broker)
+                    .map(move |shared| (client, shared))
+            })
+            .map(move |(client, shared)| Connection {
+                client,
+                shared: Arc::new(Mutex::new(shared)),
+                queues,
+                sidekiq: None,
+            });
+        Box::new(task)
+    }
+
+    /// Store jobs in Sidekiq's format, using its key names, optionally prefixed by the given
+    /// namespace.
+    ///
+    /// This allows workers to drain existing Sidekiq queues, and clients to send jobs to Sidekiq
+    /// workers. A job is deserialized from the only argument of the Sidekiq job if it is an
+    /// object, or from all its arguments otherwise, and the `class` of the Sidekiq job is used as
+    /// the name of the job. Sidekiq's `retry` field overrides the number of retries the job was
+    /// registered with, unless it is `true`. Scheduled jobs and jobs waiting to be retried are
+    /// moved to their queue by consumers once they are due, according to their `at` score.
+    ///
+    /// Jobs that exhausted their retries are still pushed to Batch's dead-letter lists.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate batch_redis;
+    /// # extern crate futures;
+    /// use futures::Future;
+    ///
+    /// # fn main() {
+    /// let task = batch_redis::Connection::open("redis://127.0.0.1/", vec!["default", "mailers"])
+    ///     .map(|connection| connection.sidekiq(Some("myapp")));
+    /// # }
+    /// ```
+    pub fn sidekiq(mut self, namespace: Option<&str>) -> Self {
+        self.sidekiq = Some(sidekiq::Keys::new(namespace));
+        self
+    }
+
+    /// Return the list storing the pending jobs of the given queue.
+    fn list_key(&self, queue: &str) -> String {
+        match self.sidekiq {
+            Some(ref keys) => keys.queue(queue),
+            None => queue_key(queue),
+        }
+    }
+
+    fn shared(&self) -> SharedConnection {
+        self.shared
+            .lock()
+            .expect("Redis shared connection mutex was poisoned")
+            .clone()
+    }
+
+    /// Remove the dead job with the given ID from the dead-letter list of the given queue.
+    ///
+    /// Returns a `Future` resolving to the removed job, or `None` if it wasn't found or another
+    /// client removed it in the meantime.
+    fn take_dead(
+        &self,
+        queue: &str,
+        id: Uuid,
+    ) -> Box<Future<Item = Option<DeadJob>, Error = Error> + Send> {
+        let key = dead_key(queue);
+        let shared = self.shared();
+        let task = redis::cmd("LRANGE")
+            .arg(&key)
+            .arg(0)
+            .arg(-1)
+            .query_async::<_, Vec<Vec<u8>>>(self.shared())
+            .map_err(Error::broker)
+            .and_then(move |(_, raws)| -> Box<Future<Item = _, Error = Error> + Send> {
+                let found = raws
+                    .into_iter()
+                    .filter_map(|raw| {
+                        serde_json::from_slice::<DeadJob>(&raw)
+                            .ok()
+                            .map(|job| (raw, job))
+                    })
+                    .find(|&(_, ref job)| job.properties.id == id);
+                let (raw, job) = match found {
+                    Some(found) => found,
+                    None => return Box::new(future::ok(None)),
+                };
+                let task = redis::cmd("LREM")
+                    .arg(key)
+                    .arg(1)
+                    .arg(raw)
+                    .query_async::<_, u32>(shared)
+                    .map_err(Error::broker)
+                    .map(move |(_, removed)| if removed == 0 { None } else { Some(job) });
+                Box::new(task)
+            });
+        Box::new(task)
+    }
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let raw = match self.sidekiq {
+            Some(_) => sidekiq::encode(payload, properties, properties.delay.is_none()),
+            None => serde_json::to_vec(&Message {
+                properties: properties.clone(),
+                payload: payload.to_vec(),
+            }),
+        };
+        let raw = match raw {
+            Ok(raw) => raw,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let mut pipe = redis::pipe();
+        match properties.delay {
+            Some(delay) => {
+                let due = now_millis() + delay.as_secs() * 1_000
+                    + u64::from(delay.subsec_nanos() / 1_000_000);
+                trace!(
+                    "Scheduling job {} to {:?} at {}",
+                    properties.id,
+                    properties.routing_key,
+                    due
+                );
+                let (key, score) = match self.sidekiq {
+                    Some(ref keys) => (keys.schedule(), sidekiq::to_seconds(due)),
+                    None => (delayed_key(&properties.routing_key), due as f64),
+                };
+                pipe.cmd("ZADD").arg(key).arg(score).arg(raw);
+            }
+            None => {
+                trace!("Pushing job {} to {:?}", properties.id, properties.routing_key);
+                if let Some(ref keys) = self.sidekiq {
+                    pipe.cmd("SADD")
+                        .arg(keys.queues())
+                        .arg(&properties.routing_key)
+                        .ignore();
+                }
+                pipe.cmd("LPUSH")
+                    .arg(self.list_key(&properties.routing_key))
+                    .arg(raw);
+            }
+        }
+        let task = pipe.query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn consume(&self, _prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let queues = Arc::new(self.queues.clone());
+        let shared = self.shared();
+        let sidekiq = self.sidekiq.clone();
+        let parse_sidekiq = sidekiq.is_some();
+        let adopted = Arc::clone(&queues);
+        let task = self.client
+            .get_async_connection()
+            .and_then(move |conn| adopt(conn, adopted))
+            .map_err(Error::broker)
+            .map(move |conn| -> Deliveries {
+                let stream = stream::unfold((conn, 0usize), move |(conn, index)| {
+                    if queues.is_empty() {
+                        return None;
+                    }
+                    let queue = queues[index % queues.len()].clone();
+                    let (list, promoted) = match sidekiq {
+                        Some(ref keys) => (keys.queue(&queue), promote_sidekiq(conn, keys.clone())),
+                        None => {
+                            let list = queue_key(&queue);
+                            let target = list.clone();
+                            let promoted = promote(
+                                conn,
+                                delayed_key(&queue),
+                                now_millis().to_string(),
+                                move |raw| Some((target.clone(), raw)),
+                            );
+                            (list, promoted)
+                        }
+                    };
+                    let recovered = (queue.clone(), list.clone());
+                    let task = promoted
+                        .and_then(move |conn| recover(conn, recovered.0, recovered.1))
+                        .and_then(move |conn| {
+                            redis::cmd("BRPOPLPUSH")
+                                .arg(list)
+                                .arg(processing_key(&queue))
+                                .arg(POLL_TIMEOUT)
+                                .query_async::<_, Option<Vec<u8>>>(conn)
+                                .map(move |(conn, raw)| ((queue, raw), (conn, index + 1)))
+                        });
+                    Some(task)
+                }).map_err(Error::broker);
+                let stream = stream
+                    .and_then(move |(queue, raw)| -> Box<Future<Item = _, Error = Error> + Send> {
+                        match raw {
+                            Some(raw) => receive(shared.clone(), queue, raw, parse_sidekiq),
+                            None => Box::new(future::ok(None)),
+                        }
+                    })
+                    .filter_map(|delivery| delivery);
+                Box::new(stream)
+            });
+        Box::new(task)
+    }
+
+    fn dead_letters(&self) -> Option<Arc<DeadLetterQueue>> {
+        Some(Arc::new(self.clone()))
+    }
+
+    fn inspector(&self) -> Option<Arc<QueueInspector>> {
+        Some(Arc::new(self.clone()))
+    }
+}
+
+impl DeadLetterQueue for Connection {
+    fn list(&self, queue: &str) -> Box<Future<Item = Vec<DeadJob>, Error = Error> + Send> {
+        let queue = queue.to_string();
+        let task = redis::cmd("LRANGE")
+            .arg(dead_key(&queue))
+            .arg(0)
+            .arg(-1)
+            .query_async::<_, Vec<Vec<u8>>>(self.shared())
+            .map_err(Error::broker)
+            .map(move |(_, raws)| {
+                // Dead jobs are pushed to the head of the list, newest first.
+                raws.iter()
+                    .rev()
+                    .filter_map(|raw| match serde_json::from_slice(raw) {
+                        Ok(job) => Some(job),
+                        Err(e) => {
+                            error!("Couldn't parse dead job of {:?}: {}", queue, e);
+                            None
+                        }
+                    })
+                    .collect()
+            });
+        Box::new(task)
+    }
+
+    fn requeue(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let connection = self.clone();
+        let task = self.take_dead(queue, id).and_then(move |job| {
+            let task: Box<Future<Item = bool, Error = Error> + Send> = match job {
+                Some(job) => {
+                    trace!("Requeuing dead job {}", id);
+                    Box::new(
+                        connection
+                            .publish(&job.payload, &job.requeue_properties())
+                            .map(|_| true),
+                    )
+                }
+                None => Box::new(future::ok(false)),
+            };
+            task
+        });
+        Box::new(task)
+    }
+
+    fn remove(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        Box::new(self.take_dead(queue, id).map(|job| job.is_some()))
+    }
+
+    fn purge(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = redis::cmd("DEL")
+            .arg(dead_key(queue))
+            .query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+impl QueueInspector for Connection {
+    fn queues(&self) -> Vec<String> {
+        self.queues.clone()
+    }
+
+    fn pending(&self, queue: &str) -> Box<Future<Item = u64, Error = Error> + Send> {
+        let task = redis::cmd("LLEN")
+            .arg(self.list_key(queue))
+            .query_async::<_, u64>(self.shared())
+            .map(|(_, pending)| pending)
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn peek(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Box<Future<Item = Vec<PendingJob>, Error = Error> + Send> {
+        if count == 0 {
+            return Box::new(future::ok(Vec::new()));
+        }
+        let queue = queue.to_string();
+        let sidekiq = self.sidekiq.is_some();
+        // Jobs are pushed to the head of the list and consumed from its tail.
+        let task = redis::cmd("LRANGE")
+            .arg(self.list_key(&queue))
+            .arg(-(count as i64))
+            .arg(-1)
+            .query_async::<_, Vec<Vec<u8>>>(self.shared())
+            .map_err(Error::broker)
+            .map(move |(_, raws)| {
+                raws.iter()
+                    .rev()
+                    .filter_map(|raw| match parse(raw, sidekiq) {
+                        Ok(message) => Some(PendingJob {
+                            properties: message.properties,
+                            payload: message.payload,
+                        }),
+                        Err(e) => {
+                            error!("Couldn't parse job of {:?}: {}", queue, e);
+                            None
+                        }
+                    })
+                    .collect()
+            });
+        Box::new(task)
+    }
+}
+
+impl StatusStore for Connection {
+    fn update(
+        &self,
+        id: Uuid,
+        status: Status,
+        at: DateTime<Utc>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let raw = match serde_json::to_string(&status) {
+            Ok(raw) => raw,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let field = match status {
+            Status::Pending => "pending_at",
+            Status::Started => "started_at",
+            Status::Success | Status::Failed(_) | Status::Revoked => "finished_at",
+        };
+        let key = status_key(&id);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("status")
+            .arg(raw)
+            .ignore()
+            .cmd("HSET")
+            .arg(&key)
+            .arg(field)
+            .arg(at.to_rfc3339())
+            .ignore();
+        if let Status::Pending = status {
+            pipe.cmd("HDEL")
+                .arg(&key)
+                .arg("finished_at")
+                .arg("progress")
+                .ignore();
+        }
+        pipe.cmd("EXPIRE").arg(&key).arg(STATUS_TTL).ignore();
+        let task = pipe.query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn fetch(&self, id: Uuid) -> Box<Future<Item = Option<StatusReport>, Error = Error> + Send> {
+        let task = redis::cmd("HGETALL")
+            .arg(status_key(&id))
+            .query_async::<_, HashMap<String, String>>(self.shared())
+            .map_err(Error::broker)
+            .and_then(move |(_, fields)| -> StdResult<Option<StatusReport>, Error> {
+                let status = match fields.get("status") {
+                    Some(status) => serde_json::from_str(status).map_err(Error::broker)?,
+                    None => return Ok(None),
+                };
+                let date = |field: &str| {
+                    fields
+                        .get(field)
+                        .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+                        .map(|date| date.with_timezone(&Utc))
+                };
+                Ok(Some(StatusReport {
+                    id,
+                    status,
+                    pending_at: date("pending_at"),
+                    started_at: date("started_at"),
+                    finished_at: date("finished_at"),
+                    progress: fields
+                        .get("progress")
+                        .and_then(|progress| serde_json::from_str(progress).ok()),
+                    panic: fields
+                        .get("panic")
+                        .and_then(|panic| serde_json::from_str(panic).ok()),
+                }))
+            });
+        Box::new(task)
+    }
+
+    fn progress(
+        &self,
+        id: Uuid,
+        progress: &ProgressReport,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let raw = match serde_json::to_string(progress) {
+            Ok(raw) => raw,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let key = status_key(&id);
+        let task = redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("progress")
+            .arg(raw)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(STATUS_TTL)
+            .ignore()
+            .query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn panicked(&self, id: Uuid, panic: &Panic) -> Box<Future<Item = (), Error = Error> + Send> {
+        let raw = match serde_json::to_string(panic) {
+            Ok(raw) => raw,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let key = status_key(&id);
+        let task = redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("panic")
+            .arg(raw)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(STATUS_TTL)
+            .ignore()
+            .query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+impl Barrier for Connection {
+    fn arrive(
+        &self,
+        chord: Uuid,
+        member: Uuid,
+    ) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let key = chord_key(&chord);
+        let task = redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg(&key)
+            .arg(member.to_string())
+            .ignore()
+            .cmd("SCARD")
+            .arg(&key)
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(STATUS_TTL)
+            .ignore()
+            .query_async::<_, (usize,)>(self.shared())
+            .map(|(_, (arrived,))| arrived)
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+impl RateLimiter for Connection {
+    fn acquire(
+        &self,
+        key: &str,
+        limit: RateLimit,
+    ) -> Box<Future<Item = Option<Duration>, Error = Error> + Send> {
+        let period = limit.period();
+        let millis = period.as_secs() * 1_000 + u64::from(period.subsec_nanos() / 1_000_000);
+        let task = redis::cmd("EVAL")
+            .arg(RATE_LIMIT_SCRIPT)
+            .arg(1)
+            .arg(rate_limit_key(key))
+            .arg(limit.amount())
+            .arg(millis.max(1))
+            .query_async::<_, u64>(self.shared())
+            .map(|(_, wait)| match wait {
+                0 => None,
+                wait => Some(Duration::from_millis(wait)),
+            })
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+impl Semaphore for Connection {
+    fn acquire(
+        &self,
+        key: &str,
+        permits: u32,
+        holder: Uuid,
+        lease: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let millis = lease.as_secs() * 1_000 + u64::from(lease.subsec_nanos() / 1_000_000);
+        let task = redis::cmd("EVAL")
+            .arg(SEMAPHORE_SCRIPT)
+            .arg(1)
+            .arg(semaphore_key(key))
+            .arg(permits)
+            .arg(holder.to_string())
+            .arg(millis)
+            .query_async::<_, bool>(self.shared())
+            .map(|(_, acquired)| acquired)
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn release(&self, key: &str, holder: Uuid) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = redis::cmd("ZREM")
+            .arg(semaphore_key(key))
+            .arg(holder.to_string())
+            .query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+impl SeenStore for Connection {
+    fn seen(&self, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let task = redis::cmd("EXISTS")
+            .arg(seen_key(&id))
+            .query_async::<_, bool>(self.shared())
+            .map(|(_, seen)| seen)
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn mark_seen(
+        &self,
+        id: Uuid,
+        window: Duration,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let millis = window.as_secs() * 1_000 + u64::from(window.subsec_nanos() / 1_000_000);
+        let task = redis::cmd("SET")
+            .arg(seen_key(&id))
+            .arg(1)
+            .arg("PX")
+            .arg(millis.max(1))
+            .query_async::<_, ()>(self.shared())
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+/// A job received from Redis.
+pub struct Delivery {
+    message: Message,
+    raw: Vec<u8>,
+    queue: String,
+    shared: SharedConnection,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ queue: {:?} properties: {:?} }}",
+            self.queue, self.message.properties
+        )
+    }
+}
+
+impl Delivery {
+    /// Lease this job for the given duration from now on, see `Lease`.
+    ///
+    /// Only existing leases are updated if `existing` is true, so that a job released in the
+    /// meantime isn't leased again.
+    fn lease_for(
+        &self,
+        duration: Duration,
+        existing: bool,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        lease(&self.shared, &self.queue, &self.raw, duration, existing)
+    }
+
+    /// Remove this job from the processing list.
+    fn release(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = redis::pipe()
+            .atomic()
+            .cmd("LREM")
+            .arg(processing_key(&self.queue))
+            .arg(1)
+            .arg(&self.raw)
+            .ignore()
+            .cmd("ZREM")
+            .arg(lease_key(&self.queue))
+            .arg(self.raw)
+            .ignore()
+            .query_async::<_, ()>(self.shared)
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+/// Parse a job moved to the processing list of the given queue, and lease it.
+///
+/// Jobs that can't be parsed are moved to the dead-letter list as is, instead of being left in the
+/// processing list.
+fn receive(
+    shared: SharedConnection,
+    queue: String,
+    raw: Vec<u8>,
+    parse_sidekiq: bool,
+) -> Box<Future<Item = Option<Box<BatchDelivery>>, Error = Error> + Send> {
+    match parse(&raw, parse_sidekiq) {
+        Ok(message) => {
+            let lease = message
+                .properties
+                .timeout
+                .map_or(DEFAULT_LEASE, |timeout| timeout.as_secs() + LEASE_GRACE);
+            let delivery = Delivery {
+                message,
+                raw,
+                queue,
+                shared,
+            };
+            let task = delivery
+                .lease_for(Duration::from_secs(lease), false)
+                .map(move |_| Some(Box::new(delivery) as Box<BatchDelivery>));
+            Box::new(task)
+        }
+        Err(e) => {
+            error!(
+                "Couldn't parse job received from {:?}, moving it to the dead-letter list: {}",
+                queue, e
+            );
+            let task = redis::pipe()
+                .atomic()
+                .cmd("LREM")
+                .arg(processing_key(&queue))
+                .arg(1)
+                .arg(&raw)
+                .ignore()
+                .cmd("LPUSH")
+                .arg(dead_key(&queue))
+                .arg(raw)
+                .ignore()
+                .query_async::<_, ()>(shared)
+                .map(|_| None)
+                .map_err(Error::broker);
+            Box::new(task)
+        }
+    }
+}
+
+/// Set the deadline of the lease of the given job of the processing list of the given queue.
+fn lease(
+    shared: &SharedConnection,
+    queue: &str,
+    raw: &[u8],
+    duration: Duration,
+    existing: bool,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let deadline = now_millis() + duration.as_secs() * 1_000;
+    let mut cmd = redis::cmd("ZADD");
+    cmd.arg(lease_key(queue));
+    if existing {
+        cmd.arg("XX");
+    }
+    let task = cmd
+        .arg(deadline)
+        .arg(raw)
+        .query_async::<_, ()>(shared.clone())
+        .map(|_| ())
+        .map_err(Error::broker);
+    Box::new(task)
+}
+
+/// The lease of a job received from Redis, extended while its handler calls `Context::touch`.
+struct Leased {
+    queue: String,
+    raw: Vec<u8>,
+    shared: SharedConnection,
+}
+
+impl fmt::Debug for Leased {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Leased {{ queue: {:?} }}", self.queue)
+    }
+}
+
+impl Lease for Leased {
+    fn extend(&self, duration: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        lease(&self.shared, &self.queue, &self.raw, duration, true)
+    }
+}
+
+impl BatchDelivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.message.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.message.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        Some(&self.queue)
+    }
+
+    fn lease(&self) -> Option<Box<Lease>> {
+        Some(Box::new(Leased {
+            queue: self.queue.clone(),
+            raw: self.raw.clone(),
+            shared: self.shared.clone(),
+        }))
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Acking job {}", self.message.properties.id);
+        self.release()
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Rejecting job {}", self.message.properties.id);
+        self.release()
+    }
+
+    fn dead_letter(
+        self: Box<Self>,
+        failure: Failure,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Moving job {} to dead-letter list", self.message.properties.id);
+        let this = *self;
+        let job = DeadJob {
+            queue: this.queue.clone(),
+            properties: this.message.properties,
+            payload: this.message.payload,
+            failure,
+            died_at: Utc::now(),
+        };
+        let raw = match serde_json::to_vec(&job) {
+            Ok(raw) => raw,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let task = redis::pipe()
+            .atomic()
+            .cmd("LREM")
+            .arg(processing_key(&this.queue))
+            .arg(1)
+            .arg(&this.raw)
+            .ignore()
+            .cmd("ZREM")
+            .arg(lease_key(&this.queue))
+            .arg(this.raw)
+            .ignore()
+            .cmd("LPUSH")
+            .arg(dead_key(&this.queue))
+            .arg(raw)
+            .ignore()
+            .query_async::<_, ()>(this.shared)
+            .map(|_| ())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+
+    fn dead_letter_with_headers(
+        mut self: Box<Self>,
+        failure: Failure,
+        headers: BTreeMap<String, String>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.message.properties.headers.extend(headers);
+        self.dead_letter(failure)
+    }
+}
+
+/// A `scheduler::Lock` implementation backed by Redis.
+///
+/// Locks are acquired using `SET` with the `NX` and `PX` options, and are never released
+/// explicitly: they expire once the requested duration elapsed.
+pub struct Lock {
+    shared: Arc<Mutex<SharedConnection>>,
+}
+
+impl fmt::Debug for Lock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Lock {{ }}")
+    }
+}
+
+impl Lock {
+    /// Open a new connection to the Redis server at the given URL.
+    pub fn open(url: &str) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let task = redis::Client::open(url)
+            .map_err(Error::broker)
+            .into_future()
+            .and_then(|client| {
+                client
+                    .get_shared_async_connection()
+                    .map_err(Error::broker)
+            })
+            .map(|shared| Lock {
+                shared: Arc::new(Mutex::new(shared)),
+            });
+        Box::new(task)
+    }
+}
+
+impl SchedulerLock for Lock {
+    fn acquire(
+        &self,
+        key: &str,
+        duration: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let millis = duration.as_secs() * 1_000 + u64::from(duration.subsec_nanos() / 1_000_000);
+        let shared = self.shared
+            .lock()
+            .expect("Redis shared connection mutex was poisoned")
+            .clone();
+        let task = redis::cmd("SET")
+            .arg(key)
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(millis)
+            .query_async::<_, Option<String>>(shared)
+            .map(|(_, reply)| reply.is_some())
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
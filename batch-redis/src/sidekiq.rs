@@ -0,0 +1,333 @@
+//! Sidekiq's job format and key names.
+//!
+//! A Sidekiq job is a JSON document holding the name of its class, its positional arguments, its
+//! ID (`jid`) and its queue. Pending jobs are pushed to the `queue:{name}` list of their queue,
+//! whose name is added to the `queues` set, while scheduled jobs and jobs waiting to be retried
+//! are stored in the `schedule` and `retry` sorted sets, scored by the time they are due at, in
+//! seconds. All the keys are prefixed by the namespace, if any.
+
+use std::collections::BTreeMap;
+
+use batch::{Codec, Priority, Properties};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::ser::Error;
+use serde_json::{self, Map, Value};
+use uuid::Uuid;
+
+use Message;
+
+/// The number of bytes of the job IDs generated by Sidekiq.
+const JID_BYTES: usize = 12;
+
+/// The fields of a Sidekiq job that can't be used as custom headers.
+const RESERVED_FIELDS: &[&str] = &[
+    "class",
+    "args",
+    "jid",
+    "queue",
+    "retry",
+    "retry_count",
+    "created_at",
+    "enqueued_at",
+    "at",
+];
+
+/// The key names used by Sidekiq.
+#[derive(Clone, Debug)]
+pub struct Keys {
+    namespace: String,
+}
+
+impl Keys {
+    /// Create the key names used by Sidekiq, optionally prefixed by the given namespace.
+    pub fn new(namespace: Option<&str>) -> Self {
+        let namespace = namespace.map_or_else(String::new, |namespace| format!("{}:", namespace));
+        Keys { namespace }
+    }
+
+    /// The list storing the pending jobs of the given queue.
+    pub fn queue(&self, queue: &str) -> String {
+        format!("{}queue:{}", self.namespace, queue)
+    }
+
+    /// The set storing the names of the queues.
+    pub fn queues(&self) -> String {
+        format!("{}queues", self.namespace)
+    }
+
+    /// The sorted set storing the scheduled jobs.
+    pub fn schedule(&self) -> String {
+        format!("{}schedule", self.namespace)
+    }
+
+    /// The sorted set storing the jobs waiting to be retried.
+    pub fn retry(&self) -> String {
+        format!("{}retry", self.namespace)
+    }
+}
+
+/// Whether a job is retried, and how many times.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Retry {
+    /// `true` to retry the job as many times as its handler allows, `false` to never retry it.
+    Enabled(bool),
+    /// The maximum number of times the job is retried.
+    Limit(u32),
+}
+
+/// A job, in the format used by Sidekiq.
+#[derive(Serialize, Deserialize)]
+struct Job {
+    class: String,
+    args: Vec<Value>,
+    jid: String,
+    queue: String,
+    #[serde(default = "retry_enabled")]
+    retry: Retry,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enqueued_at: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    at: Option<f64>,
+    #[serde(flatten)]
+    extra: BTreeMap<String, Value>,
+}
+
+fn retry_enabled() -> Retry {
+    Retry::Enabled(true)
+}
+
+/// Return the given time as a number of seconds since the UNIX epoch.
+pub fn to_seconds(millis: u64) -> f64 {
+    millis as f64 / 1_000.0
+}
+
+fn to_datetime(seconds: f64) -> DateTime<Utc> {
+    Utc.timestamp(seconds.trunc() as i64, (seconds.fract() * 1e9) as u32)
+}
+
+fn from_datetime(date: DateTime<Utc>) -> f64 {
+    date.timestamp() as f64 + f64::from(date.timestamp_subsec_nanos()) / 1e9
+}
+
+/// Return the ID of the job with the given Sidekiq ID.
+///
+/// The 12 bytes IDs generated by Sidekiq are padded with zeros, so that they can be converted
+/// back. Other IDs are expected to be UUIDs.
+fn to_id(jid: &str) -> Uuid {
+    if let Ok(id) = Uuid::parse_str(jid) {
+        return id;
+    }
+    let mut bytes = [0; 16];
+    let parsed = jid.len() == 2 * JID_BYTES
+        && (0..JID_BYTES).all(|i| match u8::from_str_radix(&jid[2 * i..2 * i + 2], 16) {
+            Ok(byte) => {
+                bytes[i] = byte;
+                true
+            }
+            Err(_) => false,
+        });
+    if !parsed {
+        warn!("Unsupported Sidekiq job ID {:?}", jid);
+        bytes = [0; 16];
+        for (byte, raw) in bytes.iter_mut().zip(jid.bytes()) {
+            *byte = raw;
+        }
+    }
+    Uuid::from_bytes(&bytes).expect("a UUID is made of 16 bytes")
+}
+
+/// Return the Sidekiq ID of the job with the given ID.
+fn to_jid(id: &Uuid) -> String {
+    let bytes = id.as_bytes();
+    if bytes[JID_BYTES..].iter().all(|&byte| byte == 0) {
+        bytes[..JID_BYTES]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    } else {
+        id.simple().to_string()
+    }
+}
+
+/// Serialize the given job in the format used by Sidekiq.
+///
+/// A job serializing to an object is sent as the only argument of the Sidekiq job, an array as
+/// its arguments. The job is marked as enqueued now unless `enqueued` is false, e.g: when it is
+/// scheduled. Fails if the job isn't serialized to JSON.
+pub fn encode(
+    payload: &[u8],
+    properties: &Properties,
+    enqueued: bool,
+) -> Result<Vec<u8>, serde_json::Error> {
+    if properties.codec != Codec::Json || properties.compression.is_some() {
+        return Err(serde_json::Error::custom(
+            "Sidekiq jobs must be serialized to JSON, without compression",
+        ));
+    }
+    let args = match serde_json::from_slice(payload)? {
+        Value::Array(args) => args,
+        Value::Null => Vec::new(),
+        arg => vec![arg],
+    };
+    let now = Utc::now();
+    let extra = properties
+        .headers
+        .iter()
+        .filter(|&(name, _)| !RESERVED_FIELDS.contains(&name.as_str()))
+        .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+        .collect();
+    let job = Job {
+        class: properties.task.clone(),
+        args,
+        jid: to_jid(&properties.id),
+        queue: properties.routing_key.clone(),
+        retry: match properties.max_retries {
+            None => Retry::Enabled(true),
+            Some(0) => Retry::Enabled(false),
+            Some(limit) => Retry::Limit(limit),
+        },
+        // Sidekiq counts retries from 0, starting with the first one.
+        retry_count: properties.retries.checked_sub(1),
+        created_at: Some(from_datetime(properties.enqueued_at.unwrap_or(now))),
+        enqueued_at: if enqueued {
+            Some(from_datetime(now))
+        } else {
+            None
+        },
+        at: None,
+        extra,
+    };
+    serde_json::to_vec(&job)
+}
+
+/// Deserialize a job stored in the format used by Sidekiq.
+///
+/// The payload of the job is the only argument of the Sidekiq job if it is an object, or all its
+/// arguments otherwise. Fields holding strings, other than the ones defined by Sidekiq, are
+/// exposed as custom headers.
+pub fn decode(raw: &[u8]) -> Result<Message, serde_json::Error> {
+    let job: Job = serde_json::from_slice(raw)?;
+    let payload = if job.args.len() == 1 && job.args[0].is_object() {
+        serde_json::to_vec(&job.args[0])?
+    } else {
+        serde_json::to_vec(&job.args)?
+    };
+    let headers = job
+        .extra
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::String(value) => Some((name, value)),
+            _ => None,
+        })
+        .collect();
+    let max_retries = match job.retry {
+        Retry::Enabled(true) => None,
+        Retry::Enabled(false) => Some(0),
+        Retry::Limit(limit) => Some(limit),
+    };
+    let properties = Properties {
+        id: to_id(&job.jid),
+        task: job.class,
+        exchange: String::new(),
+        routing_key: job.queue,
+        priority: Priority::default(),
+        timeout: None,
+        delay: None,
+        retries: job.retry_count.map_or(0, |count| count + 1),
+        max_retries,
+        reply_to: None,
+        enqueued_at: job.enqueued_at.or(job.created_at).map(to_datetime),
+        expires: None,
+        origin: None,
+        codec: Codec::Json,
+        compression: None,
+        version: None,
+        headers,
+    };
+    Ok(Message {
+        properties,
+        payload,
+    })
+}
+
+/// Prepare a due job of the `schedule` or `retry` sorted sets to be pushed to its queue.
+///
+/// Returns the name of the queue and the job, marked as enqueued now.
+pub fn enqueue(raw: &[u8], now: f64) -> Result<(String, Vec<u8>), serde_json::Error> {
+    let mut job: Map<String, Value> = serde_json::from_slice(raw)?;
+    let queue = match job.get("queue") {
+        Some(&Value::String(ref queue)) => queue.clone(),
+        _ => "default".to_string(),
+    };
+    job.remove("at");
+    job.insert("enqueued_at".to_string(), Value::from(now));
+    Ok((queue, serde_json::to_vec(&job)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jid() {
+        let jid = "b4a577edbccf1d805744efa9";
+        assert_eq!(to_jid(&to_id(jid)), jid);
+        let id = Uuid::parse_str("5f4c2e8e-0d0a-4c8e-a0f5-3a8f0c0f7f43").unwrap();
+        assert_eq!(to_id(&to_jid(&id)), id);
+    }
+
+    #[test]
+    fn decode_sidekiq_job() {
+        let raw = br#"{
+            "class": "HardWorker",
+            "args": [{"user_id": 42}],
+            "jid": "b4a577edbccf1d805744efa9",
+            "queue": "critical",
+            "retry": 3,
+            "retry_count": 0,
+            "created_at": 1234567890.5,
+            "tenant": "ferris",
+            "tags": ["migration"]
+        }"#;
+        let message = decode(raw).unwrap();
+        assert_eq!(message.payload, br#"{"user_id":42}"#.to_vec());
+        let properties = message.properties;
+        assert_eq!(properties.task, "HardWorker");
+        assert_eq!(properties.routing_key, "critical");
+        assert_eq!(properties.retries, 1);
+        assert_eq!(properties.max_retries, Some(3));
+        assert_eq!(properties.headers.len(), 1);
+        assert_eq!(properties.headers["tenant"], "ferris");
+    }
+
+    #[test]
+    fn round_trip() {
+        let raw = br#"{"class":"HardWorker","args":[1,"two"],"jid":"b4a577edbccf1d805744efa9","queue":"default","retry":false}"#;
+        let message = decode(raw).unwrap();
+        assert_eq!(message.payload, br#"[1,"two"]"#.to_vec());
+        assert_eq!(message.properties.max_retries, Some(0));
+        let encoded = encode(&message.payload, &message.properties, true).unwrap();
+        let job: Map<String, Value> = serde_json::from_slice(&encoded).unwrap();
+        assert_eq!(job["jid"], "b4a577edbccf1d805744efa9");
+        assert_eq!(job["retry"], false);
+        let args: Value = serde_json::from_str(r#"[1,"two"]"#).unwrap();
+        assert_eq!(job["args"], args);
+        assert!(job.contains_key("enqueued_at"));
+        assert!(!job.contains_key("retry_count"));
+    }
+
+    #[test]
+    fn enqueue_scheduled_job() {
+        let raw = br#"{"class":"HardWorker","args":[],"jid":"b4a577edbccf1d805744efa9","queue":"low","at":12.5}"#;
+        let (queue, raw) = enqueue(raw, 42.0).unwrap();
+        assert_eq!(queue, "low");
+        let job: Map<String, Value> = serde_json::from_slice(&raw).unwrap();
+        assert!(!job.contains_key("at"));
+        assert_eq!(job["enqueued_at"], 42.0);
+    }
+}
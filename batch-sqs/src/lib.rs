@@ -0,0 +1,528 @@
+//! An Amazon SQS broker for the batch crate.
+//!
+//! Each queue consumed by a worker is an SQS queue, and the routing key of a job is used as the
+//! name of the SQS queue it is sent to. Exchanges are ignored.
+//!
+//! Retries are driven by the visibility timeout of SQS messages: when a job is received, its
+//! visibility timeout is extended to match the job's `timeout`, so that a job whose worker died
+//! is delivered again once its time limit elapsed. The number of times a message was received is
+//! accounted for in the job's retries, so that these redeliveries are bounded by the job's
+//! `retries` value: once exhausted, the worker moves the job to its dead-letter queue with the
+//! `Failure::Crash` reason instead of executing it again. Messages that can't be parsed are
+//! deleted. Handlers running longer than their visibility timeout can keep their job by
+//! calling `Context::touch`, see `WorkerBuilder::lease_duration`.
+//!
+//! Delayed jobs are sent using the `DelaySeconds` parameter of SQS messages, which can't exceed
+//! 15 minutes: longer delays are truncated.
+//!
+//! Priorities are not supported by this broker.
+//!
+//! SQS messages can't exceed 256 KB: jobs with larger payloads must be sent by a `Client` storing
+//! them in S3, see `S3`.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate batch_sqs;
+//! extern crate futures;
+//! extern crate rusoto_core;
+//! extern crate tokio;
+//!
+//! use batch::Client;
+//! use futures::Future;
+//! use rusoto_core::Region;
+//!
+//! fn main() {
+//!     let task = batch_sqs::Connection::open(Region::EuWest1, vec!["emails"])
+//!         .map(Client::new)
+//!         .map(|_client| {
+//!             // Send jobs using the client
+//!         })
+//!         .map_err(|e| eprintln!("Couldn't connect to SQS: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/batch-sqs/0.1.0")]
+#![deny(missing_debug_implementations)]
+#![deny(missing_docs)]
+
+extern crate batch;
+extern crate failure;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+extern crate rusoto_sqs;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use batch::{
+    BlobStore, Broker, Deliveries, Delivery as BatchDelivery, Error, Lease, Properties,
+};
+use futures::{future, stream, Future, Stream};
+use rusoto_core::Region;
+use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3 as S3Api};
+use rusoto_sqs::{
+    ChangeMessageVisibilityRequest, DeleteMessageRequest, GetQueueUrlRequest,
+    ReceiveMessageRequest, SendMessageRequest, Sqs, SqsClient,
+};
+
+/// The maximum number of seconds a `ReceiveMessage` call waits for messages.
+const WAIT_TIME_SECONDS: i64 = 20;
+
+/// The maximum number of messages a single `ReceiveMessage` call can return.
+const MAX_MESSAGES: u16 = 10;
+
+/// The number of seconds added to a job's timeout when extending its visibility timeout.
+const VISIBILITY_GRACE: u64 = 30;
+
+/// The maximum visibility timeout allowed by SQS (12 hours).
+const MAX_VISIBILITY: u64 = 12 * 60 * 60;
+
+/// The maximum delay of a message allowed by SQS (15 minutes).
+const MAX_DELAY: u64 = 15 * 60;
+
+/// The representation of a job stored in SQS.
+#[derive(Serialize, Deserialize)]
+struct Message {
+    properties: Properties,
+    payload: Vec<u8>,
+}
+
+/// A `Broker` implementation backed by Amazon SQS.
+pub struct Connection {
+    client: Arc<SqsClient>,
+    queues: Vec<String>,
+    urls: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Connection {{ queues: {:?} }}", self.queues)
+    }
+}
+
+impl Connection {
+    /// Create a new connection to SQS in the given region.
+    ///
+    /// The given queues are the ones jobs will be consumed from when this connection is used by
+    /// a `Worker`. Their URLs are resolved when opening the connection, the queues must exist.
+    pub fn open<Q, S>(region: Region, queues: Q) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        Q: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let connection = Connection {
+            client: Arc::new(SqsClient::new(region)),
+            queues: queues.into_iter().map(Into::into).collect(),
+            urls: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let lookups = connection
+            .queues
+            .iter()
+            .map(|queue| connection.queue_url(queue))
+            .collect::<Vec<_>>();
+        let task = future::join_all(lookups).map(move |_| connection);
+        Box::new(task)
+    }
+
+    /// Resolve the URL of the given queue, caching the result.
+    fn queue_url(&self, queue: &str) -> Box<Future<Item = String, Error = Error> + Send> {
+        if let Some(url) = self.urls.lock().unwrap().get(queue) {
+            return Box::new(future::ok(url.clone()));
+        }
+        trace!("Resolving URL of SQS queue {:?}", queue);
+        let urls = Arc::clone(&self.urls);
+        let name = queue.to_string();
+        let task = self.client
+            .get_queue_url(GetQueueUrlRequest {
+                queue_name: queue.into(),
+                ..Default::default()
+            })
+            .map_err(Error::broker)
+            .and_then(move |result| -> StdResult<String, Error> {
+                let url = result.queue_url.ok_or_else(|| {
+                    Error::broker(failure::err_msg(format!(
+                        "SQS didn't return an URL for queue {:?}",
+                        name
+                    )))
+                })?;
+                urls.lock().unwrap().insert(name, url.clone());
+                Ok(url)
+            });
+        Box::new(task)
+    }
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let message = Message {
+            properties: properties.clone(),
+            payload: payload.to_vec(),
+        };
+        let body = match serde_json::to_string(&message) {
+            Ok(body) => body,
+            Err(e) => return Box::new(future::err(Error::broker(e))),
+        };
+        let client = Arc::clone(&self.client);
+        let id = properties.id;
+        let delay_seconds = properties.delay.map(|delay| {
+            if delay.as_secs() > MAX_DELAY {
+                warn!(
+                    "Delay of job {} exceeds what SQS supports, truncating it to {} seconds",
+                    id, MAX_DELAY
+                );
+            }
+            cmp::min(delay.as_secs(), MAX_DELAY) as i64
+        });
+        let task = self.queue_url(&properties.routing_key)
+            .and_then(move |queue_url| {
+                trace!("Sending job {} to {:?}", id, queue_url);
+                client
+                    .send_message(SendMessageRequest {
+                        queue_url,
+                        message_body: body,
+                        delay_seconds,
+                        ..Default::default()
+                    })
+                    .map_err(Error::broker)
+            })
+            .map(|_| ());
+        Box::new(task)
+    }
+
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let client = Arc::clone(&self.client);
+        let max_messages = cmp::max(1, cmp::min(prefetch, MAX_MESSAGES));
+        let lookups = self.queues
+            .iter()
+            .map(|queue| self.queue_url(queue))
+            .collect::<Vec<_>>();
+        let task = future::join_all(lookups).map(move |urls| -> Deliveries {
+            let urls = Arc::new(urls);
+            let stream = stream::unfold(0usize, move |index| {
+                if urls.is_empty() {
+                    return None;
+                }
+                let queue_url = urls[index % urls.len()].clone();
+                let client = Arc::clone(&client);
+                let task = client
+                    .receive_message(ReceiveMessageRequest {
+                        queue_url: queue_url.clone(),
+                        max_number_of_messages: Some(i64::from(max_messages)),
+                        wait_time_seconds: Some(WAIT_TIME_SECONDS),
+                        attribute_names: Some(vec!["ApproximateReceiveCount".into()]),
+                        ..Default::default()
+                    })
+                    .map_err(Error::broker)
+                    .and_then(move |result| {
+                        let received = result
+                            .messages
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|message| {
+                                Delivery::receive(message, queue_url.clone(), Arc::clone(&client))
+                            })
+                            .collect::<Vec<_>>();
+                        future::join_all(received).map(move |deliveries| {
+                            let deliveries = deliveries.into_iter().filter_map(|d| d);
+                            (stream::iter_ok::<_, Error>(deliveries), index + 1)
+                        })
+                    });
+                Some(task)
+            }).flatten()
+                .and_then(|delivery| delivery.extend_visibility());
+            Box::new(stream)
+        });
+        Box::new(task)
+    }
+}
+
+/// A job received from SQS.
+pub struct Delivery {
+    message: Message,
+    queue_url: String,
+    receipt_handle: String,
+    client: Arc<SqsClient>,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ queue_url: {:?} properties: {:?} }}",
+            self.queue_url, self.message.properties
+        )
+    }
+}
+
+impl Delivery {
+    /// Parse a message received from SQS.
+    ///
+    /// Messages that can't be parsed are deleted, instead of being delivered again forever.
+    fn receive(
+        raw: rusoto_sqs::Message,
+        queue_url: String,
+        client: Arc<SqsClient>,
+    ) -> Box<Future<Item = Option<Self>, Error = Error> + Send> {
+        let receipt_handle = match raw.receipt_handle {
+            Some(ref receipt_handle) => receipt_handle.clone(),
+            None => return Box::new(future::ok(None)),
+        };
+        let body = raw.body.as_ref().map_or("", |body| body.as_str());
+        let mut message = match serde_json::from_str::<Message>(body) {
+            Ok(message) => message,
+            Err(e) => {
+                error!(
+                    "Couldn't parse message {:?} received from {:?}, deleting it: {}",
+                    raw.message_id, queue_url, e
+                );
+                let task = client
+                    .delete_message(DeleteMessageRequest {
+                        queue_url,
+                        receipt_handle,
+                    })
+                    .then(|result| -> StdResult<_, Error> {
+                        if let Err(e) = result {
+                            error!("Couldn't delete unparseable message: {}", e);
+                        }
+                        Ok(None)
+                    });
+                return Box::new(task);
+            }
+        };
+        let receive_count = raw.attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get("ApproximateReceiveCount"))
+            .and_then(|count| count.parse::<u32>().ok())
+            .unwrap_or(1);
+        let redeliveries = receive_count.saturating_sub(1);
+        message.properties.retries = cmp::max(message.properties.retries, redeliveries);
+        Box::new(future::ok(Some(Delivery {
+            message,
+            queue_url,
+            receipt_handle,
+            client,
+        })))
+    }
+
+    /// Extend the visibility timeout of this job to cover its whole time limit.
+    fn extend_visibility(self) -> Box<Future<Item = Box<BatchDelivery>, Error = Error> + Send> {
+        let timeout = match self.message.properties.timeout {
+            Some(timeout) => timeout,
+            None => return Box::new(future::ok(Box::new(self) as Box<BatchDelivery>)),
+        };
+        let visibility = cmp::min(
+            timeout
+                .checked_add(Duration::from_secs(VISIBILITY_GRACE))
+                .map_or(MAX_VISIBILITY, |d| d.as_secs()),
+            MAX_VISIBILITY,
+        );
+        let task = self.client
+            .change_message_visibility(ChangeMessageVisibilityRequest {
+                queue_url: self.queue_url.clone(),
+                receipt_handle: self.receipt_handle.clone(),
+                visibility_timeout: visibility as i64,
+            })
+            .map_err(Error::broker)
+            .map(move |_| Box::new(self) as Box<BatchDelivery>);
+        Box::new(task)
+    }
+
+    /// Delete this job from its queue.
+    fn delete(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = self.client
+            .delete_message(DeleteMessageRequest {
+                queue_url: self.queue_url,
+                receipt_handle: self.receipt_handle,
+            })
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+impl BatchDelivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.message.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.message.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        self.queue_url.rsplit('/').next()
+    }
+
+    fn lease(&self) -> Option<Box<Lease>> {
+        Some(Box::new(Visibility {
+            queue_url: self.queue_url.clone(),
+            receipt_handle: self.receipt_handle.clone(),
+            client: Arc::clone(&self.client),
+        }))
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Deleting acked job {}", self.message.properties.id);
+        self.delete()
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Deleting rejected job {}", self.message.properties.id);
+        self.delete()
+    }
+}
+
+/// The visibility timeout of a job received from SQS, extended while its handler calls
+/// `Context::touch`.
+struct Visibility {
+    queue_url: String,
+    receipt_handle: String,
+    client: Arc<SqsClient>,
+}
+
+impl fmt::Debug for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Visibility {{ queue_url: {:?} }}", self.queue_url)
+    }
+}
+
+impl Lease for Visibility {
+    fn extend(&self, duration: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        let visibility = cmp::min(duration.as_secs(), MAX_VISIBILITY);
+        let task = self.client
+            .change_message_visibility(ChangeMessageVisibilityRequest {
+                queue_url: self.queue_url.clone(),
+                receipt_handle: self.receipt_handle.clone(),
+                visibility_timeout: visibility as i64,
+            })
+            .map_err(Error::broker);
+        Box::new(task)
+    }
+}
+
+/// A `BlobStore` keeping payloads in an S3 bucket, see `Client::blob_store`.
+///
+/// # Example
+///
+/// ```rust
+/// extern crate batch;
+/// extern crate batch_sqs;
+/// extern crate futures;
+/// extern crate rusoto_core;
+///
+/// use batch::Client;
+/// use futures::Future;
+/// use rusoto_core::Region;
+///
+/// fn main() {
+///     let task = batch_sqs::Connection::open(Region::EuWest1, vec!["videos"]).map(|connection| {
+///         let store = batch_sqs::S3::new(Region::EuWest1, "batch-payloads");
+///         Client::new(connection).blob_store(store, 200 * 1024)
+///     });
+/// }
+/// ```
+pub struct S3 {
+    client: Arc<S3Client>,
+    bucket: String,
+    prefix: String,
+}
+
+impl fmt::Debug for S3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "S3 {{ bucket: {:?} prefix: {:?} }}",
+            self.bucket, self.prefix
+        )
+    }
+}
+
+impl S3 {
+    /// Create a new store keeping payloads in the given bucket, which must exist.
+    pub fn new<B>(region: Region, bucket: B) -> Self
+    where
+        B: Into<String>,
+    {
+        S3 {
+            client: Arc::new(S3Client::new(region)),
+            bucket: bucket.into(),
+            prefix: String::new(),
+        }
+    }
+
+    /// Prepend the given prefix to the keys of the payloads. Chainable.
+    pub fn prefix<P>(mut self, prefix: P) -> Self
+    where
+        P: Into<String>,
+    {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl BlobStore for S3 {
+    fn put(&self, key: &str, payload: Vec<u8>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(key),
+                body: Some(payload.into()),
+                ..Default::default()
+            })
+            .map_err(Error::blob)
+            .map(|_| ());
+        Box::new(task)
+    }
+
+    fn get(&self, key: &str) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let task = self.client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(key),
+                ..Default::default()
+            })
+            .map_err(Error::blob)
+            .and_then(|output| -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+                match output.body {
+                    Some(body) => Box::new(body.concat2().map_err(Error::blob)),
+                    None => Box::new(future::ok(Vec::new())),
+                }
+            });
+        Box::new(task)
+    }
+
+    fn delete(&self, key: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(key),
+                ..Default::default()
+            })
+            .map_err(Error::blob)
+            .map(|_| ());
+        Box::new(task)
+    }
+}
@@ -12,8 +12,7 @@ use batch::{exchange, job, queue, Client};
 use futures::{future, Future};
 
 #[derive(Serialize, Deserialize, Job)]
-#[job_name = "batch::SayHello"]
-#[job_routing_key = "hello-world"]
+#[job(name = "batch::SayHello", routing_key = "hello-world")]
 struct SayHello {
     to: String,
 }
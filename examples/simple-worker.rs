@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate batch;
 extern crate env_logger;
+extern crate failure;
 extern crate futures;
 #[macro_use]
 extern crate lazy_static;
@@ -8,25 +9,28 @@ extern crate lazy_static;
 extern crate serde;
 extern crate tokio;
 
-use batch::{exchange, queue, Perform, Worker};
+use batch::{exchange, queue, Context, Perform, Worker};
 use futures::Future;
 use std::{thread, time};
 
 #[derive(Serialize, Deserialize, Job)]
-#[job_name = "batch::SayHello"]
-#[job_routing_key = "hello-world"]
+#[job(name = "batch::SayHello", routing_key = "hello-world")]
 struct SayHello {
     to: String,
 }
 
 impl Perform for SayHello {
     type Context = ();
+    type Output = ();
+    type Error = failure::Error;
+    type Future = Result<Self::Output, Self::Error>;
 
-    fn perform(&self, _ctx: Self::Context) {
+    fn perform(&self, _ctx: Context<Self::Context>) -> Self::Future {
         println!("Hello {}", self.to);
         let second = time::Duration::from_secs(1);
         thread::sleep(second);
         println!("Goodbye {}", self.to);
+        Ok(())
     }
 }
 
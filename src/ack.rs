@@ -0,0 +1,291 @@
+//! Acknowledgement of the jobs received by workers.
+//!
+//! By default, a worker acknowledges a job once it was executed, so that the broker delivers it
+//! again if the worker dies while executing it: jobs are delivered at least once. The `AckMode`
+//! of a queue, set with `WorkerBuilder::ack_mode`, lets its jobs be acknowledged before they are
+//! executed instead, or by their handler with `Context::ack`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+use blob::BLOB_HEADER;
+use broker::{Delivery, Properties};
+use error::Error;
+use job::Failure;
+
+/// When a worker acknowledges the jobs of a queue, see `WorkerBuilder::ack_mode`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AckMode {
+    /// Acknowledge jobs before executing them.
+    ///
+    /// Jobs are delivered at most once: a job is lost if its worker dies while executing it.
+    BeforeExecute,
+    /// Acknowledge jobs once they were executed, the default.
+    ///
+    /// Jobs are delivered at least once: a job is delivered again if its worker dies while
+    /// executing it.
+    AfterExecute,
+    /// Acknowledge jobs when their handler calls `Context::ack`, or once they were executed if
+    /// it doesn't.
+    Manual,
+}
+
+impl Default for AckMode {
+    fn default() -> Self {
+        AckMode::AfterExecute
+    }
+}
+
+/// The function acknowledging the job being executed, see `Context::ack`.
+#[derive(Clone)]
+pub(crate) struct Acknowledgement(Option<Arc<Fn() + Send + Sync>>);
+
+impl Acknowledgement {
+    /// Return an `Acknowledgement` calling the given function.
+    pub(crate) fn new<F>(ack: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Acknowledgement(Some(Arc::new(ack)))
+    }
+
+    /// Return an `Acknowledgement` doing nothing, for jobs not acknowledged manually.
+    pub(crate) fn none() -> Self {
+        Acknowledgement(None)
+    }
+
+    /// Return an `Acknowledgement` along with the receiver notified once it was called.
+    pub(crate) fn channel() -> (Self, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        let sender = Mutex::new(Some(sender));
+        let acknowledgement = Acknowledgement::new(move || {
+            if let Some(sender) = sender.lock().unwrap().take() {
+                let _ = sender.send(());
+            }
+        });
+        (acknowledgement, receiver)
+    }
+
+    /// Acknowledge the job, if it is acknowledged manually.
+    ///
+    /// Returns whether the job is acknowledged manually.
+    pub(crate) fn ack(&self) -> bool {
+        match self.0 {
+            Some(ref ack) => {
+                ack();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Acknowledgement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Acknowledgement {{ manual: {:?} }}", self.0.is_some())
+    }
+}
+
+/// A job that was already acknowledged.
+///
+/// Settling it again does nothing: the job can still be retried, as retries are published as new
+/// messages, but it can't be requeued or moved to its dead-letter queue anymore. Its payload is
+/// kept, as the payload stored in a blob store is deleted once the job is acknowledged.
+#[derive(Debug)]
+struct Settled {
+    properties: Properties,
+    payload: Vec<u8>,
+    queue: Option<String>,
+}
+
+impl Delivery for Settled {
+    fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        self.queue.as_ref().map(|queue| queue.as_str())
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    fn dead_letter(
+        self: Box<Self>,
+        failure: Failure,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        warn!(
+            "[{}] Job was already acknowledged, dropping it instead of dead-lettering it ({:?})",
+            self.properties.id, failure
+        );
+        Box::new(future::ok(()))
+    }
+
+    fn dead_letter_with_headers(
+        self: Box<Self>,
+        failure: Failure,
+        _headers: BTreeMap<String, String>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.dead_letter(failure)
+    }
+
+    fn requeue(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        warn!(
+            "[{}] Job was already acknowledged, it can't be requeued",
+            self.properties.id
+        );
+        Box::new(future::ok(()))
+    }
+}
+
+/// Acknowledge the given job, returning a `Future` resolving to the settled job.
+pub(crate) fn settle(
+    delivery: Box<Delivery>,
+) -> Box<Future<Item = Box<Delivery>, Error = Error> + Send> {
+    let mut properties = delivery.properties().clone();
+    // The stored payload is deleted once acknowledged, the job now carries it.
+    properties.headers.remove(BLOB_HEADER);
+    let settled = Settled {
+        properties,
+        payload: delivery.payload().to_vec(),
+        queue: delivery.queue().map(|queue| queue.to_string()),
+    };
+    debug!(
+        "[{}] Acknowledging job before it completed",
+        settled.properties.id
+    );
+    let task = delivery
+        .ack()
+        .map(move |_| Box::new(settled) as Box<Delivery>);
+    Box::new(task)
+}
+
+/// Settle the given job if `acked`, otherwise return it as is.
+pub(crate) fn settle_if(
+    acked: bool,
+    delivery: Box<Delivery>,
+) -> Box<Future<Item = Box<Delivery>, Error = Error> + Send> {
+    if acked {
+        settle(delivery)
+    } else {
+        Box::new(future::ok(delivery))
+    }
+}
+
+/// Type of the `Future` returned by `manually`, resolving to the job and the outcome of its task.
+type Manually<T> = Box<Future<Item = (Box<Delivery>, StdResult<T, Error>), Error = Error> + Send>;
+
+/// Return a `Future` executing the given job with the given task, settling it as soon as the
+/// given receiver is notified, i.e: the handler called `Context::ack`.
+///
+/// The returned `Future` resolves to the job along with the outcome of the task, so that a job
+/// whose task failed before it was acknowledged is still settled by its failure policy. It only
+/// fails if the job couldn't be acknowledged.
+pub(crate) fn manually<F>(
+    delivery: Box<Delivery>,
+    task: F,
+    acked: oneshot::Receiver<()>,
+) -> Manually<F::Item>
+where
+    F: Future<Error = Error> + Send + 'static,
+    F::Item: Send + 'static,
+{
+    let task = task
+        .then(Ok::<_, Error>)
+        .select2(acked)
+        .then(move |result| -> Manually<F::Item> {
+            match result {
+                Ok(future::Either::A((outcome, mut acked))) => {
+                    // The handler may have acknowledged the job right before completing.
+                    let acked = match acked.try_recv() {
+                        Ok(Some(())) => true,
+                        _ => false,
+                    };
+                    Box::new(settle_if(acked, delivery).map(move |delivery| (delivery, outcome)))
+                }
+                Err(future::Either::A(_)) => unreachable!("the outcome of a task never fails"),
+                Ok(future::Either::B((_, task))) => Box::new(settle(delivery).join(task)),
+                // The handler dropped its context without acknowledging the job.
+                Err(future::Either::B((_, task))) => {
+                    Box::new(task.map(move |outcome| (delivery, outcome)))
+                }
+            }
+        });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use broker::Broker;
+    use error::ErrorKind;
+    use memory;
+    use topology::queue;
+
+    #[test]
+    fn settle_jobs() {
+        let connection = memory::Connection::new(vec![queue("tests.ack")]);
+        let broker: Arc<Broker> = Arc::new(connection.clone());
+        let properties = Properties::named("ack", "", "tests.ack");
+        broker.publish(b"[1]", &properties).wait().unwrap();
+        let deliveries = broker.consume(1).wait().unwrap();
+        let delivery = deliveries.wait().next().unwrap().unwrap();
+
+        let (acknowledgement, acked) = Acknowledgement::channel();
+        assert!(acknowledgement.ack());
+        assert!(!Acknowledgement::none().ack());
+        // The job completes right after being acknowledged.
+        let execution = manually(delivery, future::ok::<(), Error>(()), acked);
+        let (settled, outcome) = execution.wait().unwrap();
+        assert!(outcome.is_ok());
+        assert_eq!(settled.payload(), b"[1]");
+        settled.requeue().wait().unwrap();
+        assert!(connection.is_empty("tests.ack"));
+    }
+
+    #[test]
+    fn failed_jobs() {
+        let connection = memory::Connection::new(vec![queue("tests.ack")]);
+        let broker: Arc<Broker> = Arc::new(connection.clone());
+        let properties = Properties::named("ack", "", "tests.ack");
+        broker.publish(b"[1]", &properties).wait().unwrap();
+        let deliveries = broker.consume(1).wait().unwrap();
+        let mut deliveries = deliveries.wait();
+
+        // The handler fails without acknowledging the job, whether it dropped its context or not.
+        let (acknowledgement, acked) = Acknowledgement::channel();
+        let delivery = deliveries.next().unwrap().unwrap();
+        let task = future::err::<(), Error>(ErrorKind::Timeout(Duration::from_secs(1)).into());
+        let (delivery, outcome) = manually(delivery, task, acked).wait().unwrap();
+        assert!(outcome.unwrap_err().is_timeout());
+        drop(acknowledgement);
+        // The job is left to the failure policy of its queue, e.g: requeued.
+        delivery.requeue().wait().unwrap();
+        assert_eq!(connection.len("tests.ack"), 1);
+
+        let (_, acked) = Acknowledgement::channel();
+        let delivery = deliveries.next().unwrap().unwrap();
+        let task = future::err::<(), Error>(ErrorKind::Timeout(Duration::from_secs(1)).into());
+        let (delivery, outcome) = manually(delivery, task, acked).wait().unwrap();
+        assert!(outcome.is_err());
+        delivery.requeue().wait().unwrap();
+        assert_eq!(connection.len("tests.ack"), 1);
+    }
+}
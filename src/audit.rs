@@ -0,0 +1,302 @@
+//! History of the attempts at executing jobs.
+//!
+//! A worker given an `AuditSink` with `WorkerBuilder::audit_sink` records each attempt at
+//! executing a job once it completed: when it started and ended, its outcome and the error it
+//! failed with, if any, along with the identity of the worker that executed it. Jobs that weren't
+//! executed, e.g: because they were revoked or expired, aren't recorded.
+//!
+//! Sinks able to query their history return the attempts of a job with `AuditSink::history`, as
+//! the `batch history` command of `batch-cli` and the dashboard of `batch-dashboard` do.
+//!
+//! This module provides `JsonLines`, appending attempts to a file, and `Stdout`, printing them.
+//! `batch_postgres::AuditLog` stores them in a PostgreSQL table.
+//!
+//! # Example
+//!
+//! ```rust
+//! use batch::audit::JsonLines;
+//! use batch::Worker;
+//!
+//! let builder = Worker::builder(()).audit_sink(JsonLines::new("/var/log/batch/attempts.jsonl"));
+//! ```
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{self, DateTime, Utc};
+use failure;
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use hostname;
+use serde_json::{self, de};
+use uuid::Uuid;
+
+use broker::Properties;
+use crash::Panic;
+use error::{Error, Result};
+use job::{Failure, Status};
+
+/// An attempt at executing a job.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attempt {
+    /// The unique ID of the job.
+    pub id: Uuid,
+    /// The name of the job.
+    pub task: String,
+    /// The queue the job was consumed from, if known.
+    pub queue: Option<String>,
+    /// The number of this attempt, starting at 1.
+    pub attempt: u32,
+    /// The unique identifier of the worker that executed the job, see `monitor::Heartbeat`.
+    pub worker: Uuid,
+    /// The name of the host the worker runs on, if it could be determined.
+    pub hostname: Option<String>,
+    /// The ID of the worker's process.
+    pub pid: u32,
+    /// When the execution of the job started.
+    pub started_at: DateTime<Utc>,
+    /// When the execution of the job ended.
+    pub finished_at: DateTime<Utc>,
+    /// How long the execution of the job took.
+    pub duration: Duration,
+    /// The outcome of the attempt, either `Status::Success` or `Status::Failed`.
+    pub status: Status,
+    /// The description of the error the attempt failed with, if it failed.
+    ///
+    /// This is the message of the panic for jobs whose handler panicked, and the reason of the
+    /// failure otherwise, the error returned by the handler being logged by the worker.
+    pub error: Option<String>,
+}
+
+/// A sink recording the attempts at executing jobs, see `WorkerBuilder::audit_sink`.
+pub trait AuditSink: fmt::Debug + Send + Sync {
+    /// Record the given attempt.
+    fn record(&self, attempt: &Attempt) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Return the recorded attempts at executing the job with the given ID, oldest first.
+    ///
+    /// Fails by default, for sinks that don't keep a queryable history.
+    fn history(&self, _id: Uuid) -> Box<Future<Item = Vec<Attempt>, Error = Error> + Send> {
+        let e = failure::err_msg("this audit sink doesn't keep a queryable history");
+        Box::new(future::err(Error::audit(e)))
+    }
+}
+
+/// An `AuditSink` appending attempts to a file, as a JSON document per line.
+///
+/// Attempts are written one at a time on a dedicated thread, not to block the event loop nor
+/// interleave the lines written by concurrent jobs. The history of a job is read by scanning the
+/// whole file: rotate it, e.g: with `logrotate`, to keep queries fast.
+#[derive(Clone, Debug)]
+pub struct JsonLines {
+    path: PathBuf,
+    pool: CpuPool,
+}
+
+impl JsonLines {
+    /// Create a new `JsonLines` sink appending attempts to the file at the given path.
+    ///
+    /// The file is created when the first attempt is recorded, if it doesn't exist.
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        JsonLines {
+            path: path.into(),
+            pool: CpuPool::new(1),
+        }
+    }
+}
+
+impl AuditSink for JsonLines {
+    fn record(&self, attempt: &Attempt) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut line = match serde_json::to_vec(attempt) {
+            Ok(line) => line,
+            Err(e) => return Box::new(future::err(Error::audit(e))),
+        };
+        line.push(b'\n');
+        let path = self.path.clone();
+        let task = self.pool.spawn_fn(move || {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(&line))
+                .map_err(Error::audit)
+        });
+        Box::new(task)
+    }
+
+    fn history(&self, id: Uuid) -> Box<Future<Item = Vec<Attempt>, Error = Error> + Send> {
+        let path = self.path.clone();
+        let task = self.pool.spawn_fn(move || {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(Error::audit(e)),
+            };
+            let attempts = contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| match serde_json::from_str::<Attempt>(line) {
+                    Ok(attempt) => Some(attempt),
+                    Err(e) => {
+                        warn!("Couldn't parse attempt in {}: {}", path.display(), e);
+                        None
+                    }
+                })
+                .filter(|attempt| attempt.id == id)
+                .collect();
+            Ok(attempts)
+        });
+        Box::new(task)
+    }
+}
+
+/// An `AuditSink` printing attempts on the standard output, as a JSON document per line.
+///
+/// This is meant for workers whose output is collected by a log aggregator. The history of jobs
+/// can't be queried from this sink.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stdout;
+
+impl AuditSink for Stdout {
+    fn record(&self, attempt: &Attempt) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = serde_json::to_string(attempt)
+            .map(|line| println!("{}", line))
+            .map_err(Error::audit);
+        Box::new(future::result(task))
+    }
+}
+
+/// The `AuditSink` of a worker, along with the identity of the worker.
+#[derive(Debug)]
+pub(crate) struct Auditor {
+    sink: Arc<AuditSink>,
+    worker: Uuid,
+    hostname: Option<String>,
+    pid: u32,
+}
+
+impl Auditor {
+    /// Create a new `Auditor` recording the attempts of the given worker in the given sink.
+    pub(crate) fn new(sink: Arc<AuditSink>, worker: Uuid) -> Self {
+        Auditor {
+            sink,
+            worker,
+            hostname: hostname::get_hostname(),
+            pid: process::id(),
+        }
+    }
+}
+
+/// Record the attempt at executing the given job that just ended, after the given duration.
+///
+/// The returned `Future` never fails: errors are logged instead.
+pub(crate) fn record(
+    auditor: Option<&Arc<Auditor>>,
+    properties: &Properties,
+    queue: Option<&str>,
+    duration: Duration,
+    execution: &Result<(Status, Vec<u8>)>,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let auditor = match auditor {
+        Some(auditor) => auditor,
+        None => return Box::new(future::ok(())),
+    };
+    let (status, error) = match *execution {
+        Ok((Status::Failed(Failure::Crash), ref output)) => {
+            let panic = de::from_slice::<Panic>(output).ok();
+            let error = panic.map_or_else(|| describe(Failure::Crash), |panic| panic.message);
+            (Status::Failed(Failure::Crash), Some(error))
+        }
+        Ok((Status::Failed(failure), _)) => (Status::Failed(failure), Some(describe(failure))),
+        Ok((ref status, _)) => (status.clone(), None),
+        Err(ref e) => (Status::Failed(Failure::Error), Some(e.to_string())),
+    };
+    let finished_at = Utc::now();
+    let elapsed = chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+    let attempt = Attempt {
+        id: properties.id,
+        task: properties.task.clone(),
+        queue: queue.map(String::from),
+        attempt: properties.retries + 1,
+        worker: auditor.worker,
+        hostname: auditor.hostname.clone(),
+        pid: auditor.pid,
+        started_at: finished_at - elapsed,
+        finished_at,
+        duration,
+        status,
+        error,
+    };
+    let id = properties.id;
+    let task = auditor.sink.record(&attempt).or_else(move |e| {
+        warn!(
+            "[{}] Couldn't record the attempt at executing job: {}",
+            id, e
+        );
+        Ok(())
+    });
+    Box::new(task)
+}
+
+/// Return the description of the given failure.
+fn describe(failure: Failure) -> String {
+    let description = match failure {
+        Failure::Error => "The job handler returned an error",
+        Failure::Timeout => "The job didn't complete in time",
+        Failure::Crash => "The job crashed",
+        Failure::Expired => "The job expired before it was executed",
+        Failure::InvalidSignature => "The job wasn't signed or its signature is invalid",
+    };
+    description.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn attempt(id: Uuid, status: Status) -> Attempt {
+        let now = Utc::now();
+        Attempt {
+            id,
+            task: "audit".into(),
+            queue: Some("tests.audit".into()),
+            attempt: 1,
+            worker: Uuid::new_v4(),
+            hostname: None,
+            pid: process::id(),
+            started_at: now,
+            finished_at: now,
+            duration: Duration::from_millis(0),
+            status,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let path = env::temp_dir().join(format!("batch-audit-{}.jsonl", Uuid::new_v4()));
+        let sink = JsonLines::new(path.clone());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(sink.history(first).wait().unwrap().is_empty());
+
+        let failed = attempt(first, Status::Failed(Failure::Timeout));
+        let succeeded = attempt(first, Status::Success);
+        sink.record(&failed).wait().unwrap();
+        sink.record(&attempt(second, Status::Success))
+            .wait()
+            .unwrap();
+        sink.record(&succeeded).wait().unwrap();
+        assert_eq!(sink.history(first).wait().unwrap(), vec![failed, succeeded]);
+        assert!(Stdout.history(first).wait().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+//! Abstraction over the storage of job results.
+//!
+//! By default, jobs are fire-and-forget: the value returned by their handler is discarded. When a
+//! `ResultBackend` is given to both the `Client` and the `Worker`, the outcome of each job sent
+//! with `Query::send_and_wait` is forwarded to the client.
+
+use std::fmt;
+
+use futures::Future;
+
+use broker::Properties;
+use error::Error;
+use job::Failure;
+
+/// The outcome of a job's execution.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Outcome {
+    /// The job completed successfully, carrying the serialized value returned by its handler.
+    Success(Vec<u8>),
+    /// The job failed and won't be retried.
+    Failed(Failure),
+}
+
+/// A storage for the outcome of jobs.
+pub trait ResultBackend: fmt::Debug + Send + Sync {
+    /// Prepare the properties of a job about to be sent so that its outcome can be retrieved.
+    ///
+    /// Returns a `Future` resolving to the outcome of the job once the worker stored it. This is
+    /// called before the job is published, so that no outcome can be missed.
+    fn subscribe(
+        &self,
+        properties: &mut Properties,
+    ) -> Box<Future<Item = Outcome, Error = Error> + Send>;
+
+    /// Store the outcome of a job.
+    ///
+    /// Returns a `Future` that completes once the outcome is stored.
+    fn store(
+        &self,
+        properties: &Properties,
+        outcome: &Outcome,
+    ) -> Box<Future<Item = (), Error = Error> + Send>;
+}
@@ -0,0 +1,108 @@
+//! A limit on the number of jobs a `Client` publishes at the same time.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+use error::Error;
+
+/// The permits to publish jobs, shared by the clones of a `Client`.
+///
+/// Publishing a job takes a permit, released once the broker accepted the job. When no permit is
+/// left, the jobs wait for one in the order they were sent, so that a client sending jobs faster
+/// than the broker accepts them is slowed down rather than buffering them.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    available: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl InFlight {
+    /// Create a new `InFlight`, allowing `limit` jobs to be published at the same time.
+    pub(crate) fn new(limit: usize) -> Arc<Self> {
+        Arc::new(InFlight {
+            state: Mutex::new(State {
+                available: limit.max(1),
+                waiting: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Return a `Future` resolving to a permit once one is available.
+    pub(crate) fn acquire(this: &Arc<Self>) -> Box<Future<Item = Permit, Error = Error> + Send> {
+        let mut state = this.state.lock().unwrap();
+        if state.available > 0 {
+            state.available -= 1;
+            return Box::new(future::ok(Permit(Some(Arc::clone(this)))));
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push_back(sender);
+        let in_flight = Arc::clone(this);
+        Box::new(receiver.map_err(move |_| {
+            drop(in_flight);
+            unreachable!("the senders are only dropped along with the permits, kept alive here")
+        }))
+    }
+
+    /// Give a released permit to the next job waiting for one, if any.
+    fn release(this: &Arc<Self>) {
+        let mut state = this.state.lock().unwrap();
+        while let Some(sender) = state.waiting.pop_front() {
+            // The permit comes back if the job stopped waiting.
+            match sender.send(Permit(Some(Arc::clone(this)))) {
+                Ok(()) => return,
+                Err(mut permit) => permit.0 = None,
+            }
+        }
+        state.available += 1;
+    }
+}
+
+/// A permit to publish a job, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit(Option<Arc<InFlight>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(in_flight) = self.0.take() {
+            InFlight::release(&in_flight);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits() {
+        let in_flight = InFlight::new(1);
+        let first = InFlight::acquire(&in_flight).wait().unwrap();
+        let mut second = InFlight::acquire(&in_flight);
+        let third = InFlight::acquire(&in_flight);
+        drop(third);
+        future::lazy(|| {
+            assert!(!second.poll().unwrap().is_ready());
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+        drop(first);
+        let second = second.wait().unwrap();
+        let mut fourth = InFlight::acquire(&in_flight);
+        future::lazy(|| {
+            assert!(!fourth.poll().unwrap().is_ready());
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+        drop(second);
+        assert!(fourth.wait().is_ok());
+        assert_eq!(in_flight.state.lock().unwrap().available, 1);
+    }
+}
@@ -0,0 +1,321 @@
+//! Execution of jobs in batches, see `PerformBatch`.
+//!
+//! The jobs of a type registered with `WorkerBuilder::job_batch` are handed to a `Batcher` once
+//! they passed the checks of the worker. The batcher holds them until it has enough of them, or
+//! until the first one waited long enough, then executes them all with a single call to their
+//! handler. Every job of the batch then completes with the outcome of this call, and is
+//! acknowledged, retried or dead-lettered on its own.
+
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure;
+use futures::sync::oneshot;
+use futures::{future, Future};
+use tokio_timer::Delay;
+
+use broker::{Delivery, Properties};
+use crash::{self, Panic};
+use encryption::{self, Encryptor};
+use error::{Error, ErrorKind};
+use job::{Failure, Status};
+use ser;
+
+/// Type of the functions executing a batch of jobs, given their properties and payloads.
+pub(crate) type BatchFn = Fn(Vec<Properties>, Vec<Vec<u8>>) -> Box<Future<Item = (), Error = failure::Error> + Send>
+    + Send
+    + Sync;
+
+/// The settings of the batches of a job type, see `WorkerBuilder::job_batch`.
+#[derive(Clone)]
+pub(crate) struct Batching {
+    pub(crate) size: usize,
+    pub(crate) wait: Duration,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) handler: Arc<BatchFn>,
+}
+
+impl fmt::Debug for Batching {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Batching {{ size: {:?} wait: {:?} timeout: {:?} }}",
+            self.size, self.wait, self.timeout
+        )
+    }
+}
+
+/// A job waiting for its batch, along with the channel its outcome is sent through.
+type Waiting = (
+    Box<Delivery>,
+    oneshot::Sender<(Box<Delivery>, (Status, Vec<u8>))>,
+);
+
+#[derive(Default)]
+struct Pending {
+    /// Incremented each time a batch is executed, so that the timer of a batch that was already
+    /// executed doesn't execute the next one early.
+    generation: u64,
+    jobs: Vec<Waiting>,
+}
+
+/// Accumulates the jobs of a type, and executes them in batches.
+pub(crate) struct Batcher {
+    batching: Batching,
+    encryptor: Option<Arc<Encryptor>>,
+    pending: Mutex<Pending>,
+}
+
+impl Batcher {
+    /// Create a new `Batcher`. Batches without a timeout get the given default one.
+    pub(crate) fn new(
+        mut batching: Batching,
+        encryptor: Option<Arc<Encryptor>>,
+        default_timeout: Option<Duration>,
+    ) -> Self {
+        batching.size = batching.size.max(1);
+        batching.timeout = batching.timeout.or(default_timeout);
+        Batcher {
+            batching,
+            encryptor,
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    /// Add the given job to the current batch.
+    ///
+    /// Returns a `Future` resolving to the job and the outcome of its batch once it was executed.
+    pub(crate) fn add(
+        batcher: &Arc<Batcher>,
+        delivery: Box<Delivery>,
+    ) -> Box<Future<Item = (Box<Delivery>, (Status, Vec<u8>)), Error = Error> + Send> {
+        let (sender, receiver) = oneshot::channel();
+        let (full, generation) = {
+            let mut pending = batcher.pending.lock().unwrap();
+            pending.jobs.push((delivery, sender));
+            let generation = pending.generation;
+            if pending.jobs.len() >= batcher.batching.size {
+                pending.generation += 1;
+                (Some(mem::replace(&mut pending.jobs, Vec::new())), None)
+            } else if pending.jobs.len() == 1 {
+                (None, Some(generation))
+            } else {
+                (None, None)
+            }
+        };
+        // The job completing a batch executes it, and the first job of a batch executes it once
+        // it waited long enough.
+        let trigger: Box<Future<Item = (), Error = ()> + Send> = match (full, generation) {
+            (Some(jobs), _) => batcher.execute(jobs),
+            (None, Some(generation)) => {
+                let batcher = Arc::clone(batcher);
+                let task = Delay::new(Instant::now() + batcher.batching.wait)
+                    .map_err(|e| error!("Couldn't wait for the batch to fill: {}", e))
+                    .and_then(move |_| batcher.flush(generation));
+                Box::new(task)
+            }
+            (None, None) => Box::new(future::empty()),
+        };
+        let receiver = receiver.map_err(|_| Error::from(ErrorKind::JobFailed(Failure::Error)));
+        let task = receiver.select2(trigger).then(|result| match result {
+            Ok(future::Either::A((outcome, _))) => future::Either::A(future::ok(outcome)),
+            Err(future::Either::A((e, _))) => future::Either::A(future::err(e)),
+            Ok(future::Either::B((_, receiver))) | Err(future::Either::B((_, receiver))) => {
+                future::Either::B(receiver)
+            }
+        });
+        Box::new(task)
+    }
+
+    /// Execute the current batch, unless it was already.
+    fn flush(&self, generation: u64) -> Box<Future<Item = (), Error = ()> + Send> {
+        let jobs = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.generation != generation || pending.jobs.is_empty() {
+                return Box::new(future::ok(()));
+            }
+            pending.generation += 1;
+            mem::replace(&mut pending.jobs, Vec::new())
+        };
+        self.execute(jobs)
+    }
+
+    /// Execute the given jobs with a single call to their handler, and send its outcome to each
+    /// of them.
+    fn execute(&self, jobs: Vec<Waiting>) -> Box<Future<Item = (), Error = ()> + Send> {
+        let encryptor = self.encryptor.as_ref().map(|encryptor| &**encryptor);
+        let mut batch = Vec::new();
+        let mut undecryptable = Vec::new();
+        let mut properties = Vec::new();
+        let mut payloads = Vec::new();
+        for (delivery, sender) in jobs {
+            match encryption::open(encryptor, delivery.properties(), delivery.payload()) {
+                Ok(payload) => {
+                    properties.push(delivery.properties().clone());
+                    payloads.push(payload);
+                    batch.push((delivery, sender));
+                }
+                Err(e) => {
+                    error!("[{}] Couldn't process job: {}", delivery.properties().id, e);
+                    undecryptable.push((delivery, sender));
+                }
+            }
+        }
+        // Outcomes are only sent once the batch was executed: the job that triggered it waits
+        // for the execution to complete before its own outcome.
+        if batch.is_empty() {
+            fail(undecryptable);
+            return Box::new(future::ok(()));
+        }
+        debug!(
+            "Executing a batch of {} {} jobs",
+            batch.len(),
+            properties[0].task
+        );
+        crash::install_hook();
+        let handler = Arc::clone(&self.batching.handler);
+        let timeout = self.batching.timeout;
+        let task = match panic::catch_unwind(AssertUnwindSafe(|| handler(properties, payloads))) {
+            Ok(task) => task,
+            Err(panicked) => Box::new(future::err(crash::caught(panicked).into())),
+        };
+        let task = AssertUnwindSafe(task)
+            .catch_unwind()
+            .then(|result| match result {
+                Ok(result) => result,
+                Err(panicked) => Err(crash::caught(panicked).into()),
+            });
+        let task: Box<Future<Item = (), Error = failure::Error> + Send> = match timeout {
+            Some(duration) => {
+                let deadline = Delay::new(Instant::now() + duration);
+                let task = task.select2(deadline).then(move |result| match result {
+                    Ok(future::Either::A((output, _))) => Ok(output),
+                    Err(future::Either::A((e, _))) => Err(e),
+                    Ok(future::Either::B(_)) => {
+                        Err(Error::from(ErrorKind::Timeout(duration)).into())
+                    }
+                    Err(future::Either::B((e, _))) => Err(Error::from(ErrorKind::Timer(e)).into()),
+                });
+                Box::new(task)
+            }
+            None => Box::new(task),
+        };
+        let task = task.then(move |result| {
+            let status = match result {
+                Ok(()) => (Status::Success, ser::to_vec(&()).unwrap_or_default()),
+                Err(e) => {
+                    error!("Batch handler failed: {}", e);
+                    match e.downcast_ref::<Panic>() {
+                        Some(panic) => (
+                            Status::Failed(Failure::Crash),
+                            ser::to_vec(panic).unwrap_or_default(),
+                        ),
+                        None => match e.downcast_ref::<Error>() {
+                            Some(e) if e.is_timeout() => {
+                                (Status::Failed(Failure::Timeout), Vec::new())
+                            }
+                            _ => (Status::Failed(Failure::Error), Vec::new()),
+                        },
+                    }
+                }
+            };
+            for (delivery, sender) in batch {
+                let _ = sender.send((delivery, status.clone()));
+            }
+            fail(undecryptable);
+            Ok(())
+        });
+        Box::new(task)
+    }
+}
+
+/// Fail the given jobs, whose payload couldn't be decrypted.
+fn fail(jobs: Vec<Waiting>) {
+    for (delivery, sender) in jobs {
+        let _ = sender.send((delivery, (Status::Failed(Failure::Error), Vec::new())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::stream::{self, Stream};
+    use tokio;
+
+    #[derive(Debug)]
+    struct Row(Properties);
+
+    impl Delivery for Row {
+        fn properties(&self) -> &Properties {
+            &self.0
+        }
+
+        fn payload(&self) -> &[u8] {
+            b"null"
+        }
+
+        fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+
+        fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    fn batcher(calls: &Arc<AtomicUsize>, fail: bool) -> Arc<Batcher> {
+        let calls = Arc::clone(calls);
+        let handler =
+            move |properties: Vec<Properties>, _| -> Box<Future<Item = _, Error = _> + Send> {
+                calls.fetch_add(properties.len() * 100 + 1, Ordering::SeqCst);
+                if fail {
+                    Box::new(future::err(format_err!("couldn't insert rows")))
+                } else {
+                    Box::new(future::ok(()))
+                }
+            };
+        let batching = Batching {
+            size: 3,
+            wait: Duration::from_millis(50),
+            timeout: None,
+            handler: Arc::new(handler),
+        };
+        Arc::new(Batcher::new(batching, None, None))
+    }
+
+    fn run(batcher: Arc<Batcher>, jobs: usize) -> Vec<Status> {
+        let task = stream::iter_ok::<_, Error>(0..jobs)
+            .map(move |_| {
+                let delivery = Box::new(Row(Properties::named("inserts", "", "inserts")));
+                Batcher::add(&batcher, delivery)
+            })
+            .buffer_unordered(jobs)
+            .map(|(_, (status, _))| status)
+            .collect();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(task).unwrap()
+    }
+
+    #[test]
+    fn batches() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        // A batch of 3 jobs is executed right away, the last 2 jobs once they waited.
+        let statuses = run(batcher(&calls, false), 5);
+        assert_eq!(statuses, vec![Status::Success; 5]);
+        assert_eq!(calls.load(Ordering::SeqCst), 301 + 201);
+    }
+
+    #[test]
+    fn failed_batch() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let statuses = run(batcher(&calls, true), 3);
+        assert_eq!(statuses, vec![Status::Failed(Failure::Error); 3]);
+        assert_eq!(calls.load(Ordering::SeqCst), 301);
+    }
+}
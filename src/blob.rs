@@ -0,0 +1,340 @@
+//! Storage of large payloads outside of the message broker.
+//!
+//! A `Client` given a `BlobStore` with `Client::blob_store` stores the payloads exceeding a
+//! threshold in it, once they were compressed, encrypted and signed, and publishes the job with
+//! an empty payload and the key of the stored one in its `x-batch-blob` header. Workers given the
+//! same store with `WorkerBuilder::blob_store` fetch the payload before verifying and decoding the
+//! job, and delete it once the job is acknowledged.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::{future, Future, Stream};
+use futures_cpupool::CpuPool;
+use uuid::Uuid;
+
+use broker::{Deliveries, Delivery, Properties};
+use error::Error;
+use job::Failure;
+use lease::Lease;
+
+/// The header holding the key of the payload of a job stored in a `BlobStore`.
+pub const BLOB_HEADER: &str = "x-batch-blob";
+
+/// A store holding the payloads too large to be published to the message broker, see
+/// `Client::blob_store`.
+pub trait BlobStore: fmt::Debug + Send + Sync {
+    /// Store the given payload under the given key.
+    fn put(&self, key: &str, payload: Vec<u8>) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Return the payload stored under the given key.
+    fn get(&self, key: &str) -> Box<Future<Item = Vec<u8>, Error = Error> + Send>;
+
+    /// Delete the payload stored under the given key, if any.
+    fn delete(&self, key: &str) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// A `BlobStore` keeping payloads in the files of a directory.
+///
+/// The directory must be shared by the clients and the workers, e.g: through a network file
+/// system. Files are read and written on a thread pool, not to block the event loop.
+///
+/// # Example
+///
+/// ```
+/// use batch::{memory, queue, Client, Filesystem};
+///
+/// let connection = memory::Connection::new(vec![queue("videos")]);
+/// let client = Client::new(connection)
+///     .blob_store(Filesystem::new("/mnt/batch/payloads"), 4 * 1024 * 1024);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Filesystem {
+    dir: PathBuf,
+    pool: CpuPool,
+}
+
+impl Filesystem {
+    /// Create a new `Filesystem` store, keeping payloads in the given directory.
+    pub fn new<P>(dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Filesystem {
+            dir: dir.into(),
+            pool: CpuPool::new(2),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+impl BlobStore for Filesystem {
+    fn put(&self, key: &str, payload: Vec<u8>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let dir = self.dir.clone();
+        let path = self.path(key);
+        let task = self.pool.spawn_fn(move || {
+            fs::create_dir_all(&dir)
+                .and_then(|_| fs::write(&path, &payload))
+                .map_err(Error::blob)
+        });
+        Box::new(task)
+    }
+
+    fn get(&self, key: &str) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let path = self.path(key);
+        let task = self
+            .pool
+            .spawn_fn(move || fs::read(&path).map_err(Error::blob));
+        Box::new(task)
+    }
+
+    fn delete(&self, key: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let path = self.path(key);
+        let task = self.pool.spawn_fn(move || match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::blob(e)),
+        });
+        Box::new(task)
+    }
+}
+
+/// Return the key of the stored payload of the job with the given properties, if any.
+pub(crate) fn key(properties: &Properties) -> Option<&str> {
+    properties.headers.get(BLOB_HEADER).map(|key| key.as_str())
+}
+
+/// Return the payload to publish when publishing the given job again.
+///
+/// Jobs whose payload is stored in a blob store still refer to it, and are published again with
+/// an empty payload.
+pub(crate) fn republished(delivery: &Delivery) -> Vec<u8> {
+    match key(delivery.properties()) {
+        Some(_) => Vec::new(),
+        None => delivery.payload().to_vec(),
+    }
+}
+
+/// Return the payload to publish when publishing the given job again before acknowledging it,
+/// updating the given properties of the job published again.
+///
+/// Acknowledging a job fetched from the given store deletes its stored payload, which is stored
+/// again under a new key the job published again refers to. Other jobs are published again like
+/// `republished` does.
+pub(crate) fn copied(
+    store: Option<&Arc<BlobStore>>,
+    delivery: &Delivery,
+    properties: &mut Properties,
+) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+    match (key(properties).is_some(), store) {
+        (true, Some(store)) => {
+            let key = Uuid::new_v4().to_string();
+            trace!("[{}] Copying stored payload under {}", properties.id, key);
+            properties.headers.insert(BLOB_HEADER.into(), key.clone());
+            let task = store
+                .put(&key, delivery.payload().to_vec())
+                .map(|_| Vec::new());
+            Box::new(task)
+        }
+        _ => Box::new(future::ok(republished(delivery))),
+    }
+}
+
+/// A job whose payload was fetched from a `BlobStore`.
+///
+/// The payload is deleted once the job is acknowledged. It is kept when the job is rejected or
+/// dead-lettered, as the message published again (e.g: to retry the job) still refers to it.
+#[derive(Debug)]
+struct Resolved {
+    delivery: Box<Delivery>,
+    payload: Vec<u8>,
+    store: Arc<BlobStore>,
+}
+
+impl Delivery for Resolved {
+    fn properties(&self) -> &Properties {
+        self.delivery.properties()
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        self.delivery.queue()
+    }
+
+    fn lease(&self) -> Option<Box<Lease>> {
+        self.delivery.lease()
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let resolved = *self;
+        let store = resolved.store;
+        let key = key(resolved.delivery.properties())
+            .unwrap_or_default()
+            .to_string();
+        let task = resolved.delivery.ack().and_then(move |_| {
+            store.delete(&key).then(move |result| {
+                if let Err(e) = result {
+                    warn!("Couldn't delete the payload stored under {}: {}", key, e);
+                }
+                Ok(())
+            })
+        });
+        Box::new(task)
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.delivery.reject()
+    }
+
+    fn dead_letter(
+        self: Box<Self>,
+        failure: Failure,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.delivery.dead_letter(failure)
+    }
+
+    fn dead_letter_with_headers(
+        self: Box<Self>,
+        failure: Failure,
+        headers: BTreeMap<String, String>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.delivery.dead_letter_with_headers(failure, headers)
+    }
+
+    fn requeue(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.delivery.requeue()
+    }
+}
+
+/// Return a `Future` resolving to the given job, with its payload fetched from the given store
+/// if it was stored there.
+///
+/// Jobs whose payload can't be fetched are moved to their dead-letter queue, and resolve to
+/// `None`.
+fn resolve(
+    store: &Arc<BlobStore>,
+    delivery: Box<Delivery>,
+) -> Box<Future<Item = Option<Box<Delivery>>, Error = Error> + Send> {
+    let key = match key(delivery.properties()) {
+        Some(key) => key.to_string(),
+        None => return Box::new(future::ok(Some(delivery))),
+    };
+    let store = Arc::clone(store);
+    let task = store.get(&key).then(
+        move |result| -> Box<Future<Item = Option<Box<Delivery>>, Error = Error> + Send> {
+            match result {
+                Ok(payload) => {
+                    trace!(
+                        "[{}] Fetched payload of {} bytes",
+                        delivery.properties().id,
+                        payload.len()
+                    );
+                    let resolved = Resolved {
+                        delivery,
+                        payload,
+                        store,
+                    };
+                    Box::new(future::ok(Some(Box::new(resolved) as Box<Delivery>)))
+                }
+                Err(e) => {
+                    error!(
+                        "[{}] Couldn't fetch the payload stored under {}: {}",
+                        delivery.properties().id,
+                        key,
+                        e
+                    );
+                    Box::new(delivery.dead_letter(Failure::Error).map(|_| None))
+                }
+            }
+        },
+    );
+    Box::new(task)
+}
+
+/// Fetch the stored payloads of the given jobs, up to `concurrency` at a time, preserving their
+/// order.
+pub(crate) fn resolved(
+    stream: Deliveries,
+    store: Arc<BlobStore>,
+    concurrency: usize,
+) -> Deliveries {
+    let stream = stream
+        .map(move |delivery| resolve(&store, delivery))
+        .buffered(concurrency.max(1))
+        .filter_map(|delivery| delivery);
+    Box::new(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use futures::stream;
+
+    use memory;
+
+    #[derive(Debug)]
+    struct Job(Properties, Arc<Mutex<Vec<&'static str>>>);
+
+    impl Delivery for Job {
+        fn properties(&self) -> &Properties {
+            &self.0
+        }
+
+        fn payload(&self) -> &[u8] {
+            &[]
+        }
+
+        fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            self.1.lock().unwrap().push("ack");
+            Box::new(future::ok(()))
+        }
+
+        fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            self.1.lock().unwrap().push("reject");
+            Box::new(future::ok(()))
+        }
+    }
+
+    #[test]
+    fn resolve_payloads() {
+        let store = memory::Connection::new(vec![]);
+        store.put("large", b"[42]".to_vec()).wait().unwrap();
+        let settled = Arc::new(Mutex::new(Vec::new()));
+        let deliveries = vec![Some("large"), None, Some("missing")]
+            .into_iter()
+            .map(|key| -> Box<Delivery> {
+                let mut properties = Properties::named("upload", "", "upload");
+                if let Some(key) = key {
+                    properties.headers.insert(BLOB_HEADER.into(), key.into());
+                }
+                Box::new(Job(properties, Arc::clone(&settled)))
+            })
+            .collect::<Vec<_>>();
+        let stream = Box::new(stream::iter_ok(deliveries));
+        let mut resolved = resolved(stream, Arc::new(store.clone()), 2)
+            .collect()
+            .wait()
+            .unwrap();
+        // The job whose payload is missing is dead-lettered, i.e: rejected by this delivery.
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(*settled.lock().unwrap(), vec!["reject"]);
+        assert_eq!(resolved[0].payload(), b"[42]");
+        assert_eq!(resolved[1].payload(), b"");
+
+        resolved.remove(0).ack().wait().unwrap();
+        assert_eq!(*settled.lock().unwrap(), vec!["reject", "ack"]);
+        assert!(store.get("large").wait().is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Abstraction over the message brokers jobs are sent through.
+//!
+//! The `Client` and the `Worker` never talk to a message broker directly: they go through the
+//! `Broker` trait, which is implemented for `RabbitMQ` in this crate (behind the `rabbitmq`
+//! feature, enabled by default) and which can be implemented by external crates to support other
+//! transports (e.g: `batch-redis`). A broker declares the topology jobs are routed through
+//! (`Broker::declare`), publishes jobs (`Broker::publish`) and streams them to workers
+//! (`Broker::consume`), which acknowledge or reject each `Delivery` once executed.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{self, DateTime, Utc};
+use futures::{future, Future, Stream};
+use uuid::Uuid;
+
+use codec::Codec;
+use compression::Compression;
+use control::ControlChannel;
+use dead_letter::DeadLetterQueue;
+use error::{Error, ErrorKind};
+use inspect::QueueInspector;
+use job::{Failure, Job, Priority};
+use lease::Lease;
+use topology::{Exchange, Queue};
+
+/// The metadata associated to a job when it is sent through a `Broker`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Properties {
+    /// The unique ID of this job.
+    pub id: Uuid,
+    /// The name of the job, used by the worker to find the matching handler.
+    pub task: String,
+    /// The exchange the job is published to.
+    pub exchange: String,
+    /// The routing key associated to the job.
+    pub routing_key: String,
+    /// The priority of the job.
+    pub priority: Priority,
+    /// The time allowed for the job's handler to complete.
+    pub timeout: Option<Duration>,
+    /// The duration to wait before the job is delivered to a worker.
+    pub delay: Option<Duration>,
+    /// The number of times this job was already retried.
+    pub retries: u32,
+    /// The maximum number of times this job is retried, overriding the number of retries its
+    /// handler was registered with.
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    /// Where the outcome of this job should be sent, see `ResultBackend`.
+    pub reply_to: Option<String>,
+    /// The time at which the job was sent by the client.
+    #[serde(default)]
+    pub enqueued_at: Option<DateTime<Utc>>,
+    /// The duration after which the job is dropped instead of executed, counted from
+    /// `enqueued_at`.
+    #[serde(default)]
+    pub expires: Option<Duration>,
+    /// The name of the host the job was sent from.
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// The codec the job's payload is serialized with.
+    #[serde(default)]
+    pub codec: Codec,
+    /// The algorithm the job's payload is compressed with, if any.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// The version of the schema of the job's payload, see `Job::version`. Jobs sent without a
+    /// version are assumed to be at version 1.
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Custom headers associated to the job (e.g: trace IDs), see `PublishHook`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Properties {
+    /// Create a new `Properties` instance from a `Job`'s defaults.
+    pub fn new<T>() -> Self
+    where
+        T: Job,
+    {
+        Properties {
+            id: Uuid::new_v4(),
+            task: T::name().into(),
+            exchange: T::exchange().into(),
+            routing_key: T::routing_key().into(),
+            priority: T::priority(),
+            timeout: T::timeout(),
+            delay: T::delay(),
+            retries: 0,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: None,
+            expires: T::expires(),
+            origin: None,
+            codec: T::codec().unwrap_or_default(),
+            compression: None,
+            version: Some(T::version()),
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// Create a new `Properties` instance for a job without a `Job` type, e.g: one registered
+    /// with `WorkerBuilder::job_fn`.
+    ///
+    /// The job has the default priority and codec, no timeout and isn't retried.
+    pub fn named(name: &str, exchange: &str, routing_key: &str) -> Self {
+        Properties {
+            id: Uuid::new_v4(),
+            task: name.into(),
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+            priority: Priority::default(),
+            timeout: None,
+            delay: None,
+            retries: 0,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: None,
+            expires: None,
+            origin: None,
+            codec: Codec::default(),
+            compression: None,
+            version: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// Return whether the job expired, and should be dropped instead of executed.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.is_expired_at(Utc::now())
+    }
+
+    fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        match (self.enqueued_at, self.expires.map(chrono::Duration::from_std)) {
+            (Some(enqueued_at), Some(Ok(expires))) => now - enqueued_at > expires,
+            _ => false,
+        }
+    }
+}
+
+/// A message broker able to transport jobs from a `Client` to a `Worker`.
+///
+/// Implementors must be cheap to share between threads: the `Client` and the `Worker` keep a
+/// reference-counted handle to their broker.
+pub trait Broker: fmt::Debug + Send + Sync {
+    /// Publish a serialized job to the broker.
+    ///
+    /// Returns a `Future` that completes once the job is sent to the broker.
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Publish many serialized jobs to the broker at once, see `Client::send_batch`.
+    ///
+    /// Returns a `Future` that completes once every job is sent to the broker. The default
+    /// implementation publishes each job with `publish`, brokers able to publish jobs in bulk
+    /// should override it.
+    fn publish_batch(
+        &self,
+        jobs: Vec<(Vec<u8>, Properties)>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let tasks = jobs.iter()
+            .map(|&(ref payload, ref properties)| self.publish(payload, properties))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks).map(|_| ()))
+    }
+
+    /// Start consuming jobs from the broker.
+    ///
+    /// The `prefetch` argument is a hint of the number of jobs that should be fetched ahead of
+    /// their execution, brokers are free to ignore it.
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send>;
+
+    /// Start consuming jobs from the broker, except from the given queues, e.g: while they are
+    /// paused, see `Command::PauseQueue`.
+    ///
+    /// The default implementation calls `consume` when no queue is excluded, and fails with
+    /// `ErrorKind::UnsupportedQueuePause` otherwise.
+    fn consume_except(
+        &self,
+        prefetch: u16,
+        excluded: &[String],
+    ) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        if excluded.is_empty() {
+            self.consume(prefetch)
+        } else {
+            Box::new(future::err(ErrorKind::UnsupportedQueuePause.into()))
+        }
+    }
+
+    /// Declare the given exchanges and queues, e.g: before a worker starts consuming jobs.
+    ///
+    /// Returns a `Future` that completes once they are declared. The default implementation
+    /// does nothing, for brokers that create their queues on the fly or don't route jobs through
+    /// exchanges.
+    fn declare(
+        &self,
+        exchanges: &[Exchange],
+        queues: &[Queue],
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let _ = (exchanges, queues);
+        Box::new(future::ok(()))
+    }
+
+    /// Return a handle to the dead-letter queues of this broker, if it supports them.
+    fn dead_letters(&self) -> Option<Arc<DeadLetterQueue>> {
+        None
+    }
+
+    /// Return a handle to the channel transporting commands to workers, if this broker supports
+    /// remote control.
+    fn control(&self) -> Option<Arc<ControlChannel>> {
+        None
+    }
+
+    /// Return a view over the queues of this broker, if it supports inspecting them.
+    fn inspector(&self) -> Option<Arc<QueueInspector>> {
+        None
+    }
+}
+
+/// A stream of jobs received from a `Broker`.
+pub type Deliveries = Box<Stream<Item = Box<Delivery>, Error = Error> + Send>;
+
+/// A job received from a `Broker`.
+pub trait Delivery: fmt::Debug + Send {
+    /// Return the metadata associated to this job.
+    fn properties(&self) -> &Properties;
+
+    /// Return the serialized job.
+    fn payload(&self) -> &[u8];
+
+    /// Return the name of the queue this job was consumed from, if known.
+    fn queue(&self) -> Option<&str> {
+        None
+    }
+
+    /// Return the lease the worker holds on this job, if the broker delivers it again after a
+    /// while unless the lease is extended.
+    ///
+    /// The worker extends the lease while the job's handler calls `Context::touch`, see `Lease`.
+    fn lease(&self) -> Option<Box<Lease>> {
+        None
+    }
+
+    /// Acknowledge the successful execution of this job.
+    ///
+    /// Returns a `Future` that completes once the acknowledgement is sent to the broker.
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Reject this job, it won't be delivered again by the broker.
+    ///
+    /// Returns a `Future` that completes once the rejection is sent to the broker.
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Move this job to the dead-letter queue of the queue it was consumed from.
+    ///
+    /// This is called instead of `reject` once the job exhausted its retries. Brokers that don't
+    /// support dead-letter queues simply reject the job, which is the default.
+    fn dead_letter(
+        self: Box<Self>,
+        failure: Failure,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let _ = failure;
+        self.reject()
+    }
+
+    /// Move this job to its dead-letter queue, adding the given headers to its properties.
+    ///
+    /// This is used to attach details about the failure to the job (e.g: the panic of its
+    /// handler, see `Panic`). The default implementation ignores the headers and calls
+    /// `dead_letter`.
+    fn dead_letter_with_headers(
+        self: Box<Self>,
+        failure: Failure,
+        headers: BTreeMap<String, String>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let _ = headers;
+        self.dead_letter(failure)
+    }
+
+    /// Reject this job, asking the broker to deliver it again.
+    ///
+    /// This is used by the `Requeue` failure policy (see `FailurePolicy`). Brokers that can't
+    /// deliver a job again simply reject it, which is the default.
+    fn requeue(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.reject()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiration() {
+        let sent = Utc::now();
+        let mut properties = Properties {
+            id: Uuid::new_v4(),
+            task: "notify".into(),
+            exchange: "".into(),
+            routing_key: "notifications".into(),
+            priority: Priority::Normal,
+            timeout: None,
+            delay: None,
+            retries: 0,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: Some(sent),
+            expires: None,
+            origin: None,
+            codec: Codec::Json,
+            compression: None,
+            version: None,
+            headers: BTreeMap::new(),
+        };
+        let later = sent + chrono::Duration::seconds(120);
+        assert!(!properties.is_expired_at(later));
+        properties.expires = Some(Duration::from_secs(60));
+        assert!(!properties.is_expired_at(sent + chrono::Duration::seconds(30)));
+        assert!(properties.is_expired_at(later));
+        properties.enqueued_at = None;
+        assert!(!properties.is_expired_at(later));
+    }
+}
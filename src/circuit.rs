@@ -0,0 +1,314 @@
+//! A circuit breaker short-circuiting the execution of jobs failing repeatedly.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The default failure rate above which a circuit opens.
+const DEFAULT_FAILURE_RATE: f64 = 0.5;
+
+/// The default number of executions in a window before a circuit can open.
+const DEFAULT_MINIMUM_EXECUTIONS: u32 = 10;
+
+/// The default number of seconds over which the failure rate is computed.
+const DEFAULT_WINDOW: u64 = 60;
+
+/// The default number of seconds a circuit stays open.
+const DEFAULT_COOL_DOWN: u64 = 30;
+
+/// The state of the circuit of a job type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Jobs are executed normally.
+    Closed,
+    /// Jobs are published again with a delay instead of being executed.
+    Open,
+    /// The cool-down period elapsed, a single job is executed to probe the downstream service.
+    HalfOpen,
+}
+
+/// Type of the functions called when the circuit of a job type changes state.
+type Listener = Fn(&str, CircuitState) + Send + Sync;
+
+/// A circuit breaker tracking the failure rate of each job type.
+///
+/// When the failure rate of a job type exceeds the given threshold, its circuit opens: instead of
+/// being executed, and burning their retries against an unavailable downstream service, its jobs
+/// are published again to be delivered once the cool-down period elapsed. The first job executed
+/// after the cool-down period decides whether the circuit closes, or opens again.
+///
+/// The circuit breaker is given to the worker using `WorkerBuilder::circuit_breaker`. Unlike
+/// `Middleware`s, it runs in the worker's process whatever the `Isolation` of jobs, so that it
+/// sees the outcome of every job.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use batch::{CircuitBreaker, Worker};
+///
+/// let breaker = CircuitBreaker::new()
+///     .failure_rate(0.5)
+///     .cool_down(Duration::from_secs(60))
+///     .on_state_change(|task, state| println!("Circuit of {} is now {:?}", task, state));
+/// let builder = Worker::builder(())
+///     .circuit_breaker(breaker);
+/// ```
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    minimum_executions: u32,
+    window: Duration,
+    cool_down: Duration,
+    listener: Option<Arc<Listener>>,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "CircuitBreaker {{ failure_rate: {:?} window: {:?} cool_down: {:?} }}",
+            self.failure_rate, self.window, self.cool_down
+        )
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new()
+    }
+}
+
+impl CircuitBreaker {
+    /// Create a new `CircuitBreaker` with the default settings.
+    ///
+    /// By default, a circuit opens for 30 seconds when at least half of the jobs executed over
+    /// the last minute failed, and at least 10 jobs were executed.
+    pub fn new() -> Self {
+        CircuitBreaker {
+            failure_rate: DEFAULT_FAILURE_RATE,
+            minimum_executions: DEFAULT_MINIMUM_EXECUTIONS,
+            window: Duration::from_secs(DEFAULT_WINDOW),
+            cool_down: Duration::from_secs(DEFAULT_COOL_DOWN),
+            listener: None,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the failure rate, between 0 and 1, above which a circuit opens.
+    pub fn failure_rate(mut self, rate: f64) -> Self {
+        self.failure_rate = rate.max(0.0).min(1.0);
+        self
+    }
+
+    /// Set the number of jobs that must be executed in a window before a circuit can open.
+    pub fn minimum_executions(mut self, executions: u32) -> Self {
+        self.minimum_executions = executions;
+        self
+    }
+
+    /// Set the duration over which the failure rate is computed.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set how long a circuit stays open before a job is executed again.
+    pub fn cool_down(mut self, cool_down: Duration) -> Self {
+        self.cool_down = cool_down;
+        self
+    }
+
+    /// Call the given function each time the circuit of a job type changes state.
+    pub fn on_state_change<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&str, CircuitState) + Send + Sync + 'static,
+    {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Return the current state of the circuit of the given job type.
+    pub fn state(&self, task: &str) -> CircuitState {
+        self.circuits
+            .lock()
+            .unwrap()
+            .get(task)
+            .map_or(CircuitState::Closed, |circuit| circuit.state)
+    }
+
+    /// Return whether a job of the given type can be executed.
+    ///
+    /// Returns `None` if it can, or the duration to wait before delivering it again otherwise.
+    pub(crate) fn allow(&self, task: &str) -> Option<Duration> {
+        self.allow_at(task, Instant::now())
+    }
+
+    /// Record the outcome of the execution of a job of the given type.
+    pub(crate) fn record(&self, task: &str, success: bool) {
+        self.record_at(task, success, Instant::now())
+    }
+
+    fn allow_at(&self, task: &str, now: Instant) -> Option<Duration> {
+        {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = match circuits.get_mut(task) {
+                Some(circuit) => circuit,
+                None => return None,
+            };
+            match circuit.state {
+                CircuitState::Closed => return None,
+                CircuitState::Open if now >= circuit.changed_at + self.cool_down => {
+                    circuit.transition(CircuitState::HalfOpen, now);
+                }
+                CircuitState::Open => return Some(circuit.changed_at + self.cool_down - now),
+                // A job is already probing the downstream service.
+                CircuitState::HalfOpen => return Some(self.cool_down),
+            }
+        }
+        self.notify(task, CircuitState::HalfOpen);
+        None
+    }
+
+    fn record_at(&self, task: &str, success: bool, now: Instant) {
+        let state = {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits
+                .entry(task.into())
+                .or_insert_with(|| Circuit::new(now));
+            let state = match circuit.state {
+                CircuitState::HalfOpen if success => CircuitState::Closed,
+                CircuitState::HalfOpen => CircuitState::Open,
+                CircuitState::Open => return,
+                CircuitState::Closed => {
+                    if now >= circuit.window_start + self.window {
+                        circuit.reset(now);
+                    }
+                    if success {
+                        circuit.successes += 1;
+                    } else {
+                        circuit.failures += 1;
+                    }
+                    let executions = circuit.successes + circuit.failures;
+                    let rate = f64::from(circuit.failures) / f64::from(executions);
+                    if executions < self.minimum_executions || rate < self.failure_rate {
+                        return;
+                    }
+                    CircuitState::Open
+                }
+            };
+            circuit.transition(state, now);
+            state
+        };
+        self.notify(task, state);
+    }
+
+    /// Log the given state change, and hand it to the listener, if any.
+    fn notify(&self, task: &str, state: CircuitState) {
+        match state {
+            CircuitState::Open => warn!("Circuit of {} opened", task),
+            _ => info!("Circuit of {} is now {:?}", task, state),
+        }
+        if let Some(ref listener) = self.listener {
+            listener(task, state);
+        }
+    }
+}
+
+/// The circuit of a job type.
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    changed_at: Instant,
+    window_start: Instant,
+    successes: u32,
+    failures: u32,
+}
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            changed_at: now,
+            window_start: now,
+            successes: 0,
+            failures: 0,
+        }
+    }
+
+    fn transition(&mut self, state: CircuitState, now: Instant) {
+        self.state = state;
+        self.changed_at = now;
+        self.reset(now);
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.window_start = now;
+        self.successes = 0;
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&changes);
+        let breaker = CircuitBreaker::new()
+            .minimum_executions(4)
+            .cool_down(Duration::from_secs(30))
+            .on_state_change(move |task, state| {
+                recorded.lock().unwrap().push((task.to_string(), state))
+            });
+        let start = Instant::now();
+        breaker.record_at("sync", true, start);
+        breaker.record_at("sync", false, start);
+        breaker.record_at("sync", true, start);
+        assert_eq!(breaker.state("sync"), CircuitState::Closed);
+        breaker.record_at("sync", false, start);
+        assert_eq!(breaker.state("sync"), CircuitState::Open);
+        assert_eq!(breaker.allow_at("other", start), None);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(breaker.allow_at("sync", later), Some(Duration::from_secs(20)));
+        let later = start + Duration::from_secs(30);
+        assert_eq!(breaker.allow_at("sync", later), None);
+        assert_eq!(breaker.allow_at("sync", later), Some(Duration::from_secs(30)));
+        breaker.record_at("sync", false, later);
+        assert_eq!(breaker.state("sync"), CircuitState::Open);
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(breaker.allow_at("sync", later), None);
+        breaker.record_at("sync", true, later);
+        assert_eq!(breaker.state("sync"), CircuitState::Closed);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                ("sync".to_string(), CircuitState::Open),
+                ("sync".to_string(), CircuitState::HalfOpen),
+                ("sync".to_string(), CircuitState::Open),
+                ("sync".to_string(), CircuitState::HalfOpen),
+                ("sync".to_string(), CircuitState::Closed),
+            ]
+        );
+    }
+
+    #[test]
+    fn window() {
+        let breaker = CircuitBreaker::new()
+            .minimum_executions(2)
+            .window(Duration::from_secs(60));
+        let start = Instant::now();
+        breaker.record_at("sync", false, start);
+        breaker.record_at("sync", false, start + Duration::from_secs(61));
+        assert_eq!(breaker.state("sync"), CircuitState::Closed);
+        breaker.record_at("sync", false, start + Duration::from_secs(62));
+        assert_eq!(breaker.state("sync"), CircuitState::Open);
+    }
+}
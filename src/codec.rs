@@ -0,0 +1,121 @@
+//! Serialization formats of job payloads.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[cfg(feature = "msgpack")]
+use rmp_serde;
+#[cfg(feature = "cbor")]
+use serde_cbor;
+
+use de;
+use error::{ErrorKind, Result};
+use ser;
+
+/// The format used to serialize the payload of a job.
+///
+/// The codec of a job is given by its `Job::codec` value, which can be set using the `codec`
+/// attribute when deriving `Job`, falling back to the client's default codec (see
+/// `Client::default_codec`). It is sent along with the job (e.g: as the `content_type` property
+/// of `RabbitMQ` messages), so that workers can decode jobs serialized with different codecs.
+///
+/// MessagePack and CBOR support are respectively enabled by the `msgpack` and `cbor` features.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Codec {
+    /// JSON, the default.
+    Json,
+    /// MessagePack, with structs serialized as maps.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// CBOR.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Json
+    }
+}
+
+impl Codec {
+    /// Return the MIME type of the payloads serialized with this codec.
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            Codec::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "application/cbor",
+        }
+    }
+
+    /// Return the codec matching the given MIME type, if it is supported.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "application/json" => Some(Codec::Json),
+            #[cfg(feature = "msgpack")]
+            "application/msgpack" | "application/x-msgpack" => Some(Codec::MessagePack),
+            #[cfg(feature = "cbor")]
+            "application/cbor" => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Serialize the given value using this codec.
+    pub fn encode<T>(&self, value: &T) -> Result<Vec<u8>>
+    where
+        T: Serialize,
+    {
+        let encoded = match *self {
+            Codec::Json => ser::to_vec(value).map_err(ErrorKind::Serialization)?,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|e| ErrorKind::Codec(e.into()))?
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => serde_cbor::to_vec(value).map_err(|e| ErrorKind::Codec(e.into()))?,
+        };
+        Ok(encoded)
+    }
+
+    /// Deserialize a value serialized using this codec.
+    pub fn decode<T>(&self, raw: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let decoded = match *self {
+            Codec::Json => de::from_slice(raw).map_err(ErrorKind::Deserialization)?,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::from_slice(raw).map_err(|e| ErrorKind::Codec(e.into()))?
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => serde_cbor::from_slice(raw).map_err(|e| ErrorKind::Codec(e.into()))?,
+        };
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn round_trip() {
+        let mut value = BTreeMap::new();
+        value.insert("to".to_string(), vec![1u32, 2, 3]);
+        #[allow(unused_mut)]
+        let mut codecs = vec![Codec::Json];
+        #[cfg(feature = "msgpack")]
+        codecs.push(Codec::MessagePack);
+        #[cfg(feature = "cbor")]
+        codecs.push(Codec::Cbor);
+        for codec in codecs {
+            let raw = codec.encode(&value).unwrap();
+            assert_eq!(codec.decode::<BTreeMap<String, Vec<u32>>>(&raw).unwrap(), value);
+            assert_eq!(Codec::from_content_type(codec.content_type()), Some(codec));
+        }
+    }
+}
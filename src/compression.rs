@@ -0,0 +1,103 @@
+//! Compression of job payloads.
+
+use std::io::{Read, Write};
+
+use flate2;
+#[cfg(feature = "zstd")]
+use zstd;
+
+use error::{ErrorKind, Result};
+
+/// An algorithm used to compress the serialized payload of a job.
+///
+/// Compression is enabled on the client with `Client::compression`, and only applies to payloads
+/// larger than a given threshold. The algorithm is sent along with the job (e.g: as the
+/// `content_encoding` property of `RabbitMQ` messages), and payloads are transparently
+/// decompressed by the worker before being deserialized.
+///
+/// Zstandard support is enabled by the `zstd` feature.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Compression {
+    /// gzip, as described in RFC 1952.
+    Gzip,
+    /// Zstandard.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// Return the content encoding of the payloads compressed with this algorithm.
+    pub fn content_encoding(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Return the algorithm matching the given content encoding, if it is supported.
+    pub fn from_content_encoding(content_encoding: &str) -> Option<Self> {
+        match content_encoding {
+            "gzip" => Some(Compression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compress the given payload using this algorithm.
+    pub fn compress(&self, raw: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match *self {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(raw)
+                    .and_then(|_| encoder.finish())
+                    .map_err(ErrorKind::Compression)?
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(raw, 0).map_err(ErrorKind::Compression)?,
+        };
+        Ok(compressed)
+    }
+
+    /// Decompress a payload compressed using this algorithm.
+    pub fn decompress(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        let raw = match *self {
+            Compression::Gzip => {
+                let mut raw = Vec::new();
+                flate2::read::GzDecoder::new(compressed)
+                    .read_to_end(&mut raw)
+                    .map_err(ErrorKind::Compression)?;
+                raw
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::decode_all(compressed).map_err(ErrorKind::Compression)?,
+        };
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let raw = "{\"to\":\"john@doe.com\"}".repeat(64).into_bytes();
+        #[allow(unused_mut)]
+        let mut algorithms = vec![Compression::Gzip];
+        #[cfg(feature = "zstd")]
+        algorithms.push(Compression::Zstd);
+        for algorithm in algorithms {
+            let compressed = algorithm.compress(&raw).unwrap();
+            assert!(compressed.len() < raw.len());
+            assert_eq!(algorithm.decompress(&compressed).unwrap(), raw);
+            assert_eq!(
+                Compression::from_content_encoding(algorithm.content_encoding()),
+                Some(algorithm)
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Limits on the number of jobs of a type executing at the same time.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use uuid::Uuid;
+
+use broker::Properties;
+use error::Error;
+
+/// A set of counting semaphores, limiting the number of jobs of a type executing at the same
+/// time.
+///
+/// The limit of a job type is given by its `Job::concurrency` value, which can be set using the
+/// `concurrency` attribute when deriving `Job`. `Semaphores` keeps its permits in memory,
+/// limiting the jobs executed by a single worker. To limit the jobs executed by a fleet of
+/// workers, give them a semaphore shared through an external storage, such as
+/// `batch_redis::Connection`, using `WorkerBuilder::semaphore`.
+pub trait Semaphore: fmt::Debug + Send + Sync {
+    /// Take one of the `permits` permits of the semaphore identified by the given key, on behalf
+    /// of the given holder.
+    ///
+    /// The permit is kept until it is released, or until `lease` elapsed if it never is (e.g:
+    /// because its worker crashed). Returns a `Future` resolving to whether a permit was taken,
+    /// taking a permit already held by the given holder always succeeds.
+    fn acquire(
+        &self,
+        key: &str,
+        permits: u32,
+        holder: Uuid,
+        lease: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send>;
+
+    /// Release the permit of the semaphore identified by the given key held by the given holder.
+    fn release(&self, key: &str, holder: Uuid) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// A `Semaphore` keeping its permits in memory.
+///
+/// This is the semaphore used by workers by default.
+#[derive(Debug, Default)]
+pub struct Semaphores {
+    holders: Mutex<HashMap<String, HashMap<Uuid, Instant>>>,
+}
+
+impl Semaphores {
+    /// Create a new `Semaphores`, with no permit taken.
+    pub fn new() -> Self {
+        Semaphores::default()
+    }
+}
+
+impl Semaphore for Semaphores {
+    fn acquire(
+        &self,
+        key: &str,
+        permits: u32,
+        holder: Uuid,
+        lease: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let now = Instant::now();
+        let mut holders = self.holders.lock().unwrap();
+        let holders = holders.entry(key.into()).or_insert_with(HashMap::new);
+        holders.retain(|_, expires_at| *expires_at > now);
+        if !holders.contains_key(&holder) && holders.len() >= permits as usize {
+            return Box::new(future::ok(false));
+        }
+        holders.insert(holder, now + lease);
+        Box::new(future::ok(true))
+    }
+
+    fn release(&self, key: &str, holder: Uuid) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut holders = self.holders.lock().unwrap();
+        if let Some(holders) = holders.get_mut(key) {
+            holders.remove(&holder);
+        }
+        Box::new(future::ok(()))
+    }
+}
+
+/// The concurrency limits enforced by a worker, along with the semaphore enforcing them.
+#[derive(Clone)]
+pub(crate) struct Limits {
+    semaphore: Arc<Semaphore>,
+    jobs: HashMap<&'static str, u32>,
+}
+
+impl fmt::Debug for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Limits {{ semaphore: {:?} jobs: {:?} }}",
+            self.semaphore, self.jobs
+        )
+    }
+}
+
+impl Limits {
+    pub(crate) fn new(semaphore: Arc<Semaphore>, jobs: HashMap<&'static str, u32>) -> Self {
+        Limits { semaphore, jobs }
+    }
+
+    /// Take a permit allowing the given job to execute, if its type is limited.
+    ///
+    /// Returns a `Future` resolving to whether the job is allowed to execute.
+    pub(crate) fn acquire(
+        &self,
+        properties: &Properties,
+        lease: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        match self.jobs.get(properties.task.as_str()) {
+            Some(&permits) => {
+                self.semaphore
+                    .acquire(&key(&properties.task), permits, properties.id, lease)
+            }
+            None => Box::new(future::ok(true)),
+        }
+    }
+
+    /// Release the permit taken by the given job, if its type is limited.
+    pub(crate) fn release(
+        &self,
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        if self.jobs.contains_key(properties.task.as_str()) {
+            self.semaphore.release(&key(&properties.task), properties.id)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+/// Return the key of the semaphore limiting the given job type.
+fn key(task: &str) -> String {
+    format!("job:{}", task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits() {
+        let semaphores = Semaphores::new();
+        let minute = Duration::from_secs(60);
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert!(semaphores.acquire("job", 2, first, minute).wait().unwrap());
+        assert!(semaphores.acquire("job", 2, second, minute).wait().unwrap());
+        assert!(semaphores.acquire("job", 2, first, minute).wait().unwrap());
+        assert!(!semaphores.acquire("job", 2, third, minute).wait().unwrap());
+        assert!(semaphores.acquire("other", 2, third, minute).wait().unwrap());
+        semaphores.release("job", first).wait().unwrap();
+        assert!(semaphores.acquire("job", 2, third, minute).wait().unwrap());
+        // Permits whose lease elapsed are released.
+        let expired = Duration::from_secs(0);
+        assert!(semaphores.acquire("expired", 1, first, expired).wait().unwrap());
+        assert!(semaphores.acquire("expired", 1, second, expired).wait().unwrap());
+    }
+}
@@ -0,0 +1,651 @@
+//! Context given to job handlers.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use failure;
+use futures::{future, Future, IntoFuture};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use uuid::Uuid;
+
+use ack::Acknowledgement;
+use blob::BlobStore;
+use broker::Properties;
+use enqueue::Enqueue;
+use error::{Error, ErrorKind, Result};
+use extensions::Extensions;
+use idempotency::Idempotency;
+use job::Job;
+use lease::Touch;
+use progress::Progress;
+use query::{self, Query};
+use reschedule::Reschedule;
+use status::StatusStore;
+use tenancy::TENANT_HEADER;
+use trace::{TraceContext, TRACEPARENT};
+use workflow;
+
+/// The context given to a job's handler.
+///
+/// It wraps the worker's context value (see `Worker::builder`), which can be accessed through
+/// `Deref`, and exposes the metadata of the job being executed: its ID, the time at which it was
+/// sent, the number of times it was already retried, the host it was sent from and its custom
+/// headers. It also carries a `CancellationToken`, allowing long-running handlers to stop early
+/// when the job is revoked, and a `Progress` handle to report their progress. The values given
+/// to `WorkerBuilder::provide` are retrieved by type using `Context::get`. Handlers can send
+/// other jobs with `Context::enqueue`, and reschedule their job with `Context::retry_in` and
+/// `Context::retry_with`.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// extern crate failure;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Context, Perform};
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job(routing_key = "emails")]
+/// struct SendPasswordResetEmail;
+///
+/// impl Perform for SendPasswordResetEmail {
+///     type Context = ();
+///     type Output = ();
+///     type Error = failure::Error;
+///     type Future = Result<Self::Output, Self::Error>;
+///
+///     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+///         println!("[{}] Attempt #{}", ctx.id(), ctx.attempt());
+///         ctx.progress().message("Sending email");
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() {}
+/// ```
+#[derive(Clone, Debug)]
+pub struct Context<C> {
+    inner: C,
+    properties: Properties,
+    cancellation: CancellationToken,
+    progress: Progress,
+    trace: Option<TraceContext>,
+    extensions: Extensions,
+    acknowledgement: Acknowledgement,
+    touch: Touch,
+    reschedule: Reschedule,
+    enqueue: Enqueue,
+    blobs: Option<Arc<BlobStore>>,
+}
+
+impl<C> Context<C> {
+    /// Create a new `Context` for the job described by the given properties.
+    ///
+    /// The progress reported through the returned context isn't recorded.
+    pub fn new(inner: C, properties: Properties) -> Self {
+        let progress = Progress::new(properties.id, None);
+        let trace = TraceContext::extract(&properties).map(|parent| parent.child());
+        Context {
+            inner,
+            properties,
+            cancellation: CancellationToken::new(),
+            progress,
+            trace,
+            extensions: Extensions::new(),
+            acknowledgement: Acknowledgement::none(),
+            touch: Touch::none(),
+            reschedule: Reschedule::new(),
+            enqueue: Enqueue::new(),
+            blobs: None,
+        }
+    }
+
+    /// Hand the given values to the job's handler, see `Context::get`.
+    pub(crate) fn provide(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+    }
+
+    /// Acknowledge the job through the given function when `Context::ack` is called.
+    pub(crate) fn acknowledge_with(&mut self, acknowledgement: Acknowledgement) {
+        self.acknowledgement = acknowledgement;
+    }
+
+    /// Report through the given function that the handler is making progress, see
+    /// `Context::touch`.
+    pub(crate) fn lease_with(&mut self, touch: Touch) {
+        self.touch = touch;
+    }
+
+    /// Hand the rescheduling requested by the handler to the given handle, see
+    /// `Context::retry_in`.
+    pub(crate) fn reschedule_with(&mut self, reschedule: Reschedule) {
+        self.reschedule = reschedule;
+    }
+
+    /// Hand the jobs enqueued by the handler to the given handle, see `Context::enqueue`.
+    pub(crate) fn enqueue_with(&mut self, enqueue: Enqueue) {
+        self.enqueue = enqueue;
+    }
+
+    /// Fetch the results handed to the job from the given store, see `Context::parent_result`.
+    pub(crate) fn fetch_results_from(&mut self, store: Option<Arc<BlobStore>>) {
+        self.blobs = store;
+    }
+
+    /// Record the progress reported through this context in the given store.
+    pub(crate) fn record_progress(&mut self, statuses: Arc<StatusStore>) {
+        self.progress = Progress::new(self.properties.id, Some(statuses));
+    }
+
+    /// Return the properties of the job being executed.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Return the unique ID of the job being executed.
+    pub fn id(&self) -> Uuid {
+        self.properties.id
+    }
+
+    /// Return the time at which the job was sent, if known.
+    pub fn enqueued_at(&self) -> Option<DateTime<Utc>> {
+        self.properties.enqueued_at
+    }
+
+    /// Return the number of times the job was already retried.
+    pub fn retries(&self) -> u32 {
+        self.properties.retries
+    }
+
+    /// Return the number of the attempt at executing the job, starting at 1.
+    pub fn attempt(&self) -> u32 {
+        self.properties.retries + 1
+    }
+
+    /// Return the name of the host the job was sent from, if known.
+    pub fn origin(&self) -> Option<&str> {
+        self.properties.origin.as_ref().map(|origin| origin.as_str())
+    }
+
+    /// Return the value of the given custom header of the job, if any.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.properties.headers.get(name).map(|value| value.as_str())
+    }
+
+    /// Return the tenant the job was sent for, if any, see `Client::for_tenant`.
+    pub fn tenant(&self) -> Option<&str> {
+        self.header(TENANT_HEADER)
+    }
+
+    /// Return the token signaling that the job was revoked.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Return whether the job was revoked while executing, see `Client::cancel`.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Return the handle used to report the progress of the job.
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Acknowledge the job before its handler completes.
+    ///
+    /// Once acknowledged, the job won't be delivered again if the worker dies while executing
+    /// it. It is still retried if its handler fails afterwards, but can't be requeued or moved to
+    /// its dead-letter queue anymore. Returns `false`, doing nothing, unless the job was consumed
+    /// from a queue acknowledged manually, see `WorkerBuilder::ack_mode`.
+    pub fn ack(&self) -> bool {
+        self.acknowledgement.ack()
+    }
+
+    /// Report that the handler is still making progress, extending the lease of the job.
+    ///
+    /// Some brokers deliver a job again if it isn't completed within a limited time, e.g: the
+    /// visibility timeout of SQS messages. The worker extends the lease of the job while its
+    /// handler calls this method at least once per lease duration, see
+    /// `WorkerBuilder::lease_duration`. This does nothing with other brokers.
+    pub fn touch(&self) {
+        self.touch.touch()
+    }
+
+    /// Send the given job once the handler of this one succeeded.
+    ///
+    /// The job is published by the worker through its own connection to the broker, before this
+    /// one is acknowledged: handlers spawning follow-up jobs don't need a `Client` of their own.
+    /// The jobs enqueued by a failed handler are discarded, as they are enqueued again when the
+    /// job is retried. Fails if the given job can't be encoded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// extern crate failure;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{Context, Perform};
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job(routing_key = "users")]
+    /// struct SignUp {
+    ///     email: String,
+    /// }
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job(routing_key = "emails")]
+    /// struct SendWelcomeEmail {
+    ///     to: String,
+    /// }
+    ///
+    /// impl Perform for SignUp {
+    ///     type Context = ();
+    ///     type Output = ();
+    ///     type Error = failure::Error;
+    ///     type Future = Result<Self::Output, Self::Error>;
+    ///
+    ///     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+    ///         ctx.enqueue(SendWelcomeEmail {
+    ///             to: self.email.clone(),
+    ///         })?;
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub fn enqueue<J>(&self, job: J) -> Result<()>
+    where
+        J: Job + Send + 'static,
+    {
+        self.enqueue_query(query::job(job))
+    }
+
+    /// Send the job of the given query once the handler of this one succeeded.
+    ///
+    /// This is `Context::enqueue` for jobs sent with options, e.g: a delay or a priority. The
+    /// job joins the trace of this one, unless the query carries a trace context already. Its
+    /// uniqueness isn't checked, see `Query::into_outbox_entry`.
+    pub fn enqueue_query<J>(&self, query: Query<J>) -> Result<()>
+    where
+        J: Job + Send + 'static,
+    {
+        let traced = query.properties().headers.contains_key(TRACEPARENT);
+        let query = match self.trace {
+            Some(ref trace) if !traced => query.trace(trace),
+            _ => query,
+        };
+        self.enqueue.push(query.into_outbox_entry()?);
+        Ok(())
+    }
+
+    /// Execute the job again after the given delay, once its handler completed.
+    ///
+    /// The job is published again instead of being completed or retried, whatever its handler
+    /// returns: this doesn't count as a retry, and the job keeps its ID and number of retries.
+    /// Combine it with `Context::retry_with` to execute it again later with an updated state.
+    /// This does nothing for jobs executed by `Client::inline`.
+    pub fn retry_in(&self, delay: Duration) {
+        self.reschedule.delay(delay)
+    }
+
+    /// Execute the given job instead of this one, once its handler completed.
+    ///
+    /// Like `Context::retry_in`, the job is published again instead of being completed or
+    /// retried, with the payload of the given job, which must be of the type of the executed
+    /// one. It is executed right away, unless `Context::retry_in` is called too. This lets a job
+    /// resume its work where it stopped, e.g: from the next page of a paginated API, instead of
+    /// failing and being executed again from the start. Fails if the given job isn't named like
+    /// the executed one, or can't be encoded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// extern crate failure;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{Context, Perform};
+    /// use std::time::Duration;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job(routing_key = "exports")]
+    /// struct ExportOrders {
+    ///     cursor: Option<String>,
+    /// }
+    ///
+    /// fn fetch_page(cursor: Option<&str>) -> Result<Option<String>, failure::Error> {
+    ///     Ok(None)
+    /// }
+    ///
+    /// impl Perform for ExportOrders {
+    ///     type Context = ();
+    ///     type Output = ();
+    ///     type Error = failure::Error;
+    ///     type Future = Result<Self::Output, Self::Error>;
+    ///
+    ///     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+    ///         let cursor = fetch_page(self.cursor.as_ref().map(|cursor| cursor.as_str()))?;
+    ///         if cursor.is_some() {
+    ///             ctx.retry_with(&ExportOrders { cursor })?;
+    ///             ctx.retry_in(Duration::from_secs(1));
+    ///         }
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub fn retry_with<J>(&self, job: &J) -> Result<()>
+    where
+        J: Job,
+    {
+        if J::name() != self.properties.task {
+            let kind = ErrorKind::MismatchedJob(self.properties.task.clone(), J::name().into());
+            return Err(kind.into());
+        }
+        let payload = self.properties.codec.encode(job)?;
+        self.reschedule.payload(payload, J::version());
+        Ok(())
+    }
+
+    /// Return the trace context of the execution of the job, if it was sent with one.
+    ///
+    /// The returned span is a child of the one carried by the job's `traceparent` header. Pass
+    /// it to `Query::trace` to propagate the trace to the jobs sent by the handler.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.trace
+    }
+
+    /// Return the value of the given type provided to the worker.
+    ///
+    /// Fails if no value of this type was given to `WorkerBuilder::provide`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// extern crate failure;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{Context, Perform};
+    ///
+    /// struct Mailer;
+    ///
+    /// impl Mailer {
+    ///     fn send(&self, to: &str) -> Result<(), failure::Error> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job(routing_key = "emails")]
+    /// struct SendWelcomeEmail {
+    ///     to: String,
+    /// }
+    ///
+    /// impl Perform for SendWelcomeEmail {
+    ///     type Context = ();
+    ///     type Output = ();
+    ///     type Error = failure::Error;
+    ///     type Future = Result<Self::Output, Self::Error>;
+    ///
+    ///     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+    ///         ctx.get::<Mailer>()?.send(&self.to)
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub fn get<T>(&self) -> Result<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.extensions
+            .get::<T>()
+            .ok_or_else(|| ErrorKind::MissingDependency.into())
+    }
+
+    /// Perform the operation returned by the given function at most once for the given key.
+    ///
+    /// The outcome of the operation is recorded by the `Idempotency` provided to the worker
+    /// (see `WorkerBuilder::provide`), and returned instead of performing the operation again
+    /// when a job with the same key is executed later, e.g: because the job was retried or
+    /// delivered twice. The key should identify the side effect, not the job: a job sent twice
+    /// by a client has two IDs. Fails if no `Idempotency` was provided to the worker.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// extern crate failure;
+    /// extern crate futures;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{Context, Perform};
+    /// use futures::Future;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job(routing_key = "payments")]
+    /// struct ChargeOrder {
+    ///     order: u64,
+    ///     amount: u64,
+    /// }
+    ///
+    /// fn charge(amount: u64) -> Result<String, failure::Error> {
+    ///     Ok("ch_1".into())
+    /// }
+    ///
+    /// impl Perform for ChargeOrder {
+    ///     type Context = ();
+    ///     type Output = ();
+    ///     type Error = failure::Error;
+    ///     type Future = Box<Future<Item = (), Error = failure::Error> + Send>;
+    ///
+    ///     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+    ///         let amount = self.amount;
+    ///         let key = format!("charge:{}", self.order);
+    ///         Box::new(ctx.idempotent(&key, move || charge(amount)).map(|_charge_id| ()))
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub fn idempotent<F, R>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> Box<Future<Item = R::Item, Error = failure::Error> + Send>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: IntoFuture<Error = failure::Error>,
+        R::Future: Send + 'static,
+        R::Item: Serialize + DeserializeOwned + Send + 'static,
+    {
+        match self.extensions.get::<Idempotency>() {
+            Some(idempotency) => idempotency.run(key, f),
+            None => Box::new(future::err(Error::from(ErrorKind::MissingDependency).into())),
+        }
+    }
+
+    /// Return a `Future` resolving to the value returned by the previous job of the chain this
+    /// job is part of, if any.
+    ///
+    /// The value is handed over by the worker executing the previous job, along with the job
+    /// (see the `workflow` module documentation). Large values are fetched from the worker's
+    /// `BlobStore`, and the returned `Future` fails if the worker has none. It resolves to `None`
+    /// if the job isn't part of a chain, is its first job, or if the value was too large to be
+    /// handed over.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate batch;
+    /// extern crate failure;
+    /// extern crate futures;
+    /// #[macro_use]
+    /// extern crate lazy_static;
+    /// #[macro_use]
+    /// extern crate serde;
+    ///
+    /// use batch::{Context, Perform};
+    /// use futures::Future;
+    ///
+    /// #[derive(Serialize, Deserialize, Job)]
+    /// #[job(routing_key = "videos")]
+    /// struct Thumbnail {
+    ///     video: u64,
+    /// }
+    ///
+    /// impl Perform for Thumbnail {
+    ///     type Context = ();
+    ///     type Output = ();
+    ///     type Error = failure::Error;
+    ///     type Future = Box<Future<Item = (), Error = failure::Error> + Send>;
+    ///
+    ///     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+    ///         // The path of the file written by the previous job of the chain.
+    ///         let task = ctx
+    ///             .parent_result::<String>()
+    ///             .map(|path| println!("Generating thumbnail of {:?}", path))
+    ///             .map_err(failure::Error::from);
+    ///         Box::new(task)
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub fn parent_result<T>(&self) -> Box<Future<Item = Option<T>, Error = Error> + Send>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let task =
+            workflow::parent_result(self.blobs.as_ref(), &self.properties).and_then(|result| {
+                match result {
+                    Some(result) => serde_json::from_slice(&result)
+                        .map(Some)
+                        .map_err(|e| ErrorKind::Deserialization(e).into()),
+                    None => Ok(None),
+                }
+            });
+        Box::new(task)
+    }
+
+    /// Return the values provided to the worker.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Replace the worker's context value with the one returned by the given function.
+    pub(crate) fn map<D, F>(self, f: F) -> Context<D>
+    where
+        F: FnOnce(C) -> D,
+    {
+        Context {
+            inner: f(self.inner),
+            properties: self.properties,
+            cancellation: self.cancellation,
+            progress: self.progress,
+            trace: self.trace,
+            extensions: self.extensions,
+            acknowledgement: self.acknowledgement,
+            touch: self.touch,
+            reschedule: self.reschedule,
+            enqueue: self.enqueue,
+            blobs: self.blobs,
+        }
+    }
+
+    /// Return a copy of this context without the worker's context value, handed to the hooks of
+    /// the job, see `Perform::on_success`.
+    pub(crate) fn detach(&self) -> Context<()> {
+        Context {
+            inner: (),
+            properties: self.properties.clone(),
+            cancellation: self.cancellation.clone(),
+            progress: self.progress.clone(),
+            trace: self.trace,
+            extensions: self.extensions.clone(),
+            acknowledgement: self.acknowledgement.clone(),
+            touch: self.touch.clone(),
+            reschedule: self.reschedule.clone(),
+            enqueue: self.enqueue.clone(),
+            blobs: self.blobs.clone(),
+        }
+    }
+
+    /// Unwrap the worker's context value.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C> Deref for Context<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> DerefMut for Context<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+}
+
+/// A token signaling that a job was revoked while executing.
+///
+/// Revocation is cooperative: handlers are never interrupted, long-running ones should check
+/// the token periodically and stop early once it is cancelled. Cloning a token returns a handle
+/// to the same token.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a new token, which isn't cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Return whether this token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,419 @@
+//! Remote control of running workers.
+//!
+//! Workers enabling `WorkerBuilder::remote_control` listen to the commands sent through the
+//! control channel of their broker, addressed either to them, using the ID they publish in their
+//! heartbeats (see the `monitor` module), or to every worker. Commands are sent using a
+//! `Control`, obtained with [`Client::control`](../struct.Client.html#method.control).
+//!
+//! The queues of a worker can also be paused from its own process, using the `QueueControl`
+//! returned by [`Worker::queue_control`](../struct.Worker.html#method.queue_control).
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use futures::{future, Async, Future, Poll, Stream};
+use uuid::Uuid;
+
+use broker::{Deliveries, Delivery};
+use error::Error;
+
+/// A command sent to running workers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Stop consuming new jobs, the jobs being executed are completed.
+    Pause,
+    /// Consume jobs again after a `Pause`.
+    Resume,
+    /// Change the number of jobs fetched ahead of their execution.
+    ///
+    /// The worker starts a new consumer with the given prefetch, the jobs fetched by the previous
+    /// consumer and not yet handed to the worker are delivered again by the broker.
+    Prefetch(u16),
+    /// Log the IDs of the jobs being processed, and publish a heartbeat right away if heartbeats
+    /// are enabled.
+    Dump,
+    /// Shut the worker down gracefully, as if it received `SIGTERM`.
+    Shutdown,
+    /// Stop consuming the jobs of the given queue, the jobs being executed are completed.
+    ///
+    /// The worker starts a new consumer without the queue, the jobs fetched from it and not yet
+    /// handed to the worker are delivered again by the broker, see `Broker::consume_except`.
+    PauseQueue(String),
+    /// Consume the jobs of the given queue again after a `PauseQueue`.
+    ResumeQueue(String),
+}
+
+/// A stream of the commands received by a worker.
+pub type Commands = Box<Stream<Item = Command, Error = Error> + Send>;
+
+/// A channel transporting commands to running workers, implemented by `Broker`s.
+pub trait ControlChannel: fmt::Debug + Send + Sync {
+    /// Send the given command to the worker with the given ID, or to every worker if `None`.
+    fn send(
+        &self,
+        worker: Option<Uuid>,
+        command: Command,
+    ) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Start receiving the commands sent to the worker with the given ID.
+    fn listen(&self, worker: Uuid) -> Box<Future<Item = Commands, Error = Error> + Send>;
+}
+
+/// A handle sending commands to the workers consuming from a `Broker`.
+#[derive(Clone, Debug)]
+pub struct Control {
+    inner: Arc<ControlChannel>,
+}
+
+impl Control {
+    /// Create a new `Control` sending commands through the given control channel.
+    pub fn new(inner: Arc<ControlChannel>) -> Self {
+        Control { inner }
+    }
+
+    /// Send the given command to the worker with the given ID.
+    pub fn send(
+        &self,
+        worker: Uuid,
+        command: Command,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.inner.send(Some(worker), command)
+    }
+
+    /// Send the given command to every running worker.
+    pub fn broadcast(&self, command: Command) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.inner.send(None, command)
+    }
+}
+
+/// Type of the functions starting a new consumer with the given prefetch, except from the given
+/// queues.
+pub(crate) type Consume =
+    Fn(u16, &[String]) -> Box<Future<Item = Deliveries, Error = Error> + Send> + Send + Sync;
+
+/// The state of the consumption of a worker, shared with the commands' handler.
+#[derive(Debug, Default)]
+struct State {
+    paused: bool,
+    queues: BTreeSet<String>,
+    prefetch: Option<u16>,
+    restart: bool,
+    task: Option<Task>,
+}
+
+/// A handle pausing, resuming or restarting the consumption of a worker.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Switch {
+    state: Arc<Mutex<State>>,
+}
+
+impl Switch {
+    pub(crate) fn new() -> Self {
+        Switch::default()
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut State),
+    {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+    }
+
+    fn pause(&self) {
+        self.update(|state| state.paused = true)
+    }
+
+    fn resume(&self) {
+        self.update(|state| state.paused = false)
+    }
+
+    fn prefetch(&self, prefetch: u16) {
+        self.update(|state| state.prefetch = Some(prefetch))
+    }
+
+    fn pause_queue(&self, queue: &str) {
+        self.update(|state| state.restart |= state.queues.insert(queue.to_string()))
+    }
+
+    fn resume_queue(&self, queue: &str) {
+        self.update(|state| state.restart |= state.queues.remove(queue))
+    }
+
+    pub(crate) fn paused_queues(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.queues.iter().cloned().collect()
+    }
+}
+
+/// A handle pausing and resuming the consumption of the queues of a worker, obtained with
+/// [`Worker::queue_control`](../struct.Worker.html#method.queue_control).
+///
+/// Pausing a queue stops the worker's consumer and starts a new one without the queue, like
+/// `Command::PauseQueue` does: this requires the broker to support `Broker::consume_except`.
+///
+/// # Example
+///
+/// ```
+/// use batch::{memory, queue, Worker};
+///
+/// let connection = memory::Connection::new(vec![queue("emails"), queue("reports")]);
+/// let worker = Worker::builder(())
+///     .broker(connection)
+///     .build()
+///     .unwrap();
+/// let control = worker.queue_control();
+/// control.pause("emails");
+/// assert_eq!(control.paused(), vec!["emails".to_string()]);
+/// control.resume("emails");
+/// assert!(control.paused().is_empty());
+/// ```
+#[derive(Clone, Debug)]
+pub struct QueueControl {
+    switch: Switch,
+}
+
+impl QueueControl {
+    pub(crate) fn new(switch: Switch) -> Self {
+        QueueControl { switch }
+    }
+
+    /// Stop consuming the jobs of the given queue, the jobs being executed are completed.
+    pub fn pause(&self, queue: &str) {
+        info!("Pausing queue {:?}", queue);
+        self.switch.pause_queue(queue)
+    }
+
+    /// Consume the jobs of the given queue again.
+    pub fn resume(&self, queue: &str) {
+        info!("Resuming queue {:?}", queue);
+        self.switch.resume_queue(queue)
+    }
+
+    /// Return the names of the paused queues.
+    pub fn paused(&self) -> Vec<String> {
+        self.switch.paused_queues()
+    }
+}
+
+/// A stream of deliveries, controlled by a `Switch`.
+///
+/// While paused, the inner stream isn't polled. When a new prefetch is requested, or when a queue
+/// is paused or resumed, a new consumer is started, and replaces the inner stream once it's
+/// ready.
+pub(crate) struct Controlled {
+    stream: Deliveries,
+    pending: Option<Box<Future<Item = Deliveries, Error = Error> + Send>>,
+    consume: Arc<Consume>,
+    prefetch: u16,
+    switch: Switch,
+}
+
+impl Controlled {
+    pub(crate) fn new(
+        stream: Deliveries,
+        consume: Arc<Consume>,
+        prefetch: u16,
+        switch: Switch,
+    ) -> Self {
+        Controlled {
+            stream,
+            pending: None,
+            consume,
+            prefetch,
+            switch,
+        }
+    }
+}
+
+impl Stream for Controlled {
+    type Item = Box<Delivery>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let paused = {
+            let mut state = self.switch.state.lock().unwrap();
+            if let Some(prefetch) = state.prefetch.take() {
+                info!("Restarting consumer with a prefetch of {}", prefetch);
+                self.prefetch = prefetch;
+                state.restart = true;
+            }
+            if state.restart {
+                state.restart = false;
+                let excluded = state.queues.iter().cloned().collect::<Vec<_>>();
+                self.pending = Some((self.consume)(self.prefetch, &excluded));
+            }
+            if state.paused {
+                state.task = Some(task::current());
+            }
+            state.paused
+        };
+        let restarted = match self.pending {
+            Some(ref mut pending) => match pending.poll() {
+                Ok(Async::Ready(stream)) => Some(Ok(stream)),
+                Ok(Async::NotReady) => None,
+                Err(e) => Some(Err(e)),
+            },
+            None => None,
+        };
+        match restarted {
+            Some(Ok(stream)) => {
+                self.pending = None;
+                self.stream = stream;
+            }
+            Some(Err(e)) => {
+                self.pending = None;
+                error!("Couldn't restart consumer, keeping the previous one: {}", e);
+            }
+            None => (),
+        }
+        if paused {
+            return Ok(Async::NotReady);
+        }
+        self.stream.poll()
+    }
+}
+
+/// Type of the functions called when a worker receives `Command::Dump`.
+pub(crate) type Dump = Fn() -> Box<Future<Item = (), Error = ()> + Send> + Send + Sync;
+
+/// Apply the commands received by a worker.
+///
+/// The returned `Future` never resolves: it is dropped once the worker stopped. `stop` is
+/// triggered when the worker is asked to shut down.
+pub(crate) fn handle(
+    commands: Commands,
+    switch: Switch,
+    stop: oneshot::Sender<()>,
+    dump: Box<Dump>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let mut stop = Some(stop);
+    let task = commands
+        .for_each(move |command| {
+            info!("Received command: {:?}", command);
+            match command {
+                Command::Pause => switch.pause(),
+                Command::Resume => switch.resume(),
+                Command::Prefetch(prefetch) => switch.prefetch(prefetch),
+                Command::PauseQueue(queue) => switch.pause_queue(&queue),
+                Command::ResumeQueue(queue) => switch.resume_queue(&queue),
+                Command::Dump => {
+                    let task: Box<Future<Item = (), Error = Error> + Send> =
+                        Box::new(dump().then(|_| Ok(())));
+                    return task;
+                }
+                Command::Shutdown => {
+                    if let Some(stop) = stop.take() {
+                        let _ = stop.send(());
+                    }
+                }
+            }
+            Box::new(future::ok(()))
+        })
+        .then(|result| {
+            match result {
+                Ok(_) => warn!("Control channel closed, no more commands will be received"),
+                Err(e) => error!("Couldn't receive commands: {}", e),
+            }
+            future::empty()
+        });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use broker::Broker;
+    use job::Priority;
+    use memory::{self, tests::properties};
+    use topology::queue;
+
+    #[test]
+    fn commands() {
+        let connection = memory::Connection::new(vec![]);
+        let worker = Uuid::new_v4();
+        let commands = connection.listen(worker).wait().unwrap();
+        let control = Control::new(Arc::new(connection.clone()));
+        control.send(worker, Command::Pause).wait().unwrap();
+        control
+            .send(Uuid::new_v4(), Command::Resume)
+            .wait()
+            .unwrap();
+        control.broadcast(Command::Prefetch(4)).wait().unwrap();
+        let received = commands.take(2).collect().wait().unwrap();
+        assert_eq!(received, vec![Command::Pause, Command::Prefetch(4)]);
+    }
+
+    #[test]
+    fn switch() {
+        let connection =
+            memory::Connection::new(vec![queue("tests.control").bind("batch.tests", "control")]);
+        for _ in 0..2 {
+            let properties = properties("job", "control", Priority::Normal);
+            connection.publish(b"{}", &properties).wait().unwrap();
+        }
+        let consumer = connection.clone();
+        let consume: Arc<Consume> = Arc::new(move |prefetch, excluded: &[String]| {
+            consumer.consume_except(prefetch, excluded)
+        });
+        let switch = Switch::new();
+        let stream = connection.consume(1).wait().unwrap();
+        let mut controlled = Controlled::new(stream, consume, 1, switch.clone());
+        let mut poll = move || {
+            future::lazy(|| Ok::<_, ()>(controlled.poll().unwrap().is_ready()))
+                .wait()
+                .unwrap()
+        };
+        switch.pause();
+        assert!(!poll());
+        switch.resume();
+        assert!(poll());
+        switch.prefetch(2);
+        assert!(poll());
+    }
+
+    #[test]
+    fn queues() {
+        let connection = memory::Connection::new(vec![
+            queue("tests.emails").bind("batch.tests", "emails"),
+            queue("tests.reports").bind("batch.tests", "reports"),
+        ]);
+        for routing_key in &["emails", "reports", "emails", "reports"] {
+            let properties = properties("job", routing_key, Priority::Normal);
+            connection.publish(b"{}", &properties).wait().unwrap();
+        }
+        let consumer = connection.clone();
+        let consume: Arc<Consume> = Arc::new(move |prefetch, excluded: &[String]| {
+            consumer.consume_except(prefetch, excluded)
+        });
+        let switch = Switch::new();
+        let control = QueueControl::new(switch.clone());
+        let stream = connection.consume(1).wait().unwrap();
+        let mut controlled = Controlled::new(stream, consume, 1, switch);
+        let mut poll = move || {
+            future::lazy(|| {
+                Ok::<_, ()>(match controlled.poll().unwrap() {
+                    Async::Ready(Some(delivery)) => delivery.queue().map(String::from),
+                    _ => None,
+                })
+            })
+            .wait()
+            .unwrap()
+        };
+        control.pause("tests.emails");
+        assert_eq!(control.paused(), vec!["tests.emails".to_string()]);
+        assert_eq!(poll(), Some("tests.reports".into()));
+        assert_eq!(poll(), Some("tests.reports".into()));
+        assert_eq!(poll(), None);
+        control.resume("tests.emails");
+        assert_eq!(poll(), Some("tests.emails".into()));
+        assert_eq!(connection.len("tests.emails"), 1);
+    }
+}
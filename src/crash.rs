@@ -0,0 +1,103 @@
+//! Capture of the panics of job handlers.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic;
+use std::sync::{Once, ONCE_INIT};
+
+use failure::Backtrace;
+
+/// The header holding the panic of a job moved to its dead-letter queue, serialized as JSON.
+pub const PANIC_HEADER: &str = "x-batch-panic";
+
+/// The details of the panic of a job's handler.
+///
+/// When a handler panics, the job fails with `Failure::Crash`, and the panic is handed to the
+/// `on_error` hook of the middlewares (see `Middleware::on_error`, the error can be downcast to
+/// a `Panic`). It is then attached to the job's `StatusReport`, and to the `x-batch-panic` header
+/// of the job if it's moved to its dead-letter queue (see `DeadJob::panic`).
+///
+/// Like the backtraces of `failure` errors, the backtrace is only captured when the
+/// `RUST_BACKTRACE` environment variable is set.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Fail)]
+#[fail(display = "Job handler panicked: {}", message)]
+pub struct Panic {
+    /// The message of the panic.
+    pub message: String,
+    /// The location of the panic in the source code, e.g: `src/main.rs:42:9`.
+    pub location: Option<String>,
+    /// The backtrace of the panic, if captured.
+    pub backtrace: Option<String>,
+}
+
+thread_local! {
+    /// The last panic of the current thread, recorded by the panic hook.
+    static LAST_PANIC: RefCell<Option<Panic>> = RefCell::new(None);
+}
+
+static HOOK: Once = ONCE_INIT;
+
+/// Install a panic hook recording the details of panics, before calling the previous hook.
+pub(crate) fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::new().to_string();
+            let panic = Panic {
+                message: message(info.payload()),
+                location: info.location().map(|location| location.to_string()),
+                backtrace: if backtrace.is_empty() {
+                    None
+                } else {
+                    Some(backtrace)
+                },
+            };
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some(panic));
+            previous(info);
+        }));
+    });
+}
+
+/// Return the details of the panic of the current thread, given its payload.
+///
+/// Only the message of the panic is known if it wasn't recorded by the panic hook.
+pub(crate) fn caught(payload: Box<Any + Send>) -> Panic {
+    let message = message(&*payload);
+    match LAST_PANIC.with(|last| last.borrow_mut().take()) {
+        Some(ref panic) if panic.message == message => panic.clone(),
+        _ => Panic {
+            message,
+            location: None,
+            backtrace: None,
+        },
+    }
+}
+
+/// Return the message carried by the given panic payload.
+fn message(payload: &(Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<Any>".into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture() {
+        install_hook();
+        let payload = panic::catch_unwind(|| panic!("card {} declined", 42)).unwrap_err();
+        let panic = caught(payload);
+        assert_eq!(panic.message, "card 42 declined");
+        assert!(panic.location.unwrap().starts_with("src/crash.rs:"));
+
+        let panic = caught(Box::new("out of memory"));
+        assert_eq!(panic.message, "out of memory");
+        assert_eq!(panic.location, None);
+    }
+}
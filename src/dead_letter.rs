@@ -0,0 +1,134 @@
+//! Inspection of the jobs that exhausted their retries.
+//!
+//! When a job fails and can't be retried anymore, the worker moves it to the dead-letter queue
+//! associated to the queue it was consumed from, along with the reason of its last failure.
+//! Dead jobs can then be listed, sent again, removed or purged using a `DeadLetterConsumer`, obtained
+//! with [`Client::dead_letters`](../struct.Client.html#method.dead_letters).
+
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use futures::{stream, Future, Stream};
+use serde_json;
+use uuid::Uuid;
+
+use broker::Properties;
+use crash::{Panic, PANIC_HEADER};
+use error::Error;
+use job::Failure;
+
+/// The suffix appended to a queue's name to get the name of its dead-letter queue.
+pub(crate) const DEAD_LETTER_SUFFIX: &str = ".dead";
+
+/// Return the name of the dead-letter queue associated to the given queue.
+pub(crate) fn dead_letter_queue(queue: &str) -> String {
+    format!("{}{}", queue, DEAD_LETTER_SUFFIX)
+}
+
+/// A job that exhausted its retries.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DeadJob {
+    /// The name of the queue the job was consumed from.
+    pub queue: String,
+    /// The metadata associated to the job.
+    pub properties: Properties,
+    /// The serialized job.
+    pub payload: Vec<u8>,
+    /// The reason of the job's last failure.
+    pub failure: Failure,
+    /// When the job was moved to the dead-letter queue.
+    pub died_at: DateTime<Utc>,
+}
+
+impl DeadJob {
+    /// Return the properties to use when sending this job again.
+    ///
+    /// The job is sent to its original exchange and routing key, with its retries reset.
+    pub fn requeue_properties(&self) -> Properties {
+        let mut properties = self.properties.clone();
+        properties.retries = 0;
+        properties.delay = None;
+        properties.headers.remove(PANIC_HEADER);
+        properties
+    }
+
+    /// Return the panic of the job's handler, if its last failure was a panic.
+    pub fn panic(&self) -> Option<Panic> {
+        self.properties
+            .headers
+            .get(PANIC_HEADER)
+            .and_then(|raw| serde_json::from_str(raw).ok())
+    }
+}
+
+/// A storage for the jobs that exhausted their retries, implemented by `Broker`s.
+pub trait DeadLetterQueue: fmt::Debug + Send + Sync {
+    /// List the dead jobs of the given queue, oldest first.
+    fn list(&self, queue: &str) -> Box<Future<Item = Vec<DeadJob>, Error = Error> + Send>;
+
+    /// Send the dead job with the given ID back to its original destination.
+    ///
+    /// Returns a `Future` resolving to whether a job with this ID was found.
+    fn requeue(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send>;
+
+    /// Remove the dead job with the given ID, without sending it again.
+    ///
+    /// Returns a `Future` resolving to whether a job with this ID was found.
+    fn remove(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send>;
+
+    /// Remove all the dead jobs of the given queue.
+    fn purge(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// A handle to the dead-letter queues of a `Broker`.
+#[derive(Clone, Debug)]
+pub struct DeadLetterConsumer {
+    inner: Arc<DeadLetterQueue>,
+}
+
+impl DeadLetterConsumer {
+    /// Create a new `DeadLetterConsumer` from the given dead-letter storage.
+    pub fn new(inner: Arc<DeadLetterQueue>) -> Self {
+        DeadLetterConsumer { inner }
+    }
+
+    /// List the dead jobs of the given queue, oldest first.
+    pub fn list(&self, queue: &str) -> Box<Future<Item = Vec<DeadJob>, Error = Error> + Send> {
+        self.inner.list(queue)
+    }
+
+    /// Send the dead job with the given ID back to its original destination.
+    ///
+    /// Returns a `Future` resolving to whether a job with this ID was found.
+    pub fn requeue(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        self.inner.requeue(queue, id)
+    }
+
+    /// Send all the dead jobs of the given queue back to their original destination.
+    ///
+    /// Returns a `Future` resolving to the number of jobs sent again.
+    pub fn requeue_all(&self, queue: &str) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let inner = Arc::clone(&self.inner);
+        let queue = queue.to_string();
+        let task = self.inner.list(&queue).and_then(move |jobs| {
+            stream::iter_ok::<_, Error>(jobs)
+                .and_then(move |job| inner.requeue(&queue, job.properties.id))
+                .filter(|found| *found)
+                .fold(0, |count, _| Ok::<_, Error>(count + 1))
+        });
+        Box::new(task)
+    }
+
+    /// Remove the dead job with the given ID, without sending it again.
+    ///
+    /// Returns a `Future` resolving to whether a job with this ID was found.
+    pub fn remove(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        self.inner.remove(queue, id)
+    }
+
+    /// Remove all the dead jobs of the given queue.
+    pub fn purge(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.inner.purge(queue)
+    }
+}
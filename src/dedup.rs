@@ -0,0 +1,179 @@
+//! Deduplication of the jobs delivered to workers.
+//!
+//! Brokers deliver jobs at least once: a job executed successfully is delivered again if its
+//! worker died, or lost its connection, before acknowledging it. When a `SeenStore` is given to
+//! the worker with `WorkerBuilder::deduplicate`, the ID of each job executed successfully is
+//! recorded once the jobs it enqueued and the next job of its chain were published, right before
+//! it is acknowledged, and the deliveries of recorded jobs are acknowledged without being executed
+//! again.
+//!
+//! IDs are only remembered for the window given to the worker, which should exceed the time it
+//! takes for an unacknowledged job to be delivered again. A job whose worker died while it was
+//! executing wasn't recorded, and is still executed again: use `Context::idempotent` for the side
+//! effects that must happen at most once.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use uuid::Uuid;
+
+use error::Error;
+
+/// A store recording the IDs of the jobs executed by workers, see `WorkerBuilder::deduplicate`.
+pub trait SeenStore: fmt::Debug + Send + Sync {
+    /// Return whether the job with the given ID was recorded as executed.
+    fn seen(&self, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send>;
+
+    /// Record the job with the given ID as executed, for the given duration.
+    fn mark_seen(&self, id: Uuid, window: Duration)
+        -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// A `SeenStore` keeping the IDs of the last executed jobs in memory.
+///
+/// At most `capacity` IDs are kept, the least recently recorded being evicted first. As the IDs
+/// are only known to the current process, this only deduplicates the jobs delivered again to the
+/// same worker, e.g: after it reconnected to the broker. Use a store shared by the workers (e.g:
+/// `batch_redis::Connection`) to deduplicate jobs delivered to another worker.
+#[derive(Debug)]
+pub struct SeenJobs {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    expirations: HashMap<Uuid, Instant>,
+    order: VecDeque<Uuid>,
+}
+
+impl SeenJobs {
+    /// Create a new `SeenJobs` keeping at most `capacity` IDs.
+    pub fn new(capacity: usize) -> Self {
+        SeenJobs {
+            capacity: capacity.max(1),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+}
+
+impl SeenStore for SeenJobs {
+    fn seen(&self, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        let seen = inner
+            .expirations
+            .get(&id)
+            .map_or(false, |expiration| *expiration > Instant::now());
+        Box::new(future::ok(seen))
+    }
+
+    fn mark_seen(
+        &self,
+        id: Uuid,
+        window: Duration,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        if inner
+            .expirations
+            .insert(id, Instant::now() + window)
+            .is_some()
+        {
+            inner.order.retain(|seen| *seen != id);
+        }
+        inner.order.push_back(id);
+        while inner.order.len() > self.capacity {
+            if let Some(evicted) = inner.order.pop_front() {
+                inner.expirations.remove(&evicted);
+            }
+        }
+        Box::new(future::ok(()))
+    }
+}
+
+/// The `SeenStore` of a worker, along with how long IDs are remembered for.
+#[derive(Debug)]
+pub(crate) struct Deduplication {
+    store: Arc<SeenStore>,
+    window: Duration,
+}
+
+impl Deduplication {
+    /// Create a new `Deduplication` remembering IDs in the given store for the given duration.
+    pub(crate) fn new(store: Arc<SeenStore>, window: Duration) -> Self {
+        Deduplication { store, window }
+    }
+}
+
+/// Return whether the given job was already executed, according to the given deduplication.
+///
+/// The returned `Future` never fails: errors are logged, and the job is considered unseen.
+pub(crate) fn seen(
+    deduplication: Option<&Arc<Deduplication>>,
+    id: Uuid,
+) -> Box<Future<Item = bool, Error = Error> + Send> {
+    let deduplication = match deduplication {
+        Some(deduplication) => deduplication,
+        None => return Box::new(future::ok(false)),
+    };
+    let task = deduplication.store.seen(id).or_else(move |e| {
+        warn!(
+            "[{}] Couldn't check whether job was already executed: {}",
+            id, e
+        );
+        Ok(false)
+    });
+    Box::new(task)
+}
+
+/// Record the given job as executed, according to the given deduplication.
+///
+/// Like `seen`, the returned `Future` never fails: errors are logged instead.
+pub(crate) fn record(
+    deduplication: Option<&Arc<Deduplication>>,
+    id: Uuid,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let deduplication = match deduplication {
+        Some(deduplication) => deduplication,
+        None => return Box::new(future::ok(())),
+    };
+    let task = deduplication
+        .store
+        .mark_seen(id, deduplication.window)
+        .or_else(move |e| {
+            warn!("[{}] Couldn't record job as executed: {}", id, e);
+            Ok(())
+        });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seen_jobs() {
+        let store = SeenJobs::new(2);
+        let window = Duration::from_secs(60);
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert!(!store.seen(first).wait().unwrap());
+        store.mark_seen(first, window).wait().unwrap();
+        store.mark_seen(second, window).wait().unwrap();
+        assert!(store.seen(first).wait().unwrap());
+        // Recording the first job again makes the second one the least recently recorded.
+        store.mark_seen(first, window).wait().unwrap();
+        store.mark_seen(third, window).wait().unwrap();
+        assert!(store.seen(first).wait().unwrap());
+        assert!(!store.seen(second).wait().unwrap());
+        assert!(store.seen(third).wait().unwrap());
+
+        store
+            .mark_seen(second, Duration::from_secs(0))
+            .wait()
+            .unwrap();
+        assert!(!store.seen(second).wait().unwrap());
+    }
+}
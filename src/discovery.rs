@@ -0,0 +1,323 @@
+//! Discovery of the jobs defined in the program.
+//!
+//! Deriving `Job` registers the job in a registry filled when the program starts, listing every
+//! job defined in the binary. Workers register a handler for each registered job implementing
+//! `Perform` with the same context type as theirs, so that jobs don't have to be registered one
+//! by one with `WorkerBuilder::job`. Jobs routed to none of the queues of a worker are left out,
+//! jobs registered explicitly keep their handler, and discovery can be disabled with
+//! `WorkerBuilder::discover_jobs`.
+//!
+//! A job can opt out of the registry with the `#[job(register = false)]` attribute. Generic jobs
+//! are never registered, as the registry can't know which instantiations of them are used. The
+//! registry is only filled when the `discovery` feature is enabled, which it is by default.
+//!
+//! # Example
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate batch;
+//! extern crate failure;
+//! #[macro_use]
+//! extern crate lazy_static;
+//! #[macro_use]
+//! extern crate serde;
+//!
+//! use batch::{discovery, Context, Perform};
+//!
+//! #[derive(Serialize, Deserialize, Job)]
+//! #[job(routing_key = "emails")]
+//! struct SendEmail {
+//!     to: String,
+//! }
+//!
+//! impl Perform for SendEmail {
+//!     type Context = ();
+//!     type Output = ();
+//!     type Error = failure::Error;
+//!     type Future = Result<Self::Output, Self::Error>;
+//!
+//!     fn perform(&self, _ctx: Context<Self::Context>) -> Self::Future {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # fn main() {
+//! for job in discovery::jobs() {
+//!     println!("{} (routing key: {:?})", job.name(), job.routing_key());
+//! }
+//! # }
+//! ```
+
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::result::Result as StdResult;
+
+use futures::IntoFuture;
+
+#[cfg(feature = "discovery")]
+use __inventory as inventory;
+use job::{Job, Perform};
+use worker::WorkerBuilder;
+
+/// The function registering the handler of a job on the `WorkerBuilder` held by the given
+/// `Option`, returning whether the worker has the context type of the job.
+type Install = fn(&mut Any) -> bool;
+
+/// A job registered by deriving `Job`, see `jobs`.
+pub struct Registration {
+    name: fn() -> &'static str,
+    exchange: fn() -> &'static str,
+    routing_key: fn() -> &'static str,
+    install: Option<Install>,
+}
+
+impl Registration {
+    #[doc(hidden)]
+    pub fn new<T>(install: Option<Install>) -> Self
+    where
+        T: Job,
+    {
+        Registration {
+            name: T::name,
+            exchange: T::exchange,
+            routing_key: T::routing_key,
+            install,
+        }
+    }
+
+    /// The name of the job, see `Job::name`.
+    pub fn name(&self) -> &'static str {
+        (self.name)()
+    }
+
+    /// The exchange the job is published to, see `Job::exchange`.
+    pub fn exchange(&self) -> &'static str {
+        (self.exchange)()
+    }
+
+    /// The routing key of the job, see `Job::routing_key`.
+    pub fn routing_key(&self) -> &'static str {
+        (self.routing_key)()
+    }
+
+    /// Whether the job implements `Perform`, i.e: whether workers can register its handler.
+    pub fn performs(&self) -> bool {
+        self.install.is_some()
+    }
+
+    /// Register the handler of the job on the given builder, if the job implements `Perform`
+    /// with the context type of the builder.
+    ///
+    /// Returns the builder, along with whether the handler was registered.
+    pub(crate) fn install<Ctx>(&self, builder: WorkerBuilder<Ctx>) -> (WorkerBuilder<Ctx>, bool)
+    where
+        Ctx: 'static,
+    {
+        let install = match self.install {
+            Some(install) => install,
+            None => return (builder, false),
+        };
+        let mut slot = Some(builder);
+        let installed = install(&mut slot);
+        let builder = slot.expect("the handler of a job took the worker builder");
+        (builder, installed)
+    }
+}
+
+impl fmt::Debug for Registration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        f.debug_struct("Registration")
+            .field("name", &self.name())
+            .field("exchange", &self.exchange())
+            .field("routing_key", &self.routing_key())
+            .field("performs", &self.performs())
+            .finish()
+    }
+}
+
+#[cfg(feature = "discovery")]
+inventory::collect!(Registration);
+
+/// Return the jobs registered by deriving `Job`, in no particular order.
+///
+/// This is always empty when the `discovery` feature is disabled.
+pub fn jobs() -> Vec<&'static Registration> {
+    #[cfg(feature = "discovery")]
+    {
+        inventory::iter::<Registration>.into_iter().collect()
+    }
+    #[cfg(not(feature = "discovery"))]
+    {
+        Vec::new()
+    }
+}
+
+/// Register the given `Registration`, if the `discovery` feature is enabled.
+#[cfg(feature = "discovery")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_job {
+    ($registration:expr) => {
+        $crate::__inventory::submit! {
+            #![crate = $crate::__inventory]
+            $registration
+        }
+    };
+}
+
+/// Register the given `Registration`, if the `discovery` feature is enabled.
+#[cfg(not(feature = "discovery"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_job {
+    ($registration:expr) => {};
+}
+
+/// A type standing for the job `T`, whose `install` method returns the function registering its
+/// handler if `T` implements `Perform`, and `None` otherwise.
+///
+/// The code generated when deriving `Job` calls `(&Tag::<T>::new()).install()`: the method of
+/// `Performs` is picked if it applies, as it takes `Tag<T>` by reference, otherwise the method of
+/// `Jobs` taking `&Tag<T>` by reference is picked.
+#[doc(hidden)]
+pub struct Tag<T>(PhantomData<T>);
+
+impl<T> Tag<T> {
+    #[doc(hidden)]
+    pub fn new() -> Self {
+        Tag(PhantomData)
+    }
+}
+
+impl<T> fmt::Debug for Tag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Tag")
+    }
+}
+
+#[doc(hidden)]
+pub trait Performs {
+    /// Return the function registering the handler of the job.
+    fn install(&self) -> Option<Install>;
+}
+
+impl<T> Performs for Tag<T>
+where
+    T: Job + Perform + 'static,
+    T::Context: 'static,
+    T::Error: 'static,
+    <T::Future as IntoFuture>::Future: Send + 'static,
+{
+    fn install(&self) -> Option<Install> {
+        Some(install::<T>)
+    }
+}
+
+#[doc(hidden)]
+pub trait Jobs {
+    /// Return `None`, as the job doesn't implement `Perform`.
+    fn install(&self) -> Option<Install>;
+}
+
+impl<'a, T> Jobs for &'a Tag<T> {
+    fn install(&self) -> Option<Install> {
+        None
+    }
+}
+
+/// Register the handler of `T` on the builder held by the given `Option`, if it has the context
+/// type of `T`.
+fn install<T>(slot: &mut Any) -> bool
+where
+    T: Job + Perform + 'static,
+    T::Context: 'static,
+    T::Error: 'static,
+    <T::Future as IntoFuture>::Future: Send + 'static,
+{
+    match slot.downcast_mut::<Option<WorkerBuilder<T::Context>>>() {
+        Some(slot) => {
+            let builder = slot.take().expect("the worker builder was already taken");
+            *slot = Some(builder.job::<T>());
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use failure;
+
+    use context::Context;
+    use job::Priority;
+    use worker::Worker;
+
+    macro_rules! job {
+        ($job:ident, $name:expr) => {
+            #[derive(Serialize, Deserialize)]
+            struct $job;
+
+            impl Job for $job {
+                fn name() -> &'static str {
+                    $name
+                }
+
+                fn exchange() -> &'static str {
+                    ""
+                }
+
+                fn routing_key() -> &'static str {
+                    "discovery"
+                }
+
+                fn retries() -> u32 {
+                    0
+                }
+
+                fn timeout() -> Option<Duration> {
+                    None
+                }
+
+                fn priority() -> Priority {
+                    Priority::Normal
+                }
+            }
+        };
+    }
+
+    job!(Ping, "ping");
+    job!(Pong, "pong");
+
+    impl Perform for Ping {
+        type Context = ();
+        type Output = ();
+        type Error = failure::Error;
+        type Future = Result<(), failure::Error>;
+
+        fn perform(&self, _ctx: Context<()>) -> Self::Future {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn install_handlers() {
+        let ping = Registration::new::<Ping>((&Tag::<Ping>::new()).install());
+        let pong = Registration::new::<Pong>((&Tag::<Pong>::new()).install());
+        assert_eq!(ping.name(), "ping");
+        assert_eq!(ping.routing_key(), "discovery");
+        assert!(ping.performs());
+        assert!(!pong.performs());
+
+        let (_, installed) = ping.install(Worker::builder(()));
+        assert!(installed);
+        // The handler of the job expects another context.
+        let (_, installed) = ping.install(Worker::builder(42));
+        assert!(!installed);
+        let (_, installed) = pong.install(Worker::builder(()));
+        assert!(!installed);
+    }
+}
@@ -0,0 +1,225 @@
+//! Encryption of job payloads.
+
+#[cfg(feature = "encryption")]
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "encryption")]
+use std::result::Result as StdResult;
+
+#[cfg(feature = "encryption")]
+use ring::aead::{self, OpeningKey, SealingKey, AES_256_GCM};
+#[cfg(feature = "encryption")]
+use ring::rand::{SecureRandom, SystemRandom};
+
+use broker::Properties;
+use error::{Error, Result};
+
+/// The header holding the identifier of the key the payload of a job was encrypted with.
+pub const KEY_ID_HEADER: &str = "x-batch-key-id";
+
+/// The length of the nonce prepended to the payloads encrypted by `AesGcm`.
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// A hook encrypting the serialized payload of jobs before they are published, and decrypting
+/// them before they are executed.
+///
+/// Encryption is enabled on the client with `Client::encryptor`, and on the worker with
+/// `WorkerBuilder::encryptor`. Payloads are encrypted after being compressed, and the identifier
+/// of the key they were encrypted with is sent along with the job in the `x-batch-key-id`
+/// header, so that keys can be rotated while jobs encrypted with the previous ones are still
+/// enqueued. The properties of jobs, including their headers, aren't encrypted.
+///
+/// An implementation based on AES-256-GCM is enabled by the `encryption` feature, see `AesGcm`.
+pub trait Encryptor: fmt::Debug + Send + Sync {
+    /// Encrypt the given payload, returning the identifier of the key it was encrypted with
+    /// along with the encrypted payload.
+    fn encrypt(&self, payload: &[u8]) -> Result<(String, Vec<u8>)>;
+
+    /// Decrypt a payload encrypted with the key of the given identifier.
+    fn decrypt(&self, key_id: &str, encrypted: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// An `Encryptor` using AES-256-GCM, supporting key rotation.
+///
+/// Payloads are encrypted with the current key, and can be decrypted with any key known to the
+/// encryptor. To rotate keys, deploy workers knowing the new key first, then make it the current
+/// key of the clients, and remove the old key once the jobs encrypted with it were consumed.
+///
+/// # Example
+///
+/// ```
+/// use batch::{memory, queue, AesGcm, Client};
+///
+/// let encryptor = AesGcm::new("2018-07", [7; 32])
+///     .key("2018-06", [6; 32]);
+/// let connection = memory::Connection::new(vec![queue("emails")]);
+/// let client = Client::new(connection)
+///     .encryptor(encryptor);
+/// ```
+#[cfg(feature = "encryption")]
+pub struct AesGcm {
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+    rng: SystemRandom,
+}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for AesGcm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "AesGcm {{ current: {:?} keys: {:?} }}",
+            self.current,
+            self.keys.keys().collect::<Vec<_>>()
+        )
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl AesGcm {
+    /// Create a new `AesGcm` encrypting payloads with the given 256-bit key.
+    pub fn new(key_id: &str, key: [u8; 32]) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key_id.into(), key);
+        AesGcm {
+            current: key_id.into(),
+            keys,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Add a key used to decrypt the payloads encrypted with it, but not to encrypt new ones.
+    /// Chainable.
+    pub fn key(mut self, key_id: &str, key: [u8; 32]) -> Self {
+        self.keys.insert(key_id.into(), key);
+        self
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Encryptor for AesGcm {
+    fn encrypt(&self, payload: &[u8]) -> Result<(String, Vec<u8>)> {
+        let key = SealingKey::new(&AES_256_GCM, &self.keys[&self.current])
+            .map_err(|_| Error::encryption(format_err!("invalid key: {}", self.current)))?;
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| Error::encryption(format_err!("couldn't generate nonce")))?;
+        let tag_len = AES_256_GCM.tag_len();
+        let mut in_out = payload.to_vec();
+        in_out.resize(payload.len() + tag_len, 0);
+        let len = aead::seal_in_place(&key, &nonce, &[], &mut in_out, tag_len)
+            .map_err(|_| Error::encryption(format_err!("couldn't encrypt payload")))?;
+        let mut encrypted = Vec::with_capacity(NONCE_LEN + len);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&in_out[..len]);
+        Ok((self.current.clone(), encrypted))
+    }
+
+    fn decrypt(&self, key_id: &str, encrypted: &[u8]) -> Result<Vec<u8>> {
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| Error::encryption(format_err!("unknown key: {}", key_id)))?;
+        if encrypted.len() < NONCE_LEN {
+            return Err(Error::encryption(format_err!("truncated payload")));
+        }
+        let key = OpeningKey::new(&AES_256_GCM, key)
+            .map_err(|_| Error::encryption(format_err!("invalid key: {}", key_id)))?;
+        let (nonce, sealed) = encrypted.split_at(NONCE_LEN);
+        let mut in_out = sealed.to_vec();
+        let len = aead::open_in_place(&key, nonce, &[], 0, &mut in_out)
+            .map_err(|_| Error::encryption(format_err!("couldn't decrypt payload")))?
+            .len();
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+}
+
+/// Return the serialized job held by the given payload, decrypting then decompressing it if
+/// needed.
+pub(crate) fn open(
+    encryptor: Option<&Encryptor>,
+    properties: &Properties,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let decrypted = match (properties.headers.get(KEY_ID_HEADER), encryptor) {
+        (Some(key_id), Some(encryptor)) => Some(encryptor.decrypt(key_id, payload)?),
+        (Some(key_id), None) => {
+            let e = format_err!("encrypted with key {}, but no encryptor is set", key_id);
+            return Err(Error::encryption(e));
+        }
+        (None, _) => None,
+    };
+    let payload = decrypted.as_ref().map_or(payload, |payload| &payload[..]);
+    match properties.compression {
+        Some(compression) => compression.decompress(payload),
+        None => Ok(payload.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Xor(u8);
+
+    impl Encryptor for Xor {
+        fn encrypt(&self, payload: &[u8]) -> Result<(String, Vec<u8>)> {
+            let encrypted = payload.iter().map(|byte| byte ^ self.0).collect();
+            Ok((self.0.to_string(), encrypted))
+        }
+
+        fn decrypt(&self, _key_id: &str, encrypted: &[u8]) -> Result<Vec<u8>> {
+            Ok(encrypted.iter().map(|byte| byte ^ self.0).collect())
+        }
+    }
+
+    #[test]
+    fn open_payload() {
+        let raw = b"{\"to\":\"john@doe.com\"}";
+        let mut properties = Properties::named("send-email", "emails", "emails");
+        assert_eq!(open(None, &properties, raw).unwrap(), &raw[..]);
+
+        let (key_id, encrypted) = Xor(42).encrypt(raw).unwrap();
+        properties.headers.insert(KEY_ID_HEADER.into(), key_id);
+        assert_eq!(
+            open(Some(&Xor(42)), &properties, &encrypted).unwrap(),
+            &raw[..]
+        );
+        assert!(open(None, &properties, &encrypted)
+            .unwrap_err()
+            .is_encryption());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn rotation() {
+        let raw = b"{\"to\":\"john@doe.com\"}";
+        let previous = AesGcm::new("1", [1; 32]);
+        let (key_id, encrypted) = previous.encrypt(raw).unwrap();
+        assert_eq!(key_id, "1");
+        assert!(encrypted
+            .windows(raw.len())
+            .all(|window| window != &raw[..]));
+
+        let current = AesGcm::new("2", [2; 32]).key("1", [1; 32]);
+        assert_eq!(current.decrypt(&key_id, &encrypted).unwrap(), &raw[..]);
+        let (key_id, encrypted) = current.encrypt(raw).unwrap();
+        assert_eq!(key_id, "2");
+        assert_eq!(current.decrypt(&key_id, &encrypted).unwrap(), &raw[..]);
+        assert!(previous
+            .decrypt(&key_id, &encrypted)
+            .unwrap_err()
+            .is_encryption());
+
+        let mut tampered = encrypted.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(current
+            .decrypt(&key_id, &tampered)
+            .unwrap_err()
+            .is_encryption());
+    }
+}
@@ -0,0 +1,68 @@
+//! Jobs sent by handlers through their context.
+//!
+//! A handler calling `Context::enqueue` doesn't publish the job itself: the job is handed to the
+//! worker, which sends it through its own connection to the broker once the handler succeeded,
+//! before acknowledging the executed job. Handlers don't need a `Client` of their own, and the
+//! jobs sent by a failed handler aren't published, as they are enqueued again when it is retried.
+//!
+//! Jobs executed in a child process report the jobs they enqueued by writing them to a file,
+//! like `Context::retry_in` does.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use outbox::OutboxEntry;
+
+/// The handle through which a job's handler enqueues jobs, see `Context::enqueue`.
+#[derive(Clone)]
+pub(crate) struct Enqueue {
+    entries: Arc<Mutex<Vec<OutboxEntry>>>,
+    report: Option<Arc<Fn(&[OutboxEntry]) + Send + Sync>>,
+}
+
+impl Enqueue {
+    /// Return an `Enqueue` whose jobs are read with `Enqueue::entries`.
+    pub(crate) fn new() -> Self {
+        Enqueue {
+            entries: Arc::new(Mutex::new(Vec::new())),
+            report: None,
+        }
+    }
+
+    /// Return an `Enqueue` also handing the jobs enqueued so far to the given function, each
+    /// time a job is enqueued.
+    pub(crate) fn reporting<F>(report: F) -> Self
+    where
+        F: Fn(&[OutboxEntry]) + Send + Sync + 'static,
+    {
+        Enqueue {
+            report: Some(Arc::new(report)),
+            ..Enqueue::new()
+        }
+    }
+
+    /// Enqueue the given job.
+    pub(crate) fn push(&self, entry: OutboxEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(entry);
+        if let Some(ref report) = self.report {
+            report(&entries);
+        }
+    }
+
+    /// Return the jobs enqueued by the handler, in the order they were enqueued.
+    pub(crate) fn entries(&self) -> Vec<OutboxEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for Enqueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Enqueue {{ entries: {:?} }}",
+            self.entries.lock().unwrap().len()
+        )
+    }
+}
@@ -21,6 +21,14 @@ pub enum ErrorKind {
     #[fail(display = "Couldn't deserialize Job: {}", _0)]
     Deserialization(#[cause] ::serde_json::Error),
 
+    /// Couldn't encode or decode a `Job` using a non-JSON `Codec`.
+    #[fail(display = "Couldn't encode or decode Job: {}", _0)]
+    Codec(::failure::Error),
+
+    /// Couldn't compress or decompress the payload of a `Job`.
+    #[fail(display = "Couldn't compress or decompress Job: {}", _0)]
+    Compression(#[cause] ::std::io::Error),
+
     /// Couldn't create Tokio reactor
     #[fail(display = "Couldn't create Tokio reactor: {}", _0)]
     Reactor(#[cause] ::std::io::Error),
@@ -50,11 +58,180 @@ pub enum ErrorKind {
     Rabbitmq(#[cause] ::std::io::Error),
 
     /// An error occured while setting up TLS.
+    #[cfg(feature = "rabbitmq")]
     #[fail(display = "An error occured while setting up TLS: {}", _0)]
     Tls(#[cause] ::native_tls::Error),
+
+    /// The given cron expression is invalid.
+    #[fail(display = "Invalid cron expression: {}", _0)]
+    InvalidCron(::std::string::String),
+
+    /// An error occured in the Tokio timer.
+    #[fail(display = "An error occured in the Tokio timer: {}", _0)]
+    Timer(#[cause] ::tokio_timer::Error),
+
+    /// No `ResultBackend` was configured, the outcome of jobs can't be retrieved.
+    #[fail(display = "No result backend was configured")]
+    NoResultBackend,
+
+    /// The outcome of a job couldn't be retrieved from the `ResultBackend`.
+    #[fail(display = "The outcome of the job couldn't be retrieved")]
+    ResultUnavailable,
+
+    /// No `StatusStore` was configured, the status of jobs can't be retrieved.
+    #[fail(display = "No status store was configured")]
+    NoStatusStore,
+
+    /// The job is unique but no lock was configured on the client.
+    #[fail(display = "No lock was configured to send unique jobs")]
+    NoUniqueLock,
+
+    /// The job failed and won't be retried.
+    #[fail(display = "The job failed: {:?}", _0)]
+    JobFailed(::job::Failure),
+
+    /// The handler of a job didn't complete before its timeout elapsed.
+    #[fail(display = "The job timed out after {:?}", _0)]
+    Timeout(::std::time::Duration),
+
+    /// An error occured in a third-party message broker.
+    #[fail(display = "An error occured in the message broker: {}", _0)]
+    Broker(::failure::Error),
+
+    /// A `PublishHook` prevented a job from being published.
+    #[fail(display = "A publish hook rejected the job: {}", _0)]
+    Hook(::failure::Error),
+
+    /// The publisher is reconnecting and its buffer of pending jobs is full.
+    #[fail(display = "The publish buffer is full while reconnecting to the broker")]
+    PublishBufferFull,
+
+    /// The broker nacked a job, even after it was published again.
+    #[fail(display = "The broker couldn't handle the job")]
+    Nacked,
+
+    /// The broker didn't confirm a job before the publisher confirms timeout elapsed.
+    #[fail(display = "The broker didn't confirm the job after {:?}", _0)]
+    ConfirmTimeout(::std::time::Duration),
+
+    /// A workflow was built incorrectly.
+    #[fail(display = "Invalid workflow: {}", _0)]
+    InvalidWorkflow(::std::string::String),
+
+    /// No `Barrier` was configured, the members of chords can't be synchronized.
+    #[fail(display = "No barrier was configured")]
+    NoBarrier,
+
+    /// No `BlobStore` was configured, the result handed to a job can't be fetched.
+    #[fail(display = "No blob store was configured")]
+    NoBlobStore,
+
+    /// The given rate limit is invalid.
+    #[fail(display = "Invalid rate limit: {}", _0)]
+    InvalidRateLimit(::std::string::String),
+
+    /// A job registered on a worker is routed to none of the worker's queues.
+    #[fail(display = "The job {} is routed to none of the worker's queues", _0)]
+    UnroutableJob(::std::string::String),
+
+    /// A job handler requested a value that wasn't provided to the worker.
+    #[fail(display = "The requested value wasn't provided to the worker, see WorkerBuilder::provide")]
+    MissingDependency,
+
+    /// The worker wasn't given a `Broker`, and the `rabbitmq` feature is disabled.
+    #[fail(display = "No broker was configured, see WorkerBuilder::broker")]
+    NoBroker,
+
+    /// A job sent with another version couldn't be migrated, see `Job::migrate`.
+    #[fail(display = "Couldn't migrate the job: {}", _0)]
+    Migration(::failure::Error),
+
+    /// The payload of a job holds a field unknown to the job, see `ParsePolicy::Strict`.
+    #[fail(display = "Unknown field in the payload of the job: {}", _0)]
+    UnknownField(::std::string::String),
+
+    /// Couldn't encrypt or decrypt the payload of a `Job`, see `Encryptor`.
+    #[fail(display = "Couldn't encrypt or decrypt Job: {}", _0)]
+    Encryption(::failure::Error),
+
+    /// A request to the `RabbitMQ` management API failed, see `Management`.
+    #[fail(display = "A request to the RabbitMQ management API failed: {}", _0)]
+    Management(::failure::Error),
+
+    /// An error occured while storing or fetching a payload, see `BlobStore`.
+    #[fail(display = "Couldn't store or fetch the payload of the Job: {}", _0)]
+    Blob(::failure::Error),
+
+    /// An error occured while recording or querying the attempts of a job, see `AuditSink`.
+    #[fail(display = "Couldn't record or query the history of the Job: {}", _0)]
+    Audit(::failure::Error),
+
+    /// No `ScheduleStore` was configured, jobs can't be scheduled, see `Client::send_at`.
+    #[fail(display = "No schedule store was configured")]
+    NoScheduleStore,
+
+    /// The broker can't stop consuming a single queue, see `Broker::consume_except`.
+    #[fail(display = "The broker doesn't support pausing queues")]
+    UnsupportedQueuePause,
+
+    /// The broker can't report the arguments of its queues, see `QueueInspector::is_prioritized`.
+    #[fail(display = "The broker can't report the arguments of its queues")]
+    UnsupportedQueueArguments,
+
+    /// A job can't be replaced by a job of another type, see `Context::retry_with`.
+    #[fail(display = "The job {} can't be replaced by a {} job", _0, _1)]
+    MismatchedJob(::std::string::String, ::std::string::String),
 }
 
 impl Error {
+    /// Create a new `Error` from an error emitted by a third-party message broker.
+    ///
+    /// This is meant to be used by `Broker` implementations living outside of this crate.
+    pub fn broker<E>(error: E) -> Error
+    where
+        E: Into<::failure::Error>,
+    {
+        ErrorKind::Broker(error.into()).into()
+    }
+
+    /// Create a new `Error` from an error emitted while migrating a job, see `Job::migrate`.
+    pub fn migration<E>(error: E) -> Error
+    where
+        E: Into<::failure::Error>,
+    {
+        ErrorKind::Migration(error.into()).into()
+    }
+
+    /// Create a new `Error` from an error emitted while encrypting or decrypting a payload.
+    ///
+    /// This is meant to be used by `Encryptor` implementations living outside of this crate.
+    pub fn encryption<E>(error: E) -> Error
+    where
+        E: Into<::failure::Error>,
+    {
+        ErrorKind::Encryption(error.into()).into()
+    }
+
+    /// Create a new `Error` from an error emitted while storing or fetching a payload.
+    ///
+    /// This is meant to be used by `BlobStore` implementations living outside of this crate.
+    pub fn blob<E>(error: E) -> Error
+    where
+        E: Into<::failure::Error>,
+    {
+        ErrorKind::Blob(error.into()).into()
+    }
+
+    /// Create a new `Error` from an error emitted while recording or querying attempts.
+    ///
+    /// This is meant to be used by `AuditSink` implementations living outside of this crate.
+    pub fn audit<E>(error: E) -> Error
+    where
+        E: Into<::failure::Error>,
+    {
+        ErrorKind::Audit(error.into()).into()
+    }
+
     /// Returns the underlying `Kind` of this error
     pub(crate) fn kind(&self) -> &ErrorKind {
         self.inner.get_context()
@@ -76,6 +253,22 @@ impl Error {
         }
     }
 
+    /// Returns true if the error is from a non-JSON `Codec`.
+    pub fn is_codec(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Codec(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from the compression of a `Job`'s payload.
+    pub fn is_compression(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Compression(_) => true,
+            _ => false,
+        }
+    }
+
     /// Returns true if the error is from the underlying I/O event loop.
     pub fn is_reactor(&self) -> bool {
         match *self.kind() {
@@ -133,12 +326,229 @@ impl Error {
     }
 
     /// Returns true if the error is from the TLS stack.
+    #[cfg(feature = "rabbitmq")]
     pub fn is_tls(&self) -> bool {
         match *self.kind() {
             ErrorKind::Tls(_) => true,
             _ => false,
         }
     }
+
+    /// Returns true if the error is from an invalid cron expression.
+    pub fn is_invalid_cron(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::InvalidCron(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from the Tokio timer.
+    pub fn is_timer(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Timer(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a missing `ResultBackend`.
+    pub fn is_no_result_backend(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoResultBackend => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an outcome that couldn't be retrieved.
+    pub fn is_result_unavailable(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::ResultUnavailable => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a missing `StatusStore`.
+    pub fn is_no_status_store(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoStatusStore => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a missing lock when sending a unique job.
+    pub fn is_no_unique_lock(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoUniqueLock => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job that failed.
+    pub fn is_job_failed(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::JobFailed(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job that timed out.
+    pub fn is_timeout(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a third-party message broker.
+    pub fn is_broker(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Broker(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a `PublishHook` rejecting a job.
+    pub fn is_hook(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Hook(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a full publish buffer while reconnecting.
+    pub fn is_publish_buffer_full(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::PublishBufferFull => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job nacked by the broker.
+    pub fn is_nacked(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Nacked => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job the broker didn't confirm in time.
+    pub fn is_confirm_timeout(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::ConfirmTimeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an incorrectly built workflow.
+    pub fn is_invalid_workflow(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::InvalidWorkflow(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a missing `Barrier`.
+    pub fn is_no_barrier(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoBarrier => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a missing `BlobStore`.
+    pub fn is_no_blob_store(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoBlobStore => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from an invalid rate limit.
+    pub fn is_invalid_rate_limit(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::InvalidRateLimit(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job routed to none of the worker's queues.
+    pub fn is_unroutable_job(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::UnroutableJob(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a value that wasn't provided to the worker.
+    pub fn is_missing_dependency(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::MissingDependency => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a worker that wasn't given a broker.
+    pub fn is_no_broker(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::NoBroker => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from the migration of a job sent with another version.
+    pub fn is_migration(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Migration(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from the encryption or decryption of a `Job`'s payload.
+    pub fn is_encryption(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Encryption(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a field unknown to the job it was sent with.
+    pub fn is_unknown_field(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::UnknownField(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a request to the `RabbitMQ` management API.
+    pub fn is_management(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Management(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from the storage of a `Job`'s payload in a `BlobStore`.
+    pub fn is_blob(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::Blob(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a broker that can't report the arguments of its queues.
+    pub fn is_unsupported_queue_arguments(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::UnsupportedQueueArguments => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is from a job replaced by a job of another type.
+    pub fn is_mismatched_job(&self) -> bool {
+        match *self.kind() {
+            ErrorKind::MismatchedJob(..) => true,
+            _ => false,
+        }
+    }
 }
 
 impl Fail for Error {
@@ -0,0 +1,96 @@
+//! Values provided to job handlers, looked up by type.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+
+/// A map of values, holding at most one value of each type.
+///
+/// The values given to `WorkerBuilder::provide` are stored in an `Extensions` map, handed to
+/// each job handler through its `Context`. Handlers retrieve the values they need by type using
+/// `Context::get`, which allows jobs sharing a worker to depend on different resources (e.g: a
+/// database pool, an HTTP client) without sharing a single context type. Cloning an `Extensions`
+/// map is cheap, the values are reference-counted.
+///
+/// # Example
+///
+/// ```
+/// use batch::Extensions;
+///
+/// struct HttpClient;
+///
+/// let mut extensions = Extensions::new();
+/// extensions.insert(HttpClient);
+/// assert!(extensions.get::<HttpClient>().is_some());
+/// assert!(extensions.get::<String>().is_none());
+/// ```
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: Arc<HashMap<TypeId, Arc<Any + Send + Sync>>>,
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Extensions {{ len: {:?} }}", self.values.len())
+    }
+}
+
+impl Extensions {
+    /// Create a new, empty, `Extensions` map.
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    /// Insert the given value, replacing the value of the same type if any.
+    pub fn insert<T>(&mut self, value: T)
+    where
+        T: Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.values).insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// Return a reference to the value of the given type, if any.
+    pub fn get<T>(&self) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    /// Return the number of values in this map.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Return whether this map holds no value.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Pool(u32);
+
+    #[test]
+    fn values() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.is_empty());
+        extensions.insert(Pool(1));
+        extensions.insert("http".to_string());
+        let shared = extensions.clone();
+        extensions.insert(Pool(2));
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions.get::<Pool>(), Some(&Pool(2)));
+        assert_eq!(shared.get::<Pool>(), Some(&Pool(1)));
+        assert_eq!(shared.get::<String>().map(|s| s.as_str()), Some("http"));
+        assert_eq!(shared.get::<u32>(), None);
+    }
+}
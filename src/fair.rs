@@ -0,0 +1,154 @@
+//! Fair consumption of the jobs of a queue shared by several customers.
+
+use std::collections::{HashMap, VecDeque};
+
+use futures::{Async, Poll, Stream};
+
+use broker::{Deliveries, Delivery};
+use error::Error;
+
+/// A stream of deliveries, interleaving the jobs received according to the value of one of their
+/// headers, e.g: the tenant they were sent for.
+///
+/// The jobs received from the broker are buffered per value of the header, up to `capacity` jobs
+/// in total, and handed to the worker in turn: one job per value, round-robin. Jobs without the
+/// header share the same sub-queue.
+pub(crate) struct Fair {
+    stream: Deliveries,
+    done: bool,
+    header: String,
+    capacity: usize,
+    buffered: usize,
+    keys: VecDeque<String>,
+    lanes: HashMap<String, VecDeque<Box<Delivery>>>,
+}
+
+impl Fair {
+    pub(crate) fn new(stream: Deliveries, header: String, capacity: usize) -> Self {
+        Fair {
+            stream,
+            done: false,
+            header,
+            capacity: capacity.max(1),
+            buffered: 0,
+            keys: VecDeque::new(),
+            lanes: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, delivery: Box<Delivery>) {
+        let key = delivery
+            .properties()
+            .headers
+            .get(&self.header)
+            .cloned()
+            .unwrap_or_default();
+        if !self.lanes.contains_key(&key) {
+            self.keys.push_back(key.clone());
+        }
+        self.lanes
+            .entry(key)
+            .or_insert_with(VecDeque::new)
+            .push_back(delivery);
+        self.buffered += 1;
+    }
+
+    fn pop(&mut self) -> Option<Box<Delivery>> {
+        let key = self.keys.pop_front()?;
+        let (delivery, empty) = {
+            let lane = self.lanes.get_mut(&key)?;
+            (lane.pop_front(), lane.is_empty())
+        };
+        // Sub-queues are dropped once empty, so that the many keys seen over time don't pile up.
+        if empty {
+            self.lanes.remove(&key);
+        } else {
+            self.keys.push_back(key);
+        }
+        self.buffered -= 1;
+        delivery
+    }
+}
+
+impl Stream for Fair {
+    type Item = Box<Delivery>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done && self.buffered < self.capacity {
+            match self.stream.poll()? {
+                Async::Ready(Some(delivery)) => self.push(delivery),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => break,
+            }
+        }
+        match self.pop() {
+            Some(delivery) => Ok(Async::Ready(Some(delivery))),
+            None if self.done => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, stream, Future};
+
+    use broker::Properties;
+
+    #[derive(Debug)]
+    struct Tenant(Properties);
+
+    impl Delivery for Tenant {
+        fn properties(&self) -> &Properties {
+            &self.0
+        }
+
+        fn payload(&self) -> &[u8] {
+            &[]
+        }
+
+        fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+
+        fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    fn order(capacity: usize) -> Vec<String> {
+        let deliveries = vec!["acme"; 4]
+            .into_iter()
+            .chain(vec!["globex", "initech", "globex"])
+            .map(|tenant| -> Box<Delivery> {
+                let mut properties = Properties::named("noop", "", "noop");
+                properties.headers.insert("tenant".into(), tenant.into());
+                Box::new(Tenant(properties))
+            })
+            .collect::<Vec<_>>();
+        let stream = Box::new(stream::iter_ok(deliveries));
+        Fair::new(stream, "tenant".into(), capacity)
+            .wait()
+            .map(|delivery| delivery.unwrap().properties().headers["tenant"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        assert_eq!(
+            order(16),
+            vec!["acme", "globex", "initech", "acme", "globex", "acme", "acme"]
+        );
+    }
+
+    #[test]
+    fn bounded() {
+        // The last jobs of acme are only buffered once others were handed to the worker.
+        assert_eq!(
+            order(5),
+            vec!["acme", "globex", "acme", "initech", "globex", "acme", "acme"]
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Hand-off of the jobs a worker received but didn't start when shutting down.
+//!
+//! A worker receives more jobs than it executes at once: the broker sends it up to `prefetch`
+//! jobs ahead, and jobs wait for a concurrency slot or for a rate limit. By default, these jobs
+//! are left unacknowledged when the worker shuts down, and the broker only delivers them to other
+//! workers once the connection of the worker is closed, i.e: after the drain timeout. With a
+//! `Handoff` other than `Keep`, they are handed back to the broker as soon as the worker is asked
+//! to shut down.
+
+use std::sync::Arc;
+
+use futures::{future, Future, Stream};
+
+use blob;
+use broker::{Broker, Deliveries, Delivery};
+use error::Error;
+
+/// What a worker does with the jobs it received but didn't start when shutting down, see
+/// `WorkerBuilder::shutdown_handoff`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Handoff {
+    /// Keep the jobs until the worker exits, the broker then delivers them again.
+    Keep,
+    /// Ask the broker to deliver the jobs again right away, as is.
+    ///
+    /// Brokers that can't deliver a job again reject it instead, use `Republish` with them.
+    Requeue,
+    /// Publish the jobs again, then reject the deliveries.
+    ///
+    /// This is supported by every broker, but the jobs are moved to the back of their queue.
+    Republish,
+}
+
+impl Default for Handoff {
+    fn default() -> Self {
+        Handoff::Keep
+    }
+}
+
+/// Hand the given job back to the broker, according to the given `Handoff`.
+pub(crate) fn hand_off(
+    handoff: Handoff,
+    broker: &Arc<Broker>,
+    delivery: Box<Delivery>,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let id = delivery.properties().id;
+    match handoff {
+        Handoff::Keep => Box::new(future::ok(())),
+        Handoff::Requeue => {
+            debug!("[{}] Requeuing job the worker didn't start", id);
+            delivery.requeue()
+        }
+        Handoff::Republish => {
+            debug!("[{}] Publishing again job the worker didn't start", id);
+            let payload = blob::republished(&*delivery);
+            let mut properties = delivery.properties().clone();
+            properties.delay = None;
+            let task = broker
+                .publish(&payload, &properties)
+                .and_then(move |_| delivery.reject());
+            Box::new(task)
+        }
+    }
+}
+
+/// Return a `Future` handing off every job received from the given stream.
+///
+/// The returned `Future` never resolves: it is dropped once the worker stopped.
+pub(crate) fn drain(
+    stream: Deliveries,
+    handoff: Handoff,
+    broker: Arc<Broker>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let task = stream
+        .then(|result| -> Result<_, ()> { Ok(result.ok()) })
+        .filter_map(|delivery| delivery)
+        .for_each(move |delivery| {
+            let id = delivery.properties().id;
+            hand_off(handoff, &broker, delivery).or_else(move |e| -> Result<(), ()> {
+                error!("[{}] Couldn't hand off job: {}", id, e);
+                Ok(())
+            })
+        })
+        .then(|_| future::empty());
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use broker::Properties;
+    use memory;
+    use topology::queue;
+
+    fn handed_off(handoff: Handoff) -> usize {
+        let connection = memory::Connection::new(vec![queue("tests.handoff")]);
+        let broker: Arc<Broker> = Arc::new(connection.clone());
+        let properties = Properties::named("handoff", "", "tests.handoff");
+        broker.publish(b"null", &properties).wait().unwrap();
+        let deliveries = broker.consume(1).wait().unwrap();
+        let delivery = deliveries.wait().next().unwrap().unwrap();
+        assert!(connection.is_empty("tests.handoff"));
+        hand_off(handoff, &broker, delivery).wait().unwrap();
+        connection.len("tests.handoff")
+    }
+
+    #[test]
+    fn hand_off_jobs() {
+        assert_eq!(handed_off(Handoff::Keep), 0);
+        assert_eq!(handed_off(Handoff::Requeue), 1);
+        assert_eq!(handed_off(Handoff::Republish), 1);
+    }
+}
@@ -0,0 +1,51 @@
+//! Hooks wrapping the publication of jobs by the client.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use failure;
+
+use broker::Properties;
+
+/// A hook called around the publication of each job by a `Client`.
+///
+/// Hooks are registered with `Client::hook`. They are the client-side counterpart of the
+/// worker's `Middleware`, and are typically used to attach metadata to jobs through their
+/// `Properties::headers` (e.g: trace IDs, tenant). The `before_publish` hooks are called in
+/// registration order, the `after_publish` hooks in reverse order. All hooks have a default
+/// implementation doing nothing.
+///
+/// # Example
+///
+/// ```
+/// extern crate batch;
+/// extern crate failure;
+///
+/// use batch::{Properties, PublishHook};
+///
+/// #[derive(Debug)]
+/// struct Tenant(String);
+///
+/// impl PublishHook for Tenant {
+///     fn before_publish(&self, properties: &mut Properties) -> Result<(), failure::Error> {
+///         properties.headers.insert("tenant".into(), self.0.clone());
+///         Ok(())
+///     }
+/// }
+/// #
+/// # fn main() {}
+/// ```
+pub trait PublishHook: fmt::Debug + Send + Sync {
+    /// Called before the job is published, allowing its properties to be modified.
+    ///
+    /// Returning an error aborts the publication of the job.
+    fn before_publish(&self, properties: &mut Properties) -> StdResult<(), failure::Error> {
+        let _ = properties;
+        Ok(())
+    }
+
+    /// Called once the job was accepted by the broker.
+    fn after_publish(&self, properties: &Properties) {
+        let _ = properties;
+    }
+}
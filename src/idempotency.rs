@@ -0,0 +1,154 @@
+//! Idempotent operations performed by job handlers.
+//!
+//! Jobs are delivered at least once: a job whose worker died before acknowledging it is
+//! delivered again, and a failed job is retried, even if some of its side effects already took
+//! place. Handlers wrap such side effects (e.g: charging a customer) in `Context::idempotent`,
+//! which records their outcome in an `IdempotencyStore` under a key identifying them, and skips
+//! them when they were already performed.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure;
+use futures::{future, Future, IntoFuture};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use error::{Error, ErrorKind};
+
+/// The default number of seconds the outcome of an operation is remembered for.
+const DEFAULT_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// A store recording the outcome of the operations performed by job handlers, see
+/// `Context::idempotent`.
+pub trait IdempotencyStore: fmt::Debug + Send + Sync {
+    /// Return the serialized outcome of the operation identified by the given key, if it was
+    /// performed.
+    fn get(&self, key: &str) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send>;
+
+    /// Record the serialized outcome of the operation identified by the given key, for the given
+    /// duration.
+    fn set(
+        &self,
+        key: &str,
+        outcome: &[u8],
+        ttl: Duration,
+    ) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// Performs the operations of job handlers at most once per key, see `Context::idempotent`.
+///
+/// Provide it to the worker with `WorkerBuilder::provide` to enable `Context::idempotent`.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use batch::{memory, Idempotency, Worker};
+///
+/// let connection = memory::Connection::new(vec![]);
+/// let builder = Worker::builder(())
+///     .provide(Idempotency::new(connection).ttl(Duration::from_secs(24 * 60 * 60)));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Idempotency {
+    store: Arc<IdempotencyStore>,
+    ttl: Duration,
+}
+
+impl Idempotency {
+    /// Create a new `Idempotency` recording the outcome of operations in the given store.
+    pub fn new<S>(store: S) -> Self
+    where
+        S: IdempotencyStore + 'static,
+    {
+        Idempotency {
+            store: Arc::new(store),
+            ttl: Duration::from_secs(DEFAULT_TTL),
+        }
+    }
+
+    /// Set how long the outcome of operations is remembered for. Chainable.
+    ///
+    /// This should exceed the time a job may spend being retried. Defaults to 7 days.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Perform the operation returned by the given function, unless an operation with the same
+    /// key was already performed, in which case its recorded outcome is returned.
+    pub fn run<F, R>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> Box<Future<Item = R::Item, Error = failure::Error> + Send>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: IntoFuture<Error = failure::Error>,
+        R::Future: Send + 'static,
+        R::Item: Serialize + DeserializeOwned + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        let ttl = self.ttl;
+        let key = key.to_string();
+        let task = self.store.get(&key).map_err(failure::Error::from).and_then(
+            move |recorded| -> Box<Future<Item = _, Error = _> + Send> {
+                if let Some(recorded) = recorded {
+                    debug!("Operation {} was already performed, skipping it", key);
+                    let outcome = serde_json::from_slice(&recorded)
+                        .map_err(|e| Error::from(ErrorKind::Deserialization(e)).into());
+                    return Box::new(future::result(outcome));
+                }
+                let task = f().into_future().and_then(move |outcome| {
+                    let serialized = match serde_json::to_vec(&outcome) {
+                        Ok(serialized) => serialized,
+                        Err(e) => {
+                            warn!("Couldn't serialize the outcome of {}: {}", key, e);
+                            return future::Either::A(future::ok(outcome));
+                        }
+                    };
+                    // The operation was performed: failing now would only make it happen again.
+                    let record = store.set(&key, &serialized, ttl).then(move |result| {
+                        if let Err(e) = result {
+                            warn!("Couldn't record the outcome of {}: {}", key, e);
+                        }
+                        Ok(outcome)
+                    });
+                    future::Either::B(record)
+                });
+                Box::new(task)
+            },
+        );
+        Box::new(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use memory;
+
+    #[test]
+    fn at_most_once() {
+        let idempotency = Idempotency::new(memory::Connection::new(vec![]));
+        let charges = Arc::new(AtomicUsize::new(0));
+        let charge = |charges: Arc<AtomicUsize>| {
+            move || -> Result<usize, failure::Error> {
+                Ok(charges.fetch_add(1, Ordering::SeqCst) + 1)
+            }
+        };
+        let first = idempotency.run("charge:42", charge(Arc::clone(&charges)));
+        assert_eq!(first.wait().unwrap(), 1);
+        let second = idempotency.run("charge:42", charge(Arc::clone(&charges)));
+        assert_eq!(second.wait().unwrap(), 1);
+        let other = idempotency.run("charge:43", charge(Arc::clone(&charges)));
+        assert_eq!(other.wait().unwrap(), 2);
+        assert_eq!(charges.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,207 @@
+//! Inspection of the queues of a broker.
+//!
+//! The queues a broker consumes from, the number of jobs waiting in each of them and the next
+//! jobs to be consumed can be retrieved using `Queues`, obtained with
+//! [`Client::queues`](../struct.Client.html#method.queues). `Queues::stats` also tells how many
+//! consumers each queue has, which is what autoscalers need (see `Scaler`).
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::{future, Future};
+
+use broker::Properties;
+use error::{Error, ErrorKind};
+
+/// The state of a queue.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueInfo {
+    /// The name of the queue.
+    pub name: String,
+    /// The number of jobs waiting in the queue, excluding the ones being executed.
+    pub pending: u64,
+}
+
+/// The statistics of a queue, used to scale the workers consuming from it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueStats {
+    /// The name of the queue.
+    pub name: String,
+    /// The number of jobs waiting in the queue, excluding the ones being executed.
+    pub messages: u64,
+    /// The number of consumers of the queue, if the broker can count them.
+    pub consumers: Option<u32>,
+}
+
+/// A job waiting in a queue.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingJob {
+    /// The metadata associated to the job.
+    pub properties: Properties,
+    /// The serialized job.
+    pub payload: Vec<u8>,
+}
+
+/// A view over the queues of a broker, implemented by `Broker`s.
+pub trait QueueInspector: fmt::Debug + Send + Sync {
+    /// Return the names of the queues known to the broker.
+    fn queues(&self) -> Vec<String>;
+
+    /// Return the names of every queue of the broker, including the ones declared on the fly,
+    /// e.g: the queues of tenants (see `Client::for_tenant`).
+    ///
+    /// Defaults to `queues`, for brokers that only know the queues they were created with.
+    fn discover(&self) -> Box<Future<Item = Vec<String>, Error = Error> + Send> {
+        Box::new(future::ok(self.queues()))
+    }
+
+    /// Return the number of jobs waiting in the given queue.
+    fn pending(&self, queue: &str) -> Box<Future<Item = u64, Error = Error> + Send>;
+
+    /// Return up to `count` jobs of the given queue, in the order they will be consumed, without
+    /// consuming them.
+    fn peek(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Box<Future<Item = Vec<PendingJob>, Error = Error> + Send>;
+
+    /// Return the statistics of the given queue.
+    ///
+    /// Brokers that can't count the consumers of a queue only report its number of pending jobs,
+    /// which is the default.
+    fn stats(&self, queue: &str) -> Box<Future<Item = QueueStats, Error = Error> + Send> {
+        let name = queue.to_string();
+        let task = self.pending(queue).map(|messages| QueueStats {
+            name,
+            messages,
+            consumers: None,
+        });
+        Box::new(task)
+    }
+
+    /// Return whether the given queue was declared with priorities enabled, i.e: with the
+    /// `x-max-priority` argument (see `QueueBuilder::enable_priorities`).
+    ///
+    /// Brokers that can't report the arguments of their queues fail with
+    /// `ErrorKind::UnsupportedQueueArguments`, which is the default.
+    fn is_prioritized(&self, queue: &str) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let _ = queue;
+        Box::new(future::err(ErrorKind::UnsupportedQueueArguments.into()))
+    }
+}
+
+/// A handle to the queues of a `Broker`.
+#[derive(Clone, Debug)]
+pub struct Queues {
+    inner: Arc<QueueInspector>,
+}
+
+impl Queues {
+    /// Create a new `Queues` from the given inspector.
+    pub fn new(inner: Arc<QueueInspector>) -> Self {
+        Queues { inner }
+    }
+
+    /// Return the names of the queues known to the broker.
+    pub fn names(&self) -> Vec<String> {
+        self.inner.queues()
+    }
+
+    /// Return the number of jobs waiting in the given queue.
+    pub fn pending(&self, queue: &str) -> Box<Future<Item = u64, Error = Error> + Send> {
+        self.inner.pending(queue)
+    }
+
+    /// Return up to `count` jobs of the given queue, in the order they will be consumed, without
+    /// consuming them.
+    pub fn peek(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Box<Future<Item = Vec<PendingJob>, Error = Error> + Send> {
+        self.inner.peek(queue, count)
+    }
+
+    /// Return the state of every queue known to the broker, in the order they were declared.
+    pub fn list(&self) -> Box<Future<Item = Vec<QueueInfo>, Error = Error> + Send> {
+        let tasks = self.inner
+            .queues()
+            .into_iter()
+            .map(|name| {
+                self.inner
+                    .pending(&name)
+                    .map(move |pending| QueueInfo { name, pending })
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks))
+    }
+
+    /// Return the statistics of every queue known to the broker, in the order they were
+    /// declared.
+    pub fn stats(&self) -> Box<Future<Item = Vec<QueueStats>, Error = Error> + Send> {
+        let tasks = self.inner
+            .queues()
+            .into_iter()
+            .map(|name| self.inner.stats(&name))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use broker::Broker;
+    use job::Priority;
+    use memory::{self, tests::properties};
+    use topology::queue;
+
+    #[test]
+    fn list() {
+        let connection = memory::Connection::new(vec![
+            queue("tests.inspect").bind("batch.tests", "inspect"),
+            queue("tests.idle"),
+        ]);
+        for _ in 0..3 {
+            let properties = properties("job", "inspect", Priority::Normal);
+            connection.publish(b"{}", &properties).wait().unwrap();
+        }
+        let queues = Queues::new(connection.inspector().unwrap());
+        let expected = vec![
+            QueueInfo {
+                name: "tests.inspect".into(),
+                pending: 3,
+            },
+            QueueInfo {
+                name: "tests.idle".into(),
+                pending: 0,
+            },
+        ];
+        assert_eq!(queues.list().wait().unwrap(), expected);
+
+        let stats = queues.stats().wait().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "tests.inspect");
+        assert_eq!(stats[0].messages, 3);
+        assert_eq!(stats[1].messages, 0);
+    }
+
+    #[test]
+    fn peek() {
+        let connection =
+            memory::Connection::new(vec![queue("tests.peek").bind("batch.tests", "peek")]);
+        let low = properties("job", "peek", Priority::Low);
+        let high = properties("job", "peek", Priority::High);
+        let normal = properties("job", "peek", Priority::Normal);
+        for properties in &[&low, &high, &normal] {
+            connection.publish(b"{}", properties).wait().unwrap();
+        }
+        let queues = Queues::new(connection.inspector().unwrap());
+        let jobs = queues.peek("tests.peek", 2).wait().unwrap();
+        let ids = jobs.iter().map(|job| job.properties.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![high.id, normal.id]);
+        assert_eq!(connection.len("tests.peek"), 3);
+    }
+}
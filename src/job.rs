@@ -1,12 +1,24 @@
 //! A trait representing a job.
 
+use std::any::TypeId;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::Duration;
 
+use futures::IntoFuture;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_json::{self, Value};
 
+use broker::Properties;
+use codec::Codec;
+use context::Context;
+use crash::Panic;
 use error::{Error, ErrorKind, Result};
+use rate_limit::RateLimit;
+use retry::{FailurePolicy, RetryStrategy};
+use ser;
 
 /// A job and its related metadata (name, queue, timeout, etc.)
 ///
@@ -25,7 +37,7 @@ use error::{Error, ErrorKind, Result};
 /// extern crate serde;
 ///
 /// #[derive(Deserialize, Serialize, Job)]
-/// #[job_routing_key = "emails"]
+/// #[job(routing_key = "emails")]
 /// struct SendConfirmationEmail;
 ///
 /// #
@@ -45,15 +57,58 @@ use error::{Error, ErrorKind, Result};
 /// struct App;
 ///
 /// #[derive(Deserialize, Serialize, Job)]
-/// #[job_name = "batch-rs:send-password-reset-email"]
-/// #[job_routing_key = "emails"]
-/// #[job_timeout = "120"]
-/// #[job_retries = "0"]
+/// #[job(
+///     name = "batch-rs:send-password-reset-email",
+///     exchange = "batch.example",
+///     routing_key = "emails",
+///     timeout = 120,
+///     retries = 5,
+///     retry_backoff = "exponential(2s, 10m)",
+///     delay = 300,
+/// )]
 /// struct SendPasswordResetEmail;
 ///
 /// #
 /// # fn main() {}
 /// ```
+///
+/// Deriving it for an enum, whose variants share the same queue and handler:
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate batch;
+/// extern crate failure;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Context, Perform};
+///
+/// #[derive(Deserialize, Serialize, Job)]
+/// #[job(routing_key = "notifications")]
+/// enum NotifyUser {
+///     Email { to: String, subject: String },
+///     Sms { to: String, text: String },
+/// }
+///
+/// impl Perform for NotifyUser {
+///     type Context = ();
+///     type Output = ();
+///     type Error = failure::Error;
+///     type Future = Result<Self::Output, Self::Error>;
+///
+///     fn perform(&self, _ctx: Context<Self::Context>) -> Self::Future {
+///         match *self {
+///             NotifyUser::Email { ref to, .. } => println!("Emailing {}", to),
+///             NotifyUser::Sms { ref to, .. } => println!("Texting {}", to),
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() {}
+/// ```
 pub trait Job: DeserializeOwned + Serialize {
     /// A should-be-unique human-readable ID for this job.
     fn name() -> &'static str;
@@ -67,17 +122,249 @@ pub trait Job: DeserializeOwned + Serialize {
     /// The number of times this job must be retried in case of error.
     fn retries() -> u32;
 
+    /// How long to wait before retrying this job after a failure.
+    fn retry_strategy() -> RetryStrategy {
+        RetryStrategy::Immediate
+    }
+
+    /// What the worker does with this job when it fails, if it shouldn't use the worker's
+    /// default policy (see `WorkerBuilder::failure_policy`).
+    fn failure_policy() -> Option<FailurePolicy> {
+        None
+    }
+
     /// An optional duration representing the time allowed for this job's handler to complete.
     fn timeout() -> Option<Duration>;
 
     /// The priority associated to this job.
     fn priority() -> Priority;
+
+    /// An optional duration to wait before this job is delivered to a worker.
+    fn delay() -> Option<Duration> {
+        None
+    }
+
+    /// An optional duration after which this job is dropped instead of executed, counted from
+    /// the time it was sent.
+    ///
+    /// This keeps workers catching up on a backlog from executing jobs that are no longer
+    /// relevant, e.g: live notifications.
+    fn expires() -> Option<Duration> {
+        None
+    }
+
+    /// An optional cron expression describing when this job is published by a `Scheduler`.
+    fn cron() -> Option<&'static str> {
+        None
+    }
+
+    /// The name of the timezone the `cron` expression is evaluated in, e.g: `"Europe/Paris"`.
+    ///
+    /// Defaults to the scheduler's timezone, see `SchedulerBuilder::timezone`.
+    fn timezone() -> Option<&'static str> {
+        None
+    }
+
+    /// An optional maximum duration by which each occurrence of the `cron` schedule is delayed.
+    ///
+    /// Defaults to the scheduler's jitter, see `SchedulerBuilder::jitter`.
+    fn jitter() -> Option<Duration> {
+        None
+    }
+
+    /// The codec used to serialize this job, if it shouldn't use the client's default one.
+    fn codec() -> Option<Codec> {
+        None
+    }
+
+    /// An optional duration during which sending this job again is a no-op.
+    ///
+    /// Two jobs are considered identical if they have the same `unique_key`.
+    fn unique_for() -> Option<Duration> {
+        None
+    }
+
+    /// The key identifying this job when it is unique, see `unique_for`.
+    ///
+    /// Defaults to the JSON serialization of the job.
+    fn unique_key(&self) -> String {
+        ser::to_string(self).unwrap_or_default()
+    }
+
+    /// An optional maximum number of jobs of this type executed over a period of time.
+    ///
+    /// The limit is enforced by each worker the job is registered on, see `RateLimit`.
+    fn rate_limit() -> Option<RateLimit> {
+        None
+    }
+
+    /// An optional maximum number of jobs of this type executing at the same time.
+    ///
+    /// The limit is enforced by each worker the job is registered on, using its `Semaphore`.
+    /// Jobs exceeding the limit are published again, so that jobs of other types aren't held.
+    fn concurrency() -> Option<u32> {
+        None
+    }
+
+    /// The version of the schema of this job's payload, sent along with each job.
+    ///
+    /// Bump it when changing the job in a way that jobs sent by older clients can't be
+    /// deserialized anymore, and handle these jobs in `migrate`.
+    fn version() -> u32 {
+        1
+    }
+
+    /// Build this job from the payload of a job sent with the given older (or newer) version.
+    ///
+    /// This allows executing the jobs sent before a change of this job's struct, instead of
+    /// failing to deserialize them. Defaults to deserializing the payload as is.
+    fn migrate(version: u32, raw: Value) -> Result<Self> {
+        let _ = version;
+        Ok(serde_json::from_value(raw).map_err(ErrorKind::Deserialization)?)
+    }
+}
+
+/// A type whose name is part of the name of the generic jobs it parameterizes.
+///
+/// When deriving `Job` for a generic type, each type parameter must implement this trait: the
+/// name of each instantiation of the job is built from the names of its type parameters, e.g:
+/// `#[job(name = "process-{T}")]` names `Process<Invoice>` `process-invoice` if
+/// `Invoice::type_name()` returns `"invoice"`. Two instantiations of a job must have different
+/// names, as workers find the handler of a job by its name.
+///
+/// # Example
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate batch;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Job, TypeName};
+/// use serde::de::DeserializeOwned;
+/// use serde::Serialize;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Invoice {
+///     id: u64,
+/// }
+///
+/// impl TypeName for Invoice {
+///     fn type_name() -> String {
+///         "invoice".into()
+///     }
+/// }
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job(name = "process-{T}", routing_key = "documents")]
+/// struct Process<T: Serialize + DeserializeOwned> {
+///     document: T,
+/// }
+///
+/// fn main() {
+///     assert_eq!(Process::<Invoice>::name(), "process-invoice");
+///     assert_eq!(Process::<u64>::name(), "process-u64");
+/// }
+/// ```
+pub trait TypeName {
+    /// Return the name of this type.
+    fn type_name() -> String;
+}
+
+macro_rules! impl_type_name {
+    ($($ty:ty),*) => {
+        $(
+            impl TypeName for $ty {
+                fn type_name() -> String {
+                    stringify!($ty).into()
+                }
+            }
+        )*
+    };
+}
+
+impl_type_name!(
+    bool, char, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64, String
+);
+
+impl<T> TypeName for Vec<T>
+where
+    T: TypeName,
+{
+    fn type_name() -> String {
+        format!("Vec<{}>", T::type_name())
+    }
+}
+
+impl<T> TypeName for Option<T>
+where
+    T: TypeName,
+{
+    fn type_name() -> String {
+        format!("Option<{}>", T::type_name())
+    }
+}
+
+/// Return the name of the job of type `T`, built by the given function the first time.
+///
+/// Used by the `Job` derive for generic types, whose names can't be stored in a static.
+#[doc(hidden)]
+pub fn __job_name<T, F>(name: F) -> &'static str
+where
+    T: 'static,
+    F: FnOnce() -> String,
+{
+    lazy_static! {
+        static ref NAMES: Mutex<HashMap<TypeId, &'static str>> = Mutex::new(HashMap::new());
+    }
+    let mut names = NAMES.lock().unwrap();
+    *names
+        .entry(TypeId::of::<T>())
+        .or_insert_with(|| Box::leak(name().replace("::", ".").into_boxed_str()))
+}
+
+/// Decode the given payload of a job of type `T`, migrating it if it was sent with another
+/// version of `T`.
+pub(crate) fn decode<T>(properties: &Properties, payload: &[u8]) -> Result<T>
+where
+    T: Job,
+{
+    let version = properties.version.unwrap_or(1);
+    if version == T::version() {
+        return properties.codec.decode(payload);
+    }
+    debug!(
+        "[{}] Migrating job {} from version {} to version {}",
+        properties.id,
+        properties.task,
+        version,
+        T::version()
+    );
+    let raw: Value = properties.codec.decode(payload)?;
+    T::migrate(version, raw)
+}
+
+/// Returns true if the given job of type `T` won't be retried once it failed, according to its
+/// failure policy.
+pub(crate) fn exhausted<T>(properties: &Properties) -> bool
+where
+    T: Job,
+{
+    match T::failure_policy() {
+        Some(FailurePolicy::Retry) | None => {
+            properties.retries >= properties.max_retries.unwrap_or_else(T::retries)
+        }
+        Some(FailurePolicy::Requeue) => false,
+        Some(_) => true,
+    }
 }
 
 /// The different priorities that can be assigned to a `Job`.
 ///
 /// The default value is `Priority::Normal`.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Priority {
     /// The lowest available priority for a job.
     Trivial,
@@ -123,6 +410,17 @@ impl Priority {
             Priority::Critical => 4,
         }
     }
+
+    /// Create a priority from a `u8` ranging from 0 to 4, values out of this range are clamped.
+    pub(crate) fn from_u8(priority: u8) -> Priority {
+        match priority {
+            0 => Priority::Trivial,
+            1 => Priority::Low,
+            2 => Priority::Normal,
+            3 => Priority::High,
+            _ => Priority::Critical,
+        }
+    }
 }
 
 /// The different states a `Job` can be in.
@@ -136,6 +434,18 @@ pub enum Status {
     Success,
     /// The job didn't complete successfully, see attached `Failure` cause.
     Failed(Failure),
+    /// The job was revoked using `Client::cancel`.
+    Revoked,
+}
+
+impl Status {
+    /// Returns true if the job won't make any progress anymore.
+    pub fn is_finished(&self) -> bool {
+        match *self {
+            Status::Success | Status::Failed(_) | Status::Revoked => true,
+            Status::Pending | Status::Started => false,
+        }
+    }
 }
 
 /// Stores the reason for a job failure.
@@ -147,40 +457,234 @@ pub enum Failure {
     Timeout,
     /// The job crashed (panic, segfault, etc.) while executing.
     Crash,
+    /// The job expired before it was executed, see `Job::expires`.
+    Expired,
+    /// The job wasn't signed or its signature is invalid, see `WorkerBuilder::signer`.
+    InvalidSignature,
 }
 
 /// The `Perform` trait allow marking a `Job` as executable.
 ///
+/// The handler returns a value convertible into a `Future`, which is driven on the worker's
+/// Tokio reactor, allowing jobs to perform non-blocking I/O. Synchronous handlers can simply
+/// return a `Result`. If the handler resolves to an error, the job is marked as failed and
+/// retried according to its `Job::retries` value.
+///
+/// The value the handler resolves to is serialized and handed to the worker's `ResultBackend`,
+/// if any, allowing clients to retrieve it (see `Query::send_and_wait`).
+///
 /// # Example
 ///
 /// ```
 /// #[macro_use]
 /// extern crate batch;
+/// extern crate failure;
 /// #[macro_use]
 /// extern crate lazy_static;
 /// #[macro_use]
 /// extern crate serde;
 ///
-/// use batch::Perform;
+/// use batch::{Context, Perform};
 ///
 /// #[derive(Serialize, Deserialize, Job)]
-/// #[job_routing_key = "emails"]
+/// #[job(routing_key = "emails")]
 /// struct SendPasswordResetEmail;
 ///
 /// impl Perform for SendPasswordResetEmail {
 ///     type Context = ();
+///     type Output = ();
+///     type Error = failure::Error;
+///     type Future = Result<Self::Output, Self::Error>;
 ///
-///     fn perform(&self, _ctx: Self::Context) {
+///     fn perform(&self, _ctx: Context<Self::Context>) -> Self::Future {
 ///         println!("Sending password reset email...");
+///         Ok(())
 ///     }
 /// }
 ///
 /// # fn main() {}
 /// ```
 pub trait Perform {
-    /// The type of the context value that will be given to this job's handler.
+    /// The type of the worker's context value, given to this job's handler wrapped in a `Context`.
     type Context;
 
+    /// The type of the value this job's handler resolves to.
+    type Output: Serialize + DeserializeOwned + Send;
+
+    /// The type of the error returned when this job's handler fails.
+    type Error: Into<::failure::Error>;
+
+    /// The value returned by this job's handler, convertible into a `Future`.
+    type Future: IntoFuture<Item = Self::Output, Error = Self::Error>;
+
     /// Perform the job's duty.
-    fn perform(&self, Self::Context);
+    fn perform(&self, Context<Self::Context>) -> Self::Future;
+
+    /// Called once this job's handler succeeded, with its output.
+    ///
+    /// Like the hooks below, it's called in the process executing the job once the middlewares
+    /// ran, and is given a context holding the values provided to the worker (see
+    /// `WorkerBuilder::provide`) but not the worker's context value. The default implementation
+    /// does nothing.
+    fn on_success(&self, output: &Self::Output, ctx: Context<()>) {
+        let _ = (output, ctx);
+    }
+
+    /// Called each time this job's handler failed, before the job is retried or moved to its
+    /// dead-letter queue.
+    ///
+    /// This allows cleaning up after a failed attempt, e.g: releasing a lease. The default
+    /// implementation does nothing.
+    fn on_failure(&self, failure: &FailureInfo, ctx: Context<()>) {
+        let _ = (failure, ctx);
+    }
+
+    /// Called once this job's handler failed and the job has no retries left, after
+    /// `on_failure`, before the job is moved to its dead-letter queue.
+    ///
+    /// This allows giving up for good, e.g: notifying the user. The default implementation does
+    /// nothing.
+    fn on_retries_exhausted(&self, failure: &FailureInfo, ctx: Context<()>) {
+        let _ = (failure, ctx);
+    }
+}
+
+/// The handler of a job executed in batches, see `WorkerBuilder::job_batch`.
+///
+/// The worker holds the jobs of this type it receives until it has enough of them, or the first
+/// one waited long enough, then executes them all at once, e.g: to insert many rows in a
+/// database with a single statement. The jobs are acknowledged once the batch succeeded, and
+/// retried according to their retry policy if it failed.
+///
+/// # Example
+///
+/// ```
+/// #[macro_use]
+/// extern crate batch;
+/// extern crate failure;
+/// #[macro_use]
+/// extern crate lazy_static;
+/// #[macro_use]
+/// extern crate serde;
+///
+/// use batch::{Context, PerformBatch};
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job(routing_key = "page-views")]
+/// struct RecordPageView {
+///     path: String,
+/// }
+///
+/// impl PerformBatch for RecordPageView {
+///     type Context = ();
+///     type Error = failure::Error;
+///     type Future = Result<(), Self::Error>;
+///
+///     fn perform_batch(jobs: Vec<Self>, _ctx: Context<Self::Context>) -> Self::Future {
+///         println!("Inserting {} page views", jobs.len());
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() {}
+/// ```
+pub trait PerformBatch: Sized {
+    /// The type of the worker's context value, given to the handler wrapped in a `Context`.
+    type Context;
+
+    /// The type of the error returned when the handler fails.
+    type Error: Into<::failure::Error>;
+
+    /// The value returned by the handler, convertible into a `Future`.
+    type Future: IntoFuture<Item = (), Error = Self::Error>;
+
+    /// Perform the duty of the given jobs at once.
+    ///
+    /// The `Context` describes the first job of the batch.
+    fn perform_batch(jobs: Vec<Self>, ctx: Context<Self::Context>) -> Self::Future;
+}
+
+/// The details of the failure of a job, given to `Perform::on_failure`.
+#[derive(Clone, Debug)]
+pub struct FailureInfo {
+    /// The reason of the failure.
+    pub failure: Failure,
+    /// The description of the error the job failed with.
+    pub error: String,
+    /// The panic of the job's handler, if it panicked.
+    pub panic: Option<Panic>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Greet {
+        first_name: String,
+        last_name: String,
+    }
+
+    impl Job for Greet {
+        fn name() -> &'static str {
+            "greet"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "greetings"
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+
+        fn version() -> u32 {
+            2
+        }
+
+        fn migrate(version: u32, raw: Value) -> Result<Self> {
+            match (version, raw.get("name").and_then(|name| name.as_str())) {
+                (1, Some(name)) => {
+                    let mut names = name.splitn(2, ' ');
+                    Ok(Greet {
+                        first_name: names.next().unwrap_or_default().into(),
+                        last_name: names.next().unwrap_or_default().into(),
+                    })
+                }
+                _ => Err(Error::migration(::failure::err_msg("unknown version"))),
+            }
+        }
+    }
+
+    #[test]
+    fn migrate() {
+        let current = Greet {
+            first_name: "Ferris".into(),
+            last_name: "Crab".into(),
+        };
+        let mut properties = Properties::new::<Greet>();
+        assert_eq!(properties.version, Some(2));
+        let payload = properties.codec.encode(&current).unwrap();
+        assert_eq!(decode::<Greet>(&properties, &payload).unwrap(), current);
+
+        properties.version = None;
+        let payload = br#"{"name": "Ferris Crab"}"#;
+        assert_eq!(decode::<Greet>(&properties, payload).unwrap(), current);
+
+        properties.version = Some(3);
+        let e = decode::<Greet>(&properties, payload).unwrap_err();
+        assert!(e.is_migration());
+    }
 }
@@ -0,0 +1,144 @@
+//! Extension of the leases of the jobs being executed.
+//!
+//! Some brokers only hide a received job from other workers for a limited time, after which it
+//! is delivered again, e.g: the visibility timeout of SQS messages. Their deliveries provide a
+//! `Lease` on the job, which the worker extends while the job's handler reports that it is still
+//! making progress by calling `Context::touch`. The worker checks whether the handler touched the
+//! job twice per lease duration, and extends the lease by this duration if it did.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_timer::Delay;
+use uuid::Uuid;
+
+use error::Error;
+
+/// The lease a worker holds on a job received from a broker, see `Delivery::lease`.
+pub trait Lease: fmt::Debug + Send + Sync {
+    /// Keep the job hidden from other workers for the given duration, from now on.
+    ///
+    /// This may shorten the lease, if the broker granted a longer one when the job was received.
+    fn extend(&self, duration: Duration) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// The function reporting that the handler of a job is making progress, see `Context::touch`.
+#[derive(Clone)]
+pub(crate) struct Touch(Option<Arc<Fn() + Send + Sync>>);
+
+impl Touch {
+    /// Return a `Touch` calling the given function.
+    pub(crate) fn new<F>(touch: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Touch(Some(Arc::new(touch)))
+    }
+
+    /// Return a `Touch` doing nothing, for jobs without a lease.
+    pub(crate) fn none() -> Self {
+        Touch(None)
+    }
+
+    /// Return a `Touch` along with the flag it raises.
+    pub(crate) fn flag() -> (Self, Arc<AtomicBool>) {
+        let flag = Arc::new(AtomicBool::new(false));
+        let raised = Arc::clone(&flag);
+        (
+            Touch::new(move || raised.store(true, Ordering::SeqCst)),
+            flag,
+        )
+    }
+
+    /// Report that the handler is making progress.
+    pub(crate) fn touch(&self) {
+        if let Some(ref touch) = self.0 {
+            touch();
+        }
+    }
+}
+
+impl fmt::Debug for Touch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Touch {{ leased: {:?} }}", self.0.is_some())
+    }
+}
+
+/// Return a `Future` extending the given lease on the given job by `duration`, whenever
+/// `touched` returns `true`.
+///
+/// `touched` is called twice per `duration`, and should return whether the handler touched the
+/// job since it was last called. The returned `Future` never resolves: it is dropped once the
+/// job completed.
+pub(crate) fn keep_alive(
+    id: Uuid,
+    lease: Box<Lease>,
+    duration: Duration,
+    touched: Box<Fn() -> bool + Send + Sync>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let lease: Arc<Lease> = Arc::from(lease);
+    let touched: Arc<Fn() -> bool + Send + Sync> = Arc::from(touched);
+    let interval = duration / 2;
+    let task = future::loop_fn((), move |_| {
+        let lease = Arc::clone(&lease);
+        let touched = Arc::clone(&touched);
+        Delay::new(Instant::now() + interval)
+            .map_err(|e| error!("Couldn't wait before extending a lease: {}", e))
+            .and_then(move |_| -> Box<Future<Item = _, Error = _> + Send> {
+                if !touched() {
+                    return Box::new(future::ok(future::Loop::Continue(())));
+                }
+                trace!("[{}] Extending lease by {:?}", id, duration);
+                let task = lease.extend(duration).then(move |result| {
+                    if let Err(e) = result {
+                        warn!("[{}] Couldn't extend the lease of the job: {}", id, e);
+                    }
+                    Ok(future::Loop::Continue(()))
+                });
+                Box::new(task)
+            })
+    });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use tokio;
+
+    #[derive(Debug, Default)]
+    struct Extended(Mutex<Vec<Duration>>);
+
+    impl Lease for Arc<Extended> {
+        fn extend(&self, duration: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+            self.0.lock().unwrap().push(duration);
+            Box::new(future::ok(()))
+        }
+    }
+
+    #[test]
+    fn extend_touched_leases() {
+        let extensions = Arc::new(Extended::default());
+        let (touch, touched) = Touch::flag();
+        touch.touch();
+        Touch::none().touch();
+        let duration = Duration::from_millis(40);
+        let task = keep_alive(
+            Uuid::new_v4(),
+            Box::new(Arc::clone(&extensions)),
+            duration,
+            Box::new(move || touched.swap(false, Ordering::SeqCst)),
+        );
+        // The lease is extended once, as the job isn't touched again.
+        let task = task.select2(Delay::new(Instant::now() + duration * 2));
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let _ = runtime.block_on(task);
+        assert_eq!(*extensions.0.lock().unwrap(), vec![duration]);
+    }
+}
@@ -4,6 +4,10 @@
 //! to pull it and execute the associated handler. It leverages the `futures` and `tokio-core`
 //! crates to provide asynchronous I/O operations.
 //!
+//! The RabbitMQ broker is enabled by the `rabbitmq` feature, which is enabled by default. Other
+//! brokers implement the `Broker` trait and can be used without it, see `Client::new` and
+//! `WorkerBuilder::broker`.
+//!
 //! # Example
 //!
 //! ```rust
@@ -22,7 +26,7 @@
 //! use futures::Future;
 //!
 //! #[derive(Serialize, Deserialize, Job)]
-//! #[job_routing_key = "hello-world"]
+//! #[job(routing_key = "hello-world")]
 //! struct SayHello {
 //!     to: String,
 //! }
@@ -54,29 +58,69 @@
 #![allow(unknown_lints)]
 
 extern crate amq_protocol;
+#[cfg(feature = "management")]
+extern crate base64;
 extern crate bytes;
 #[cfg(test)]
 extern crate env_logger;
+extern crate chrono;
+extern crate chrono_tz;
+extern crate cron;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate futures;
+extern crate futures_cpupool;
+extern crate hostname;
+#[cfg(any(feature = "metrics", feature = "management"))]
+extern crate hyper;
+#[cfg(feature = "discovery")]
+#[doc(hidden)]
+pub extern crate inventory as __inventory;
+#[cfg(feature = "rabbitmq")]
 extern crate lapin_futures as lapin;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
+#[cfg(feature = "rabbitmq")]
 extern crate native_tls;
 extern crate num_cpus;
+#[cfg(feature = "metrics")]
+#[macro_use]
+extern crate prometheus;
+extern crate rand;
+#[cfg(any(feature = "encryption", feature = "signing"))]
+extern crate ring;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+#[cfg(feature = "sentry")]
+extern crate sentry;
 #[macro_use]
 extern crate serde;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
 extern crate serde_json;
 #[cfg(test)]
 extern crate tokio;
 extern crate tokio_executor;
+#[cfg(feature = "rabbitmq")]
 extern crate tokio_io;
 extern crate tokio_reactor;
+extern crate tokio_signal;
+#[cfg(feature = "rabbitmq")]
 extern crate tokio_tcp;
+extern crate tokio_timer;
+#[cfg(feature = "rabbitmq")]
 extern crate tokio_tls;
+#[cfg(feature = "tracing-spans")]
+extern crate tracing;
+#[cfg(feature = "tracing-spans")]
+extern crate tracing_futures;
 extern crate uuid;
 extern crate wait_timeout;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 #[cfg(feature = "codegen")]
 #[macro_use]
@@ -89,16 +133,120 @@ pub use batch_codegen::*;
 use serde_json::de;
 use serde_json::ser;
 
+#[macro_use]
+mod trace;
+
+mod ack;
+pub mod audit;
+mod backend;
+mod backpressure;
+mod batching;
+mod blob;
+mod broker;
+mod circuit;
 mod client;
+mod codec;
+mod compression;
+mod concurrency;
+mod context;
+mod control;
+mod crash;
+mod dead_letter;
+mod dedup;
+pub mod discovery;
+mod encryption;
+mod enqueue;
 mod error;
+mod extensions;
+mod fair;
+mod handoff;
+mod hook;
+mod idempotency;
+mod inspect;
 mod job;
+mod lease;
+mod logger;
+pub mod memory;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod middleware;
+pub mod monitor;
+mod outbox;
+mod parse;
+mod progress;
 mod query;
+#[cfg(feature = "rabbitmq")]
 mod rabbitmq;
+mod rate_limit;
+#[cfg(feature = "sentry")]
+mod reporting;
+mod reschedule;
+mod retry;
+mod scaling;
+mod scheduled;
+pub mod scheduler;
+mod signing;
+mod status;
+mod tenancy;
+pub mod testing;
+pub mod topology;
+mod weights;
 mod worker;
+pub mod workflow;
 
-pub use client::{Client, ClientBuilder};
+pub use ack::AckMode;
+pub use backend::{Outcome, ResultBackend};
+pub use blob::{BlobStore, Filesystem, BLOB_HEADER};
+pub use broker::{Broker, Deliveries, Delivery, Properties};
+pub use circuit::{CircuitBreaker, CircuitState};
+pub use client::Client;
+#[cfg(feature = "rabbitmq")]
+pub use client::ClientBuilder;
+pub use codec::Codec;
+pub use compression::Compression;
+pub use concurrency::{Semaphore, Semaphores};
+pub use context::{CancellationToken, Context};
+pub use control::{Command, Commands, Control, ControlChannel, QueueControl};
+pub use crash::Panic;
+pub use dead_letter::{DeadJob, DeadLetterConsumer, DeadLetterQueue};
+pub use dedup::{SeenJobs, SeenStore};
+#[cfg(feature = "encryption")]
+pub use encryption::AesGcm;
+pub use encryption::Encryptor;
 pub use error::Error;
-pub use job::{Job, Perform, Priority};
-pub use query::{job, Query};
-pub use rabbitmq::{exchange, queue, Exchange, ExchangeBuilder, Queue, QueueBuilder};
-pub use worker::{Worker, WorkerBuilder};
+pub use extensions::Extensions;
+pub use handoff::Handoff;
+pub use hook::PublishHook;
+pub use idempotency::{Idempotency, IdempotencyStore};
+pub use inspect::{PendingJob, QueueInfo, QueueInspector, QueueStats, Queues};
+pub use job::{Failure, FailureInfo, Job, Perform, PerformBatch, Priority, Status, TypeName};
+#[doc(hidden)]
+pub use job::__job_name;
+pub use lease::Lease;
+pub use logger::{Event, LogLogger, Logger, Record};
+pub use middleware::Middleware;
+pub use outbox::{Outbox, OutboxEntry, Relay};
+pub use parse::ParsePolicy;
+pub use progress::{Progress, ProgressReport};
+pub use query::{job, Query, RawOptions};
+#[cfg(feature = "rabbitmq")]
+pub use rabbitmq::{ConnectionBuilder, Declaration, Failover, Protocol};
+#[cfg(feature = "management")]
+pub use rabbitmq::{ConnectionDetails, Management, QueueDetails};
+pub use rate_limit::{RateLimit, RateLimiter, TokenBuckets};
+#[cfg(feature = "sentry")]
+pub use reporting::Sentry;
+pub use retry::{FailurePolicy, RetryStrategy};
+pub use scaling::{Backlog, Scaler};
+pub use scheduled::{Dispatcher, ScheduleStore, ScheduledJob};
+#[cfg(feature = "signing")]
+pub use signing::Hmac;
+pub use signing::Signer;
+pub use status::{StatusReport, StatusStore};
+pub use tenancy::TENANT_HEADER;
+pub use topology::{
+    exchange, queue, Exchange, ExchangeBuilder, ExchangeKind, ExchangeOptions, Queue, QueueBuilder,
+    QueueOptions,
+};
+pub use trace::{TraceContext, TRACEPARENT};
+pub use worker::{Isolation, Worker, WorkerBuilder};
@@ -0,0 +1,190 @@
+//! Structured records of the life of jobs.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::time::Duration;
+
+use log::Level;
+
+use broker::Properties;
+use job::Failure;
+
+/// An event in the life of a job, see `Record`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The job was published by a `Client`.
+    Published,
+    /// The job was received by a `Worker`.
+    Received,
+    /// The job was executed successfully.
+    Succeeded,
+    /// The execution of the job failed, it will be retried.
+    Retried(Failure),
+    /// The execution of the job failed, and it won't be retried.
+    Failed(Failure),
+    /// The job was revoked, and won't be executed or retried.
+    Revoked,
+    /// The job expired, and won't be executed or retried.
+    Expired,
+    /// The job was already executed successfully, and won't be executed again.
+    Duplicate,
+    /// The job was published again by its handler, see `Context::retry_in`.
+    Rescheduled,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match *self {
+            Event::Published => "published",
+            Event::Received => "received",
+            Event::Succeeded => "succeeded",
+            Event::Retried(_) => "retried",
+            Event::Failed(_) => "failed",
+            Event::Revoked => "revoked",
+            Event::Expired => "expired",
+            Event::Duplicate => "duplicate",
+            Event::Rescheduled => "rescheduled",
+        }
+    }
+}
+
+/// A structured record of an event in the life of a job, handed to a `Logger`.
+#[derive(Clone, Debug)]
+pub struct Record<'a> {
+    /// The event this record describes.
+    pub event: Event,
+    /// The properties of the job.
+    pub properties: &'a Properties,
+    /// The queue the job was consumed from, if known.
+    pub queue: Option<&'a str>,
+    /// How long publishing or executing the job took, if relevant.
+    pub duration: Option<Duration>,
+}
+
+impl<'a> Record<'a> {
+    /// Return the number of the attempt at executing the job this record relates to, starting
+    /// at 1.
+    pub fn attempt(&self) -> u32 {
+        self.properties.retries + 1
+    }
+}
+
+impl<'a> fmt::Display for Record<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "event={} job={} id={}",
+            self.event.name(),
+            self.properties.task,
+            self.properties.id
+        )?;
+        if let Some(queue) = self.queue {
+            write!(f, " queue={}", queue)?;
+        }
+        write!(f, " attempt={}", self.attempt())?;
+        match self.event {
+            Event::Retried(failure) | Event::Failed(failure) => {
+                write!(f, " failure={:?}", failure)?;
+            }
+            _ => (),
+        }
+        if let Some(duration) = self.duration {
+            let millis = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+            write!(f, " duration_ms={}", millis)?;
+        }
+        Ok(())
+    }
+}
+
+/// A sink for the records of the events in the life of jobs.
+///
+/// Loggers are set with `Client::logger` and `WorkerBuilder::logger`, allowing the records to be
+/// forwarded to a structured logging library (e.g: `slog`, `tracing`). The default logger is
+/// `LogLogger`.
+///
+/// # Example
+///
+/// ```
+/// extern crate batch;
+///
+/// use batch::{Event, Logger, Record};
+///
+/// #[derive(Debug)]
+/// struct FailuresOnly;
+///
+/// impl Logger for FailuresOnly {
+///     fn log(&self, record: &Record) {
+///         if let Event::Failed(failure) = record.event {
+///             eprintln!("{} failed: {:?}", record.properties.id, failure);
+///         }
+///     }
+/// }
+/// #
+/// # fn main() {}
+/// ```
+pub trait Logger: fmt::Debug + Send + Sync {
+    /// Handle the given record.
+    fn log(&self, record: &Record);
+}
+
+/// A `Logger` emitting records through the `log` crate, with the `batch` target.
+///
+/// Records are formatted as `key=value` pairs. Failures are logged at the `Error` level, retries
+/// at the `Warn` level, and other events at the `Info` level.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogLogger;
+
+impl Logger for LogLogger {
+    fn log(&self, record: &Record) {
+        let level = match record.event {
+            Event::Failed(_) => Level::Error,
+            Event::Retried(_) => Level::Warn,
+            _ => Level::Info,
+        };
+        log!(target: "batch", level, "{}", record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use uuid::Uuid;
+
+    use codec::Codec;
+    use job::Priority;
+
+    #[test]
+    fn format() {
+        let properties = Properties {
+            id: Uuid::nil(),
+            task: "send-email".into(),
+            exchange: "".into(),
+            routing_key: "emails".into(),
+            priority: Priority::Normal,
+            timeout: None,
+            delay: None,
+            retries: 1,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: None,
+            expires: None,
+            origin: None,
+            codec: Codec::Json,
+            compression: None,
+            version: None,
+            headers: BTreeMap::new(),
+        };
+        let record = Record {
+            event: Event::Retried(Failure::Timeout),
+            properties: &properties,
+            queue: Some("emails"),
+            duration: Some(Duration::from_millis(1500)),
+        };
+        assert_eq!(
+            record.to_string(),
+            "event=retried job=send-email id=00000000-0000-0000-0000-000000000000 \
+             queue=emails attempt=2 failure=Timeout duration_ms=1500"
+        );
+    }
+}
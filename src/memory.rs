@@ -0,0 +1,944 @@
+//! An in-memory message broker.
+//!
+//! This broker is meant to be used in tests: jobs never leave the current process, allowing a
+//! `Client` and a `Worker` to be exercised without a running `RabbitMQ` instance. It honors the
+//! bindings of the declared queues, and the priorities and delays of the published jobs. It also
+//! implements `ResultBackend`, forwarding the outcome of jobs to the `Client` waiting for them,
+//! `StatusStore`, `scheduler::Lock`, `workflow::Barrier`, `monitor::Registry`, `ControlChannel`,
+//! `QueueInspector`, `IdempotencyStore`, `BlobStore` and `ScheduleStore`, and keeps the jobs that
+//! exhausted their retries in dead-letter queues.
+//!
+//! # Example
+//!
+//! ```
+//! use batch::{memory, queue, Client};
+//!
+//! let connection = memory::Connection::new(vec![
+//!     queue("emails").bind("batch.example", "emails"),
+//! ]);
+//! let client = Client::new(connection.clone());
+//! ```
+
+use std::cmp;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::sync::{mpsc, oneshot};
+use futures::task::{self, Task};
+use futures::{future, Async, Future, Poll, Stream};
+use uuid::Uuid;
+
+use backend::{Outcome, ResultBackend};
+use blob::BlobStore;
+use broker::{self, Broker, Deliveries, Properties};
+use control::{Command, Commands, ControlChannel};
+use crash::Panic;
+use dead_letter::{DeadJob, DeadLetterQueue};
+use error::{Error, ErrorKind};
+use idempotency::IdempotencyStore;
+use inspect::{PendingJob, QueueInspector};
+use job::{Failure, Status};
+use monitor::{Heartbeat, Registry};
+use progress::ProgressReport;
+use scheduled::{ScheduleStore, ScheduledJob};
+use scheduler::Lock;
+use status::{StatusReport, StatusStore};
+use topology::{Queue, QueueBuilder};
+use workflow::Barrier;
+
+/// The maximum number of seconds the timer of a `Connection` sleeps for, before checking whether
+/// the connection was dropped.
+const TIMER_INTERVAL: u64 = 1;
+
+/// A job waiting in a queue, ordered by priority then by publication order.
+#[derive(Debug)]
+struct Entry {
+    sequence: u64,
+    payload: Vec<u8>,
+    properties: Properties,
+}
+
+impl cmp::PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.sequence == other.sequence
+    }
+}
+
+impl cmp::Eq for Entry {}
+
+impl cmp::PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for Entry {
+    fn cmp(&self, other: &Entry) -> cmp::Ordering {
+        self.properties
+            .priority
+            .cmp(&other.properties.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// A delayed job, waiting to be pushed to its queue.
+#[derive(Debug)]
+struct Scheduled {
+    due: Instant,
+    queue: String,
+    entry: Entry,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    sequence: u64,
+    queues: HashMap<String, BinaryHeap<Entry>>,
+    scheduled: Vec<Scheduled>,
+    consumers: u64,
+    waiters: HashMap<u64, Task>,
+    timer: bool,
+    outcomes: HashMap<Uuid, oneshot::Sender<Outcome>>,
+    statuses: HashMap<Uuid, StatusReport>,
+    dead: HashMap<String, Vec<DeadJob>>,
+    locks: HashMap<String, Instant>,
+    idempotency: HashMap<String, (Vec<u8>, Instant)>,
+    blobs: HashMap<String, Vec<u8>>,
+    chords: HashMap<Uuid, HashSet<Uuid>>,
+    workers: HashMap<Uuid, Heartbeat>,
+    controls: HashMap<Uuid, mpsc::UnboundedSender<Command>>,
+    timetable: HashMap<Uuid, ScheduledJob>,
+}
+
+impl Inner {
+    /// Register the current task of the given consumer to be notified once a job is pushed to a
+    /// queue, replacing the task it previously registered.
+    fn wait(&mut self, consumer: u64) {
+        self.waiters.insert(consumer, task::current());
+    }
+
+    /// Notify the tasks waiting for a job to be pushed to a queue.
+    fn wake(&mut self) {
+        for (_, waiter) in self.waiters.drain() {
+            waiter.notify();
+        }
+    }
+
+    /// Push the delayed jobs that are due to their queues.
+    ///
+    /// Returns the instant the next delayed job will be due, if any.
+    fn promote(&mut self, now: Instant) -> Option<Instant> {
+        let (due, pending): (Vec<_>, Vec<_>) =
+            self.scheduled.drain(..).partition(|s| s.due <= now);
+        self.scheduled = pending;
+        for scheduled in due {
+            self.queues
+                .entry(scheduled.queue)
+                .or_insert_with(BinaryHeap::new)
+                .push(scheduled.entry);
+        }
+        self.scheduled.iter().map(|s| s.due).min()
+    }
+}
+
+/// A `Broker` implementation storing jobs in memory.
+///
+/// Cloning a `Connection` returns a handle to the same broker.
+#[derive(Clone)]
+pub struct Connection {
+    queues: Arc<Vec<Queue>>,
+    inner: Arc<Mutex<Inner>>,
+    alarm: Arc<Condvar>,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Connection {{ queues: {:?} }}", self.queues)
+    }
+}
+
+impl Connection {
+    /// Create a new in-memory broker with the given queues.
+    pub fn new<QIter>(queues: QIter) -> Self
+    where
+        QIter: IntoIterator<Item = QueueBuilder>,
+    {
+        let queues = queues.into_iter().map(|q| q.build()).collect::<Vec<_>>();
+        let mut inner = Inner::default();
+        for queue in &queues {
+            inner.queues.insert(queue.name().into(), BinaryHeap::new());
+            if queue.dead_letter_queue().is_some() {
+                inner.dead.insert(queue.name().into(), Vec::new());
+            }
+        }
+        Connection {
+            queues: Arc::new(queues),
+            inner: Arc::new(Mutex::new(inner)),
+            alarm: Arc::new(Condvar::new()),
+        }
+    }
+
+    /// Wake up the timer pushing the delayed jobs to their queues, starting it if needed.
+    fn schedule(&self, inner: &mut Inner) {
+        if inner.timer {
+            self.alarm.notify_one();
+            return;
+        }
+        inner.timer = true;
+        let weak = Arc::downgrade(&self.inner);
+        let alarm = Arc::clone(&self.alarm);
+        thread::spawn(move || timer(&weak, &alarm));
+    }
+
+    /// Return the number of jobs waiting in the given queue.
+    pub fn len(&self, queue: &str) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.queues.get(queue).map_or(0, BinaryHeap::len)
+    }
+
+    /// Return whether the given queue has no job waiting in it.
+    pub fn is_empty(&self, queue: &str) -> bool {
+        self.len(queue) == 0
+    }
+
+    /// Return the names of the queues bound to the given exchange and routing key.
+    ///
+    /// Exchanges aren't declared to this broker: bindings are matched as topic patterns, which
+    /// match routing keys without wildcards exactly.
+    fn route(&self, exchange: &str, routing_key: &str) -> Vec<String> {
+        self.queues
+            .iter()
+            .filter(|queue| queue.routes(exchange, "topic", routing_key))
+            .map(|queue| queue.name().to_string())
+            .collect()
+    }
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let destinations = self.route(&properties.exchange, &properties.routing_key);
+        if destinations.is_empty() {
+            warn!(
+                "[{}] No queue bound to exchange {:?} with routing key {:?}, dropping job",
+                properties.id, properties.exchange, properties.routing_key
+            );
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let due = properties.delay.map(|delay| Instant::now() + delay);
+        for destination in destinations {
+            inner.sequence += 1;
+            let entry = Entry {
+                sequence: inner.sequence,
+                payload: payload.to_vec(),
+                properties: properties.clone(),
+            };
+            match due {
+                Some(due) => inner.scheduled.push(Scheduled {
+                    due,
+                    queue: destination,
+                    entry,
+                }),
+                None => inner
+                    .queues
+                    .entry(destination)
+                    .or_insert_with(BinaryHeap::new)
+                    .push(entry),
+            }
+        }
+        if due.is_some() {
+            self.schedule(&mut inner);
+        }
+        inner.wake();
+        Box::new(future::ok(()))
+    }
+
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        self.consume_except(prefetch, &[])
+    }
+
+    fn consume_except(
+        &self,
+        _prefetch: u16,
+        excluded: &[String],
+    ) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let queues = self
+            .queues
+            .iter()
+            .map(|q| q.name().to_string())
+            .filter(|name| !excluded.contains(name))
+            .collect();
+        let consumer = Consumer::new(queues, &self.inner);
+        Box::new(future::ok(Box::new(consumer) as Deliveries))
+    }
+
+    fn dead_letters(&self) -> Option<Arc<DeadLetterQueue>> {
+        Some(Arc::new(self.clone()))
+    }
+
+    fn control(&self) -> Option<Arc<ControlChannel>> {
+        Some(Arc::new(self.clone()))
+    }
+
+    fn inspector(&self) -> Option<Arc<QueueInspector>> {
+        Some(Arc::new(self.clone()))
+    }
+}
+
+impl ResultBackend for Connection {
+    fn subscribe(
+        &self,
+        properties: &mut Properties,
+    ) -> Box<Future<Item = Outcome, Error = Error> + Send> {
+        let (sender, receiver) = oneshot::channel();
+        properties.reply_to = Some("memory".into());
+        let mut inner = self.inner.lock().unwrap();
+        inner.outcomes.insert(properties.id, sender);
+        Box::new(receiver.map_err(|_| ErrorKind::ResultUnavailable.into()))
+    }
+
+    fn store(
+        &self,
+        properties: &Properties,
+        outcome: &Outcome,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(sender) = inner.outcomes.remove(&properties.id) {
+            let _ = sender.send(outcome.clone());
+        }
+        Box::new(future::ok(()))
+    }
+}
+
+impl StatusStore for Connection {
+    fn update(
+        &self,
+        id: Uuid,
+        status: Status,
+        at: DateTime<Utc>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .statuses
+            .entry(id)
+            .or_insert_with(|| StatusReport::new(id))
+            .apply(status, at);
+        Box::new(future::ok(()))
+    }
+
+    fn fetch(&self, id: Uuid) -> Box<Future<Item = Option<StatusReport>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        Box::new(future::ok(inner.statuses.get(&id).cloned()))
+    }
+
+    fn progress(
+        &self,
+        id: Uuid,
+        progress: &ProgressReport,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .statuses
+            .entry(id)
+            .or_insert_with(|| StatusReport::new(id))
+            .progress = Some(progress.clone());
+        Box::new(future::ok(()))
+    }
+
+    fn panicked(&self, id: Uuid, panic: &Panic) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .statuses
+            .entry(id)
+            .or_insert_with(|| StatusReport::new(id))
+            .panic = Some(panic.clone());
+        Box::new(future::ok(()))
+    }
+}
+
+impl DeadLetterQueue for Connection {
+    fn list(&self, queue: &str) -> Box<Future<Item = Vec<DeadJob>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        Box::new(future::ok(inner.dead.get(queue).cloned().unwrap_or_default()))
+    }
+
+    fn requeue(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let job = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.dead.get_mut(queue).and_then(|jobs| {
+                jobs.iter()
+                    .position(|job| job.properties.id == id)
+                    .map(|index| jobs.remove(index))
+            })
+        };
+        match job {
+            Some(job) => Box::new(
+                self.publish(&job.payload, &job.requeue_properties())
+                    .map(|_| true),
+            ),
+            None => Box::new(future::ok(false)),
+        }
+    }
+
+    fn remove(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        let removed = inner.dead.get_mut(queue).map_or(false, |jobs| {
+            let before = jobs.len();
+            jobs.retain(|job| job.properties.id != id);
+            jobs.len() != before
+        });
+        Box::new(future::ok(removed))
+    }
+
+    fn purge(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(jobs) = inner.dead.get_mut(queue) {
+            jobs.clear();
+        }
+        Box::new(future::ok(()))
+    }
+}
+
+impl QueueInspector for Connection {
+    fn queues(&self) -> Vec<String> {
+        self.queues.iter().map(|q| q.name().to_string()).collect()
+    }
+
+    fn pending(&self, queue: &str) -> Box<Future<Item = u64, Error = Error> + Send> {
+        Box::new(future::ok(self.len(queue) as u64))
+    }
+
+    fn is_prioritized(&self, queue: &str) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let prioritized = self
+            .queues
+            .iter()
+            .any(|q| q.name() == queue && q.is_prioritized());
+        Box::new(future::ok(prioritized))
+    }
+
+    fn peek(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Box<Future<Item = Vec<PendingJob>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries = inner
+            .queues
+            .get(queue)
+            .map_or_else(Vec::new, |entries| entries.iter().collect());
+        // Entries are popped from the heap greatest first.
+        entries.sort_by(|a, b| b.cmp(a));
+        let jobs = entries
+            .into_iter()
+            .take(count)
+            .map(|entry| PendingJob {
+                properties: entry.properties.clone(),
+                payload: entry.payload.clone(),
+            })
+            .collect();
+        Box::new(future::ok(jobs))
+    }
+}
+
+impl Lock for Connection {
+    fn acquire(
+        &self,
+        key: &str,
+        duration: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let acquired = inner.locks.get(key).map_or(true, |expiry| *expiry <= now);
+        if acquired {
+            inner.locks.insert(key.to_string(), now + duration);
+        }
+        Box::new(future::ok(acquired))
+    }
+}
+
+impl IdempotencyStore for Connection {
+    fn get(&self, key: &str) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+        let now = Instant::now();
+        let inner = self.inner.lock().unwrap();
+        let outcome = inner
+            .idempotency
+            .get(key)
+            .and_then(|&(ref outcome, expiry)| {
+                if expiry > now {
+                    Some(outcome.clone())
+                } else {
+                    None
+                }
+            });
+        Box::new(future::ok(outcome))
+    }
+
+    fn set(
+        &self,
+        key: &str,
+        outcome: &[u8],
+        ttl: Duration,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .idempotency
+            .insert(key.to_string(), (outcome.to_vec(), Instant::now() + ttl));
+        Box::new(future::ok(()))
+    }
+}
+
+impl BlobStore for Connection {
+    fn put(&self, key: &str, payload: Vec<u8>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.blobs.insert(key.to_string(), payload);
+        Box::new(future::ok(()))
+    }
+
+    fn get(&self, key: &str) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        let task = match inner.blobs.get(key) {
+            Some(payload) => future::ok(payload.clone()),
+            None => future::err(Error::blob(format_err!("No payload stored under {}", key))),
+        };
+        Box::new(task)
+    }
+
+    fn delete(&self, key: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.blobs.remove(key);
+        Box::new(future::ok(()))
+    }
+}
+
+impl ScheduleStore for Connection {
+    fn schedule(&self, job: &ScheduledJob) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.timetable.insert(job.properties.id, job.clone());
+        Box::new(future::ok(()))
+    }
+
+    fn pending(&self) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        let mut jobs = inner.timetable.values().cloned().collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.due);
+        Box::new(future::ok(jobs))
+    }
+
+    fn due(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        let mut jobs = inner
+            .timetable
+            .values()
+            .filter(|job| job.due <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.due);
+        jobs.truncate(limit);
+        Box::new(future::ok(jobs))
+    }
+
+    fn remove(&self, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        Box::new(future::ok(inner.timetable.remove(&id).is_some()))
+    }
+}
+
+impl Barrier for Connection {
+    fn arrive(
+        &self,
+        chord: Uuid,
+        member: Uuid,
+    ) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        let members = inner.chords.entry(chord).or_insert_with(HashSet::new);
+        members.insert(member);
+        Box::new(future::ok(members.len()))
+    }
+}
+
+impl ControlChannel for Connection {
+    fn send(
+        &self,
+        worker: Option<Uuid>,
+        command: Command,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.controls.retain(|id, sender| {
+            if worker.map_or(false, |worker| worker != *id) {
+                return true;
+            }
+            // Forget the workers that stopped listening.
+            sender.unbounded_send(command.clone()).is_ok()
+        });
+        Box::new(future::ok(()))
+    }
+
+    fn listen(&self, worker: Uuid) -> Box<Future<Item = Commands, Error = Error> + Send> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.lock().unwrap().controls.insert(worker, sender);
+        let commands = receiver.map_err(|_| unreachable!("receivers never fail"));
+        Box::new(future::ok(Box::new(commands) as Commands))
+    }
+}
+
+impl Registry for Connection {
+    fn beat(&self, heartbeat: &Heartbeat) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.workers.insert(heartbeat.worker, heartbeat.clone());
+        Box::new(future::ok(()))
+    }
+
+    fn heartbeats(&self) -> Box<Future<Item = Vec<Heartbeat>, Error = Error> + Send> {
+        let inner = self.inner.lock().unwrap();
+        Box::new(future::ok(inner.workers.values().cloned().collect()))
+    }
+
+    fn forget(&self, worker: Uuid) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.workers.remove(&worker);
+        Box::new(future::ok(()))
+    }
+}
+
+/// Push the delayed jobs of the given broker to their queues once they are due, waking up the
+/// waiting consumers, until the broker is dropped.
+///
+/// A single timer runs per broker, sleeping until the earliest delayed job is due or until it is
+/// woken up by the publication of another one.
+fn timer(inner: &Weak<Mutex<Inner>>, alarm: &Condvar) {
+    while let Some(inner) = inner.upgrade() {
+        let mut guard = inner.lock().unwrap();
+        let now = Instant::now();
+        let scheduled = guard.scheduled.len();
+        let next_due = guard.promote(now);
+        if guard.scheduled.len() < scheduled {
+            guard.wake();
+        }
+        let interval = Duration::from_secs(TIMER_INTERVAL);
+        let timeout = next_due.map_or(interval, |due| cmp::min(due - now, interval));
+        drop(alarm.wait_timeout(guard, timeout).unwrap());
+    }
+}
+
+/// A stream of the jobs published to a `Connection`.
+struct Consumer {
+    id: u64,
+    queues: Vec<String>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Consumer {
+    /// Create a new stream of the jobs pushed to the given queues of the given broker.
+    fn new(queues: Vec<String>, inner: &Arc<Mutex<Inner>>) -> Self {
+        let id = {
+            let mut inner = inner.lock().unwrap();
+            inner.consumers += 1;
+            inner.consumers
+        };
+        Consumer {
+            id,
+            queues,
+            inner: Arc::clone(inner),
+        }
+    }
+}
+
+impl Stream for Consumer {
+    type Item = Box<broker::Delivery>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.promote(Instant::now());
+        let next = {
+            let candidates = inner
+                .queues
+                .iter()
+                .filter(|&(name, _)| self.queues.contains(name))
+                .filter_map(|(name, heap)| heap.peek().map(|entry| (name, entry)));
+            candidates.max_by(|a, b| a.1.cmp(b.1)).map(|(name, _)| name.clone())
+        };
+        let entry = next.and_then(|name| {
+            inner
+                .queues
+                .get_mut(&name)
+                .and_then(BinaryHeap::pop)
+                .map(|entry| (name, entry))
+        });
+        match entry {
+            Some((queue, entry)) => Ok(Async::Ready(Some(Box::new(Delivery {
+                queue,
+                payload: entry.payload,
+                properties: entry.properties,
+                inner: Arc::clone(&self.inner),
+            })))),
+            None => {
+                // The timer of the connection wakes the consumer up once a delayed job is due.
+                inner.wait(self.id);
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// A job received from a `Connection`.
+struct Delivery {
+    queue: String,
+    payload: Vec<u8>,
+    properties: Properties,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ queue: {:?} properties: {:?} }}",
+            self.queue, self.properties
+        )
+    }
+}
+
+impl broker::Delivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    fn queue(&self) -> Option<&str> {
+        Some(&self.queue)
+    }
+
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    fn requeue(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let this = *self;
+        let mut inner = this.inner.lock().unwrap();
+        inner.sequence += 1;
+        let entry = Entry {
+            sequence: inner.sequence,
+            payload: this.payload,
+            properties: this.properties,
+        };
+        inner
+            .queues
+            .entry(this.queue)
+            .or_insert_with(BinaryHeap::new)
+            .push(entry);
+        inner.wake();
+        Box::new(future::ok(()))
+    }
+
+    fn dead_letter(
+        self: Box<Self>,
+        failure: Failure,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let this = *self;
+        let mut inner = this.inner.lock().unwrap();
+        if let Some(jobs) = inner.dead.get_mut(&this.queue) {
+            jobs.push(DeadJob {
+                queue: this.queue.clone(),
+                properties: this.properties,
+                payload: this.payload,
+                failure,
+                died_at: Utc::now(),
+            });
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn dead_letter_with_headers(
+        mut self: Box<Self>,
+        failure: Failure,
+        headers: BTreeMap<String, String>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.properties.headers.extend(headers);
+        self.dead_letter(failure)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use codec::Codec;
+    use job::Priority;
+    use std::collections::BTreeMap;
+    use topology::queue;
+    use uuid::Uuid;
+
+    pub(crate) fn properties(task: &str, routing_key: &str, priority: Priority) -> Properties {
+        Properties {
+            id: Uuid::new_v4(),
+            task: task.into(),
+            exchange: "batch.tests".into(),
+            routing_key: routing_key.into(),
+            priority,
+            timeout: None,
+            delay: None,
+            retries: 0,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: None,
+            expires: None,
+            origin: None,
+            codec: Codec::Json,
+            compression: None,
+            version: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn routing_and_priorities() {
+        let connection = Connection::new(vec![
+            queue("tests.memory").bind("batch.tests", "memory"),
+            queue("tests.other").bind("batch.tests", "other"),
+        ]);
+        let jobs = vec![
+            ("job-1", "memory", Priority::Normal),
+            ("job-2", "memory", Priority::Critical),
+            ("job-3", "other", Priority::Critical),
+            ("job-4", "memory", Priority::Normal),
+            ("job-5", "memory", Priority::Trivial),
+        ];
+        for (task, routing_key, priority) in jobs {
+            connection
+                .publish(b"{}", &properties(task, routing_key, priority))
+                .wait()
+                .unwrap();
+        }
+        assert_eq!(connection.len("tests.memory"), 4);
+        assert_eq!(connection.len("tests.other"), 1);
+
+        let consumer = Consumer::new(vec!["tests.memory".into()], &connection.inner);
+        let order = consumer
+            .wait()
+            .take(4)
+            .map(|delivery| delivery.unwrap().properties().task.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec!["job-2", "job-1", "job-4", "job-5"]);
+        assert!(connection.is_empty("tests.memory"));
+    }
+
+    #[test]
+    fn barrier() {
+        let connection = Connection::new(vec![queue("tests.barrier")]);
+        let chord = Uuid::new_v4();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(connection.arrive(chord, first).wait().unwrap(), 1);
+        assert_eq!(connection.arrive(chord, first).wait().unwrap(), 1);
+        assert_eq!(connection.arrive(chord, second).wait().unwrap(), 2);
+        assert_eq!(connection.arrive(Uuid::new_v4(), first).wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn results() {
+        let connection = Connection::new(vec![queue("tests.results")]);
+        let mut properties = properties("job", "tests.results", Priority::Normal);
+        let outcome = connection.subscribe(&mut properties);
+        assert!(properties.reply_to.is_some());
+        connection
+            .store(&properties, &Outcome::Success(b"42".to_vec()))
+            .wait()
+            .unwrap();
+        assert_eq!(outcome.wait().unwrap(), Outcome::Success(b"42".to_vec()));
+    }
+
+    #[test]
+    fn dead_letters() {
+        let connection = Connection::new(vec![queue("tests.dead").bind("batch.tests", "dead")]);
+        let mut properties = properties("job", "dead", Priority::Normal);
+        properties.retries = 3;
+        connection.publish(b"{}", &properties).wait().unwrap();
+
+        let consumer = Consumer::new(vec!["tests.dead".into()], &connection.inner);
+        let delivery = consumer.wait().next().unwrap().unwrap();
+        delivery.dead_letter(Failure::Timeout).wait().unwrap();
+        assert!(connection.is_empty("tests.dead"));
+
+        let dead = connection.list("tests.dead").wait().unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].failure, Failure::Timeout);
+        assert_eq!(dead[0].properties, properties);
+        assert!(connection.requeue("tests.dead", properties.id).wait().unwrap());
+        assert!(connection.list("tests.dead").wait().unwrap().is_empty());
+        assert_eq!(connection.len("tests.dead"), 1);
+
+        let delivery = consumer.wait().next().unwrap().unwrap();
+        delivery.dead_letter(Failure::Error).wait().unwrap();
+        let id = properties.id;
+        assert!(connection.remove("tests.dead", id).wait().unwrap());
+        assert!(!connection.remove("tests.dead", id).wait().unwrap());
+        assert!(connection.is_empty("tests.dead"));
+    }
+
+    #[test]
+    fn delayed_jobs() {
+        let connection = Connection::new(vec![queue("tests.delayed").bind("batch.tests", "delayed")]);
+        let mut properties = properties("job", "delayed", Priority::Normal);
+        properties.delay = Some(Duration::from_millis(50));
+        for _ in 0..3 {
+            connection.publish(b"{}", &properties).wait().unwrap();
+        }
+        assert!(connection.is_empty("tests.delayed"));
+
+        let consumer = Consumer::new(vec!["tests.delayed".into()], &connection.inner);
+        let mut deliveries = consumer.wait();
+        for _ in 0..3 {
+            deliveries.next().unwrap().unwrap();
+        }
+        // A single timer runs, and each consumer waits once, however often it is polled.
+        let inner = connection.inner.lock().unwrap();
+        assert!(inner.timer);
+        assert!(inner.waiters.len() <= 1);
+    }
+
+    #[test]
+    fn requeue_delivery() {
+        let connection = Connection::new(vec![queue("tests.requeue").bind("batch.tests", "requeue")]);
+        let properties = properties("job", "requeue", Priority::Normal);
+        connection.publish(b"{}", &properties).wait().unwrap();
+
+        let consumer = Consumer::new(vec!["tests.requeue".into()], &connection.inner);
+        let mut deliveries = consumer.wait();
+        let delivery = deliveries.next().unwrap().unwrap();
+        assert!(connection.is_empty("tests.requeue"));
+        delivery.requeue().wait().unwrap();
+        assert_eq!(connection.len("tests.requeue"), 1);
+
+        let delivery = deliveries.next().unwrap().unwrap();
+        assert_eq!(delivery.properties(), &properties);
+        assert_eq!(delivery.payload(), b"{}");
+    }
+
+    #[test]
+    fn locks() {
+        let connection = Connection::new(vec![queue("tests.locks")]);
+        let minute = Duration::from_secs(60);
+        assert!(connection.acquire("unique", minute).wait().unwrap());
+        assert!(!connection.acquire("unique", minute).wait().unwrap());
+        let expired = Duration::from_secs(0);
+        assert!(connection.acquire("expired", expired).wait().unwrap());
+        assert!(connection.acquire("expired", expired).wait().unwrap());
+    }
+}
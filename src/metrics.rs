@@ -0,0 +1,170 @@
+//! Prometheus metrics of the client and the worker.
+//!
+//! Metrics are recorded in the default Prometheus registry, and can be exposed by the worker
+//! (see `WorkerBuilder::metrics_addr`) or retrieved with `encode` to be served by the
+//! application. The following metrics are available:
+//!
+//! * `batch_jobs_consumed_total`: the number of jobs consumed by the worker, labelled by job
+//! and routing key.
+//! * `batch_jobs_succeeded_total`: the number of jobs executed successfully, labelled by job.
+//! * `batch_jobs_failed_total`: the number of failed executions of jobs, labelled by job and
+//! reason of failure (`error`, `timeout` or `crash`).
+//! * `batch_job_duration_seconds`: the duration of the execution of jobs, labelled by job.
+//! * `batch_publish_duration_seconds`: the time taken by the client to publish jobs, labelled by
+//! job and routing key.
+//! * `batch_queue_messages`: the number of jobs waiting in the queues the worker consumes from,
+//! labelled by queue. Only recorded when the worker watches its queues (see
+//! `WorkerBuilder::queue_stats_interval`).
+//! * `batch_queue_consumers`: the number of consumers of the queues the worker consumes from,
+//! labelled by queue, when the broker can count them.
+//! * `batch_worker_concurrency`: the number of jobs the worker executes at the same time, when
+//! it's scaled (see `WorkerBuilder::autoscale`).
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{future, Future};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn_ok;
+use hyper::{Body, Response, Server};
+use prometheus::{self, CounterVec, Encoder, Gauge, GaugeVec, HistogramVec, TextEncoder};
+
+use broker::Properties;
+use inspect::QueueStats;
+use job::{Failure, Status};
+
+lazy_static! {
+    static ref CONSUMED: CounterVec = register_counter_vec!(
+        "batch_jobs_consumed_total",
+        "Number of jobs consumed by the worker.",
+        &["job", "routing_key"]
+    ).expect("couldn't register batch_jobs_consumed_total");
+    static ref SUCCEEDED: CounterVec = register_counter_vec!(
+        "batch_jobs_succeeded_total",
+        "Number of jobs executed successfully.",
+        &["job"]
+    ).expect("couldn't register batch_jobs_succeeded_total");
+    static ref FAILED: CounterVec = register_counter_vec!(
+        "batch_jobs_failed_total",
+        "Number of failed executions of jobs.",
+        &["job", "failure"]
+    ).expect("couldn't register batch_jobs_failed_total");
+    static ref DURATION: HistogramVec = register_histogram_vec!(
+        "batch_job_duration_seconds",
+        "Duration of the execution of jobs.",
+        &["job"]
+    ).expect("couldn't register batch_job_duration_seconds");
+    static ref PUBLISH_DURATION: HistogramVec = register_histogram_vec!(
+        "batch_publish_duration_seconds",
+        "Time taken to publish jobs.",
+        &["job", "routing_key"]
+    ).expect("couldn't register batch_publish_duration_seconds");
+    static ref QUEUE_MESSAGES: GaugeVec = register_gauge_vec!(
+        "batch_queue_messages",
+        "Number of jobs waiting in the queue.",
+        &["queue"]
+    ).expect("couldn't register batch_queue_messages");
+    static ref QUEUE_CONSUMERS: GaugeVec = register_gauge_vec!(
+        "batch_queue_consumers",
+        "Number of consumers of the queue.",
+        &["queue"]
+    ).expect("couldn't register batch_queue_consumers");
+    static ref CONCURRENCY: Gauge = register_gauge!(
+        "batch_worker_concurrency",
+        "Number of jobs the worker executes at the same time."
+    ).expect("couldn't register batch_worker_concurrency");
+}
+
+/// Encode the metrics of the default Prometheus registry in the text exposition format.
+pub fn encode() -> Vec<u8> {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        warn!("Couldn't encode metrics: {}", e);
+    }
+    buffer
+}
+
+/// Record that the given job was consumed by the worker.
+pub(crate) fn consumed(properties: &Properties) {
+    CONSUMED
+        .with_label_values(&[&properties.task, &properties.routing_key])
+        .inc();
+}
+
+/// Record the outcome of the execution of the given job, which took the given duration.
+pub(crate) fn executed(properties: &Properties, status: &Status, duration: Duration) {
+    match *status {
+        Status::Success => SUCCEEDED.with_label_values(&[&properties.task]).inc(),
+        Status::Failed(failure) => {
+            let failure = match failure {
+                Failure::Error => "error",
+                Failure::Timeout => "timeout",
+                Failure::Crash => "crash",
+                Failure::Expired => "expired",
+                Failure::InvalidSignature => "invalid_signature",
+            };
+            FAILED
+                .with_label_values(&[&properties.task, failure])
+                .inc()
+        }
+        _ => return,
+    }
+    DURATION
+        .with_label_values(&[&properties.task])
+        .observe(seconds(duration));
+}
+
+/// Record that the given job was published in the given duration.
+pub(crate) fn published(properties: &Properties, duration: Duration) {
+    PUBLISH_DURATION
+        .with_label_values(&[&properties.task, &properties.routing_key])
+        .observe(seconds(duration));
+}
+
+/// Record the given statistics of a queue.
+pub(crate) fn queue_stats(stats: &QueueStats) {
+    QUEUE_MESSAGES
+        .with_label_values(&[&stats.name])
+        .set(stats.messages as f64);
+    if let Some(consumers) = stats.consumers {
+        QUEUE_CONSUMERS
+            .with_label_values(&[&stats.name])
+            .set(f64::from(consumers));
+    }
+}
+
+/// Record the number of jobs the worker executes at the same time.
+pub(crate) fn concurrency(concurrency: u16) {
+    CONCURRENCY.set(f64::from(concurrency));
+}
+
+/// Return a `Future` serving the metrics over HTTP on the given address.
+///
+/// The returned `Future` never resolves, errors are logged.
+pub(crate) fn serve(addr: &SocketAddr) -> Box<Future<Item = (), Error = ()> + Send> {
+    let builder = match Server::try_bind(addr) {
+        Ok(builder) => builder,
+        Err(e) => {
+            error!("Couldn't serve metrics on {}: {}", addr, e);
+            return Box::new(future::empty());
+        }
+    };
+    info!("Serving metrics on http://{}/metrics", addr);
+    let server = builder
+        .serve(|| {
+            service_fn_ok(|_| {
+                let mut response = Response::new(Body::from(encode()));
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static(prometheus::TEXT_FORMAT));
+                response
+            })
+        })
+        .map_err(|e| error!("Couldn't serve metrics: {}", e))
+        .then(|_| future::empty());
+    Box::new(server)
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
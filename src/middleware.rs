@@ -0,0 +1,171 @@
+//! Hooks wrapping the execution of jobs by the worker.
+
+use std::result::Result as StdResult;
+
+use failure;
+
+use broker::Properties;
+
+/// A hook wrapping the execution of each job by the `Worker`.
+///
+/// Middlewares are registered with `WorkerBuilder::middleware`, and run in the process executing
+/// the job, around its handler. The `before` hooks are called in registration order, the `after`
+/// and `on_error` hooks in reverse order, so that each middleware wraps the ones registered after
+/// it. All hooks have a default implementation doing nothing.
+///
+/// # Example
+///
+/// ```
+/// extern crate batch;
+/// extern crate failure;
+///
+/// use batch::{Middleware, Properties};
+///
+/// struct Logger;
+///
+/// impl Middleware for Logger {
+///     fn before(&self, properties: &Properties) -> Result<(), failure::Error> {
+///         println!("[{}] Starting {}", properties.id, properties.task);
+///         Ok(())
+///     }
+///
+///     fn on_error(&self, properties: &Properties, error: &failure::Error) {
+///         println!("[{}] {} failed: {}", properties.id, properties.task, error);
+///     }
+/// }
+/// #
+/// # fn main() {}
+/// ```
+pub trait Middleware: Send + Sync {
+    /// Called before the job's handler is executed.
+    ///
+    /// Returning an error fails the job without executing its handler, the `on_error` hooks of
+    /// the middlewares whose `before` hook already ran are then called.
+    fn before(&self, properties: &Properties) -> StdResult<(), failure::Error> {
+        let _ = properties;
+        Ok(())
+    }
+
+    /// Called after the job's handler completed successfully.
+    ///
+    /// Returning an error fails the job, the `on_error` hooks of the outer middlewares are then
+    /// called.
+    fn after(&self, properties: &Properties) -> StdResult<(), failure::Error> {
+        let _ = properties;
+        Ok(())
+    }
+
+    /// Called after the job failed, either in its handler or in an inner middleware.
+    ///
+    /// If the handler panicked, the error can be downcast to a `Panic`.
+    fn on_error(&self, properties: &Properties, error: &failure::Error) {
+        let _ = (properties, error);
+    }
+
+    /// Called after the job failed, like `on_error`, along with its serialized payload (once
+    /// decrypted and decompressed).
+    ///
+    /// The default implementation ignores the payload and calls `on_error`.
+    fn on_error_with_payload(
+        &self,
+        properties: &Properties,
+        payload: &[u8],
+        error: &failure::Error,
+    ) {
+        let _ = payload;
+        self.on_error(properties, error)
+    }
+}
+
+/// Run the `after` or `on_error` hooks of the given middlewares, innermost first, depending on
+/// the given result.
+///
+/// Returns the final result of the job.
+pub(crate) fn unwind(
+    middlewares: &[Box<Middleware>],
+    properties: &Properties,
+    payload: &[u8],
+    result: StdResult<(), failure::Error>,
+) -> StdResult<(), failure::Error> {
+    middlewares
+        .iter()
+        .rev()
+        .fold(result, |result, middleware| match result {
+            Ok(()) => middleware.after(properties),
+            Err(e) => {
+                middleware.on_error_with_payload(properties, payload, &e);
+                Err(e)
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    use codec::Codec;
+    use job::Priority;
+
+    struct Recorder {
+        name: &'static str,
+        fail_after: bool,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn after(&self, _properties: &Properties) -> StdResult<(), failure::Error> {
+            self.calls.lock().unwrap().push(format!("after {}", self.name));
+            if self.fail_after {
+                Err(format_err!("{} failed", self.name))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn on_error(&self, _properties: &Properties, _error: &failure::Error) {
+            self.calls.lock().unwrap().push(format!("error {}", self.name));
+        }
+    }
+
+    #[test]
+    fn unwind_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let middlewares: Vec<Box<Middleware>> = ["outer", "middle", "inner"]
+            .iter()
+            .map(|name| -> Box<Middleware> {
+                Box::new(Recorder {
+                    name: *name,
+                    fail_after: *name == "middle",
+                    calls: Arc::clone(&calls),
+                })
+            })
+            .collect();
+        let properties = Properties {
+            id: Uuid::new_v4(),
+            task: "job".into(),
+            exchange: "".into(),
+            routing_key: "".into(),
+            priority: Priority::Normal,
+            timeout: None,
+            delay: None,
+            retries: 0,
+            max_retries: None,
+            reply_to: None,
+            enqueued_at: None,
+            expires: None,
+            origin: None,
+            codec: Codec::Json,
+            compression: None,
+            version: None,
+            headers: BTreeMap::new(),
+        };
+        assert!(unwind(&middlewares, &properties, b"{}", Ok(())).is_err());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["after inner", "after middle", "error outer"]
+        );
+    }
+}
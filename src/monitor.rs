@@ -0,0 +1,255 @@
+//! Monitoring of the workers consuming jobs.
+//!
+//! When enabled with `WorkerBuilder::heartbeats`, each worker periodically publishes a
+//! `Heartbeat` describing itself: the host and process it runs in, the queues it consumes from,
+//! its concurrency, and the jobs it's currently processing. By default, heartbeats are published
+//! to the `batch.heartbeats` fanout exchange of `RabbitMQ`, another `Registry` can be given to
+//! the worker using `WorkerBuilder::registry`.
+//!
+//! `Workers` reads the heartbeats back, to list the workers that are alive and detect the ones
+//! that died: a worker is considered dead once it missed 3 heartbeats in a row.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate batch;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use batch::monitor::Workers;
+//! use batch::ConnectionBuilder;
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let connection = ConnectionBuilder::new("amqp://localhost/%2f");
+//!     let task = Workers::connect(&connection)
+//!         .and_then(|workers| workers.dead())
+//!         .map(|dead| {
+//!             for heartbeat in dead {
+//!                 println!("Worker {} on {:?} died", heartbeat.worker, heartbeat.hostname);
+//!             }
+//!         })
+//!         .map_err(|e| eprintln!("An error occured: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{self, DateTime, Utc};
+use futures::{future, Future};
+use hostname;
+#[cfg(feature = "rabbitmq")]
+use tokio_reactor::Handle;
+use tokio_timer::Delay;
+use uuid::Uuid;
+
+use error::Error;
+#[cfg(feature = "rabbitmq")]
+use rabbitmq::{self, ConnectionBuilder};
+
+/// The number of heartbeats a worker can miss before being considered dead.
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// The description of a worker, published periodically while it's running.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// The unique identifier of the worker, generated when it starts.
+    pub worker: Uuid,
+    /// The name of the host the worker runs on, if it could be determined.
+    pub hostname: Option<String>,
+    /// The ID of the worker's process.
+    pub pid: u32,
+    /// The names of the queues the worker consumes from.
+    pub queues: Vec<String>,
+    /// The maximum number of jobs the worker executes at the same time.
+    pub concurrency: u16,
+    /// The IDs of the jobs the worker is processing.
+    pub running: Vec<Uuid>,
+    /// The interval between two heartbeats of the worker.
+    pub interval: Duration,
+    /// When the worker started.
+    pub started_at: DateTime<Utc>,
+    /// When this heartbeat was sent.
+    pub sent_at: DateTime<Utc>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(queues: Vec<String>, concurrency: u16, interval: Duration) -> Self {
+        let now = Utc::now();
+        Heartbeat {
+            worker: Uuid::new_v4(),
+            hostname: hostname::get_hostname(),
+            pid: process::id(),
+            queues,
+            concurrency,
+            running: Vec::new(),
+            interval,
+            started_at: now,
+            sent_at: now,
+        }
+    }
+
+    /// Return whether the worker that sent this heartbeat is still alive.
+    ///
+    /// A worker is considered dead when it didn't send a heartbeat for 3 times its interval.
+    pub fn is_alive(&self) -> bool {
+        self.is_alive_at(Utc::now())
+    }
+
+    fn is_alive_at(&self, now: DateTime<Utc>) -> bool {
+        let timeout = match chrono::Duration::from_std(self.interval * MISSED_HEARTBEATS) {
+            Ok(timeout) => timeout,
+            Err(_) => return true,
+        };
+        now.signed_duration_since(self.sent_at) <= timeout
+    }
+}
+
+/// A registry the heartbeats of workers are published to.
+///
+/// The registry keeps the last heartbeat received from each worker, until it is forgotten. This
+/// trait is implemented for `RabbitMQ`, broadcasting heartbeats through the `batch.heartbeats`
+/// exchange, and by `memory::Connection`.
+pub trait Registry: fmt::Debug + Send + Sync {
+    /// Record the given heartbeat, replacing the previous heartbeat of its worker.
+    fn beat(&self, heartbeat: &Heartbeat) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Return the last heartbeat received from each worker.
+    fn heartbeats(&self) -> Box<Future<Item = Vec<Heartbeat>, Error = Error> + Send>;
+
+    /// Forget the heartbeats of the given worker.
+    fn forget(&self, worker: Uuid) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// A handle listing the workers known to a `Registry`.
+#[derive(Clone, Debug)]
+pub struct Workers {
+    registry: Arc<Registry>,
+}
+
+impl Workers {
+    /// Create a new `Workers` reading the heartbeats from the given registry.
+    pub fn new<R>(registry: R) -> Self
+    where
+        R: Registry + 'static,
+    {
+        Workers {
+            registry: Arc::new(registry),
+        }
+    }
+
+    /// Connect to `RabbitMQ` and listen to the heartbeats published to the `batch.heartbeats`
+    /// exchange.
+    ///
+    /// Only the heartbeats sent after the connection was established are known: workers are
+    /// listed once they sent their next heartbeat. This function requires a Tokio reactor to be
+    /// available, which is the case when running on the default runtime. Requires the `rabbitmq`
+    /// feature.
+    #[cfg(feature = "rabbitmq")]
+    pub fn connect(
+        connection: &ConnectionBuilder,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let task = rabbitmq::WorkerRegistry::new_with_handle(connection, Handle::current(), true)
+            .map(Workers::new);
+        Box::new(task)
+    }
+
+    /// Return the last heartbeat of each worker that is alive.
+    pub fn list(&self) -> Box<Future<Item = Vec<Heartbeat>, Error = Error> + Send> {
+        self.select(true)
+    }
+
+    /// Return the last heartbeat of each worker that is considered dead.
+    pub fn dead(&self) -> Box<Future<Item = Vec<Heartbeat>, Error = Error> + Send> {
+        self.select(false)
+    }
+
+    /// Forget the given worker, e.g: once it was reported dead.
+    pub fn forget(&self, worker: Uuid) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.registry.forget(worker)
+    }
+
+    fn select(&self, alive: bool) -> Box<Future<Item = Vec<Heartbeat>, Error = Error> + Send> {
+        let task = self.registry.heartbeats().map(move |heartbeats| {
+            let now = Utc::now();
+            let mut heartbeats = heartbeats
+                .into_iter()
+                .filter(|heartbeat| heartbeat.is_alive_at(now) == alive)
+                .collect::<Vec<_>>();
+            heartbeats.sort_by(|a, b| {
+                (&a.hostname, a.pid, a.started_at).cmp(&(&b.hostname, b.pid, b.started_at))
+            });
+            heartbeats
+        });
+        Box::new(task)
+    }
+}
+
+/// Publish the given worker's heartbeat to the given registry, every `interval` of the heartbeat.
+///
+/// The returned `Future` never resolves: it is dropped once the worker stopped. Failing to
+/// publish a heartbeat is logged, and doesn't stop the worker.
+pub(crate) fn beat(
+    registry: Arc<Registry>,
+    heartbeat: Heartbeat,
+    running: Arc<Mutex<HashSet<Uuid>>>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let task = future::loop_fn((), move |_| {
+        let interval = heartbeat.interval;
+        publish(&*registry, heartbeat.clone(), &running)
+            .then(move |_| {
+                Delay::new(Instant::now() + interval)
+                    .map_err(|e| error!("Couldn't wait for the next heartbeat: {}", e))
+            })
+            .map(|_| future::Loop::Continue::<(), ()>(()))
+    });
+    Box::new(task)
+}
+
+/// Publish the given heartbeat to the given registry, along with the IDs of the jobs being
+/// processed by its worker.
+pub(crate) fn publish(
+    registry: &Registry,
+    mut heartbeat: Heartbeat,
+    running: &Mutex<HashSet<Uuid>>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    heartbeat.sent_at = Utc::now();
+    heartbeat.running = running.lock().unwrap().iter().cloned().collect();
+    trace!("Publishing heartbeat of worker {}", heartbeat.worker);
+    let task = registry
+        .beat(&heartbeat)
+        .map_err(|e| warn!("Couldn't publish the worker's heartbeat: {}", e));
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory;
+
+    #[test]
+    fn liveness() {
+        let connection = memory::Connection::new(vec![]);
+        let workers = Workers::new(connection.clone());
+        let alive = Heartbeat::new(vec!["emails".into()], 4, Duration::from_secs(10));
+        let mut dead = Heartbeat::new(vec!["emails".into()], 4, Duration::from_secs(10));
+        dead.sent_at = Utc::now() - chrono::Duration::seconds(31);
+        connection.beat(&alive).wait().unwrap();
+        connection.beat(&dead).wait().unwrap();
+        assert_eq!(workers.list().wait().unwrap(), vec![alive.clone()]);
+        assert_eq!(workers.dead().wait().unwrap(), vec![dead.clone()]);
+
+        workers.forget(dead.worker).wait().unwrap();
+        assert!(workers.dead().wait().unwrap().is_empty());
+        let later = alive.sent_at + chrono::Duration::seconds(31);
+        assert!(!alive.is_alive_at(later));
+    }
+}
@@ -0,0 +1,228 @@
+//! Publication of jobs through a transactional outbox.
+//!
+//! Sending a job from a handler that also writes to a database is racy: the job can be
+//! published for a transaction that is later rolled back, or the transaction can commit without
+//! the job ever reaching the broker. An `Outbox` stores jobs alongside the application's data
+//! instead, within the same transaction, and a `Relay` publishes the stored jobs afterwards,
+//! marking each one sent once the broker accepted it.
+//!
+//! Jobs are stored as `OutboxEntry` values, built with `Query::into_outbox_entry`. A job whose
+//! entry couldn't be marked sent after its publication is published again by the next relay
+//! iteration: handlers of jobs sent through an outbox should be idempotent, see `Idempotency`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_timer::Delay;
+
+use broker::Properties;
+use client::Client;
+use error::{Error, ErrorKind};
+
+/// The default number of entries published by each iteration of a `Relay`.
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// The default number of milliseconds a `Relay` waits for once its outbox is empty.
+const DEFAULT_POLL_INTERVAL: u64 = 1_000;
+
+/// A job stored in an `Outbox`, waiting to be published.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    /// The serialized job, encoded with the codec given by its properties.
+    pub payload: Vec<u8>,
+    /// The properties of the job.
+    pub properties: Properties,
+}
+
+/// Storage of the jobs waiting to be published by a `Relay`.
+///
+/// Implementations usually provide a way to store entries within a transaction of the
+/// application's database, which isn't part of this trait as it depends on the database.
+pub trait Outbox: fmt::Debug + Send + Sync {
+    /// Return at most `limit` entries that weren't marked sent yet, in the order they were
+    /// stored.
+    fn pending(&self, limit: usize) -> Box<Future<Item = Vec<OutboxEntry>, Error = Error> + Send>;
+
+    /// Mark the given entry as sent, so that it isn't returned by `pending` anymore.
+    fn mark_sent(&self, entry: &OutboxEntry) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// A task publishing the jobs stored in an `Outbox`.
+///
+/// Entries are published one at a time in the order they were stored, so that jobs reach the
+/// broker in the order they were sent. A single relay should run for a given outbox, as
+/// concurrent relays would publish the same entries.
+#[derive(Debug)]
+pub struct Relay {
+    outbox: Arc<Outbox>,
+    client: Client,
+    batch_size: usize,
+    poll_interval: Duration,
+}
+
+impl Relay {
+    /// Create a new `Relay` publishing the entries of the given outbox with the given client.
+    pub fn new<O>(outbox: O, client: Client) -> Self
+    where
+        O: Outbox + 'static,
+    {
+        Relay {
+            outbox: Arc::new(outbox),
+            client,
+            batch_size: DEFAULT_BATCH_SIZE,
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL),
+        }
+    }
+
+    /// Set the maximum number of entries fetched from the outbox at once.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Set the duration to wait before polling the outbox again once it is empty.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Publish the pending entries of the outbox once, returning the number of entries
+    /// published.
+    ///
+    /// At most `batch_size` entries are published. The returned `Future` fails on the first
+    /// entry that couldn't be published or marked sent, leaving it and the following entries
+    /// pending.
+    pub fn relay_once(&self) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let outbox = Arc::clone(&self.outbox);
+        let client = self.client.clone();
+        let task = self
+            .outbox
+            .pending(self.batch_size)
+            .and_then(move |entries| {
+                future::loop_fn(
+                    (entries.into_iter(), 0),
+                    move |(mut entries, published)| -> Box<
+                        Future<Item = future::Loop<usize, _>, Error = Error> + Send,
+                    > {
+                        let entry = match entries.next() {
+                            Some(entry) => entry,
+                            None => return Box::new(future::ok(future::Loop::Break(published))),
+                        };
+                        let outbox = Arc::clone(&outbox);
+                        let task = client
+                            .send(&entry.payload, &entry.properties)
+                            .and_then(move |_| outbox.mark_sent(&entry))
+                            .map(move |_| future::Loop::Continue((entries, published + 1)));
+                        Box::new(task)
+                    },
+                )
+            });
+        Box::new(task)
+    }
+
+    /// Return a `Future` publishing the entries of the outbox until it is dropped.
+    ///
+    /// The outbox is polled again right away after a full batch, and after `poll_interval`
+    /// otherwise. Failures are logged and retried after `poll_interval`.
+    pub fn run(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = future::loop_fn(self, |relay| {
+            let poll_interval = relay.poll_interval;
+            let batch_size = relay.batch_size;
+            let task = relay.relay_once();
+            task.then(move |result| {
+                let wait = match result {
+                    Ok(published) => {
+                        if published > 0 {
+                            debug!("Relayed {} jobs from the outbox", published);
+                        }
+                        published < batch_size
+                    }
+                    Err(e) => {
+                        error!("Couldn't relay the jobs of the outbox: {}", e);
+                        true
+                    }
+                };
+                let delay: Box<Future<Item = (), Error = Error> + Send> = if wait {
+                    let task = Delay::new(Instant::now() + poll_interval)
+                        .map_err(|e| -> Error { ErrorKind::Timer(e).into() });
+                    Box::new(task)
+                } else {
+                    Box::new(future::ok(()))
+                };
+                delay
+            })
+            .map(move |_| future::Loop::Continue::<(), _>(relay))
+        });
+        Box::new(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use job::Priority;
+    use memory;
+    use query::RawOptions;
+    use topology::queue;
+
+    #[derive(Debug, Default)]
+    struct Entries(Mutex<Vec<(OutboxEntry, bool)>>);
+
+    impl Outbox for Arc<Entries> {
+        fn pending(
+            &self,
+            limit: usize,
+        ) -> Box<Future<Item = Vec<OutboxEntry>, Error = Error> + Send> {
+            let entries = self.0.lock().unwrap();
+            let pending = entries
+                .iter()
+                .filter(|&&(_, sent)| !sent)
+                .map(|&(ref entry, _)| entry.clone())
+                .take(limit)
+                .collect();
+            Box::new(future::ok(pending))
+        }
+
+        fn mark_sent(&self, entry: &OutboxEntry) -> Box<Future<Item = (), Error = Error> + Send> {
+            let mut entries = self.0.lock().unwrap();
+            for &mut (ref stored, ref mut sent) in entries.iter_mut() {
+                if stored.properties.id == entry.properties.id {
+                    *sent = true;
+                }
+            }
+            Box::new(future::ok(()))
+        }
+    }
+
+    #[test]
+    fn relay() {
+        let connection =
+            memory::Connection::new(vec![queue("tests.outbox").bind("batch.tests", "outbox")]);
+        let client = Client::new(connection.clone());
+        let entries = Arc::new(Entries::default());
+        for _ in 0..3 {
+            let properties = RawOptions::new()
+                .exchange("batch.tests")
+                .priority(Priority::Normal)
+                .properties("outbox-job", "outbox", client.codec());
+            let payload = properties.codec.encode(&"payload").unwrap();
+            let entry = OutboxEntry {
+                payload,
+                properties,
+            };
+            entries.0.lock().unwrap().push((entry, false));
+        }
+
+        let relay = Relay::new(Arc::clone(&entries), client).batch_size(2);
+        assert_eq!(relay.relay_once().wait().unwrap(), 2);
+        assert_eq!(connection.len("tests.outbox"), 2);
+        assert_eq!(relay.relay_once().wait().unwrap(), 1);
+        assert_eq!(relay.relay_once().wait().unwrap(), 0);
+        assert_eq!(connection.len("tests.outbox"), 3);
+        assert!(entries.0.lock().unwrap().iter().all(|&(_, sent)| sent));
+    }
+}
@@ -0,0 +1,172 @@
+//! Checks of the payload of jobs before they are executed.
+//!
+//! Before executing a job, the worker checks that its payload can be decoded into the type of
+//! the job. How strictly it is decoded depends on the `ParsePolicy` of the queue it was consumed
+//! from. Jobs that fail this check aren't executed: they are published to the `parse-errors`
+//! queue along with their raw payload, so that they can be inspected, then acknowledged.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+
+use serde_json::{self, Value};
+
+use broker::{Delivery, Properties};
+use encryption::{self, Encryptor};
+use error::{ErrorKind, Result};
+use job::{self, Job};
+
+/// The name of the queue the jobs that couldn't be decoded are published to.
+pub const PARSE_ERRORS_QUEUE: &str = "parse-errors";
+
+/// The header holding the reason why a job couldn't be decoded.
+pub const PARSE_ERROR_HEADER: &str = "x-batch-parse-error";
+
+/// The header holding the name of the queue a job that couldn't be decoded was consumed from.
+pub const PARSE_ERROR_QUEUE_HEADER: &str = "x-batch-parse-error-queue";
+
+/// How strictly the payload of jobs is decoded, see `WorkerBuilder::parse_policy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParsePolicy {
+    /// Fields of the payload unknown to the job are ignored, and missing fields with a default
+    /// value (e.g: `#[serde(default)]`) are filled. This is the default policy.
+    Lenient,
+    /// Jobs whose payload holds fields unknown to the job are rejected.
+    ///
+    /// Fields that the job skips when it is serialized (e.g: `#[serde(skip_serializing_if)]`)
+    /// are considered unknown when they are present in the payload.
+    Strict,
+}
+
+impl Default for ParsePolicy {
+    fn default() -> Self {
+        ParsePolicy::Lenient
+    }
+}
+
+/// Type of the functions checking the payload of a job.
+type ParseFn = Fn(&Properties, &[u8], ParsePolicy) -> Result<()> + Send + Sync;
+
+/// The functions checking the payload of the jobs registered on a worker, along with the
+/// policies of its queues.
+#[derive(Default)]
+pub(crate) struct Parsers {
+    parsers: HashMap<String, Box<ParseFn>>,
+    policies: HashMap<String, ParsePolicy>,
+}
+
+impl fmt::Debug for Parsers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Parsers {{ jobs: {:?} policies: {:?} }}",
+            self.parsers.keys().collect::<Vec<_>>(),
+            self.policies
+        )
+    }
+}
+
+impl Parsers {
+    /// Check the payload of the jobs of type `T`.
+    pub fn register<T>(&mut self)
+    where
+        T: Job + 'static,
+    {
+        self.parsers.insert(T::name().into(), Box::new(parse::<T>));
+    }
+
+    /// Set the policy used to decode the jobs consumed from the given queue.
+    pub fn policy(&mut self, queue: &str, policy: ParsePolicy) {
+        self.policies.insert(queue.into(), policy);
+    }
+
+    /// Check that the payload of the given job can be decoded.
+    ///
+    /// Jobs without a `Job` type (e.g: registered with `WorkerBuilder::job_fn`) aren't checked.
+    /// Encrypted jobs are decrypted using the given encryptor.
+    pub fn check(&self, delivery: &Delivery, encryptor: Option<&Encryptor>) -> Result<()> {
+        let properties = delivery.properties();
+        let parser = match self.parsers.get(&properties.task) {
+            Some(parser) => parser,
+            None => return Ok(()),
+        };
+        let policy = delivery
+            .queue()
+            .and_then(|queue| self.policies.get(queue))
+            .cloned()
+            .unwrap_or_default();
+        let payload = encryption::open(encryptor, properties, delivery.payload())?;
+        parser(properties, &payload, policy)
+    }
+}
+
+/// Check that the given payload can be decoded into a job of type `T`.
+fn parse<T>(properties: &Properties, payload: &[u8], policy: ParsePolicy) -> Result<()>
+where
+    T: Job,
+{
+    let job: T = job::decode(properties, payload)?;
+    // Migrated jobs are expected to differ from their current schema.
+    if policy == ParsePolicy::Lenient || properties.version.unwrap_or(1) != T::version() {
+        return Ok(());
+    }
+    let raw: Value = properties.codec.decode(payload)?;
+    let parsed = serde_json::to_value(&job).map_err(ErrorKind::Serialization)?;
+    match unknown_field(&raw, &parsed, "") {
+        Some(field) => Err(ErrorKind::UnknownField(field).into()),
+        None => Ok(()),
+    }
+}
+
+/// Return the path of the first field of `raw` that isn't part of `parsed`, if any.
+fn unknown_field(raw: &Value, parsed: &Value, path: &str) -> Option<String> {
+    match (raw, parsed) {
+        (&Value::Object(ref raw), &Value::Object(ref parsed)) => raw
+            .iter()
+            .filter_map(|(name, value)| {
+                let field = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                match parsed.get(name) {
+                    Some(parsed) => unknown_field(value, parsed, &field),
+                    None => Some(field),
+                }
+            })
+            .next(),
+        (&Value::Array(ref raw), &Value::Array(ref parsed)) => raw
+            .iter()
+            .zip(parsed)
+            .enumerate()
+            .filter_map(|(i, (raw, parsed))| {
+                unknown_field(raw, parsed, &format!("{}[{}]", path, i))
+            })
+            .next(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(raw: &str) -> Value {
+        serde_json::from_str(raw).unwrap()
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let parsed = value(r#"{"to": "ferris@example.com", "attachments": [{"name": "a.png"}]}"#);
+        assert_eq!(unknown_field(&parsed, &parsed, ""), None);
+
+        let raw = value(r#"{"to": "ferris@example.com", "cc": "crab@example.com"}"#);
+        assert_eq!(unknown_field(&raw, &parsed, ""), Some("cc".into()));
+
+        let raw = value(r#"{"attachments": [{"name": "a.png", "size": 42}]}"#);
+        assert_eq!(
+            unknown_field(&raw, &parsed, ""),
+            Some("attachments[0].size".into())
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Progress reporting from running jobs.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use futures::Future;
+use tokio_executor::{DefaultExecutor, Executor};
+use uuid::Uuid;
+
+use status::StatusStore;
+
+/// The progress of a job, as last reported by its handler.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ProgressReport {
+    /// The number of units of work completed.
+    pub current: u64,
+    /// The total number of units of work, if known.
+    pub total: Option<u64>,
+    /// A human-readable description of the current step.
+    pub message: Option<String>,
+    /// When the progress was last updated.
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ProgressReport {
+    /// Return the completed fraction of the work, between 0 and 1, if the total is known.
+    pub fn ratio(&self) -> Option<f64> {
+        match self.total {
+            Some(0) | None => None,
+            Some(total) => Some(self.current as f64 / total as f64),
+        }
+    }
+}
+
+/// A handle allowing a job's handler to report its progress.
+///
+/// It is obtained with `Context::progress`. Each update is recorded in the worker's
+/// `StatusStore` in the background, and can be retrieved by clients using `Client::status` or
+/// `Client::watch_progress`. Updates are ignored if the worker has no `StatusStore`.
+///
+/// Cloning a `Progress` returns a handle to the same progress.
+#[derive(Clone)]
+pub struct Progress {
+    id: Uuid,
+    statuses: Option<Arc<StatusStore>>,
+    state: Arc<Mutex<Option<ProgressReport>>>,
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Progress {{ id: {:?} state: {:?} }}",
+            self.id, self.state
+        )
+    }
+}
+
+impl Progress {
+    /// Create a new `Progress` handle for the given job, recording updates in the given store.
+    pub(crate) fn new(id: Uuid, statuses: Option<Arc<StatusStore>>) -> Self {
+        Progress {
+            id,
+            statuses,
+            state: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Report that `current` units of work out of `total` were completed.
+    pub fn set(&self, current: u64, total: u64) {
+        self.update(|report| {
+            report.current = current;
+            report.total = Some(total);
+        });
+    }
+
+    /// Report the current step of the job, keeping the number of units of work completed.
+    pub fn message(&self, message: &str) {
+        self.update(|report| report.message = Some(message.to_string()));
+    }
+
+    /// Return the last reported progress, if any.
+    pub fn get(&self) -> Option<ProgressReport> {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut ProgressReport),
+    {
+        let report = {
+            let mut state = self.state.lock().unwrap();
+            let report = state.get_or_insert_with(|| ProgressReport {
+                current: 0,
+                total: None,
+                message: None,
+                updated_at: Utc::now(),
+            });
+            f(report);
+            report.updated_at = Utc::now();
+            report.clone()
+        };
+        let statuses = match self.statuses {
+            Some(ref statuses) => statuses,
+            None => return,
+        };
+        let id = self.id;
+        let task = statuses.progress(id, &report).map_err(move |e| {
+            warn!("[{}] Couldn't record progress of job: {}", id, e);
+        });
+        if let Err(e) = DefaultExecutor::current().spawn(Box::new(task)) {
+            warn!("[{}] Couldn't record progress of job: {:?}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates() {
+        let progress = Progress::new(Uuid::new_v4(), None);
+        assert_eq!(progress.get(), None);
+        progress.set(42, 100);
+        progress.message("step 3");
+        let report = progress.get().unwrap();
+        assert_eq!(report.current, 42);
+        assert_eq!(report.total, Some(100));
+        assert_eq!(report.message, Some("step 3".to_string()));
+        assert_eq!(report.ratio(), Some(0.42));
+    }
+}
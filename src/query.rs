@@ -2,31 +2,78 @@
 
 use std::fmt;
 use std::result::Result as StdResult;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use futures::{Future, IntoFuture};
-use lapin::channel::{BasicProperties, BasicPublishOptions};
-use lapin::types::{AMQPValue, FieldTable};
-use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use futures::{future, Future, IntoFuture};
 
+use backend::Outcome;
+use broker::Properties;
 use client::Client;
-use error::{self, Error, Result};
-use job::{Job, Priority};
-use rabbitmq::Exchange;
-use ser;
+use codec::Codec;
+use de;
+use error::{self, Error};
+use job::{Job, Perform, Priority};
+use outbox::OutboxEntry;
+use scheduled::ScheduledJob;
+use trace::TraceContext;
+use uuid::Uuid;
+use workflow;
 
-/// A `Query` is responsible for publishing jobs to `RabbitMQ`.
+/// A `Query` is responsible for publishing jobs to a message broker.
+///
+/// The settings of a `Query` default to the ones given by the `Job` trait, and can be
+/// overridden for each job sent, e.g: to bump the priority of a single job.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate batch;
+/// # extern crate futures;
+/// # #[macro_use]
+/// # extern crate lazy_static;
+/// # #[macro_use]
+/// # extern crate serde;
+/// # extern crate tokio;
+/// #
+/// use std::time::Duration;
+///
+/// use batch::{job, Client, Priority};
+/// use futures::Future;
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job(routing_key = "exports")]
+/// struct ExportOrders {
+///     customer: u64,
+/// }
+///
+/// # fn main() {
+/// let task = Client::builder()
+///     .connection_url("amqp://localhost/%2f")
+///     .build()
+///     .and_then(|client| {
+///         job(ExportOrders { customer: 42 })
+///             .priority(Priority::High)
+///             .delay(Some(Duration::from_secs(60)))
+///             .send(&client)
+///     })
+///     .map_err(|e| eprintln!("An error occured: {}", e));
+///
+/// # if false {
+/// tokio::run(task);
+/// # }
+/// # }
+/// ```
 pub struct Query<T>
 where
     T: Job + 'static,
 {
     job: T,
-    exchange: String,
-    routing_key: String,
-    timeout: Option<Duration>,
     retries: u32,
-    options: BasicPublishOptions,
-    properties: BasicProperties,
+    codec: Option<Codec>,
+    unique_for: Option<Duration>,
+    properties: Properties,
 }
 
 impl<T> fmt::Debug for Query<T>
@@ -36,14 +83,8 @@ where
     fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
         write!(
             f,
-            "Query {{ job: {:?} exchange: {:?} routing_key: {:?} timeout: {:?} retries: {:?} options: {:?} properties: {:?} }}",
-            self.job,
-            self.exchange,
-            self.routing_key,
-            self.timeout,
-            self.retries,
-            self.options,
-            self.properties
+            "Query {{ job: {:?} retries: {:?} properties: {:?} }}",
+            self.job, self.retries, self.properties
         )
     }
 }
@@ -54,78 +95,79 @@ where
 {
     /// Create a new `Query` from a `Job` instance.
     pub fn new(job: T) -> Self {
-        let task_id = Uuid::new_v4().to_string();
-        let mut headers = FieldTable::new();
-        headers.insert("lang".to_string(), AMQPValue::LongString("rs".to_string()));
-        headers.insert(
-            "task".to_string(),
-            AMQPValue::LongString(T::name().to_string()),
-        );
-        headers.insert("id".to_string(), AMQPValue::LongString(task_id.clone()));
-        headers.insert("root_id".to_string(), AMQPValue::Void);
-        headers.insert("parent_id".to_string(), AMQPValue::Void);
-        headers.insert("group".to_string(), AMQPValue::Void);
-        headers.insert(
-            "timelimit".to_string(),
-            AMQPValue::FieldArray(vec![
-                AMQPValue::Void,
-                T::timeout().map_or(AMQPValue::Void, |d| AMQPValue::Timestamp(d.as_secs())),
-            ]),
-        );
-        let properties = BasicProperties {
-            priority: Some(T::priority().to_u8()),
-            content_type: Some("application/json".to_string()),
-            content_encoding: Some("utf-8".to_string()),
-            headers: Some(headers),
-            correlation_id: Some(task_id),
-            ..Default::default()
-        };
         Query {
             job,
-            exchange: T::exchange().to_string(),
-            routing_key: T::routing_key().to_string(),
-            timeout: T::timeout(),
             retries: T::retries(),
-            options: BasicPublishOptions::default(),
-            properties,
+            codec: T::codec(),
+            unique_for: T::unique_for(),
+            properties: Properties::new::<T>(),
         }
     }
 
+    /// Return the unique ID of this job.
+    pub fn id(&self) -> Uuid {
+        self.properties.id
+    }
+
     /// Return a reference the properties of this message.
-    pub fn properties(&self) -> &BasicProperties {
+    pub fn properties(&self) -> &Properties {
         &self.properties
     }
 
     /// Return a mutable reference the properties of this message.
-    pub fn properties_mut(&mut self) -> &mut BasicProperties {
+    pub fn properties_mut(&mut self) -> &mut Properties {
         &mut self.properties
     }
 
-    /// Return a reference the options of this message.
-    pub fn options(&self) -> &BasicPublishOptions {
-        &self.options
-    }
-
-    /// Return a mutable reference the options of this message.
-    pub fn options_mut(&mut self) -> &mut BasicPublishOptions {
-        &mut self.options
-    }
-
     /// Set the exchange this job will be published to.
     pub fn exchange(mut self, exchange: &str) -> Self {
-        self.exchange = exchange.into();
+        self.properties.exchange = exchange.into();
         self
     }
 
     /// Set the routing key associated with this job.
+    ///
+    /// This allows sharding a job type across queues, e.g: bound to a topic exchange with
+    /// `emails.#`, with a routing key for each region such as `emails.us-east`.
     pub fn routing_key(mut self, routing_key: &str) -> Self {
-        self.routing_key = routing_key.into();
+        self.properties.routing_key = routing_key.into();
         self
     }
 
+    /// Publish this job straight to the given queue, through the default exchange.
+    ///
+    /// This is a shorthand for `.exchange("").routing_key(queue)`.
+    pub fn queue(self, queue: &str) -> Self {
+        self.exchange("").routing_key(queue)
+    }
+
     /// Set the timeout associated to this job's execution.
     pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
-        self.timeout = timeout;
+        self.properties.timeout = timeout;
+        self
+    }
+
+    /// Set the duration to wait before this job is delivered to a worker.
+    pub fn delay(mut self, delay: Option<Duration>) -> Self {
+        self.properties.delay = delay;
+        self
+    }
+
+    /// Set the duration after which this job is dropped instead of executed.
+    ///
+    /// See `Job::expires`.
+    pub fn expires(mut self, expires: Option<Duration>) -> Self {
+        self.properties.expires = expires;
+        self
+    }
+
+    /// Hold this job until the jobs with the given IDs succeeded.
+    ///
+    /// The status of the dependencies is checked in the worker's `StatusStore`: the job is
+    /// published again a few seconds later until all of them succeeded, and fails if any of them
+    /// failed or was revoked. See the `workflow` module documentation.
+    pub fn after_jobs(mut self, ids: &[Uuid]) -> Self {
+        workflow::depend_on(&mut self.properties, ids);
         self
     }
 
@@ -137,28 +179,133 @@ where
 
     /// Set the priority for this job.
     pub fn priority(mut self, priority: Priority) -> Self {
-        {
-            let properties = self.properties_mut();
-            properties.priority = Some(priority.to_u8());
-        }
+        self.properties.priority = priority;
+        self
+    }
+
+    /// Set the codec used to serialize this job, instead of the client's default one.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Set the duration during which sending an identical job is a no-op.
+    ///
+    /// See `Job::unique_for`.
+    pub fn unique_for(mut self, unique_for: Option<Duration>) -> Self {
+        self.unique_for = unique_for;
+        self
+    }
+
+    /// Set a custom header associated to this job.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.properties
+            .headers
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Send this job as part of the trace of the given span.
+    ///
+    /// A child of the given span is carried by the `traceparent` header of the job, see
+    /// `TraceContext`.
+    pub fn trace(mut self, parent: &TraceContext) -> Self {
+        parent.child().inject(&mut self.properties);
         self
     }
 
     /// Send the job using the given client.
-    pub fn send(self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+    ///
+    /// If the job is unique and an identical job was already sent, this is a no-op.
+    pub fn send(mut self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
         let client = client.clone();
-        let task = ser::to_vec(&self.job)
-            .map_err(error::ErrorKind::Serialization)
-            .into_future()
-            .map_err(|e| e.into())
-            .and_then(move |serialized| {
-                client.send(
-                    &self.exchange,
-                    &self.routing_key,
-                    &serialized,
-                    &self.options,
-                    self.properties,
-                )
+        self.properties.codec = self.codec.unwrap_or_else(|| client.codec());
+        let acquire: Box<Future<Item = bool, Error = Error> + Send> = match self.unique_for {
+            Some(duration) => {
+                let key = format!("batch:unique:{}:{}", T::name(), self.job.unique_key());
+                client.acquire_unique(&key, duration)
+            }
+            None => Box::new(future::ok(true)),
+        };
+        let task = acquire.and_then(
+            move |acquired| -> Box<Future<Item = (), Error = Error> + Send> {
+                if !acquired {
+                    debug!(
+                        "[{}] Skipping duplicate of unique job {}",
+                        self.properties.id,
+                        T::name()
+                    );
+                    return Box::new(future::ok(()));
+                }
+                let task = self.properties
+                    .codec
+                    .encode(&self.job)
+                    .into_future()
+                    .and_then(move |serialized| client.send(&serialized, &self.properties));
+                Box::new(task)
+            },
+        );
+        Box::new(task)
+    }
+
+    /// Serialize the job into an entry to store in an `Outbox`, published later by a `Relay`.
+    ///
+    /// The job is encoded with the codec set on this query, or the default codec. Uniqueness
+    /// isn't checked for jobs sent through an outbox.
+    pub fn into_outbox_entry(mut self) -> Result<OutboxEntry, Error> {
+        self.properties.codec = self.codec.unwrap_or_default();
+        let payload = self.properties.codec.encode(&self.job)?;
+        Ok(OutboxEntry {
+            payload,
+            properties: self.properties,
+        })
+    }
+
+    /// Store the job in the `ScheduleStore` of the given client, to be published at the given
+    /// date by a `Dispatcher`.
+    ///
+    /// Returns the ID of the job, which can be used to cancel it with `Client::cancel_scheduled`.
+    /// Uniqueness isn't checked for scheduled jobs.
+    pub fn send_at(
+        mut self,
+        client: &Client,
+        at: SystemTime,
+    ) -> Box<Future<Item = Uuid, Error = Error> + Send> {
+        self.properties.codec = self.codec.unwrap_or_else(|| client.codec());
+        let payload = match self.properties.codec.encode(&self.job) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let id = self.properties.id;
+        let job = ScheduledJob {
+            due: DateTime::<Utc>::from(at),
+            payload,
+            properties: self.properties,
+        };
+        Box::new(client.schedule(&job).map(move |_| id))
+    }
+}
+
+impl<T> Query<T>
+where
+    T: Job + Perform + Send + 'static,
+    T::Output: 'static,
+{
+    /// Send the job using the given client, and wait for the value returned by its handler.
+    ///
+    /// The client and the worker executing the job must share a `ResultBackend`. The returned
+    /// `Future` fails if the job failed and won't be retried.
+    pub fn send_and_wait(
+        mut self,
+        client: &Client,
+    ) -> Box<Future<Item = T::Output, Error = Error> + Send> {
+        let outcome = client.subscribe(&mut self.properties);
+        let task = self.send(client)
+            .and_then(move |_| outcome)
+            .and_then(|outcome| match outcome {
+                Outcome::Success(raw) => de::from_slice(&raw)
+                    .map_err(|e| error::ErrorKind::Deserialization(e).into()),
+                Outcome::Failed(failure) => Err(error::ErrorKind::JobFailed(failure).into()),
             });
         Box::new(task)
     }
@@ -171,3 +318,143 @@ where
 {
     Query::new(job)
 }
+
+/// The settings of a job sent by name with `Client::send_raw`.
+///
+/// The job is published to the default exchange, with the default priority and without timeout,
+/// delay nor expiration, unless overridden.
+#[derive(Clone, Debug, Default)]
+pub struct RawOptions {
+    exchange: String,
+    priority: Priority,
+    timeout: Option<Duration>,
+    delay: Option<Duration>,
+    expires: Option<Duration>,
+    codec: Option<Codec>,
+    headers: Vec<(String, String)>,
+}
+
+impl RawOptions {
+    /// Create a new `RawOptions` with the default settings.
+    pub fn new() -> Self {
+        RawOptions::default()
+    }
+
+    /// Set the exchange the job is published to.
+    pub fn exchange(mut self, exchange: &str) -> Self {
+        self.exchange = exchange.into();
+        self
+    }
+
+    /// Set the priority of the job.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set the time allowed for the job's handler to complete.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the duration to wait before the job is delivered to a worker.
+    pub fn delay(mut self, delay: Option<Duration>) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Set the duration after which the job is dropped instead of executed.
+    pub fn expires(mut self, expires: Option<Duration>) -> Self {
+        self.expires = expires;
+        self
+    }
+
+    /// Set the codec the payload is encoded with, instead of the client's default codec.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Add a custom header to the job.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Return the properties of a job with the given name and routing key sent with these
+    /// settings, encoded with the given codec.
+    pub(crate) fn properties(self, name: &str, routing_key: &str, codec: Codec) -> Properties {
+        let mut properties = Properties::named(name, &self.exchange, routing_key);
+        properties.priority = self.priority;
+        properties.timeout = self.timeout;
+        properties.delay = self.delay;
+        properties.expires = self.expires;
+        properties.codec = self.codec.unwrap_or(codec);
+        properties.headers.extend(self.headers);
+        properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use retry::RetryStrategy;
+
+    use memory;
+    use topology::queue;
+
+    #[derive(Serialize, Deserialize)]
+    struct Invoice {
+        amount: u64,
+    }
+
+    impl Job for Invoice {
+        fn name() -> &'static str {
+            "invoice"
+        }
+
+        fn exchange() -> &'static str {
+            "batch.tests"
+        }
+
+        fn routing_key() -> &'static str {
+            "invoices"
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn retry_strategy() -> RetryStrategy {
+            RetryStrategy::default()
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    #[test]
+    fn send_to_queue() {
+        // Every job published to the exchange of invoices is routed to their queue.
+        let connection = memory::Connection::new(vec![
+            queue("tests.invoices").bind("batch.tests", "#"),
+            queue("tests.urgent"),
+        ]);
+        let client = Client::new(connection.clone());
+        job(Invoice { amount: 1 }).send(&client).wait().unwrap();
+        job(Invoice { amount: 2 })
+            .queue("tests.urgent")
+            .send(&client)
+            .wait()
+            .unwrap();
+        assert_eq!(connection.len("tests.invoices"), 1);
+        assert_eq!(connection.len("tests.urgent"), 1);
+    }
+}
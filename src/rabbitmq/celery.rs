@@ -0,0 +1,203 @@
+use chrono::{self, Utc};
+use lapin::channel::BasicProperties;
+use lapin::message::Delivery as Message;
+use lapin::types::{AMQPValue, FieldTable};
+use serde_json::{Map, Value};
+
+use broker::Properties;
+use encryption::KEY_ID_HEADER;
+use error::{Error, ErrorKind};
+use rabbitmq::delivery::to_amqp_properties;
+use ser;
+
+/// The protocol used to publish jobs to `RabbitMQ`.
+///
+/// Workers consume jobs published with either protocol, whatever the protocol of their
+/// connection, which allows migrating a Celery deployment to Batch one task at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Batch's own protocol: the body of a message is the serialized job.
+    Batch,
+    /// Version 2 of the Celery message protocol: the body of a message is the
+    /// `[args, kwargs, embed]` triple expected by Celery workers.
+    ///
+    /// A job serializing to an object is sent as the keyword arguments of the task, any other
+    /// value as its positional arguments. Jobs can't be compressed with this protocol.
+    Celery,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::Batch
+    }
+}
+
+/// The header holding the representation of the keyword arguments of a task.
+///
+/// Celery producers always set it, unlike Batch producers using their own protocol.
+const KWARGSREPR_HEADER: &str = "kwargsrepr";
+
+/// Return whether the given message follows the Celery message protocol.
+fn is_celery(message: &Message) -> bool {
+    message
+        .properties
+        .headers
+        .as_ref()
+        .map_or(false, |headers| headers.contains_key(KWARGSREPR_HEADER))
+}
+
+/// Convert the given job to a message following the Celery message protocol, returning its
+/// body and its properties.
+pub(crate) fn to_celery_message(
+    payload: &[u8],
+    properties: &Properties,
+) -> Result<(Vec<u8>, BasicProperties), Error> {
+    if properties.compression.is_some() {
+        let e = format_err!("jobs sent using the Celery protocol can't be compressed");
+        return Err(ErrorKind::Codec(e).into());
+    }
+    if properties.headers.contains_key(KEY_ID_HEADER) {
+        let e = format_err!("jobs sent using the Celery protocol can't be encrypted");
+        return Err(ErrorKind::Codec(e).into());
+    }
+    let (args, kwargs) = match properties.codec.decode(payload)? {
+        Value::Object(kwargs) => (Vec::new(), kwargs),
+        Value::Array(args) => (args, Map::new()),
+        Value::Null => (Vec::new(), Map::new()),
+        arg => (vec![arg], Map::new()),
+    };
+    let argsrepr = ser::to_string(&args).map_err(ErrorKind::Serialization)?;
+    let kwargsrepr = ser::to_string(&kwargs).map_err(ErrorKind::Serialization)?;
+    let embed = ["callbacks", "errbacks", "chain", "chord"]
+        .iter()
+        .map(|key| (key.to_string(), Value::Null))
+        .collect::<Map<_, _>>();
+    let body = properties.codec.encode(&(args, kwargs, embed))?;
+
+    let mut amqp_properties = to_amqp_properties(properties);
+    {
+        let headers = amqp_properties.headers.get_or_insert_with(FieldTable::new);
+        headers.insert(
+            "root_id".to_string(),
+            AMQPValue::LongString(properties.id.to_string()),
+        );
+        headers.insert("shadow".to_string(), AMQPValue::Void);
+        headers.insert("eta".to_string(), AMQPValue::Void);
+        let expires = properties
+            .expires
+            .and_then(|expires| chrono::Duration::from_std(expires).ok())
+            .map(|expires| (Utc::now() + expires).to_rfc3339());
+        headers.insert(
+            "expires".to_string(),
+            expires.map_or(AMQPValue::Void, AMQPValue::LongString),
+        );
+        // Celery workers expect durations in seconds, as numbers rather than timestamps.
+        let timeout = properties
+            .timeout
+            .map(|timeout| timeout.as_secs() as f64 + f64::from(timeout.subsec_nanos()) / 1e9);
+        headers.insert(
+            "timelimit".to_string(),
+            AMQPValue::FieldArray(vec![
+                AMQPValue::Void,
+                timeout.map_or(AMQPValue::Void, AMQPValue::Double),
+            ]),
+        );
+        headers.insert("argsrepr".to_string(), AMQPValue::LongString(argsrepr));
+        headers.insert(
+            KWARGSREPR_HEADER.to_string(),
+            AMQPValue::LongString(kwargsrepr),
+        );
+    }
+    Ok((body, amqp_properties))
+}
+
+/// Extract the payload of the job carried by the given message, if it follows the Celery
+/// message protocol.
+///
+/// The job is deserialized from the keyword arguments of the task, or from its positional
+/// arguments when it has no keyword argument. Returns `None` for other messages, and for
+/// malformed Celery messages, whose body is then handed to the job as is.
+pub(crate) fn from_celery_message(message: &Message, properties: &Properties) -> Option<Vec<u8>> {
+    if !is_celery(message) {
+        return None;
+    }
+    let payload = properties
+        .codec
+        .decode::<(Vec<Value>, Map<String, Value>, Value)>(&message.data)
+        .and_then(|(args, kwargs, _)| {
+            let job = if kwargs.is_empty() && !args.is_empty() {
+                Value::Array(args)
+            } else {
+                Value::Object(kwargs)
+            };
+            properties.codec.encode(&job)
+        });
+    match payload {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            warn!("[{}] Invalid Celery message: {}", properties.id, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use codec::Codec;
+    use job::Priority;
+    use memory::tests::properties;
+
+    fn message(body: Vec<u8>, amqp_properties: BasicProperties) -> Message {
+        Message {
+            delivery_tag: 1,
+            exchange: "batch.tests".into(),
+            routing_key: "celery".into(),
+            redelivered: false,
+            properties: amqp_properties,
+            data: body,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let properties = properties("tasks.add", "celery", Priority::Normal);
+        let (body, amqp_properties) = to_celery_message(br#"{"x":1,"y":2}"#, &properties).unwrap();
+        let decoded: Value = Codec::Json.decode(&body).unwrap();
+        let expected: Value = ::serde_json::from_str(
+            r#"[[], {"x": 1, "y": 2},
+                {"callbacks": null, "errbacks": null, "chain": null, "chord": null}]"#,
+        )
+        .unwrap();
+        assert_eq!(decoded, expected);
+
+        let message = message(body, amqp_properties);
+        let payload = from_celery_message(&message, &properties).unwrap();
+        assert_eq!(payload, br#"{"x":1,"y":2}"#.to_vec());
+    }
+
+    #[test]
+    fn positional_arguments() {
+        let properties = properties("tasks.add", "celery", Priority::Normal);
+        let mut headers = FieldTable::new();
+        headers.insert(
+            KWARGSREPR_HEADER.to_string(),
+            AMQPValue::LongString("{}".into()),
+        );
+        let amqp_properties = BasicProperties {
+            headers: Some(headers),
+            ..Default::default()
+        };
+        let message = message(br#"[[1, 2], {}, {}]"#.to_vec(), amqp_properties);
+        let payload = from_celery_message(&message, &properties).unwrap();
+        assert_eq!(payload, b"[1,2]".to_vec());
+    }
+
+    #[test]
+    fn batch_messages() {
+        let properties = properties("tasks.add", "celery", Priority::Normal);
+        let message = message(b"{}".to_vec(), to_amqp_properties(&properties));
+        assert!(from_celery_message(&message, &properties).is_none());
+    }
+}
@@ -1,23 +1,53 @@
 use std::io;
-use std::net;
-use std::str::FromStr;
+use std::net::{self, ToSocketAddrs};
+use std::time::Duration;
 
 use amq_protocol::uri::{AMQPScheme, AMQPUri};
+use futures::future::Either;
 use futures::{future, Future, IntoFuture};
-use lapin::channel::{Channel, ExchangeBindOptions, QueueBindOptions};
+use lapin::channel::{
+    Channel, ExchangeBindOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+};
 use lapin::client::{self, Client, ConnectionOptions};
 use lapin::types::FieldTable;
-use native_tls::TlsConnector;
 use tokio_executor;
 use tokio_reactor::Handle;
 use tokio_tcp::TcpStream;
+use tokio_timer::{timeout, Timeout};
 use tokio_tls::TlsConnectorExt;
 
 use error::{Error, ErrorKind};
+use rabbitmq::builder::ConnectionBuilder;
 use rabbitmq::stream::Stream;
-use rabbitmq::types::{Exchange, Queue};
+use topology::{self, Exchange, ExchangeOptions, Queue, QueueOptions};
+
+/// Return the options used to declare an exchange with the given options.
+pub fn exchange_options(options: &ExchangeOptions) -> ExchangeDeclareOptions {
+    ExchangeDeclareOptions {
+        passive: options.passive,
+        durable: options.durable,
+        auto_delete: options.auto_delete,
+        internal: options.internal,
+        nowait: options.nowait,
+        ticket: options.ticket,
+    }
+}
+
+/// Return the options used to declare a queue with the given options.
+pub fn queue_options(options: &QueueOptions) -> QueueDeclareOptions {
+    QueueDeclareOptions {
+        passive: options.passive,
+        durable: options.durable,
+        exclusive: options.exclusive,
+        auto_delete: options.auto_delete,
+        nowait: options.nowait,
+        ticket: options.ticket,
+    }
+}
 
 /// Declare the given queues to the given `Channel`.
+///
+/// The dead-letter queues associated to the given queues are declared as well.
 pub fn declare_queues<Q>(
     queues: Q,
     channel: Channel<Stream>,
@@ -26,7 +56,21 @@ where
     Q: IntoIterator<Item = Queue> + 'static,
     Q::IntoIter: Send,
 {
-    let task = future::loop_fn(queues.into_iter(), move |mut iter| {
+    let mut declared = Vec::new();
+    for queue in queues {
+        let dead_letter = queue.dead_letter_queue();
+        let provisioned = queue.is_provisioned();
+        declared.push(queue);
+        if let Some(name) = dead_letter {
+            let dead_letter = topology::queue(&name)
+                .durable(true)
+                .dead_letter(false)
+                .passive(provisioned)
+                .build();
+            declared.push(dead_letter);
+        }
+    }
+    let task = future::loop_fn(declared.into_iter(), move |mut iter| {
         let next = iter.next();
         let task: Box<Future<Item = future::Loop<_, _>, Error = io::Error> + Send> =
             if let Some(queue) = next {
@@ -35,7 +79,7 @@ where
                 let task = channel
                     .queue_declare(
                         queue.name(),
-                        queue.options().clone(),
+                        queue_options(queue.options()),
                         queue.arguments().clone(),
                     )
                     .and_then(move |_| {
@@ -88,7 +132,7 @@ where
                     .exchange_declare(
                         exchange.name(),
                         exchange.kind(),
-                        exchange.options().clone(),
+                        exchange_options(exchange.options()),
                         exchange.arguments().clone(),
                     )
                     .and_then(move |_| {
@@ -119,22 +163,55 @@ where
     Box::new(task.map(|_| ()))
 }
 
+/// Connect to `RabbitMQ`, trying each URL of the given settings in turn until one succeeds.
+///
+/// The order the URLs are tried in depends on `ConnectionBuilder::failover`.
 pub fn connect(
-    connection_url: &str,
+    connection: &ConnectionBuilder,
+    handle: Handle,
+) -> Box<Future<Item = (Client<Stream>, HeartbeatHandle), Error = Error> + Send> {
+    let connection = connection.clone();
+    let uris = match connection.uris() {
+        Ok(uris) => uris,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let task = future::loop_fn((uris.into_iter(), None), move |(mut uris, error)| {
+        match uris.next() {
+            None => Either::A(future::err(error.expect("no URL to connect to"))),
+            Some(uri) => {
+                let node = format!("{}:{}", uri.authority.host, uri.authority.port);
+                let task = connect_uri(connection.clone(), uri, handle.clone()).then(
+                    move |result| match result {
+                        Ok(connected) => Ok(future::Loop::Break(connected)),
+                        Err(e) => {
+                            warn!("Couldn't connect to RabbitMQ at {}: {}", node, e);
+                            Ok(future::Loop::Continue((uris, Some(e))))
+                        }
+                    },
+                );
+                Either::B(task)
+            }
+        }
+    });
+    Box::new(task)
+}
+
+/// Connect to `RabbitMQ` at the given URL, with the given settings.
+fn connect_uri(
+    connection: ConnectionBuilder,
+    uri: AMQPUri,
     handle: Handle,
 ) -> Box<Future<Item = (Client<Stream>, HeartbeatHandle), Error = Error> + Send> {
-    let task = AMQPUri::from_str(connection_url)
-        .map_err(|e| ErrorKind::InvalidUrl(e).into())
+    let connect_timeout = connection.get_connect_timeout();
+    trace!("Establishing TCP connection");
+    let stream = tcp_connect(
+        (uri.authority.host.as_ref(), uri.authority.port),
+        connect_timeout,
+    );
+    let task = stream
+        .map_err(|e| ErrorKind::Io(e).into())
         .into_future()
-        .and_then(|uri| {
-            trace!("Establishing TCP connection");
-            let addr_uri = uri.clone();
-            let addr = (addr_uri.authority.host.as_ref(), addr_uri.authority.port);
-            net::TcpStream::connect(addr)
-                .map_err(|e| ErrorKind::Io(e).into())
-                .into_future()
-                .join(future::ok(uri))
-        })
+        .join(future::ok(uri))
         .and_then(move |(stream, uri)| {
             let task: Box<Future<Item = Stream, Error = Error> + Send> =
                 if uri.scheme == AMQPScheme::AMQP {
@@ -146,11 +223,10 @@ pub fn connect(
                     Box::new(task)
                 } else {
                     trace!("Wrapping TCP connection into tokio-tls");
-                    let host = uri.authority.host.clone();
-                    let task = TlsConnector::builder()
-                        .map_err(|e| ErrorKind::Tls(e).into())
+                    let host = connection.tls_domain(&uri.authority.host).to_string();
+                    let task = connection
+                        .tls_connector()
                         .into_future()
-                        .and_then(|builder| builder.build().map_err(|e| ErrorKind::Tls(e).into()))
                         .and_then(move |connector| {
                             TcpStream::from_std(stream, &handle)
                                 .map_err(|e| ErrorKind::Io(e).into())
@@ -176,8 +252,15 @@ pub fn connect(
                 frame_max: uri.query.frame_max.unwrap_or(0),
                 heartbeat: uri.query.heartbeat.unwrap_or(0),
             };
-            Client::connect(stream, opts)
-                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            let handshake =
+                Client::connect(stream, opts).map_err(|e| ErrorKind::Rabbitmq(e).into());
+            let handshake = match connect_timeout {
+                Some(duration) => Either::A(
+                    Timeout::new(handshake, duration).map_err(move |e| timed_out(e, duration)),
+                ),
+                None => Either::B(handshake),
+            };
+            handshake
                 .map(move |(client, mut heartbeat)| {
                     let heartbeat_handle = HeartbeatHandle(heartbeat.handle());
                     trace!("Spawning RabbitMQ heartbeat future");
@@ -190,6 +273,42 @@ pub fn connect(
     Box::new(task)
 }
 
+/// Open a TCP connection to the given address, giving up after the given timeout, if any.
+fn tcp_connect(addr: (&str, u16), timeout: Option<Duration>) -> io::Result<net::TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return net::TcpStream::connect(addr),
+    };
+    let mut error = None;
+    for addr in addr.to_socket_addrs()? {
+        match net::TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "the host resolved to no address")
+    }))
+}
+
+/// Return the error of an AMQP handshake that had to complete within the given duration.
+fn timed_out(error: timeout::Error<Error>, duration: Duration) -> Error {
+    if error.is_elapsed() {
+        let e = io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("couldn't connect to RabbitMQ within {:?}", duration),
+        );
+        return ErrorKind::Rabbitmq(e).into();
+    }
+    if error.is_timer() {
+        let e = error.into_timer().expect("the timeout failed without a timer error");
+        return ErrorKind::Timer(e).into();
+    }
+    error
+        .into_inner()
+        .expect("the handshake failed without an error")
+}
+
 pub struct HeartbeatHandle(Option<client::HeartbeatHandle>);
 
 impl Drop for HeartbeatHandle {
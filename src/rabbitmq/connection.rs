@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use lapin::channel::{BasicProperties, BasicPublishOptions};
+use lapin::types::AMQPValue;
+use tokio_reactor::Handle;
+
+use broker::{Broker, Deliveries, Delivery, Properties};
+use control::ControlChannel;
+use dead_letter::DeadLetterQueue;
+use error::Error;
+use inspect::QueueInspector;
+use rabbitmq::builder::{ConnectionBuilder, Declaration};
+use rabbitmq::celery::{to_celery_message, Protocol};
+use rabbitmq::consumer::Consumer;
+use rabbitmq::control::ControlExchange;
+use rabbitmq::dead_letters::DeadLetters;
+use rabbitmq::delivery::to_amqp_properties;
+use rabbitmq::inspector::Inspector;
+use rabbitmq::pool::Pool;
+use rabbitmq::publisher::Publisher;
+use rabbitmq::reconnect::{Connect, ReconnectingConsumer};
+use topology::{queue, Exchange, Queue};
+
+/// The number of milliseconds a delay queue is kept alive by the broker after its last job was
+/// dead-lettered, i.e: after the delay elapsed since it was last declared.
+const DELAY_QUEUE_EXPIRATION: u64 = 60_000;
+
+/// A `Broker` implementation backed by `RabbitMQ`.
+///
+/// Jobs are published on a pool of shared channels, each call to `consume` opens a new
+/// connection dedicated to the returned stream. Both reconnect with an exponential backoff when
+/// the connection to `RabbitMQ` is lost, declaring their exchanges and queues again.
+///
+/// Delayed jobs are published to a dedicated queue per delay, exchange and routing key, whose
+/// messages expire after the delay and are then dead-lettered to the job's exchange. Each delay
+/// queue is declared again whenever a job is published to it, and deleted by `RabbitMQ` once it
+/// held no job for a minute.
+///
+/// Jobs are published using the protocol of the connection settings, see
+/// `ConnectionBuilder::protocol`, and the exchanges and queues are declared according to
+/// `ConnectionBuilder::declaration`.
+pub struct Connection {
+    connection: ConnectionBuilder,
+    exchanges: Vec<Exchange>,
+    queues: Vec<Queue>,
+    handle: Handle,
+    publishers: Pool,
+    routes: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Connection {{ connection_url: {:?} exchanges: {:?} queues: {:?} }}",
+            self.connection.url(),
+            self.exchanges,
+            self.queues
+        )
+    }
+}
+
+impl Connection {
+    /// Create a `Connection` instance from RabbitMQ connection settings and an explicit tokio
+    /// handle.
+    ///
+    /// Jobs are published over `pool_size` connections to `RabbitMQ`.
+    pub fn new_with_handle(
+        connection: &ConnectionBuilder,
+        exchanges: Vec<Exchange>,
+        queues: Vec<Queue>,
+        pool_size: usize,
+        handle: Handle,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let connection = connection.clone();
+        let (declared_exchanges, declared_queues) = match connection.get_declaration() {
+            Declaration::Lazy => (Vec::new(), Vec::new()),
+            declaration => declarable(declaration, &exchanges, &queues),
+        };
+        let task = Pool::new_with_handle(
+            &connection,
+            declared_exchanges,
+            declared_queues,
+            pool_size,
+            handle.clone(),
+        ).map(move |publishers| Connection {
+            connection,
+            exchanges,
+            queues,
+            handle,
+            publishers,
+            routes: Arc::new(Mutex::new(HashSet::new())),
+        });
+        Box::new(task)
+    }
+
+    /// Set the maximum number of jobs buffered by each publisher while it is reconnecting.
+    pub fn set_publish_buffer(&self, capacity: usize) {
+        self.publishers.set_buffer_capacity(capacity);
+    }
+
+    /// Enable publisher confirms, waiting at most `timeout` for `RabbitMQ` to confirm each job.
+    ///
+    /// Returns a `Future` that completes once confirms are enabled.
+    pub fn enable_confirms(&self, timeout: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.publishers.enable_confirms(timeout)
+    }
+
+    /// Return the body and the properties of the message carrying the given job.
+    fn to_message(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Result<(Vec<u8>, BasicProperties), Error> {
+        match self.connection.get_protocol() {
+            Protocol::Batch => Ok((payload.to_vec(), to_amqp_properties(properties))),
+            Protocol::Celery => to_celery_message(payload, properties),
+        }
+    }
+
+    /// Publish a job to its exchange, or to the delay queue matching its properties.
+    fn publish_one(
+        &self,
+        publisher: &Publisher,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let (body, amqp_properties) = match self.to_message(payload, properties) {
+            Ok(message) => message,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        if let Some(declare) = self.declare_route(properties) {
+            return match properties.delay {
+                // The job only reaches its exchange once the delay expired.
+                Some(delay) => {
+                    let publish = self.publish_delayed(body, amqp_properties, properties, delay);
+                    Box::new(declare.join(publish).map(|_| ()))
+                }
+                None => {
+                    let publisher = publisher.clone();
+                    let exchange = properties.exchange.clone();
+                    let routing_key = properties.routing_key.clone();
+                    let task = declare.and_then(move |_| {
+                        publisher.send(
+                            &exchange,
+                            &routing_key,
+                            &body,
+                            &BasicPublishOptions::default(),
+                            amqp_properties,
+                        )
+                    });
+                    Box::new(task)
+                }
+            };
+        }
+        match properties.delay {
+            Some(delay) => self.publish_delayed(body, amqp_properties, properties, delay),
+            None => publisher.send(
+                &properties.exchange,
+                &properties.routing_key,
+                &body,
+                &BasicPublishOptions::default(),
+                amqp_properties,
+            ),
+        }
+    }
+
+    /// Publish a job to the delay queue matching its properties, declaring it beforehand.
+    fn publish_delayed(
+        &self,
+        body: Vec<u8>,
+        amqp_properties: BasicProperties,
+        properties: &Properties,
+        delay: Duration,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let millis = delay.as_secs() * 1_000 + u64::from(delay.subsec_nanos() / 1_000_000);
+        let name = format!(
+            "batch.delay.{}.{}.{}",
+            millis, properties.exchange, properties.routing_key
+        );
+        // Redeclaring the queue renews its expiration, which is only reached once the last job
+        // published to it was dead-lettered. It isn't cached, so that a queue that expired in the
+        // meantime is declared again.
+        let mut builder = queue(&name);
+        {
+            let arguments = builder.arguments_mut();
+            arguments.insert(
+                "x-message-ttl".to_string(),
+                AMQPValue::LongLongInt(millis as i64),
+            );
+            arguments.insert(
+                "x-expires".to_string(),
+                AMQPValue::LongLongInt((millis + DELAY_QUEUE_EXPIRATION) as i64),
+            );
+            arguments.insert(
+                "x-dead-letter-exchange".to_string(),
+                AMQPValue::LongString(properties.exchange.clone()),
+            );
+            arguments.insert(
+                "x-dead-letter-routing-key".to_string(),
+                AMQPValue::LongString(properties.routing_key.clone()),
+            );
+        }
+        let declare = self
+            .publishers
+            .get()
+            .declare_queue(builder.durable(true).dead_letter(false).build());
+        let publisher = self.publishers.get().clone();
+        let task = declare.and_then(move |_| {
+            publisher.send(
+                "",
+                &name,
+                &body,
+                &BasicPublishOptions::default(),
+                amqp_properties,
+            )
+        });
+        Box::new(task)
+    }
+
+    /// Declare the exchange a job is published to and the queues it routes the job to, if
+    /// declarations are lazy and they weren't declared yet.
+    fn declare_route(
+        &self,
+        properties: &Properties,
+    ) -> Option<Box<Future<Item = (), Error = Error> + Send>> {
+        if self.connection.get_declaration() != Declaration::Lazy {
+            return None;
+        }
+        let route = (
+            properties.exchange.clone(),
+            properties.routing_key.clone(),
+        );
+        if self.routes.lock().unwrap().contains(&route) {
+            return None;
+        }
+        let exchanges = self.exchanges
+            .iter()
+            .filter(|e| e.name() == properties.exchange)
+            .cloned()
+            .collect::<Vec<_>>();
+        let queues = {
+            let kind = exchanges.first().map_or("direct", |e| e.kind());
+            self.queues
+                .iter()
+                .filter(|q| q.routes(&properties.exchange, kind, &properties.routing_key))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        debug!(
+            "Declaring exchanges {:?} and queues {:?} on first use",
+            exchanges, queues
+        );
+        let routes = Arc::clone(&self.routes);
+        let task = self.publishers
+            .get()
+            .declare(exchanges, queues)
+            .map(move |_| {
+                routes.lock().unwrap().insert(route);
+            });
+        Some(Box::new(task))
+    }
+}
+
+/// Return the given exchanges and queues as they should be declared with the given
+/// `Declaration`.
+fn declarable(
+    declaration: Declaration,
+    exchanges: &[Exchange],
+    queues: &[Queue],
+) -> (Vec<Exchange>, Vec<Queue>) {
+    match declaration {
+        Declaration::Eager | Declaration::Lazy => (exchanges.to_vec(), queues.to_vec()),
+        Declaration::Passive => (
+            exchanges.iter().cloned().map(Exchange::provisioned).collect(),
+            queues.iter().cloned().map(Queue::provisioned).collect(),
+        ),
+    }
+}
+
+impl Broker for Connection {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.publish_one(self.publishers.get(), payload, properties)
+    }
+
+    fn publish_batch(
+        &self,
+        jobs: Vec<(Vec<u8>, Properties)>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        // Publish the whole batch over the same channel, so that its jobs are confirmed together.
+        let publisher = self.publishers.get();
+        let tasks = jobs.iter()
+            .map(|&(ref payload, ref properties)| self.publish_one(publisher, payload, properties))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks).map(|_| ()))
+    }
+
+    fn consume(&self, prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        self.consume_except(prefetch, &[])
+    }
+
+    fn consume_except(
+        &self,
+        prefetch: u16,
+        excluded: &[String],
+    ) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        let consumed = self.queues
+            .iter()
+            .filter(|queue| !excluded.iter().any(|name| name == queue.name()))
+            .cloned()
+            .collect::<Vec<_>>();
+        if consumed.is_empty() {
+            // Every queue is paused: no consumer is started until one of them is resumed.
+            let idle = future::empty::<Box<Delivery>, Error>().into_stream();
+            return Box::new(future::ok(Box::new(idle) as Deliveries));
+        }
+        let connection = self.connection.clone();
+        let (exchanges, queues) =
+            declarable(connection.get_declaration(), &self.exchanges, &consumed);
+        let handle = self.handle.clone();
+        let connect: Arc<Connect<Consumer>> = Arc::new(move || {
+            Consumer::new_with_handle(
+                &connection,
+                exchanges.clone(),
+                queues.clone(),
+                prefetch,
+                handle.clone(),
+            )
+        });
+        let task = connect().map(move |consumer| -> Deliveries {
+            let consumer = ReconnectingConsumer::new(consumer, connect);
+            Box::new(consumer.map(|delivery| -> Box<Delivery> { Box::new(delivery) }))
+        });
+        Box::new(task)
+    }
+
+    fn declare(
+        &self,
+        exchanges: &[Exchange],
+        queues: &[Queue],
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let (exchanges, queues) =
+            declarable(self.connection.get_declaration(), exchanges, queues);
+        self.publishers.get().declare(exchanges, queues)
+    }
+
+    fn dead_letters(&self) -> Option<Arc<DeadLetterQueue>> {
+        Some(Arc::new(DeadLetters::new_with_handle(
+            &self.connection,
+            self.handle.clone(),
+        )))
+    }
+
+    fn control(&self) -> Option<Arc<ControlChannel>> {
+        Some(Arc::new(ControlExchange::new_with_handle(
+            &self.connection,
+            self.handle.clone(),
+        )))
+    }
+
+    fn inspector(&self) -> Option<Arc<QueueInspector>> {
+        let queues = self.queues.iter().map(|q| q.name().to_string()).collect();
+        Some(Arc::new(Inspector::new_with_handle(
+            &self.connection,
+            self.handle.clone(),
+            queues,
+        )))
+    }
+}
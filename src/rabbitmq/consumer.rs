@@ -4,7 +4,9 @@ use std::result::Result as StdResult;
 use std::sync::Arc;
 
 use futures::{self, future, Async, Future, Poll};
-use lapin::channel::{BasicConsumeOptions, BasicQosOptions, Channel};
+use lapin::channel::{
+    BasicConsumeOptions, BasicProperties, BasicPublishOptions, BasicQosOptions, Channel,
+};
 use lapin::client::Client;
 use lapin::message::Delivery as Message;
 use lapin::queue::Queue as LapinQueue;
@@ -12,18 +14,19 @@ use lapin::types::FieldTable;
 use tokio_reactor::Handle;
 
 use error::{Error, ErrorKind};
+use rabbitmq::builder::ConnectionBuilder;
 use rabbitmq::common::{connect, declare_exchanges, declare_queues, HeartbeatHandle};
 use rabbitmq::delivery::Delivery;
 use rabbitmq::stream::Stream;
-use rabbitmq::types::{Exchange, Queue};
+use topology::{Exchange, Queue};
+
+/// A message received from `RabbitMQ`, along with the queue it was consumed from.
+type Incoming = (Arc<Queue>, Message);
 
 /// A `Consumer` of incoming jobs.
-///
-/// The type of the stream is a tuple containing a `u64` which is a unique ID for the
-/// job used when `ack`'ing or `reject`'ing it, and a `Job` instance.
 pub struct Consumer {
     channel: Channel<Stream>,
-    stream: Box<futures::Stream<Item = Message, Error = io::Error> + Send>,
+    stream: Box<futures::Stream<Item = Incoming, Error = io::Error> + Send>,
     heartbeat_handle: Arc<HeartbeatHandle>,
 }
 
@@ -36,7 +39,7 @@ impl fmt::Debug for Consumer {
 impl Consumer {
     /// Create a `Consumer` instance from a RabbitMQ URI and an explicit tokio handle.
     pub fn new_with_handle<E, Q>(
-        connection_url: &str,
+        connection: &ConnectionBuilder,
         exchanges_iter: E,
         queues_iter: Q,
         prefetch_count: u16,
@@ -50,7 +53,7 @@ impl Consumer {
         let queues = queues_iter.into_iter().collect::<Vec<_>>();
         let queues_ = queues.clone();
 
-        let task = connect(connection_url, handle)
+        let task = connect(connection, handle)
             .and_then(|(client, heartbeat_handle)| {
                 trace!("Creating consumer's RabbitMQ channel");
                 client
@@ -89,6 +92,7 @@ impl Consumer {
                         "Creating RabbitMQ consumer batch-rs-consumer-{}",
                         queue.name()
                     );
+                    let queue = Arc::new(queue);
                     consumer_channel
                         .basic_consume(
                             &LapinQueue::new(queue.name().into()),
@@ -96,12 +100,15 @@ impl Consumer {
                             BasicConsumeOptions::default(),
                             FieldTable::new(),
                         )
+                        .map(move |consumer| {
+                            consumer.map(move |message| (Arc::clone(&queue), message))
+                        })
                         .map_err(|e| ErrorKind::Rabbitmq(e).into())
                 })).join(future::ok((channel, heartbeat_handle)))
             })
             .map(move |(mut consumers, (channel, heartbeat_handle))| {
                 let initial: Box<
-                    futures::Stream<Item = Message, Error = io::Error> + Send,
+                    futures::Stream<Item = Incoming, Error = io::Error> + Send,
                 > = Box::new(consumers.pop().unwrap());
                 let stream = consumers.into_iter().fold(initial, |acc, consumer| {
                     Box::new(futures::Stream::select(acc, consumer))
@@ -134,14 +141,20 @@ impl futures::Stream for Consumer {
             Async::Ready(option) => option,
             Async::NotReady => return Ok(Async::NotReady),
         };
-        let message = match option {
-            Some(message) => message,
+        let (queue, message) = match option {
+            Some(incoming) => incoming,
             None => return Ok(Async::Ready(None)),
         };
-        Ok(Async::Ready(Some(Delivery(message))))
+        Ok(Async::Ready(Some(Delivery::new(
+            message,
+            queue,
+            self.handle(),
+        ))))
     }
 }
 
+/// A handle used to acknowledge or reject the messages received by a `Consumer`.
+#[derive(Clone)]
 pub struct ConsumerHandle(Channel<Stream>, Arc<HeartbeatHandle>);
 
 impl ConsumerHandle {
@@ -171,4 +184,32 @@ impl ConsumerHandle {
             .map_err(|e| ErrorKind::Rabbitmq(e).into());
         Box::new(task)
     }
+
+    /// Reject a `Job`, asking the broker to deliver it again.
+    ///
+    /// Returns a `Future` that completes once the `reject` is sent to the broker.
+    pub fn requeue(&self, uid: u64) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Requeuing message {}", uid);
+        let task = self.0
+            .basic_reject(uid, true)
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
+
+    /// Publish a message to the given queue through the default exchange.
+    ///
+    /// Returns a `Future` that completes once the message is sent to the broker.
+    pub fn publish(
+        &self,
+        queue: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        trace!("Publishing message to {:?}", queue);
+        let task = self.0
+            .basic_publish("", queue, payload, BasicPublishOptions::default(), properties)
+            .map(|_| ())
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
 }
@@ -0,0 +1,178 @@
+use std::fmt;
+use std::result::Result as StdResult;
+
+use futures::{future, Future, Stream as FuturesStream};
+use lapin::channel::{
+    BasicConsumeOptions, BasicProperties, BasicPublishOptions, Channel, QueueBindOptions,
+    QueueDeclareOptions,
+};
+use lapin::types::FieldTable;
+use tokio_reactor::Handle;
+use uuid::Uuid;
+
+use control::{Command, Commands, ControlChannel};
+use de;
+use error::{Error, ErrorKind};
+use rabbitmq::builder::ConnectionBuilder;
+use rabbitmq::common::{connect, exchange_options, HeartbeatHandle};
+use rabbitmq::stream::Stream;
+use ser;
+use topology::exchange;
+
+/// The name of the exchange commands are sent through.
+const CONTROL_EXCHANGE: &str = "batch.control";
+
+/// The routing key of the commands sent to every worker.
+const BROADCAST_ROUTING_KEY: &str = "all";
+
+/// A `ControlChannel` implementation backed by a `RabbitMQ` direct exchange.
+///
+/// Each listening worker declares an exclusive queue, bound to the `batch.control` exchange with
+/// its ID and with the `all` routing key. Sending a command opens a dedicated connection.
+#[derive(Clone)]
+pub struct ControlExchange {
+    connection: ConnectionBuilder,
+    handle: Handle,
+}
+
+impl fmt::Debug for ControlExchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "ControlExchange {{ connection_url: {:?} }}",
+            self.connection.url()
+        )
+    }
+}
+
+impl ControlExchange {
+    /// Create a `ControlExchange` instance from RabbitMQ connection settings and an explicit
+    /// tokio handle.
+    pub fn new_with_handle(connection: &ConnectionBuilder, handle: Handle) -> Self {
+        ControlExchange {
+            connection: connection.clone(),
+            handle,
+        }
+    }
+
+    /// Open a channel, and declare the control exchange on it.
+    fn channel(
+        &self,
+    ) -> Box<Future<Item = (Channel<Stream>, HeartbeatHandle), Error = Error> + Send> {
+        let exchange = exchange(CONTROL_EXCHANGE).build();
+        let task = connect(&self.connection, self.handle.clone())
+            .and_then(|(client, heartbeat_handle)| {
+                trace!("Creating control's RabbitMQ channel");
+                client
+                    .create_channel()
+                    .map(|channel| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .and_then(move |(channel, heartbeat_handle)| {
+                channel
+                    .exchange_declare(
+                        exchange.name(),
+                        exchange.kind(),
+                        exchange_options(exchange.options()),
+                        exchange.arguments().clone(),
+                    )
+                    .map(|_| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            });
+        Box::new(task)
+    }
+}
+
+impl ControlChannel for ControlExchange {
+    fn send(
+        &self,
+        worker: Option<Uuid>,
+        command: Command,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let payload = match ser::to_vec(&command) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(ErrorKind::Serialization(e).into())),
+        };
+        let routing_key = worker.map_or(BROADCAST_ROUTING_KEY.to_string(), |id| id.to_string());
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            trace!("Sending command {:?} to {:?}", command, routing_key);
+            let properties = BasicProperties {
+                content_type: Some("application/json".to_string()),
+                ..Default::default()
+            };
+            channel
+                .basic_publish(
+                    CONTROL_EXCHANGE,
+                    &routing_key,
+                    &payload,
+                    BasicPublishOptions::default(),
+                    properties,
+                )
+                .and_then(move |_| channel.close(200, "Bye"))
+                .map(move |_| drop(heartbeat_handle))
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+
+    fn listen(&self, worker: Uuid) -> Box<Future<Item = Commands, Error = Error> + Send> {
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            trace!("Declaring control's queue");
+            let options = QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            };
+            let binding_channel = channel.clone();
+            let consuming_channel = channel.clone();
+            channel
+                .queue_declare("", options, FieldTable::new())
+                .and_then(move |queue| {
+                    let bindings = vec![worker.to_string(), BROADCAST_ROUTING_KEY.to_string()]
+                        .into_iter()
+                        .map(|routing_key| {
+                            binding_channel.queue_bind(
+                                queue.name(),
+                                CONTROL_EXCHANGE,
+                                &routing_key,
+                                QueueBindOptions::default(),
+                                FieldTable::new(),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    future::join_all(bindings).map(|_| queue)
+                })
+                .and_then(move |queue| {
+                    let options = BasicConsumeOptions {
+                        no_ack: true,
+                        exclusive: true,
+                        ..Default::default()
+                    };
+                    consuming_channel.basic_consume(
+                        &queue,
+                        "batch-rs-control",
+                        options,
+                        FieldTable::new(),
+                    )
+                })
+                .map(move |consumer| -> Commands {
+                    let commands = consumer
+                        .map_err(|e| -> Error { ErrorKind::Rabbitmq(e).into() })
+                        .filter_map(move |message| {
+                            // Keep the channel open as long as commands are received.
+                            let _ = (&channel, &heartbeat_handle);
+                            match de::from_slice::<Command>(&message.data) {
+                                Ok(command) => Some(command),
+                                Err(e) => {
+                                    error!("Couldn't deserialize command: {}", e);
+                                    None
+                                }
+                            }
+                        });
+                    Box::new(commands)
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+}
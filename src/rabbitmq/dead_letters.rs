@@ -0,0 +1,287 @@
+use std::fmt;
+use std::io;
+use std::result::Result as StdResult;
+
+use chrono::{TimeZone, Utc};
+use futures::{future, Future};
+use lapin::channel::{
+    BasicGetOptions, BasicProperties, BasicPublishOptions, Channel, QueuePurgeOptions,
+};
+use lapin::message::Delivery as Message;
+use lapin::types::{AMQPValue, FieldTable};
+use tokio_reactor::Handle;
+use uuid::Uuid;
+
+use broker::Properties;
+use de;
+use dead_letter::{dead_letter_queue, DeadJob, DeadLetterQueue};
+use error::{Error, ErrorKind};
+use job::Failure;
+use rabbitmq::builder::ConnectionBuilder;
+use rabbitmq::common::{connect, HeartbeatHandle};
+use rabbitmq::delivery::{from_amqp_properties, to_amqp_properties};
+use rabbitmq::stream::Stream;
+use ser;
+
+/// The header holding the name of the queue a dead job was consumed from.
+const QUEUE_HEADER: &str = "x-batch-queue";
+/// The header holding the exchange a dead job was originally published to.
+const EXCHANGE_HEADER: &str = "x-batch-exchange";
+/// The header holding the routing key a dead job was originally published with.
+const ROUTING_KEY_HEADER: &str = "x-batch-routing-key";
+/// The header holding the serialized reason of a dead job's last failure.
+const FAILURE_HEADER: &str = "x-batch-failure";
+/// The header holding the timestamp at which a job was dead-lettered.
+const DIED_AT_HEADER: &str = "x-batch-died-at";
+
+/// Convert the given job properties to the `RabbitMQ` message properties of a dead job.
+///
+/// The original destination of the job and the reason of its failure are stored as headers.
+pub(crate) fn to_dead_letter_properties(
+    properties: &Properties,
+    queue: &str,
+    failure: &Failure,
+) -> Result<BasicProperties, Error> {
+    let failure = ser::to_string(failure).map_err(ErrorKind::Serialization)?;
+    let mut amqp_properties = to_amqp_properties(properties);
+    {
+        let headers = amqp_properties.headers.get_or_insert_with(FieldTable::new);
+        headers.insert(
+            QUEUE_HEADER.to_string(),
+            AMQPValue::LongString(queue.to_string()),
+        );
+        headers.insert(
+            EXCHANGE_HEADER.to_string(),
+            AMQPValue::LongString(properties.exchange.clone()),
+        );
+        headers.insert(
+            ROUTING_KEY_HEADER.to_string(),
+            AMQPValue::LongString(properties.routing_key.clone()),
+        );
+        headers.insert(FAILURE_HEADER.to_string(), AMQPValue::LongString(failure));
+        headers.insert(
+            DIED_AT_HEADER.to_string(),
+            AMQPValue::Timestamp(Utc::now().timestamp() as u64),
+        );
+    }
+    Ok(amqp_properties)
+}
+
+/// Extract a dead job from a message of a dead-letter queue.
+fn from_dead_letter(message: &Message) -> Option<DeadJob> {
+    let headers = message.properties.headers.as_ref()?;
+    let string = |name| match headers.get(name) {
+        Some(&AMQPValue::LongString(ref value)) => Some(value.clone()),
+        _ => None,
+    };
+    let mut properties = from_amqp_properties(message);
+    properties.exchange = string(EXCHANGE_HEADER)?;
+    properties.routing_key = string(ROUTING_KEY_HEADER)?;
+    let failure = de::from_str(&string(FAILURE_HEADER)?).ok()?;
+    let died_at = match headers.get(DIED_AT_HEADER) {
+        Some(&AMQPValue::Timestamp(timestamp)) => Utc.timestamp(timestamp as i64, 0),
+        _ => return None,
+    };
+    Some(DeadJob {
+        queue: string(QUEUE_HEADER)?,
+        properties,
+        payload: message.data.clone(),
+        failure,
+        died_at,
+    })
+}
+
+/// A `DeadLetterQueue` implementation backed by `RabbitMQ`.
+///
+/// Each operation opens a dedicated connection. Dead jobs are read without being acknowledged,
+/// so that closing the channel puts back the ones that were only listed.
+#[derive(Clone)]
+pub struct DeadLetters {
+    connection: ConnectionBuilder,
+    handle: Handle,
+}
+
+impl fmt::Debug for DeadLetters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "DeadLetters {{ connection_url: {:?} }}",
+            self.connection.url()
+        )
+    }
+}
+
+impl DeadLetters {
+    /// Create a `DeadLetters` instance from RabbitMQ connection settings and an explicit tokio
+    /// handle.
+    pub fn new_with_handle(connection: &ConnectionBuilder, handle: Handle) -> Self {
+        DeadLetters {
+            connection: connection.clone(),
+            handle,
+        }
+    }
+
+    /// Open a channel dedicated to a single operation.
+    fn channel(
+        &self,
+    ) -> Box<Future<Item = (Channel<Stream>, HeartbeatHandle), Error = Error> + Send> {
+        let task = connect(&self.connection, self.handle.clone()).and_then(
+            |(client, heartbeat_handle)| {
+                trace!("Creating dead-letters' RabbitMQ channel");
+                client
+                    .create_channel()
+                    .map(|channel| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            },
+        );
+        Box::new(task)
+    }
+}
+
+/// Fetch the messages of the given queue without acknowledging them, until it looks empty or
+/// the given predicate returns `true`.
+pub(crate) fn fetch<F>(
+    channel: Channel<Stream>,
+    queue: String,
+    mut done: F,
+) -> Box<Future<Item = Vec<Message>, Error = io::Error> + Send>
+where
+    F: FnMut(&Message) -> bool + Send + 'static,
+{
+    let task = future::loop_fn(Vec::new(), move |mut messages| {
+        channel
+            .basic_get(&queue, BasicGetOptions::default())
+            .then(move |result| match result {
+                Ok(message) => {
+                    let stop = done(&message.delivery);
+                    messages.push(message.delivery);
+                    if stop {
+                        Ok(future::Loop::Break(messages))
+                    } else {
+                        Ok(future::Loop::Continue(messages))
+                    }
+                }
+                // `basic.get` fails once the queue is empty.
+                Err(_) => Ok(future::Loop::Break(messages)),
+            })
+    });
+    Box::new(task)
+}
+
+/// Fetch the messages of the given dead-letter queue until the dead job with the given ID is
+/// found, without acknowledging them.
+fn find(
+    channel: Channel<Stream>,
+    queue: String,
+    id: Uuid,
+) -> Box<Future<Item = Option<(Message, DeadJob)>, Error = io::Error> + Send> {
+    let matches = move |message: &Message| {
+        message
+            .properties
+            .correlation_id
+            .as_ref()
+            .map_or(false, |correlation_id| *correlation_id == id.to_string())
+    };
+    let task = fetch(channel, queue, matches).map(move |mut messages| {
+        messages
+            .pop()
+            .and_then(|message| from_dead_letter(&message).map(|job| (message, job)))
+            .filter(|&(_, ref job)| job.properties.id == id)
+    });
+    Box::new(task)
+}
+
+impl DeadLetterQueue for DeadLetters {
+    fn list(&self, queue: &str) -> Box<Future<Item = Vec<DeadJob>, Error = Error> + Send> {
+        let queue = dead_letter_queue(queue);
+        let task = self.channel()
+            .and_then(move |(channel, heartbeat_handle)| {
+                fetch(channel.clone(), queue, |_| false)
+                    .and_then(move |messages| {
+                        channel.close(200, "Bye").map(move |_| {
+                            drop(heartbeat_handle);
+                            messages
+                        })
+                    })
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .map(|messages| messages.iter().filter_map(from_dead_letter).collect());
+        Box::new(task)
+    }
+
+    fn requeue(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let queue = dead_letter_queue(queue);
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            find(channel.clone(), queue, id)
+                .and_then(move |found| {
+                    let requeue: Box<Future<Item = bool, Error = io::Error> + Send> = match found {
+                        Some((message, job)) => {
+                            debug!(
+                                "[{}] Sending dead job back to {:?}",
+                                id, job.properties.exchange
+                            );
+                            let properties = job.requeue_properties();
+                            let channel_ = channel.clone();
+                            let task = channel
+                                .basic_publish(
+                                    &properties.exchange,
+                                    &properties.routing_key,
+                                    &job.payload,
+                                    BasicPublishOptions::default(),
+                                    to_amqp_properties(&properties),
+                                )
+                                .and_then(move |_| channel_.basic_ack(message.delivery_tag))
+                                .map(|_| true);
+                            Box::new(task)
+                        }
+                        None => Box::new(future::ok(false)),
+                    };
+                    requeue.and_then(move |found| {
+                        channel.close(200, "Bye").map(move |_| {
+                            drop(heartbeat_handle);
+                            found
+                        })
+                    })
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+
+    fn remove(&self, queue: &str, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send> {
+        let queue = dead_letter_queue(queue);
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            find(channel.clone(), queue, id)
+                .and_then(move |found| {
+                    let remove: Box<Future<Item = bool, Error = io::Error> + Send> = match found {
+                        Some((message, _)) => {
+                            debug!("[{}] Removing dead job", id);
+                            Box::new(channel.basic_ack(message.delivery_tag).map(|_| true))
+                        }
+                        None => Box::new(future::ok(false)),
+                    };
+                    remove.and_then(move |found| {
+                        channel.close(200, "Bye").map(move |_| {
+                            drop(heartbeat_handle);
+                            found
+                        })
+                    })
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+
+    fn purge(&self, queue: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let queue = dead_letter_queue(queue);
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            trace!("Purging dead-letter queue {:?}", queue);
+            channel
+                .queue_purge(&queue, QueuePurgeOptions::default())
+                .and_then(move |_| channel.close(200, "Bye"))
+                .map(move |_| drop(heartbeat_handle))
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+}
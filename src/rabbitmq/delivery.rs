@@ -1,131 +1,315 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::Arc;
 use std::time::Duration;
 
-use lapin::channel::BasicProperties as Properties;
+use chrono::{TimeZone, Utc};
+use futures::{future, Future};
+use lapin::channel::BasicProperties;
 use lapin::message::Delivery as Message;
-use lapin::types::{self, AMQPValue, FieldTable};
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "Properties")]
-struct PropertiesDef {
-    pub content_type: Option<types::ShortString>,
-    pub content_encoding: Option<types::ShortString>,
-    pub headers: Option<types::FieldTable>,
-    pub delivery_mode: Option<types::ShortShortUInt>,
-    pub priority: Option<types::ShortShortUInt>,
-    pub correlation_id: Option<types::ShortString>,
-    pub reply_to: Option<types::ShortString>,
-    pub expiration: Option<types::ShortString>,
-    pub message_id: Option<types::ShortString>,
-    pub timestamp: Option<types::Timestamp>,
-    pub type_: Option<types::ShortString>,
-    pub user_id: Option<types::ShortString>,
-    pub app_id: Option<types::ShortString>,
-    pub cluster_id: Option<types::ShortString>,
-}
+use lapin::types::{AMQPValue, FieldTable};
+use uuid::Uuid;
+
+use broker::{self, Properties};
+use codec::Codec;
+use compression::Compression;
+use error::Error;
+use job::{Failure, Priority};
+use rabbitmq::celery::from_celery_message;
+use rabbitmq::consumer::ConsumerHandle;
+use rabbitmq::dead_letters::to_dead_letter_properties;
+use topology::Queue;
 
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "Message")]
-struct MessageDef {
-    pub delivery_tag: types::LongLongUInt,
-    pub exchange: String,
-    pub routing_key: String,
-    pub redelivered: bool,
-    #[serde(with = "PropertiesDef")]
-    pub properties: Properties,
-    pub data: Vec<u8>,
+/// A job received from `RabbitMQ`.
+pub struct Delivery {
+    message: Message,
+    properties: Properties,
+    /// The payload of the job, when it differs from the body of the message.
+    payload: Option<Vec<u8>>,
+    queue: Arc<Queue>,
+    handle: ConsumerHandle,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Delivery(#[serde(with = "MessageDef")] pub Message);
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Delivery {{ tag: {:?} properties: {:?} }}",
+            self.message.delivery_tag, self.properties
+        )
+    }
+}
 
 impl Delivery {
+    pub(crate) fn new(message: Message, queue: Arc<Queue>, handle: ConsumerHandle) -> Self {
+        let properties = from_amqp_properties(&message);
+        let payload = from_celery_message(&message, &properties);
+        Delivery {
+            message,
+            properties,
+            payload,
+            queue,
+            handle,
+        }
+    }
+
     pub fn tag(&self) -> u64 {
-        self.0.delivery_tag
+        self.message.delivery_tag
     }
 
     pub fn task(&self) -> &str {
-        self.0
-            .properties
-            .headers
-            .as_ref()
-            .map(|hdrs| match hdrs.get("task") {
-                Some(&AMQPValue::LongString(ref task)) => task.as_ref(),
-                _ => "",
-            })
-            .unwrap_or("")
+        &self.properties.task
     }
+}
 
-    pub fn task_id(&self) -> &str {
-        self.0
-            .properties
-            .correlation_id
-            .as_ref()
-            .map_or("", String::as_ref)
+impl broker::Delivery for Delivery {
+    fn properties(&self) -> &Properties {
+        &self.properties
     }
 
-    pub fn exchange(&self) -> &str {
-        &self.0.exchange
+    fn payload(&self) -> &[u8] {
+        self.payload.as_ref().unwrap_or(&self.message.data)
     }
 
-    pub fn routing_key(&self) -> &str {
-        &self.0.routing_key
+    fn queue(&self) -> Option<&str> {
+        Some(self.queue.name())
     }
 
-    pub fn data(&self) -> &[u8] {
-        &self.0.data
+    fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.handle.ack(self.message.delivery_tag)
     }
 
-    pub fn properties(&self) -> &Properties {
-        &self.0.properties
+    fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.handle.reject(self.message.delivery_tag)
     }
 
-    pub fn timeout(&self) -> (Option<Duration>, Option<Duration>) {
-        self.0
-            .properties
-            .headers
-            .as_ref()
-            .map(|hdrs| match hdrs.get("timelimit") {
-                Some(&AMQPValue::FieldArray(ref vec)) if vec.len() == 2 => {
-                    let soft_limit = match vec[0] {
-                        AMQPValue::Timestamp(s) => Some(Duration::from_secs(s)),
-                        _ => None,
-                    };
-                    let hard_limit = match vec[1] {
-                        AMQPValue::Timestamp(s) => Some(Duration::from_secs(s)),
-                        _ => None,
-                    };
-                    (soft_limit, hard_limit)
-                }
-                _ => (None, None),
-            })
-            .unwrap_or((None, None))
+    fn requeue(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.handle.requeue(self.message.delivery_tag)
     }
 
-    pub fn retries(&self) -> u32 {
-        self.0
-            .properties
-            .headers
-            .as_ref()
-            .map(|hdrs| match hdrs.get("retries") {
-                Some(&AMQPValue::LongUInt(retries)) => retries,
-                _ => 0,
-            })
-            .unwrap_or(0)
+    fn dead_letter(
+        self: Box<Self>,
+        failure: Failure,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let dead_letter_queue = match self.queue.dead_letter_queue() {
+            Some(dead_letter_queue) => dead_letter_queue,
+            None => return self.reject(),
+        };
+        let amqp_properties =
+            match to_dead_letter_properties(&self.properties, self.queue.name(), &failure) {
+                Ok(amqp_properties) => amqp_properties,
+                Err(e) => return Box::new(future::err(e)),
+            };
+        debug!(
+            "[{}] Moving job to dead-letter queue {:?}",
+            self.properties.id, dead_letter_queue
+        );
+        let handle = self.handle.clone();
+        let tag = self.message.delivery_tag;
+        // Celery messages are dead-lettered using Batch's protocol, like their properties.
+        let payload = self.payload.as_ref().unwrap_or(&self.message.data);
+        let task = self.handle
+            .publish(&dead_letter_queue, payload, amqp_properties)
+            .and_then(move |_| handle.ack(tag));
+        Box::new(task)
     }
 
-    pub fn incr_retries(&mut self) -> u32 {
-        let incrd_retries = self.retries() + 1;
-        let mut headers = self.0
-            .properties
+    fn dead_letter_with_headers(
+        mut self: Box<Self>,
+        failure: Failure,
+        headers: BTreeMap<String, String>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.properties.headers.extend(headers);
+        self.dead_letter(failure)
+    }
+}
+
+/// The header holding the custom headers of a job, see `Properties::headers`.
+const CUSTOM_HEADERS: &str = "x-batch-headers";
+
+/// The header holding the maximum number of retries of a job, see `Properties::max_retries`.
+const MAX_RETRIES_HEADER: &str = "x-batch-max-retries";
+
+/// The header holding the version of the schema of a job, see `Properties::version`.
+const VERSION_HEADER: &str = "x-batch-version";
+
+/// Convert the given job properties to `RabbitMQ` message properties.
+///
+/// The headers follow the layout of the Celery message protocol.
+pub(crate) fn to_amqp_properties(properties: &Properties) -> BasicProperties {
+    let task_id = properties.id.to_string();
+    let mut headers = FieldTable::new();
+    headers.insert("lang".to_string(), AMQPValue::LongString("rs".to_string()));
+    headers.insert(
+        "task".to_string(),
+        AMQPValue::LongString(properties.task.clone()),
+    );
+    headers.insert("id".to_string(), AMQPValue::LongString(task_id.clone()));
+    headers.insert("root_id".to_string(), AMQPValue::Void);
+    headers.insert("parent_id".to_string(), AMQPValue::Void);
+    headers.insert("group".to_string(), AMQPValue::Void);
+    headers.insert(
+        "origin".to_string(),
+        properties
+            .origin
+            .clone()
+            .map_or(AMQPValue::Void, AMQPValue::LongString),
+    );
+    headers.insert(
+        "timelimit".to_string(),
+        AMQPValue::FieldArray(vec![
+            AMQPValue::Void,
+            properties
+                .timeout
+                .map_or(AMQPValue::Void, |d| AMQPValue::Timestamp(d.as_secs())),
+        ]),
+    );
+    headers.insert(
+        "retries".to_string(),
+        AMQPValue::LongUInt(properties.retries),
+    );
+    if let Some(max_retries) = properties.max_retries {
+        headers.insert(
+            MAX_RETRIES_HEADER.to_string(),
+            AMQPValue::LongUInt(max_retries),
+        );
+    }
+    if let Some(version) = properties.version {
+        headers.insert(VERSION_HEADER.to_string(), AMQPValue::LongUInt(version));
+    }
+    if !properties.headers.is_empty() {
+        let custom = properties
             .headers
-            .take()
-            .unwrap_or_else(FieldTable::new);
-        headers.insert("retries".to_string(), AMQPValue::LongUInt(incrd_retries));
-        self.0.properties.headers = Some(headers);
-        incrd_retries
+            .iter()
+            .map(|(name, value)| (name.clone(), AMQPValue::LongString(value.clone())))
+            .collect();
+        headers.insert(CUSTOM_HEADERS.to_string(), AMQPValue::FieldTable(custom));
     }
+    BasicProperties {
+        priority: Some(properties.priority.to_u8()),
+        content_type: Some(properties.codec.content_type().to_string()),
+        content_encoding: Some(
+            properties
+                .compression
+                .map_or("utf-8", |compression| compression.content_encoding())
+                .to_string(),
+        ),
+        headers: Some(headers),
+        correlation_id: Some(task_id),
+        reply_to: properties.reply_to.clone(),
+        timestamp: properties
+            .enqueued_at
+            .map(|enqueued_at| enqueued_at.timestamp() as u64),
+        expiration: properties.expires.map(|expires| {
+            (expires.as_secs() * 1000 + u64::from(expires.subsec_millis())).to_string()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Return the value of the given integer field, as sent by Batch or Celery producers.
+fn to_u64(value: &AMQPValue) -> Option<u64> {
+    match *value {
+        AMQPValue::ShortShortUInt(n) => Some(u64::from(n)),
+        AMQPValue::ShortUInt(n) => Some(u64::from(n)),
+        AMQPValue::LongUInt(n) => Some(u64::from(n)),
+        AMQPValue::ShortShortInt(n) if n > 0 => Some(n as u64),
+        AMQPValue::ShortInt(n) if n > 0 => Some(n as u64),
+        AMQPValue::LongInt(n) if n > 0 => Some(n as u64),
+        AMQPValue::LongLongInt(n) if n > 0 => Some(n as u64),
+        _ => None,
+    }
+}
 
-    pub fn should_retry(&mut self, max_retries: u32) -> bool {
-        self.incr_retries() < max_retries
+/// Extract the job properties from a `RabbitMQ` message.
+pub(crate) fn from_amqp_properties(message: &Message) -> Properties {
+    let empty = FieldTable::new();
+    let headers = message.properties.headers.as_ref().unwrap_or(&empty);
+    let task = match headers.get("task") {
+        Some(&AMQPValue::LongString(ref task)) => task.clone(),
+        _ => "".into(),
+    };
+    let id = message
+        .properties
+        .correlation_id
+        .as_ref()
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .unwrap_or_else(Uuid::nil);
+    // Celery producers send the soft and hard time limits as numbers of seconds.
+    let timeout = match headers.get("timelimit") {
+        Some(&AMQPValue::FieldArray(ref vec)) if vec.len() == 2 => match vec[1] {
+            AMQPValue::Timestamp(s) => Some(Duration::from_secs(s)),
+            AMQPValue::Double(s) if s > 0.0 => Some(Duration::from_millis((s * 1000.0) as u64)),
+            AMQPValue::Float(s) if s > 0.0 => Some(Duration::from_millis((s * 1000.0) as u64)),
+            ref value => to_u64(value).map(Duration::from_secs),
+        },
+        _ => None,
+    };
+    let retries = headers.get("retries").and_then(to_u64).unwrap_or(0) as u32;
+    let max_retries = headers
+        .get(MAX_RETRIES_HEADER)
+        .and_then(to_u64)
+        .map(|max_retries| max_retries as u32);
+    let version = headers
+        .get(VERSION_HEADER)
+        .and_then(to_u64)
+        .map(|version| version as u32);
+    let origin = match headers.get("origin") {
+        Some(&AMQPValue::LongString(ref origin)) => Some(origin.clone()),
+        _ => None,
+    };
+    let codec = match message.properties.content_type {
+        Some(ref content_type) => Codec::from_content_type(content_type).unwrap_or_else(|| {
+            warn!("Unsupported content type {:?}, assuming JSON", content_type);
+            Codec::Json
+        }),
+        None => Codec::Json,
+    };
+    let expires = message
+        .properties
+        .expiration
+        .as_ref()
+        .and_then(|expiration| expiration.parse::<u64>().ok())
+        .map(Duration::from_millis);
+    let compression = message
+        .properties
+        .content_encoding
+        .as_ref()
+        .and_then(|content_encoding| Compression::from_content_encoding(content_encoding));
+    let custom = match headers.get(CUSTOM_HEADERS) {
+        Some(&AMQPValue::FieldTable(ref custom)) => custom
+            .iter()
+            .filter_map(|(name, value)| match *value {
+                AMQPValue::LongString(ref value) => Some((name.clone(), value.clone())),
+                _ => None,
+            })
+            .collect(),
+        _ => BTreeMap::new(),
+    };
+    Properties {
+        id,
+        task,
+        exchange: message.exchange.clone(),
+        routing_key: message.routing_key.clone(),
+        priority: message
+            .properties
+            .priority
+            .map_or_else(Priority::default, Priority::from_u8),
+        timeout,
+        delay: None,
+        retries,
+        max_retries,
+        reply_to: message.properties.reply_to.clone(),
+        enqueued_at: message
+            .properties
+            .timestamp
+            .map(|timestamp| Utc.timestamp(timestamp as i64, 0)),
+        expires,
+        origin,
+        codec,
+        compression,
+        version,
+        headers: custom,
     }
 }
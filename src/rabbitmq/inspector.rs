@@ -0,0 +1,208 @@
+use std::fmt;
+use std::io;
+use std::result::Result as StdResult;
+
+use futures::{future, Future};
+use lapin::channel::{BasicGetOptions, Channel, QueueDeclareOptions};
+use lapin::message::Delivery as Message;
+use lapin::types::FieldTable;
+use tokio_reactor::Handle;
+
+use error::{Error, ErrorKind};
+use inspect::{PendingJob, QueueInspector, QueueStats};
+use rabbitmq::builder::ConnectionBuilder;
+use rabbitmq::celery::from_celery_message;
+use rabbitmq::common::{connect, HeartbeatHandle};
+use rabbitmq::dead_letters::fetch;
+use rabbitmq::delivery::from_amqp_properties;
+use rabbitmq::stream::Stream;
+
+/// A `QueueInspector` implementation backed by `RabbitMQ`.
+///
+/// Each operation opens a dedicated connection. The number of pending jobs is read from the
+/// response to `basic.get`, and jobs are peeked using `basic.get` as well. Fetched messages
+/// aren't acknowledged: closing the channel puts them back, flagged as redelivered. Statistics
+/// are read from the response to a passive `queue.declare`, which doesn't fetch any message, or
+/// from the management API if one is set (see `ConnectionBuilder::management`). Only the
+/// management API can list the queues declared on the fly, see `QueueInspector::discover`.
+#[derive(Clone)]
+pub struct Inspector {
+    connection: ConnectionBuilder,
+    handle: Handle,
+    queues: Vec<String>,
+}
+
+impl fmt::Debug for Inspector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Inspector {{ connection_url: {:?} queues: {:?} }}",
+            self.connection.url(),
+            self.queues
+        )
+    }
+}
+
+impl Inspector {
+    /// Create an `Inspector` instance for the given queues from RabbitMQ connection settings and
+    /// an explicit tokio handle.
+    pub fn new_with_handle(
+        connection: &ConnectionBuilder,
+        handle: Handle,
+        queues: Vec<String>,
+    ) -> Self {
+        Inspector {
+            connection: connection.clone(),
+            handle,
+            queues,
+        }
+    }
+
+    /// Open a channel dedicated to a single operation.
+    fn channel(
+        &self,
+    ) -> Box<Future<Item = (Channel<Stream>, HeartbeatHandle), Error = Error> + Send> {
+        let task = connect(&self.connection, self.handle.clone()).and_then(
+            |(client, heartbeat_handle)| {
+                trace!("Creating inspector's RabbitMQ channel");
+                client
+                    .create_channel()
+                    .map(|channel| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            },
+        );
+        Box::new(task)
+    }
+}
+
+impl QueueInspector for Inspector {
+    fn queues(&self) -> Vec<String> {
+        self.queues.clone()
+    }
+
+    fn discover(&self) -> Box<Future<Item = Vec<String>, Error = Error> + Send> {
+        #[cfg(feature = "management")]
+        {
+            if let Some(management) = self.connection.get_management() {
+                let task = management
+                    .queues()
+                    .map(|queues| queues.into_iter().map(|queue| queue.name).collect());
+                return Box::new(task);
+            }
+        }
+        Box::new(future::ok(self.queues.clone()))
+    }
+
+    fn pending(&self, queue: &str) -> Box<Future<Item = u64, Error = Error> + Send> {
+        #[cfg(feature = "management")]
+        {
+            if let Some(management) = self.connection.get_management() {
+                return Box::new(management.stats(queue).map(|stats| stats.messages));
+            }
+        }
+        let queue = queue.to_string();
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            channel
+                .basic_get(&queue, BasicGetOptions::default())
+                .then(|result| -> StdResult<u64, io::Error> {
+                    // `message_count` doesn't include the message that was fetched, and
+                    // `basic.get` fails once the queue is empty.
+                    Ok(result.map_or(0, |message| u64::from(message.message_count) + 1))
+                })
+                .and_then(move |pending| {
+                    channel.close(200, "Bye").map(move |_| {
+                        drop(heartbeat_handle);
+                        pending
+                    })
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+
+    fn stats(&self, queue: &str) -> Box<Future<Item = QueueStats, Error = Error> + Send> {
+        #[cfg(feature = "management")]
+        {
+            if let Some(management) = self.connection.get_management() {
+                return management.stats(queue);
+            }
+        }
+        let queue = queue.to_string();
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            let options = QueueDeclareOptions {
+                passive: true,
+                ..Default::default()
+            };
+            channel
+                .queue_declare(&queue, options, FieldTable::new())
+                .map(move |declared| QueueStats {
+                    name: queue,
+                    messages: u64::from(declared.message_count()),
+                    consumers: Some(declared.consumer_count()),
+                })
+                .and_then(move |stats| {
+                    channel.close(200, "Bye").map(move |_| {
+                        drop(heartbeat_handle);
+                        stats
+                    })
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+
+    fn is_prioritized(&self, queue: &str) -> Box<Future<Item = bool, Error = Error> + Send> {
+        #[cfg(feature = "management")]
+        {
+            if let Some(management) = self.connection.get_management() {
+                let task = management
+                    .queue(queue)
+                    .map(|details| details.arguments.contains_key("x-max-priority"));
+                return Box::new(task);
+            }
+        }
+        let _ = queue;
+        Box::new(future::err(ErrorKind::UnsupportedQueueArguments.into()))
+    }
+
+    fn peek(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Box<Future<Item = Vec<PendingJob>, Error = Error> + Send> {
+        if count == 0 {
+            return Box::new(future::ok(Vec::new()));
+        }
+        let queue = queue.to_string();
+        let task = self.channel().and_then(move |(channel, heartbeat_handle)| {
+            let mut fetched = 0;
+            let done = move |_: &Message| {
+                fetched += 1;
+                fetched >= count
+            };
+            fetch(channel.clone(), queue, done)
+                .and_then(move |messages| {
+                    channel.close(200, "Bye").map(move |_| {
+                        drop(heartbeat_handle);
+                        messages
+                    })
+                })
+                .map(|messages| {
+                    messages
+                        .into_iter()
+                        .map(|message| {
+                            let properties = from_amqp_properties(&message);
+                            let payload =
+                                from_celery_message(&message, &properties).unwrap_or(message.data);
+                            PendingJob {
+                                properties,
+                                payload,
+                            }
+                        })
+                        .collect()
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        });
+        Box::new(task)
+    }
+}
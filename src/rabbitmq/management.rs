@@ -0,0 +1,407 @@
+use std::fmt;
+use std::result::Result as StdResult;
+
+use base64;
+use futures::{future, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Body, Client, Method, Request};
+use amq_protocol::types::{AMQPValue, FieldTable};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+
+use error::{Error, ErrorKind};
+use inspect::QueueStats;
+use topology::{self, Topology};
+
+/// A client of the `RabbitMQ` management HTTP API.
+///
+/// AMQP only tells how many jobs are ready to be consumed from a queue. The management API also
+/// knows about the jobs delivered but not acknowledged yet, and about the connections to the
+/// broker. Give it to `ConnectionBuilder::management` to read the statistics of queues from it
+/// (see `Queues`), or use it directly. Only plain HTTP is supported.
+///
+/// # Example
+///
+/// ```
+/// use batch::{ConnectionBuilder, Management};
+///
+/// let management = Management::new("http://localhost:15672", "guest", "guest")
+///     .vhost("/");
+/// let connection = ConnectionBuilder::new("amqp://localhost/%2f")
+///     .management(management);
+/// ```
+#[derive(Clone)]
+pub struct Management {
+    client: Client<HttpConnector>,
+    url: String,
+    authorization: String,
+    vhost: String,
+}
+
+impl fmt::Debug for Management {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Management {{ url: {:?} vhost: {:?} }}",
+            self.url, self.vhost
+        )
+    }
+}
+
+/// The details of a queue, as reported by the `RabbitMQ` management API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDetails {
+    /// The name of the queue.
+    pub name: String,
+    /// The virtual host of the queue.
+    pub vhost: String,
+    /// The number of jobs in the queue, ready or unacknowledged.
+    #[serde(default)]
+    pub messages: u64,
+    /// The number of jobs ready to be consumed.
+    #[serde(default)]
+    pub messages_ready: u64,
+    /// The number of jobs delivered to consumers but not acknowledged yet.
+    #[serde(default)]
+    pub messages_unacknowledged: u64,
+    /// The number of consumers of the queue.
+    #[serde(default)]
+    pub consumers: u32,
+    /// The arguments the queue was declared with.
+    #[serde(default)]
+    pub arguments: Map<String, Value>,
+}
+
+/// The details of a connection to the broker, as reported by the `RabbitMQ` management API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionDetails {
+    /// The name of the connection.
+    pub name: String,
+    /// The user the connection is authenticated as.
+    pub user: String,
+    /// The virtual host the connection is opened on.
+    pub vhost: String,
+    /// The address of the client.
+    #[serde(default)]
+    pub peer_host: Option<String>,
+    /// The port of the client.
+    #[serde(default)]
+    pub peer_port: Option<u16>,
+    /// The number of channels opened on the connection.
+    #[serde(default)]
+    pub channels: u32,
+    /// The state of the connection, e.g: `running` or `blocked`.
+    #[serde(default)]
+    pub state: Option<String>,
+}
+
+/// An exchange, as reported by the `RabbitMQ` management API.
+#[derive(Debug, Deserialize)]
+struct ExchangeEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    internal: bool,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+}
+
+/// A queue, as reported by the `RabbitMQ` management API.
+#[derive(Debug, Deserialize)]
+struct QueueEntry {
+    name: String,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default)]
+    auto_delete: bool,
+    #[serde(default)]
+    exclusive: bool,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+}
+
+/// A binding, as reported by the `RabbitMQ` management API.
+#[derive(Debug, Deserialize)]
+struct BindingEntry {
+    source: String,
+    destination: String,
+    destination_type: String,
+    routing_key: String,
+}
+
+impl Management {
+    /// Create a new `Management` client for the management API at the given URL (ex:
+    /// `http://localhost:15672`), authenticated with the given credentials.
+    pub fn new(url: &str, username: &str, password: &str) -> Self {
+        let credentials = base64::encode(&format!("{}:{}", username, password));
+        Management {
+            client: Client::new(),
+            url: url.trim_right_matches('/').into(),
+            authorization: format!("Basic {}", credentials),
+            vhost: "/".into(),
+        }
+    }
+
+    /// Set the virtual host whose queues and connections are inspected. Chainable.
+    ///
+    /// Defaults to `/`.
+    pub fn vhost(mut self, vhost: &str) -> Self {
+        self.vhost = vhost.into();
+        self
+    }
+
+    /// Return the details of the queues of the virtual host.
+    pub fn queues(&self) -> Box<Future<Item = Vec<QueueDetails>, Error = Error> + Send> {
+        self.get(&format!("/api/queues/{}", encode(&self.vhost)))
+    }
+
+    /// Return the details of the given queue.
+    pub fn queue(&self, name: &str) -> Box<Future<Item = QueueDetails, Error = Error> + Send> {
+        self.get(&format!(
+            "/api/queues/{}/{}",
+            encode(&self.vhost),
+            encode(name)
+        ))
+    }
+
+    /// Remove the jobs ready to be consumed from the given queue.
+    pub fn purge(&self, name: &str) -> Box<Future<Item = (), Error = Error> + Send> {
+        let path = format!(
+            "/api/queues/{}/{}/contents",
+            encode(&self.vhost),
+            encode(name)
+        );
+        Box::new(self.request(Method::DELETE, &path).map(|_| ()))
+    }
+
+    /// Return the details of the connections opened on the virtual host.
+    pub fn connections(&self) -> Box<Future<Item = Vec<ConnectionDetails>, Error = Error> + Send> {
+        self.get(&format!("/api/vhosts/{}/connections", encode(&self.vhost)))
+    }
+
+    /// Return the exchanges, queues and bindings of the virtual host, see `topology::plan`.
+    pub fn topology(&self) -> Box<Future<Item = Topology, Error = Error> + Send> {
+        let vhost = encode(&self.vhost);
+        let task = self.get(&format!("/api/exchanges/{}", vhost))
+            .join3(
+                self.get(&format!("/api/queues/{}", vhost)),
+                self.get(&format!("/api/bindings/{}", vhost)),
+            )
+            .map(|(exchanges, queues, bindings)| live_topology(exchanges, queues, bindings));
+        Box::new(task)
+    }
+
+    /// Return the statistics of the given queue.
+    pub(crate) fn stats(&self, name: &str) -> Box<Future<Item = QueueStats, Error = Error> + Send> {
+        let task = self.queue(name).map(|details| QueueStats {
+            name: details.name,
+            messages: details.messages_ready,
+            consumers: Some(details.consumers),
+        });
+        Box::new(task)
+    }
+
+    /// Send a `GET` request to the given path, and decode its JSON response.
+    fn get<T>(&self, path: &str) -> Box<Future<Item = T, Error = Error> + Send>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let task = self.request(Method::GET, path).and_then(|body| {
+            serde_json::from_slice(&body)
+                .map_err(|e| Error::from(ErrorKind::Management(e.into())))
+        });
+        Box::new(task)
+    }
+
+    /// Send a request to the given path, returning the body of its response.
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        let mut request = Request::new(Body::empty());
+        let uri = match format!("{}{}", self.url, path).parse() {
+            Ok(uri) => uri,
+            Err(e) => {
+                let e = format_err!("invalid URL {}{}: {}", self.url, path, e);
+                return Box::new(future::err(ErrorKind::Management(e).into()));
+            }
+        };
+        let authorization = match HeaderValue::from_str(&self.authorization) {
+            Ok(authorization) => authorization,
+            Err(e) => return Box::new(future::err(ErrorKind::Management(e.into()).into())),
+        };
+        trace!("Sending {} {} to the management API", method, path);
+        *request.method_mut() = method.clone();
+        *request.uri_mut() = uri;
+        request.headers_mut().insert(AUTHORIZATION, authorization);
+        let path = path.to_string();
+        let task = self.client
+            .request(request)
+            .and_then(|response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, body.to_vec()))
+            })
+            .map_err(|e| Error::from(ErrorKind::Management(e.into())))
+            .and_then(move |(status, body)| {
+                if status.is_success() {
+                    Ok(body)
+                } else {
+                    let e = format_err!("{} {} returned {}", method, path, status);
+                    Err(ErrorKind::Management(e).into())
+                }
+            });
+        Box::new(task)
+    }
+}
+
+/// Build the live topology of a virtual host from the entities reported by the management API.
+fn live_topology(
+    exchanges: Vec<ExchangeEntry>,
+    queues: Vec<QueueEntry>,
+    bindings: Vec<BindingEntry>,
+) -> Topology {
+    let exchanges = exchanges.into_iter().map(|entry| {
+        let mut builder = topology::exchange(&entry.name)
+            .durable(entry.durable)
+            .auto_delete(entry.auto_delete)
+            .internal(entry.internal);
+        arguments(builder.arguments_mut(), entry.arguments);
+        for binding in &bindings {
+            if binding.destination_type == "exchange" && binding.source == entry.name {
+                builder = builder.bind(&binding.destination, &binding.routing_key);
+            }
+        }
+        builder.build().with_kind(&entry.kind)
+    });
+    let queues = queues.into_iter().map(|entry| {
+        let mut builder = topology::queue(&entry.name)
+            .durable(entry.durable)
+            .auto_delete(entry.auto_delete)
+            .exclusive(entry.exclusive)
+            .dead_letter(false);
+        arguments(builder.arguments_mut(), entry.arguments);
+        for binding in &bindings {
+            if binding.destination_type == "queue" && binding.destination == entry.name {
+                builder = builder.bind(&binding.source, &binding.routing_key);
+            }
+        }
+        builder.build()
+    });
+    Topology {
+        exchanges: exchanges.collect(),
+        queues: queues.collect(),
+    }
+}
+
+/// Add the given JSON arguments to the given table.
+///
+/// The management API doesn't report the AMQP type of the arguments: numbers are converted to
+/// signed 64-bit integers when possible, nested values are left out.
+fn arguments(table: &mut FieldTable, arguments: Map<String, Value>) {
+    for (key, value) in arguments {
+        let value = match value {
+            Value::Bool(value) => AMQPValue::Boolean(value),
+            Value::String(value) => AMQPValue::LongString(value),
+            Value::Number(ref number) if number.is_i64() => {
+                AMQPValue::LongLongInt(number.as_i64().unwrap())
+            }
+            Value::Number(ref number) => AMQPValue::Double(number.as_f64().unwrap_or_default()),
+            _ => continue,
+        };
+        table.insert(key, value);
+    }
+}
+
+/// Percent-encode the given segment of the path of a request.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_segments() {
+        assert_eq!(encode("/"), "%2F");
+        assert_eq!(encode("emails.dead"), "emails.dead");
+        assert_eq!(encode("jobs #1"), "jobs%20%231");
+    }
+
+    #[test]
+    fn queue_details() {
+        let raw = br#"{
+            "name": "emails",
+            "vhost": "/",
+            "messages": 12,
+            "messages_ready": 10,
+            "messages_unacknowledged": 2,
+            "consumers": 3,
+            "durable": true
+        }"#;
+        let details: QueueDetails = serde_json::from_slice(raw).unwrap();
+        assert_eq!(details.messages_ready, 10);
+        assert_eq!(details.messages_unacknowledged, 2);
+        assert_eq!(details.consumers, 3);
+    }
+
+    #[test]
+    fn live_topology_entities() {
+        let exchanges = serde_json::from_slice(
+            br#"[
+            {"name": "", "type": "direct", "durable": true, "arguments": {}},
+            {"name": "batch.example", "type": "x-delayed-message", "durable": true,
+             "arguments": {"x-delayed-type": "direct"}}
+        ]"#,
+        ).unwrap();
+        let queues = serde_json::from_slice(
+            br#"[
+            {"name": "emails", "durable": true, "exclusive": false,
+             "arguments": {"x-max-priority": 4, "x-queue-mode": "lazy"}}
+        ]"#,
+        ).unwrap();
+        let bindings = serde_json::from_slice(
+            br#"[
+            {"source": "", "destination": "emails", "destination_type": "queue",
+             "routing_key": "emails"},
+            {"source": "batch.example", "destination": "emails", "destination_type": "queue",
+             "routing_key": "emails.*"}
+        ]"#,
+        ).unwrap();
+        let topology = live_topology(exchanges, queues, bindings);
+
+        let exchange = &topology.exchanges[1];
+        assert_eq!(exchange.kind(), "x-delayed-message");
+        assert!(exchange.options().durable);
+        assert_eq!(
+            exchange.arguments().get("x-delayed-type"),
+            Some(&AMQPValue::LongString("direct".into()))
+        );
+        let queue = &topology.queues[0];
+        assert_eq!(queue.dead_letter_queue(), None);
+        assert_eq!(
+            queue.arguments().get("x-max-priority"),
+            Some(&AMQPValue::LongLongInt(4))
+        );
+        assert_eq!(queue.bindings().len(), 2);
+        assert!(queue.routes("batch.example", "topic", "emails.welcome"));
+    }
+}
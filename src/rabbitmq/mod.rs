@@ -1,19 +1,40 @@
+mod builder;
+mod celery;
 mod common;
+mod connection;
 mod consumer;
+mod control;
+mod dead_letters;
 mod delivery;
+mod inspector;
+#[cfg(feature = "management")]
+mod management;
+mod pool;
 mod publisher;
+mod reconnect;
+mod registry;
+mod results;
 mod stream;
-mod types;
 
+pub use self::builder::{ConnectionBuilder, Declaration, Failover};
+pub use self::celery::Protocol;
+pub use self::connection::Connection;
 pub use self::consumer::{Consumer, ConsumerHandle};
+pub use self::control::ControlExchange;
+pub use self::dead_letters::DeadLetters;
 pub use self::delivery::Delivery;
+pub use self::inspector::Inspector;
+#[cfg(feature = "management")]
+pub use self::management::{ConnectionDetails, Management, QueueDetails};
 pub use self::publisher::Publisher;
-pub use self::types::{exchange, queue, Exchange, ExchangeBuilder, Queue, QueueBuilder};
+pub use self::registry::WorkerRegistry;
+pub use self::results::Results;
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use job::Priority;
+    use topology::{exchange, queue};
 
     #[test]
     fn default_queue() {
@@ -38,12 +59,12 @@ mod tests {
         ];
         let expected = VecDeque::from(vec!["job-1", "job-2", "job-3", "job-4", "job-5"]);
 
-        let conn_url = "amqp://localhost/%2f";
+        let conn_url = ConnectionBuilder::new("amqp://localhost/%2f");
         let exchanges = vec![exchange(ex).build()];
         let queues = vec![queue("tests.default").bind(ex, rk).build()];
         let handle = Handle::current();
         let task =
-            Publisher::new_with_handle(conn_url, exchanges.clone(), queues.clone(), handle.clone())
+            Publisher::new_with_handle(&conn_url, exchanges.clone(), queues.clone(), handle.clone())
                 .and_then(move |publisher| {
                     info!("Publishing messages");
                     let tasks = jobs.into_iter().map(move |(job, priority)| {
@@ -68,7 +89,7 @@ mod tests {
                 })
                 .and_then(move |_| {
                     info!("Published all messages");
-                    Consumer::new_with_handle(conn_url, exchanges, queues, 1, handle)
+                    Consumer::new_with_handle(&conn_url, exchanges, queues, 1, handle)
                 })
                 .and_then(move |consumer| {
                     info!("Starting recursive loop fn");
@@ -126,7 +147,7 @@ mod tests {
         ];
         let expected = VecDeque::from(vec!["job-2", "job-4", "job-1", "job-5", "job-3"]);
 
-        let conn_url = "amqp://localhost/%2f";
+        let conn_url = ConnectionBuilder::new("amqp://localhost/%2f");
         let exchanges = vec![exchange(ex).build()];
         let queues = vec![
             queue("tests.priorities")
@@ -136,7 +157,7 @@ mod tests {
         ];
         let handle = Handle::current();
         let task =
-            Publisher::new_with_handle(conn_url, exchanges.clone(), queues.clone(), handle.clone())
+            Publisher::new_with_handle(&conn_url, exchanges.clone(), queues.clone(), handle.clone())
                 .and_then(move |publisher| {
                     info!("Publishing messages");
                     let tasks = jobs.into_iter().map(move |(job, priority)| {
@@ -161,7 +182,7 @@ mod tests {
                 })
                 .and_then(move |_| {
                     info!("Published all messages");
-                    Consumer::new_with_handle(conn_url, exchanges, queues, 1, handle)
+                    Consumer::new_with_handle(&conn_url, exchanges, queues, 1, handle)
                 })
                 .and_then(move |consumer| {
                     info!("Starting recursive loop fn");
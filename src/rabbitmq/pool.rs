@@ -0,0 +1,80 @@
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::{future, Future};
+use tokio_reactor::Handle;
+
+use error::Error;
+use rabbitmq::builder::ConnectionBuilder;
+use rabbitmq::publisher::Publisher;
+use topology::{Exchange, Queue};
+
+/// A pool of publishers, each using its own connection to `RabbitMQ`.
+///
+/// Publishes are spread over the publishers in a round-robin fashion, so that no publisher is
+/// favored over the others, and jobs sent concurrently from multiple threads don't wait on a
+/// single channel.
+pub struct Pool {
+    publishers: Vec<Publisher>,
+    next: AtomicUsize,
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Pool {{ size: {:?} }}", self.publishers.len())
+    }
+}
+
+impl Pool {
+    /// Create a `Pool` of `size` publishers from RabbitMQ connection settings and an explicit
+    /// tokio handle.
+    ///
+    /// A pool always holds at least one publisher.
+    pub fn new_with_handle(
+        connection: &ConnectionBuilder,
+        exchanges: Vec<Exchange>,
+        queues: Vec<Queue>,
+        size: usize,
+        handle: Handle,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let publishers = (0..size.max(1)).map(|_| {
+            Publisher::new_with_handle(
+                connection,
+                exchanges.clone(),
+                queues.clone(),
+                handle.clone(),
+            )
+        });
+        let task = future::join_all(publishers).map(|publishers| Pool {
+            publishers,
+            next: AtomicUsize::new(0),
+        });
+        Box::new(task)
+    }
+
+    /// Return the publisher the next job should be sent with.
+    pub fn get(&self) -> &Publisher {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.publishers[next % self.publishers.len()]
+    }
+
+    /// Set the maximum number of jobs buffered by each publisher while reconnecting.
+    pub fn set_buffer_capacity(&self, capacity: usize) {
+        for publisher in &self.publishers {
+            publisher.set_buffer_capacity(capacity);
+        }
+    }
+
+    /// Enable publisher confirms on each publisher of the pool.
+    ///
+    /// Returns a `Future` that completes once confirms are enabled on every publisher.
+    pub fn enable_confirms(&self, timeout: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        let tasks = self.publishers
+            .iter()
+            .map(|publisher| publisher.enable_confirms(timeout))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks).map(|_| ()))
+    }
+}
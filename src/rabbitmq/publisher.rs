@@ -1,22 +1,87 @@
+use std::collections::VecDeque;
 use std::fmt;
+use std::io;
+use std::mem;
 use std::result::Result as StdResult;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use futures::sync::oneshot;
+use futures::future::Either;
 use futures::{future, Future};
-use lapin::channel::{BasicProperties, BasicPublishOptions, Channel};
+use lapin::channel::{BasicProperties, BasicPublishOptions, Channel, ConfirmSelectOptions};
 use lapin::client::Client;
+use tokio_executor;
 use tokio_reactor::Handle;
+use tokio_timer::Delay;
 
 use error::{Error, ErrorKind};
+use rabbitmq::builder::ConnectionBuilder;
 use rabbitmq::common::{connect, declare_exchanges, declare_queues, HeartbeatHandle};
+use rabbitmq::reconnect::{with_backoff, Connect};
 use rabbitmq::stream::Stream;
-use rabbitmq::types::{Exchange, Queue};
+use topology::{Exchange, Queue};
+
+/// The default number of jobs buffered while the publisher is reconnecting.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 1_000;
+
+/// The number of times a job is published again after being nacked by the broker.
+const NACK_RETRIES: u32 = 3;
 
 /// An AMQP based publisher for the Batch distributed job queue.
+///
+/// When publishing fails, the publisher reconnects to `RabbitMQ` in the background, declaring
+/// its exchanges and queues again. The jobs sent in the meantime are buffered, up to a bounded
+/// capacity, and published once the connection is restored.
+///
+/// When publisher confirms are enabled (see `enable_confirms`), jobs are only considered sent
+/// once the broker acknowledged them, and are published again when the broker nacks them.
 #[derive(Clone)]
 pub struct Publisher {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    connect: Arc<Connect<Link>>,
+    capacity: AtomicUsize,
+    confirms: Arc<Mutex<Option<Duration>>>,
+    state: Mutex<State>,
+}
+
+enum State {
+    Connected(Link),
+    Reconnecting(VecDeque<(Message, oneshot::Sender<Result<(), Error>>)>),
+}
+
+/// A channel to `RabbitMQ`, along with the number of connections established before it.
+#[derive(Clone)]
+struct Link {
     channel: Channel<Stream>,
     heartbeat_handle: Arc<HeartbeatHandle>,
+    generation: usize,
+}
+
+/// A message waiting to be published.
+#[derive(Clone)]
+struct Message {
+    exchange: String,
+    routing_key: String,
+    payload: Vec<u8>,
+    options: BasicPublishOptions,
+    properties: BasicProperties,
+}
+
+/// The outcome of publishing a message to the broker.
+enum Outcome {
+    /// The broker acknowledged the message, or confirms aren't enabled.
+    Sent,
+    /// The broker couldn't handle the message.
+    Nacked,
+    /// The broker didn't confirm the message in time.
+    TimedOut(Duration),
+    /// The message couldn't be sent to the broker.
+    Failed(io::Error),
 }
 
 impl fmt::Debug for Publisher {
@@ -26,9 +91,10 @@ impl fmt::Debug for Publisher {
 }
 
 impl Publisher {
-    /// Create a `Publisher` instance from a RabbitMQ URI and an explicit tokio handle.
+    /// Create a `Publisher` instance from RabbitMQ connection settings and an explicit tokio
+    /// handle.
     pub fn new_with_handle<E, Q>(
-        connection_url: &str,
+        connection: &ConnectionBuilder,
         exchanges_iter: E,
         queues_iter: Q,
         handle: Handle,
@@ -37,44 +103,83 @@ impl Publisher {
         E: IntoIterator<Item = Exchange> + Send,
         Q: IntoIterator<Item = Queue> + Send,
     {
+        let connection = connection.clone();
         let exchanges = exchanges_iter.into_iter().collect::<Vec<_>>();
         let queues = queues_iter.into_iter().collect::<Vec<_>>();
+        let confirms = Arc::new(Mutex::new(None));
+        let confirms_ = Arc::clone(&confirms);
+        let connect: Arc<Connect<Link>> = Arc::new(move || {
+            connect_link(
+                &connection,
+                exchanges.clone(),
+                queues.clone(),
+                handle.clone(),
+                confirms_.lock().unwrap().is_some(),
+            )
+        });
+        let task = connect().map(move |link| Publisher {
+            shared: Arc::new(Shared {
+                connect,
+                capacity: AtomicUsize::new(DEFAULT_BUFFER_CAPACITY),
+                confirms,
+                state: Mutex::new(State::Connected(link)),
+            }),
+        });
+        Box::new(task)
+    }
 
-        let task = connect(connection_url, handle)
-            .and_then(|(client, heartbeat_handle)| {
-                trace!("Creating publisher's RabbitMQ channel");
-                client
-                    .create_channel()
-                    .map(move |channel| {
-                        trace!("Created publisher's RabbitMQ channel");
-                        (channel, heartbeat_handle)
-                    })
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-            })
-            .and_then(move |(channel, heartbeat_handle)| {
-                trace!("Declaring publisher's RabbitMQ exchanges");
-                let channel_ = channel.clone();
-                declare_exchanges(exchanges, channel_)
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-                    .map(|_| (channel, heartbeat_handle))
-            })
-            .and_then(move |(channel, heartbeat_handle)| {
-                trace!("Declaring publisher's RabbitMQ queues");
-                let channel_ = channel.clone();
-                declare_queues(queues, channel_)
-                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
-                    .map(|_| (channel, heartbeat_handle))
-            })
-            .map(move |(channel, heartbeat_handle)| Publisher {
-                channel,
-                heartbeat_handle: Arc::new(heartbeat_handle),
-            });
+    /// Set the maximum number of jobs buffered while the publisher is reconnecting.
+    pub fn set_buffer_capacity(&self, capacity: usize) {
+        self.shared.capacity.store(capacity, Ordering::SeqCst);
+    }
+
+    /// Enable publisher confirms, waiting at most `timeout` for the broker to confirm each job.
+    ///
+    /// Returns a `Future` that completes once the publisher's channel is in confirm mode.
+    pub fn enable_confirms(&self, timeout: Duration) -> Box<Future<Item = (), Error = Error> + Send> {
+        *self.shared.confirms.lock().unwrap() = Some(timeout);
+        let channel = match *self.shared.state.lock().unwrap() {
+            State::Connected(ref link) => link.channel.clone(),
+            // The channel will be put in confirm mode once reconnected.
+            State::Reconnecting(_) => return Box::new(future::ok(())),
+        };
+        let task = channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
+
+    /// Declare the given queue on the publisher's channel.
+    ///
+    /// Returns a `Future` that completes once the queue is declared.
+    pub fn declare_queue(&self, queue: Queue) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.declare(Vec::new(), vec![queue])
+    }
+
+    /// Declare the given exchanges, then the given queues, on the publisher's channel.
+    ///
+    /// Returns a `Future` that completes once they are declared.
+    pub fn declare(
+        &self,
+        exchanges: Vec<Exchange>,
+        queues: Vec<Queue>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let channel = match *self.shared.state.lock().unwrap() {
+            State::Connected(ref link) => link.channel.clone(),
+            State::Reconnecting(_) => return Box::new(future::err(not_connected())),
+        };
+        let queues_channel = channel.clone();
+        let task = declare_exchanges(exchanges, channel)
+            .and_then(move |_| declare_queues(queues, queues_channel))
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
         Box::new(task)
     }
 
     /// Send a job to the broker.
     ///
-    /// Returns a `Future` that completes once the job is sent to the broker.
+    /// Returns a `Future` that completes once the job is sent to the broker, or once the broker
+    /// confirmed it if publisher confirms are enabled. If the publisher is reconnecting, the job is
+    /// buffered and the returned `Future` completes once it is sent.
     pub fn send(
         &self,
         exchange: &str,
@@ -83,16 +188,204 @@ impl Publisher {
         options: &BasicPublishOptions,
         properties: BasicProperties,
     ) -> Box<Future<Item = (), Error = Error> + Send> {
-        let task = self.channel
-            .basic_publish(
-                exchange,
-                routing_key,
-                serialized,
-                options.clone(),
-                properties,
-            )
-            .and_then(move |_| future::ok(()))
-            .map_err(|e| ErrorKind::Rabbitmq(e).into());
-        Box::new(task)
+        let message = Message {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            payload: serialized.to_vec(),
+            options: options.clone(),
+            properties,
+        };
+        self.publish(message, true, 0)
+    }
+
+    /// Publish the given message, or buffer it if the publisher is reconnecting.
+    ///
+    /// If publishing fails, the publisher reconnects and the message is published again once if
+    /// `retry` is true. `nacks` is the number of times the broker nacked the message so far.
+    fn publish(
+        &self,
+        message: Message,
+        retry: bool,
+        nacks: u32,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let confirms = *self.shared.confirms.lock().unwrap();
+        let mut state = self.shared.state.lock().unwrap();
+        match *state {
+            State::Connected(ref link) => {
+                let publisher = self.clone();
+                let generation = link.generation;
+                let retried = message.clone();
+                let published = link.channel.basic_publish(
+                    &message.exchange,
+                    &message.routing_key,
+                    &message.payload,
+                    message.options,
+                    message.properties,
+                );
+                let outcome: Box<Future<Item = Outcome, Error = Error> + Send> = match confirms {
+                    Some(timeout) => {
+                        let task = published
+                            .select2(Delay::new(Instant::now() + timeout))
+                            .then(move |result| match result {
+                                Ok(Either::A((Some(false), _))) => Ok(Outcome::Nacked),
+                                Ok(Either::A(_)) => Ok(Outcome::Sent),
+                                Ok(Either::B(_)) => Ok(Outcome::TimedOut(timeout)),
+                                Err(Either::A((e, _))) => Ok(Outcome::Failed(e)),
+                                Err(Either::B((e, _))) => Err(ErrorKind::Timer(e).into()),
+                            });
+                        Box::new(task)
+                    }
+                    None => {
+                        let task = published.then(|result| match result {
+                            Ok(_) => Ok(Outcome::Sent),
+                            Err(e) => Ok(Outcome::Failed(e)),
+                        });
+                        Box::new(task)
+                    }
+                };
+                let task = outcome.and_then(
+                    move |outcome| -> Box<Future<Item = (), Error = Error> + Send> {
+                        match outcome {
+                            Outcome::Sent => Box::new(future::ok(())),
+                            Outcome::Nacked if nacks < NACK_RETRIES => {
+                                warn!("Job was nacked by RabbitMQ, publishing it again");
+                                publisher.publish(retried, retry, nacks + 1)
+                            }
+                            Outcome::Nacked => Box::new(future::err(ErrorKind::Nacked.into())),
+                            Outcome::TimedOut(timeout) => {
+                                Box::new(future::err(ErrorKind::ConfirmTimeout(timeout).into()))
+                            }
+                            Outcome::Failed(e) => {
+                                warn!("Couldn't publish job, reconnecting to RabbitMQ: {}", e);
+                                publisher.disconnected(generation);
+                                if retry {
+                                    publisher.publish(retried, false, nacks)
+                                } else {
+                                    Box::new(future::err(ErrorKind::Rabbitmq(e).into()))
+                                }
+                            }
+                        }
+                    },
+                );
+                Box::new(task)
+            }
+            State::Reconnecting(ref mut buffer) => {
+                if buffer.len() >= self.shared.capacity.load(Ordering::SeqCst) {
+                    return Box::new(future::err(ErrorKind::PublishBufferFull.into()));
+                }
+                trace!("Buffering job while reconnecting to RabbitMQ");
+                let (sender, receiver) = oneshot::channel();
+                buffer.push_back((message, sender));
+                let task = receiver.then(|result| match result {
+                    Ok(result) => result,
+                    Err(_) => Err(not_connected()),
+                });
+                Box::new(task)
+            }
+        }
     }
+
+    /// Start reconnecting to `RabbitMQ` in the background, if the given connection is the
+    /// current one.
+    fn disconnected(&self, generation: usize) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            match *state {
+                State::Connected(ref link) if link.generation == generation => (),
+                _ => return,
+            }
+            *state = State::Reconnecting(VecDeque::new());
+        }
+        let publisher = self.clone();
+        let task = with_backoff("publisher", Arc::clone(&self.shared.connect))
+            .map(move |mut link| {
+                link.generation = generation + 1;
+                publisher.connected(link);
+            })
+            .map_err(|e| error!("Couldn't reconnect publisher to RabbitMQ: {}", e));
+        tokio_executor::spawn(task);
+    }
+
+    /// Use the given connection, and publish the jobs buffered while reconnecting.
+    fn connected(&self, link: Link) {
+        let buffered = {
+            let mut state = self.shared.state.lock().unwrap();
+            match mem::replace(&mut *state, State::Connected(link)) {
+                State::Reconnecting(buffered) => buffered,
+                State::Connected(_) => VecDeque::new(),
+            }
+        };
+        if !buffered.is_empty() {
+            info!("Publishing {} jobs buffered while reconnecting", buffered.len());
+        }
+        for (message, sender) in buffered {
+            let task = self.publish(message, false, 0).then(move |result| {
+                let _ = sender.send(result);
+                Ok(())
+            });
+            tokio_executor::spawn(task);
+        }
+    }
+}
+
+/// Connect to `RabbitMQ`, open a channel and declare the given exchanges and queues on it.
+///
+/// If `confirms` is true, the channel is put in confirm mode.
+fn connect_link(
+    connection: &ConnectionBuilder,
+    exchanges: Vec<Exchange>,
+    queues: Vec<Queue>,
+    handle: Handle,
+    confirms: bool,
+) -> Box<Future<Item = Link, Error = Error> + Send> {
+    let task = connect(connection, handle)
+        .and_then(|(client, heartbeat_handle)| {
+            trace!("Creating publisher's RabbitMQ channel");
+            client
+                .create_channel()
+                .map(move |channel| {
+                    trace!("Created publisher's RabbitMQ channel");
+                    (channel, heartbeat_handle)
+                })
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+        })
+        .and_then(move |(channel, heartbeat_handle)| {
+            trace!("Declaring publisher's RabbitMQ exchanges");
+            let channel_ = channel.clone();
+            declare_exchanges(exchanges, channel_)
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+                .map(|_| (channel, heartbeat_handle))
+        })
+        .and_then(move |(channel, heartbeat_handle)| {
+            trace!("Declaring publisher's RabbitMQ queues");
+            let channel_ = channel.clone();
+            declare_queues(queues, channel_)
+                .map_err(|e| ErrorKind::Rabbitmq(e).into())
+                .map(|_| (channel, heartbeat_handle))
+        })
+        .and_then(move |(channel, heartbeat_handle)| {
+            let task: Box<Future<Item = (), Error = Error> + Send> = if confirms {
+                trace!("Enabling publisher confirms on publisher's RabbitMQ channel");
+                let task = channel
+                    .confirm_select(ConfirmSelectOptions::default())
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into());
+                Box::new(task)
+            } else {
+                Box::new(future::ok(()))
+            };
+            task.map(|_| (channel, heartbeat_handle))
+        })
+        .map(move |(channel, heartbeat_handle)| Link {
+            channel,
+            heartbeat_handle: Arc::new(heartbeat_handle),
+            generation: 0,
+        });
+    Box::new(task)
+}
+
+fn not_connected() -> Error {
+    ErrorKind::Rabbitmq(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "the publisher is reconnecting to RabbitMQ",
+    )).into()
 }
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Future, Poll, Stream};
+use tokio_timer::Delay;
+
+use error::{Error, ErrorKind};
+use rabbitmq::consumer::Consumer;
+use rabbitmq::delivery::Delivery;
+use retry::RetryStrategy;
+
+/// The number of seconds to wait before the first reconnection attempt.
+const RECONNECT_BASE_DELAY: u64 = 1;
+
+/// The maximum number of seconds to wait between two reconnection attempts.
+const RECONNECT_MAX_DELAY: u64 = 60;
+
+/// Type of the functions establishing a new connection to `RabbitMQ`.
+pub type Connect<T> = Fn() -> Box<Future<Item = T, Error = Error> + Send> + Send + Sync;
+
+/// Return a `Future` calling `connect` until it succeeds, waiting between attempts using an
+/// exponential backoff.
+///
+/// The given name describes the connection in the logs.
+pub fn with_backoff<T>(
+    name: &'static str,
+    connect: Arc<Connect<T>>,
+) -> Box<Future<Item = T, Error = Error> + Send>
+where
+    T: Send + 'static,
+{
+    let strategy = RetryStrategy::exponential(
+        Duration::from_secs(RECONNECT_BASE_DELAY),
+        Duration::from_secs(RECONNECT_MAX_DELAY),
+    );
+    let task = future::loop_fn(1, move |attempt| {
+        let strategy = strategy.clone();
+        connect().then(
+            move |result| -> Box<Future<Item = future::Loop<T, u32>, Error = Error> + Send> {
+                match result {
+                    Ok(connection) => {
+                        info!("Reconnected {} to RabbitMQ", name);
+                        Box::new(future::ok(future::Loop::Break(connection)))
+                    }
+                    Err(e) => {
+                        let delay = strategy.delay(attempt).unwrap_or_default();
+                        warn!(
+                            "Couldn't reconnect {} to RabbitMQ, retrying in {:?}: {}",
+                            name, delay, e
+                        );
+                        let task = Delay::new(Instant::now() + delay)
+                            .map_err(|e| ErrorKind::Timer(e).into())
+                            .map(move |_| future::Loop::Continue(attempt + 1));
+                        Box::new(task)
+                    }
+                }
+            },
+        )
+    });
+    Box::new(task)
+}
+
+/// A stream of jobs received from `RabbitMQ`, reconnecting when its consumer fails.
+///
+/// Exchanges and queues are declared again on each reconnection. The jobs received before the
+/// connection was lost can't be acknowledged anymore, and are delivered again by the broker.
+pub struct ReconnectingConsumer {
+    connect: Arc<Connect<Consumer>>,
+    state: State,
+}
+
+enum State {
+    Consuming(Consumer),
+    Reconnecting(Box<Future<Item = Consumer, Error = Error> + Send>),
+}
+
+impl ReconnectingConsumer {
+    /// Create a new `ReconnectingConsumer`, using `connect` to replace the given consumer.
+    pub fn new(consumer: Consumer, connect: Arc<Connect<Consumer>>) -> Self {
+        ReconnectingConsumer {
+            connect,
+            state: State::Consuming(consumer),
+        }
+    }
+}
+
+impl Stream for ReconnectingConsumer {
+    type Item = Delivery;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let next = match self.state {
+                State::Consuming(ref mut consumer) => match consumer.poll() {
+                    Ok(Async::Ready(Some(delivery))) => return Ok(Async::Ready(Some(delivery))),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(None)) => {
+                        warn!("RabbitMQ consumer was closed, reconnecting");
+                        State::Reconnecting(with_backoff("consumer", Arc::clone(&self.connect)))
+                    }
+                    Err(e) => {
+                        warn!("RabbitMQ consumer failed, reconnecting: {}", e);
+                        State::Reconnecting(with_backoff("consumer", Arc::clone(&self.connect)))
+                    }
+                },
+                State::Reconnecting(ref mut task) => match task.poll()? {
+                    Async::Ready(consumer) => State::Consuming(consumer),
+                    Async::NotReady => return Ok(Async::NotReady),
+                },
+            };
+            self.state = next;
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future, Stream as FuturesStream};
+use lapin::channel::{
+    BasicConsumeOptions, BasicProperties, BasicPublishOptions, Channel, QueueBindOptions,
+    QueueDeclareOptions,
+};
+use lapin::message::Delivery as Message;
+use lapin::types::FieldTable;
+use tokio_executor;
+use tokio_reactor::Handle;
+use uuid::Uuid;
+
+use de;
+use error::{Error, ErrorKind};
+use monitor::{Heartbeat, Registry};
+use rabbitmq::builder::ConnectionBuilder;
+use rabbitmq::common::{connect, exchange_options, HeartbeatHandle};
+use rabbitmq::stream::Stream;
+use ser;
+use topology::{exchange, ExchangeKind};
+
+/// The name of the exchange the heartbeats of workers are published to.
+const HEARTBEATS_EXCHANGE: &str = "batch.heartbeats";
+
+type Heartbeats = Arc<Mutex<HashMap<Uuid, Heartbeat>>>;
+
+/// A `Registry` implementation broadcasting heartbeats through a `RabbitMQ` fanout exchange.
+///
+/// Workers publish their heartbeats to the `batch.heartbeats` exchange. When listening, an
+/// instance declares an exclusive queue bound to this exchange, and keeps the last heartbeat
+/// received from each worker in memory.
+pub struct WorkerRegistry {
+    channel: Channel<Stream>,
+    heartbeats: Heartbeats,
+    heartbeat_handle: Arc<HeartbeatHandle>,
+}
+
+impl fmt::Debug for WorkerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "WorkerRegistry {{ workers: {:?} }}",
+            self.heartbeats.lock().unwrap().len()
+        )
+    }
+}
+
+impl WorkerRegistry {
+    /// Create a `WorkerRegistry` instance from RabbitMQ connection settings and an explicit
+    /// tokio handle.
+    ///
+    /// When `listen` is false, the instance only publishes heartbeats.
+    pub fn new_with_handle(
+        connection: &ConnectionBuilder,
+        handle: Handle,
+        listen: bool,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let exchange = exchange(HEARTBEATS_EXCHANGE)
+            .kind(ExchangeKind::Fanout)
+            .build();
+        let task = connect(connection, handle)
+            .and_then(|(client, heartbeat_handle)| {
+                trace!("Creating registry's RabbitMQ channel");
+                client
+                    .create_channel()
+                    .map(|channel| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .and_then(move |(channel, heartbeat_handle)| {
+                trace!("Declaring heartbeats' exchange");
+                channel
+                    .exchange_declare(
+                        exchange.name(),
+                        exchange.kind(),
+                        exchange_options(exchange.options()),
+                        exchange.arguments().clone(),
+                    )
+                    .map(|_| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .and_then(move |(channel, heartbeat_handle)| {
+                let registry = WorkerRegistry {
+                    channel,
+                    heartbeats: Arc::new(Mutex::new(HashMap::new())),
+                    heartbeat_handle: Arc::new(heartbeat_handle),
+                };
+                if listen {
+                    registry.listen()
+                } else {
+                    Box::new(future::ok(registry))
+                }
+            });
+        Box::new(task)
+    }
+
+    /// Start recording the heartbeats published to the heartbeats' exchange.
+    fn listen(self) -> Box<Future<Item = Self, Error = Error> + Send> {
+        trace!("Declaring registry's heartbeats queue");
+        let options = QueueDeclareOptions {
+            exclusive: true,
+            auto_delete: true,
+            ..Default::default()
+        };
+        let channel = self.channel.clone();
+        let binding_channel = self.channel.clone();
+        let consuming_channel = self.channel.clone();
+        let task = channel
+            .queue_declare("", options, FieldTable::new())
+            .and_then(move |queue| {
+                binding_channel
+                    .queue_bind(
+                        queue.name(),
+                        HEARTBEATS_EXCHANGE,
+                        "",
+                        QueueBindOptions::default(),
+                        FieldTable::new(),
+                    )
+                    .map(|_| queue)
+            })
+            .and_then(move |queue| {
+                let options = BasicConsumeOptions {
+                    no_ack: true,
+                    exclusive: true,
+                    ..Default::default()
+                };
+                consuming_channel.basic_consume(
+                    &queue,
+                    "batch-rs-monitor",
+                    options,
+                    FieldTable::new(),
+                )
+            })
+            .map_err(|e| -> Error { ErrorKind::Rabbitmq(e).into() })
+            .map(move |consumer| {
+                let heartbeats = Arc::clone(&self.heartbeats);
+                trace!("Spawning registry's heartbeats future");
+                tokio_executor::spawn(
+                    consumer
+                        .for_each(move |message| {
+                            record(&heartbeats, &message);
+                            Ok(())
+                        })
+                        .map_err(|e| error!("Couldn't receive heartbeat from RabbitMQ: {}", e)),
+                );
+                self
+            });
+        Box::new(task)
+    }
+}
+
+/// Record the heartbeat contained in the given message.
+fn record(heartbeats: &Heartbeats, message: &Message) {
+    match de::from_slice::<Heartbeat>(&message.data) {
+        Ok(heartbeat) => {
+            heartbeats
+                .lock()
+                .unwrap()
+                .insert(heartbeat.worker, heartbeat);
+        }
+        Err(e) => error!("Couldn't deserialize heartbeat: {}", e),
+    }
+}
+
+impl Registry for WorkerRegistry {
+    fn beat(&self, heartbeat: &Heartbeat) -> Box<Future<Item = (), Error = Error> + Send> {
+        let payload = match ser::to_vec(heartbeat) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(ErrorKind::Serialization(e).into())),
+        };
+        // Heartbeats are useless once the next one was sent, don't let them pile up in the
+        // queues of listeners that can't keep up.
+        let expiration =
+            heartbeat.interval.as_secs() * 1000 + u64::from(heartbeat.interval.subsec_millis());
+        let properties = BasicProperties {
+            content_type: Some("application/json".to_string()),
+            expiration: Some(expiration.to_string()),
+            ..Default::default()
+        };
+        let task = self.channel
+            .basic_publish(
+                HEARTBEATS_EXCHANGE,
+                "",
+                &payload,
+                BasicPublishOptions::default(),
+                properties,
+            )
+            .map(|_| ())
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
+
+    fn heartbeats(&self) -> Box<Future<Item = Vec<Heartbeat>, Error = Error> + Send> {
+        let heartbeats = self.heartbeats.lock().unwrap();
+        Box::new(future::ok(heartbeats.values().cloned().collect()))
+    }
+
+    fn forget(&self, worker: Uuid) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.heartbeats.lock().unwrap().remove(&worker);
+        Box::new(future::ok(()))
+    }
+}
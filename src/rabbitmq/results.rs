@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{future, Future, Stream as FuturesStream};
+use lapin::channel::{
+    BasicConsumeOptions, BasicProperties, BasicPublishOptions, Channel, QueueDeclareOptions,
+};
+use lapin::message::Delivery as Message;
+use lapin::types::FieldTable;
+use tokio_executor;
+use tokio_reactor::Handle;
+use uuid::Uuid;
+
+use backend::{Outcome, ResultBackend};
+use broker::Properties;
+use de;
+use error::{Error, ErrorKind};
+use rabbitmq::builder::ConnectionBuilder;
+use rabbitmq::common::{connect, HeartbeatHandle};
+use rabbitmq::stream::Stream;
+use ser;
+
+type Pending = Arc<Mutex<HashMap<Uuid, oneshot::Sender<Outcome>>>>;
+
+/// A `ResultBackend` implementation using `RabbitMQ` as an RPC transport.
+///
+/// Each instance declares an exclusive queue, whose name is given as the `reply_to` property of
+/// the jobs whose outcome is awaited. Workers publish the outcome of these jobs to this queue
+/// through the default exchange, using the job's ID as correlation ID.
+pub struct Results {
+    channel: Channel<Stream>,
+    reply_to: String,
+    pending: Pending,
+    heartbeat_handle: Arc<HeartbeatHandle>,
+}
+
+impl fmt::Debug for Results {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Results {{ reply_to: {:?} }}", self.reply_to)
+    }
+}
+
+impl Results {
+    /// Create a `Results` instance from RabbitMQ connection settings and an explicit tokio handle.
+    pub fn new_with_handle(
+        connection: &ConnectionBuilder,
+        handle: Handle,
+    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+        let task = connect(connection, handle)
+            .and_then(|(client, heartbeat_handle)| {
+                trace!("Creating results' RabbitMQ channel");
+                client
+                    .create_channel()
+                    .map(|channel| (channel, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .and_then(|(channel, heartbeat_handle)| {
+                trace!("Declaring results' reply queue");
+                let options = QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..Default::default()
+                };
+                channel
+                    .queue_declare("", options, FieldTable::new())
+                    .map(|queue| (channel, queue, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .and_then(|(channel, queue, heartbeat_handle)| {
+                let options = BasicConsumeOptions {
+                    no_ack: true,
+                    exclusive: true,
+                    ..Default::default()
+                };
+                channel
+                    .basic_consume(&queue, "batch-rs-results", options, FieldTable::new())
+                    .map(move |consumer| (channel, queue, consumer, heartbeat_handle))
+                    .map_err(|e| ErrorKind::Rabbitmq(e).into())
+            })
+            .map(|(channel, queue, consumer, heartbeat_handle)| {
+                let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+                let dispatched = Arc::clone(&pending);
+                let closed = Arc::clone(&pending);
+                trace!("Spawning results' dispatch future");
+                tokio_executor::spawn(
+                    consumer
+                        .for_each(move |message| {
+                            dispatch(&dispatched, &message);
+                            Ok(())
+                        })
+                        .then(move |result| {
+                            if let Err(e) = result {
+                                error!("Couldn't receive job outcome from RabbitMQ: {}", e);
+                            }
+                            closed.lock().unwrap().clear();
+                            Ok(())
+                        }),
+                );
+                Results {
+                    channel,
+                    reply_to: queue.name().to_string(),
+                    pending,
+                    heartbeat_handle: Arc::new(heartbeat_handle),
+                }
+            });
+        Box::new(task)
+    }
+}
+
+/// Forward the outcome contained in the given message to the matching subscriber.
+fn dispatch(pending: &Pending, message: &Message) {
+    let id = match message
+        .properties
+        .correlation_id
+        .as_ref()
+        .and_then(|id| Uuid::parse_str(id).ok())
+    {
+        Some(id) => id,
+        None => {
+            warn!("Received job outcome without a valid correlation ID");
+            return;
+        }
+    };
+    let outcome = match de::from_slice::<Outcome>(&message.data) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            error!("[{}] Couldn't deserialize job outcome: {}", id, e);
+            return;
+        }
+    };
+    match pending.lock().unwrap().remove(&id) {
+        Some(sender) => {
+            let _ = sender.send(outcome);
+        }
+        None => debug!("[{}] Received outcome of a job nobody is waiting for", id),
+    }
+}
+
+impl ResultBackend for Results {
+    fn subscribe(
+        &self,
+        properties: &mut Properties,
+    ) -> Box<Future<Item = Outcome, Error = Error> + Send> {
+        let (sender, receiver) = oneshot::channel();
+        properties.reply_to = Some(self.reply_to.clone());
+        self.pending.lock().unwrap().insert(properties.id, sender);
+        let task = receiver.map_err(|_| ErrorKind::ResultUnavailable.into());
+        Box::new(task)
+    }
+
+    fn store(
+        &self,
+        properties: &Properties,
+        outcome: &Outcome,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let reply_to = match properties.reply_to {
+            Some(ref reply_to) => reply_to,
+            None => return Box::new(future::ok(())),
+        };
+        let payload = match ser::to_vec(outcome) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(ErrorKind::Serialization(e).into())),
+        };
+        trace!("[{}] Sending job outcome to {:?}", properties.id, reply_to);
+        let amqp_properties = BasicProperties {
+            content_type: Some("application/json".to_string()),
+            correlation_id: Some(properties.id.to_string()),
+            ..Default::default()
+        };
+        let task = self.channel
+            .basic_publish(
+                "",
+                reply_to,
+                &payload,
+                BasicPublishOptions::default(),
+                amqp_properties,
+            )
+            .map(|_| ())
+            .map_err(|e| ErrorKind::Rabbitmq(e).into());
+        Box::new(task)
+    }
+}
@@ -0,0 +1,282 @@
+//! Rate limiting of the execution of jobs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Loop};
+use futures::Future;
+use tokio_timer::Delay;
+
+use broker::Delivery;
+use error::{Error, ErrorKind, Result};
+
+/// A maximum number of jobs executed over a period of time.
+///
+/// Rate limits are enforced by the workers using a token bucket: up to `amount` jobs can be
+/// executed in a burst, then jobs are executed at a steady pace of `amount` per `period`. A rate
+/// limit can be set on a job type using the `rate_limit` attribute when deriving `Job`, or on
+/// a queue using `WorkerBuilder::rate_limit`.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use batch::RateLimit;
+///
+/// let limit: RateLimit = "100/min".parse().unwrap();
+/// assert_eq!(limit, RateLimit::new(100, Duration::from_secs(60)));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    amount: u32,
+    period: Duration,
+}
+
+impl RateLimit {
+    /// Create a new `RateLimit` allowing `amount` jobs per `period`.
+    pub fn new(amount: u32, period: Duration) -> Self {
+        RateLimit { amount, period }
+    }
+
+    /// Return the number of jobs allowed per period.
+    pub fn amount(&self) -> u32 {
+        self.amount
+    }
+
+    /// Return the period over which `amount` jobs are allowed.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Return the number of jobs allowed per second.
+    fn rate(&self) -> f64 {
+        let period = self.period.as_secs() as f64 + f64::from(self.period.subsec_nanos()) / 1e9;
+        f64::from(self.amount) / period
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    /// Parse a rate limit such as `100/min`, `10/s` or `5000/h`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || -> Error {
+            let message = format!("{:?}, expected <amount>/<s|min|h|d>", s);
+            ErrorKind::InvalidRateLimit(message).into()
+        };
+        let mut parts = s.splitn(2, '/');
+        let amount = parts
+            .next()
+            .and_then(|amount| amount.trim().parse::<u32>().ok())
+            .ok_or_else(invalid)?;
+        let period = match parts.next().map(|unit| unit.trim()) {
+            Some("s") | Some("sec") | Some("second") => 1,
+            Some("m") | Some("min") | Some("minute") => 60,
+            Some("h") | Some("hour") => 60 * 60,
+            Some("d") | Some("day") => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        if amount == 0 {
+            return Err(invalid());
+        }
+        Ok(RateLimit::new(amount, Duration::from_secs(period)))
+    }
+}
+
+/// A limiter handing out the tokens allowing jobs to be executed.
+///
+/// Each rate limit is enforced using a token bucket identified by a key. `TokenBuckets` stores
+/// its buckets in memory, limiting the jobs executed by a single worker. To limit the jobs
+/// executed by a fleet of workers, give them a limiter shared through an external storage, such
+/// as `batch_redis::Connection`, using `WorkerBuilder::rate_limiter`.
+pub trait RateLimiter: fmt::Debug + Send + Sync {
+    /// Take a token from the bucket identified by the given key, refilled according to the
+    /// given limit.
+    ///
+    /// Returns a `Future` resolving to `None` if a token was taken, or to the duration to wait
+    /// before a token is available otherwise.
+    fn acquire(
+        &self,
+        key: &str,
+        limit: RateLimit,
+    ) -> Box<Future<Item = Option<Duration>, Error = Error> + Send>;
+}
+
+/// A `RateLimiter` storing its token buckets in memory.
+///
+/// This is the limiter used by workers by default.
+#[derive(Debug, Default)]
+pub struct TokenBuckets {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl TokenBuckets {
+    /// Create a new, empty, `TokenBuckets`.
+    pub fn new() -> Self {
+        TokenBuckets::default()
+    }
+}
+
+impl RateLimiter for TokenBuckets {
+    fn acquire(
+        &self,
+        key: &str,
+        limit: RateLimit,
+    ) -> Box<Future<Item = Option<Duration>, Error = Error> + Send> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.into()).or_insert_with(|| Bucket {
+            tokens: f64::from(limit.amount),
+            updated: now,
+        });
+        Box::new(future::ok(bucket.take(limit, now)))
+    }
+}
+
+/// A token bucket, refilled continuously up to the amount of its rate limit.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * limit.rate()).min(f64::from(limit.amount));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        let wait = (1.0 - self.tokens) / limit.rate();
+        Some(Duration::new(
+            wait.trunc() as u64,
+            (wait.fract() * 1e9).ceil() as u32,
+        ))
+    }
+}
+
+/// The rate limits enforced by a worker, along with the limiter enforcing them.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    limiter: Arc<RateLimiter>,
+    queues: HashMap<String, RateLimit>,
+    jobs: HashMap<&'static str, RateLimit>,
+}
+
+impl fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Throttle {{ limiter: {:?} queues: {:?} jobs: {:?} }}",
+            self.limiter, self.queues, self.jobs
+        )
+    }
+}
+
+impl Throttle {
+    pub(crate) fn new(
+        limiter: Arc<RateLimiter>,
+        queues: HashMap<String, RateLimit>,
+        jobs: HashMap<&'static str, RateLimit>,
+    ) -> Self {
+        Throttle {
+            limiter,
+            queues,
+            jobs,
+        }
+    }
+
+    /// Wait until the given job is allowed to be executed by the limits of its queue and type.
+    pub(crate) fn wait(&self, delivery: &Delivery) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut keys = Vec::new();
+        if let Some(queue) = delivery.queue() {
+            if let Some(limit) = self.queues.get(queue) {
+                keys.push((format!("queue:{}", queue), *limit));
+            }
+        }
+        let task = delivery.properties().task.as_str();
+        if let Some(limit) = self.jobs.get(task) {
+            keys.push((format!("job:{}", task), *limit));
+        }
+        let limiter = Arc::clone(&self.limiter);
+        let task = future::loop_fn(keys.into_iter(), move |mut keys| {
+            let (key, limit) = match keys.next() {
+                Some(next) => next,
+                None => return future::Either::A(future::ok(Loop::Break(()))),
+            };
+            let task = acquire(Arc::clone(&limiter), key, limit);
+            future::Either::B(task.map(move |_| Loop::Continue(keys)))
+        });
+        Box::new(task)
+    }
+}
+
+/// Wait until a token is taken from the bucket identified by the given key.
+fn acquire(
+    limiter: Arc<RateLimiter>,
+    key: String,
+    limit: RateLimit,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let task = future::loop_fn((), move |_| {
+        limiter.acquire(&key, limit).and_then(
+            |wait| -> Box<Future<Item = Loop<(), ()>, Error = Error> + Send> {
+                match wait {
+                    Some(wait) => Box::new(
+                        Delay::new(Instant::now() + wait)
+                            .map(|_| Loop::Continue(()))
+                            .map_err(|e| ErrorKind::Timer(e).into()),
+                    ),
+                    None => Box::new(future::ok(Loop::Break(()))),
+                }
+            },
+        )
+    });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let minute = Duration::from_secs(60);
+        assert_eq!("100/min".parse::<RateLimit>().unwrap(), RateLimit::new(100, minute));
+        assert_eq!(
+            " 10 / s ".parse::<RateLimit>().unwrap(),
+            RateLimit::new(10, Duration::from_secs(1))
+        );
+        assert!("0/min".parse::<RateLimit>().unwrap_err().is_invalid_rate_limit());
+        assert!("100".parse::<RateLimit>().unwrap_err().is_invalid_rate_limit());
+        assert!("100/week".parse::<RateLimit>().unwrap_err().is_invalid_rate_limit());
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimit::new(2, Duration::from_secs(1));
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2.0,
+            updated: start,
+        };
+        assert_eq!(bucket.take(limit, start), None);
+        assert_eq!(bucket.take(limit, start), None);
+        let wait = bucket.take(limit, start).unwrap();
+        assert!(wait > Duration::from_millis(499) && wait <= Duration::from_millis(501));
+        assert_eq!(bucket.take(limit, start + Duration::from_millis(500)), None);
+        assert!(bucket.take(limit, start + Duration::from_millis(500)).is_some());
+        // The bucket never holds more tokens than the amount of its limit.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(limit, later), None);
+        assert_eq!(bucket.take(limit, later), None);
+        assert!(bucket.take(limit, later).is_some());
+    }
+}
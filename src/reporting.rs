@@ -0,0 +1,143 @@
+//! Reporting of job failures to Sentry.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use failure;
+use sentry;
+use sentry::integrations::failure::capture_error;
+use serde_json::Value;
+
+use broker::Properties;
+use crash::Panic;
+use middleware::Middleware;
+
+/// Type of the functions removing sensitive data from the payload of jobs.
+type Scrubber = Fn(&str, &mut Value) + Send + Sync;
+
+/// A `Middleware` reporting the failures of jobs, including panics, to Sentry.
+///
+/// Each failure is reported with the name of the job (`job` tag), its attempt (`attempt` tag,
+/// starting at 1) and its routing key (`queue` tag), along with its ID and payload. Use `scrub`
+/// to remove sensitive data from payloads before they are sent, or `without_payload` to leave
+/// them out.
+///
+/// Failures are reported through the current Sentry hub: `sentry::init` must be called at the
+/// start of the program, so that child processes executing jobs report their failures too.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "sentry")]
+/// # fn main() {
+/// use batch::{Sentry, Worker};
+///
+/// let sentry = Sentry::new().scrub(|_job, payload| {
+///     if let Some(card) = payload.get_mut("card") {
+///         *card = "[redacted]".into();
+///     }
+/// });
+/// let builder = Worker::builder(()).middleware(sentry);
+/// # }
+/// # #[cfg(not(feature = "sentry"))]
+/// # fn main() {}
+/// ```
+pub struct Sentry {
+    payload: bool,
+    scrubber: Option<Box<Scrubber>>,
+}
+
+impl fmt::Debug for Sentry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Sentry {{ payload: {:?} scrubbed: {:?} }}",
+            self.payload,
+            self.scrubber.is_some()
+        )
+    }
+}
+
+impl Default for Sentry {
+    fn default() -> Self {
+        Sentry::new()
+    }
+}
+
+impl Sentry {
+    /// Create a new `Sentry` middleware, reporting the payload of failed jobs as is.
+    pub fn new() -> Self {
+        Sentry {
+            payload: true,
+            scrubber: None,
+        }
+    }
+
+    /// Remove sensitive data from the payload of jobs before they are reported. Chainable.
+    ///
+    /// The given function is called with the name of the job and its decoded payload.
+    pub fn scrub<F>(mut self, scrubber: F) -> Self
+    where
+        F: Fn(&str, &mut Value) + Send + Sync + 'static,
+    {
+        self.scrubber = Some(Box::new(scrubber));
+        self
+    }
+
+    /// Don't report the payload of jobs. Chainable.
+    pub fn without_payload(mut self) -> Self {
+        self.payload = false;
+        self
+    }
+
+    /// Return the payload of the given job to report, if any.
+    fn payload(&self, properties: &Properties, payload: &[u8]) -> Option<Value> {
+        if !self.payload {
+            return None;
+        }
+        let mut payload = match properties.codec.decode(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("[{}] Couldn't decode payload of job: {}", properties.id, e);
+                return None;
+            }
+        };
+        if let Some(ref scrubber) = self.scrubber {
+            scrubber(&properties.task, &mut payload);
+        }
+        Some(payload)
+    }
+}
+
+impl Middleware for Sentry {
+    fn on_error_with_payload(
+        &self,
+        properties: &Properties,
+        payload: &[u8],
+        error: &failure::Error,
+    ) {
+        let payload = self.payload(properties, payload);
+        let panic = error.downcast_ref::<Panic>();
+        let event = sentry::with_scope(
+            |scope| {
+                scope.set_tag("job", &properties.task);
+                scope.set_tag("attempt", properties.retries + 1);
+                scope.set_tag("queue", &properties.routing_key);
+                scope.set_extra("job_id", properties.id.to_string().into());
+                if let Some(payload) = payload {
+                    scope.set_extra("payload", payload);
+                }
+                if let Some(panic) = panic {
+                    if let Some(ref location) = panic.location {
+                        scope.set_extra("location", location.as_str().into());
+                    }
+                    if let Some(ref backtrace) = panic.backtrace {
+                        scope.set_extra("backtrace", backtrace.as_str().into());
+                    }
+                }
+            },
+            || capture_error(error),
+        );
+        debug!("[{}] Reported failure to Sentry: {}", properties.id, event);
+    }
+}
@@ -0,0 +1,109 @@
+//! Rescheduling of jobs by their handlers.
+//!
+//! A handler calling `Context::retry_in` or `Context::retry_with` asks the worker to publish its
+//! job again once the handler completed, after a delay or with an updated payload, instead of
+//! completing it or retrying it according to its retry policy. This lets a job carry its state
+//! from one execution to the next, e.g: the cursor of a paginated export.
+//!
+//! Jobs executed in a child process report their rescheduling by writing it to a file, like
+//! `Context::ack` does.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The rescheduling of a job, requested by its handler.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Rescheduling {
+    /// The delay after which the job is executed again, if any.
+    pub(crate) delay: Option<Duration>,
+    /// The serialized job to publish instead of the executed one, along with its version, if
+    /// the handler updated it.
+    pub(crate) payload: Option<(Vec<u8>, u32)>,
+}
+
+/// The handle through which a job's handler requests its rescheduling, see `Context::retry_in`.
+#[derive(Clone)]
+pub(crate) struct Reschedule {
+    requested: Arc<Mutex<Option<Rescheduling>>>,
+    report: Option<Arc<Fn(&Rescheduling) + Send + Sync>>,
+}
+
+impl Reschedule {
+    /// Return a `Reschedule` whose requests are read with `Reschedule::requested`.
+    pub(crate) fn new() -> Self {
+        Reschedule {
+            requested: Arc::new(Mutex::new(None)),
+            report: None,
+        }
+    }
+
+    /// Return a `Reschedule` also handing each request to the given function.
+    pub(crate) fn reporting<F>(report: F) -> Self
+    where
+        F: Fn(&Rescheduling) + Send + Sync + 'static,
+    {
+        Reschedule {
+            report: Some(Arc::new(report)),
+            ..Reschedule::new()
+        }
+    }
+
+    /// Request the job to be executed again after the given delay.
+    pub(crate) fn delay(&self, delay: Duration) {
+        self.update(|rescheduling| rescheduling.delay = Some(delay));
+    }
+
+    /// Request the given serialized job, of the given version, to be published instead of the
+    /// executed one.
+    pub(crate) fn payload(&self, payload: Vec<u8>, version: u32) {
+        self.update(|rescheduling| rescheduling.payload = Some((payload, version)));
+    }
+
+    /// Return the rescheduling requested by the handler, if any.
+    pub(crate) fn requested(&self) -> Option<Rescheduling> {
+        self.requested.lock().unwrap().clone()
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Rescheduling),
+    {
+        let mut requested = self.requested.lock().unwrap();
+        let rescheduling = requested.get_or_insert_with(Rescheduling::default);
+        f(rescheduling);
+        if let Some(ref report) = self.report {
+            report(rescheduling);
+        }
+    }
+}
+
+impl fmt::Debug for Reschedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Reschedule {{ requested: {:?} }}", self.requested())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let reports = Arc::clone(&reported);
+        let reschedule = Reschedule::reporting(move |rescheduling: &Rescheduling| {
+            reports.lock().unwrap().push(rescheduling.clone())
+        });
+        assert_eq!(reschedule.requested(), None);
+        reschedule.payload(b"{\"cursor\":2}".to_vec(), 1);
+        reschedule.delay(Duration::from_secs(5));
+        let expected = Rescheduling {
+            delay: Some(Duration::from_secs(5)),
+            payload: Some((b"{\"cursor\":2}".to_vec(), 1)),
+        };
+        assert_eq!(reschedule.clone().requested(), Some(expected.clone()));
+        assert_eq!(reported.lock().unwrap().last(), Some(&expected));
+    }
+}
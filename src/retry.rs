@@ -0,0 +1,206 @@
+//! Strategies deciding when failed jobs are retried.
+
+use std::cmp;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::{self, Rng};
+
+/// The granularity of the delays of `RetryStrategy::Exponential`, in milliseconds, which bounds
+/// the number of distinct delays, e.g: of delay queues declared by the `RabbitMQ` broker.
+const JITTER_GRANULARITY: u64 = 100;
+
+/// How long a failed job waits before being retried.
+///
+/// The strategy of a job is given by its `Job::retry_strategy` value, which can be set using the
+/// `retry_backoff` attribute when deriving `Job`. It is applied by the worker when a job
+/// fails, using the delayed jobs support of the `Broker` to postpone the retry.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use batch::RetryStrategy;
+///
+/// let strategy = RetryStrategy::exponential(Duration::from_secs(2), Duration::from_secs(600));
+/// assert!(strategy.delay(1).unwrap() <= Duration::from_secs(2));
+/// ```
+#[derive(Clone)]
+pub enum RetryStrategy {
+    /// Retry the job immediately.
+    Immediate,
+    /// Wait for the given duration before each retry.
+    Fixed(Duration),
+    /// Double the waiting time on each retry, starting at `base` and up to `max`.
+    ///
+    /// A random jitter is applied to each delay, so that jobs failing together are not all
+    /// retried at the same time: each delay is picked between half and the whole of its
+    /// exponential value, rounded to a tenth of a second. Delays shorter than that aren't rounded
+    /// down to zero.
+    Exponential {
+        /// The delay before the first retry.
+        base: Duration,
+        /// The maximum delay between two retries.
+        max: Duration,
+    },
+    /// Compute the delay from the retry number (starting at 1) using the given function.
+    Custom(Arc<Fn(u32) -> Duration + Send + Sync>),
+}
+
+impl fmt::Debug for RetryStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        match *self {
+            RetryStrategy::Immediate => write!(f, "Immediate"),
+            RetryStrategy::Fixed(ref delay) => write!(f, "Fixed({:?})", delay),
+            RetryStrategy::Exponential { ref base, ref max } => {
+                write!(f, "Exponential {{ base: {:?}, max: {:?} }}", base, max)
+            }
+            RetryStrategy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl Default for RetryStrategy {
+    fn default() -> Self {
+        RetryStrategy::Immediate
+    }
+}
+
+impl RetryStrategy {
+    /// Create a strategy waiting for the given duration before each retry.
+    pub fn fixed(delay: Duration) -> Self {
+        RetryStrategy::Fixed(delay)
+    }
+
+    /// Create a strategy doubling the waiting time on each retry, from `base` up to `max`.
+    pub fn exponential(base: Duration, max: Duration) -> Self {
+        RetryStrategy::Exponential { base, max }
+    }
+
+    /// Create a strategy computing the delay from the retry number using the given function.
+    pub fn custom<F>(f: F) -> Self
+    where
+        F: Fn(u32) -> Duration + Send + Sync + 'static,
+    {
+        RetryStrategy::Custom(Arc::new(f))
+    }
+
+    /// Return the duration to wait before the given retry, starting at 1.
+    pub fn delay(&self, retry: u32) -> Option<Duration> {
+        let delay = match *self {
+            RetryStrategy::Immediate => return None,
+            RetryStrategy::Fixed(delay) => delay,
+            RetryStrategy::Exponential { base, max } => {
+                let factor = 2u32.checked_pow(retry.saturating_sub(1));
+                let delay = factor
+                    .and_then(|factor| base.checked_mul(factor))
+                    .map_or(max, |delay| cmp::min(delay, max));
+                jitter(delay)
+            }
+            RetryStrategy::Custom(ref f) => f(retry),
+        };
+        if delay == Duration::from_secs(0) {
+            None
+        } else {
+            Some(delay)
+        }
+    }
+}
+
+/// Shorten the given delay by up to half of it, rounded to `JITTER_GRANULARITY`.
+///
+/// The returned delay never exceeds the given one, and is never rounded down to zero.
+fn jitter(delay: Duration) -> Duration {
+    let millis = delay.as_secs() * 1_000 + u64::from(delay.subsec_nanos() / 1_000_000);
+    let half = millis / 2;
+    let jitter = if half > 0 {
+        rand::thread_rng().gen_range(0, half + 1)
+    } else {
+        0
+    };
+    let rounded =
+        (millis - jitter + JITTER_GRANULARITY / 2) / JITTER_GRANULARITY * JITTER_GRANULARITY;
+    if rounded == 0 {
+        delay
+    } else {
+        cmp::min(Duration::from_millis(rounded), delay)
+    }
+}
+
+/// What the worker does with a job that failed.
+///
+/// The policy of a job is given by its `Job::failure_policy` value, which can be set using the
+/// `failure_policy` attribute when deriving `Job`, and defaults to the policy of the worker
+/// (see `WorkerBuilder::failure_policy`), which is `FailurePolicy::Retry` unless set otherwise.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailurePolicy {
+    /// Publish the job again after the delay given by its `RetryStrategy`, until it exhausted
+    /// its retries, then move it to its dead-letter queue.
+    Retry,
+    /// Ask the broker to deliver the job again right away, as is.
+    ///
+    /// As the job isn't published again, its retries aren't counted: it's delivered again until
+    /// it succeeds. Brokers that can't deliver a job again reject it instead.
+    Requeue,
+    /// Reject the job without retrying it.
+    ///
+    /// With `RabbitMQ`, rejected jobs are dead-lettered by the broker to the dead-letter exchange
+    /// of their queue, if any (see `QueueBuilder::dead_letter_exchange`), and dropped otherwise.
+    Reject,
+    /// Move the job to its dead-letter queue without retrying it.
+    DeadLetter,
+    /// Drop the job without retrying it.
+    Drop,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        FailurePolicy::Retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delays() {
+        let strategy = RetryStrategy::exponential(Duration::from_secs(2), Duration::from_secs(60));
+        for retry in 1..40 {
+            let expected = cmp::min(2u64.checked_pow(retry).unwrap_or(u64::max_value()), 60);
+            let delay = strategy.delay(retry).unwrap().as_secs();
+            assert!(delay <= expected, "retry {}: {} > {}", retry, delay, expected);
+            assert!(delay >= expected / 2, "retry {}: {} < {}", retry, delay, expected / 2);
+        }
+    }
+
+    #[test]
+    fn sub_second_exponential_delays() {
+        let strategy =
+            RetryStrategy::exponential(Duration::from_millis(500), Duration::from_secs(10));
+        for _ in 0..100 {
+            let delay = strategy.delay(1).unwrap();
+            assert!(delay >= Duration::from_millis(200), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(500), "{:?}", delay);
+            assert_eq!(delay.subsec_nanos() % 100_000_000, 0);
+        }
+        let strategy =
+            RetryStrategy::exponential(Duration::from_millis(30), Duration::from_secs(1));
+        assert_eq!(strategy.delay(1), Some(Duration::from_millis(30)));
+        let strategy = RetryStrategy::exponential(Duration::new(0, 10), Duration::from_secs(1));
+        assert_eq!(strategy.delay(1), Some(Duration::new(0, 10)));
+    }
+
+    #[test]
+    fn fixed_and_immediate_delays() {
+        let strategy = RetryStrategy::fixed(Duration::from_secs(30));
+        assert_eq!(strategy.delay(1), Some(Duration::from_secs(30)));
+        assert_eq!(strategy.delay(5), Some(Duration::from_secs(30)));
+        assert_eq!(RetryStrategy::Immediate.delay(1), None);
+        let strategy = RetryStrategy::custom(|retry| Duration::from_secs(u64::from(retry)));
+        assert_eq!(strategy.delay(3), Some(Duration::from_secs(3)));
+    }
+}
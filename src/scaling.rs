@@ -0,0 +1,267 @@
+//! Scaling of the number of jobs executed by a worker at the same time.
+//!
+//! When enabled with `WorkerBuilder::autoscale`, the worker periodically retrieves the
+//! statistics of the queues it consumes from (see `QueueStats`), and hands them to a `Scaler`
+//! deciding how many jobs it should execute at the same time, between a minimum and its
+//! concurrency. The statistics are also exported as metrics when the `metrics` feature is
+//! enabled, for autoscalers running outside of the worker.
+
+use std::cmp;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::task::{self, Task};
+use futures::{future, Async, Future, Poll};
+use tokio_timer::Delay;
+
+use inspect::{QueueInspector, QueueStats};
+#[cfg(feature = "metrics")]
+use metrics;
+
+/// Decides how many jobs a worker executes at the same time, given the statistics of the queues
+/// it consumes from.
+///
+/// The number returned is bounded by the worker to the minimum given to
+/// `WorkerBuilder::autoscale` and its concurrency (see `WorkerBuilder::concurrency`).
+pub trait Scaler: fmt::Debug + Send + Sync {
+    /// Return the number of jobs to execute at the same time, given the current one and the
+    /// statistics of the queues.
+    fn scale(&self, current: u16, stats: &[QueueStats]) -> u16;
+}
+
+/// A `Scaler` executing one job at the same time per given number of jobs waiting in the queues.
+///
+/// # Example
+///
+/// ```
+/// use batch::{Backlog, Worker};
+///
+/// // Execute between 2 and 16 jobs at the same time, one per 10 jobs waiting.
+/// let builder = Worker::builder(())
+///     .concurrency(16)
+///     .autoscale(2, Backlog::new(10));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Backlog {
+    jobs: u64,
+}
+
+impl Backlog {
+    /// Create a new `Backlog` executing one job at the same time per `jobs` waiting jobs.
+    pub fn new(jobs: u64) -> Self {
+        Backlog {
+            jobs: cmp::max(jobs, 1),
+        }
+    }
+}
+
+impl Scaler for Backlog {
+    fn scale(&self, _current: u16, stats: &[QueueStats]) -> u16 {
+        let messages = stats.iter().map(|stats| stats.messages).sum::<u64>();
+        let wanted = (messages + self.jobs - 1) / self.jobs;
+        cmp::min(wanted, u64::from(u16::max_value())) as u16
+    }
+}
+
+/// The number of jobs a worker may execute at the same time, which can change while they
+/// execute.
+#[derive(Debug)]
+pub(crate) struct Capacity {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    used: usize,
+    waiters: Vec<Task>,
+}
+
+impl Capacity {
+    pub(crate) fn new(limit: usize) -> Self {
+        Capacity {
+            state: Mutex::new(State {
+                limit,
+                used: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    /// Return the number of jobs that may execute at the same time.
+    pub(crate) fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Change the number of jobs that may execute at the same time.
+    ///
+    /// Jobs already executing above the new limit aren't interrupted.
+    pub(crate) fn resize(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.limit = limit;
+        for waiter in state.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
+
+    /// Return a `Future` resolving to a permit to execute a job, once the limit allows it.
+    pub(crate) fn acquire(capacity: &Arc<Capacity>) -> Acquire {
+        Acquire {
+            capacity: Arc::clone(capacity),
+        }
+    }
+}
+
+/// A `Future` resolving to a `Permit`, see `Capacity::acquire`.
+pub(crate) struct Acquire {
+    capacity: Arc<Capacity>,
+}
+
+impl Future for Acquire {
+    type Item = Permit;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Permit, ()> {
+        let mut state = self.capacity.state.lock().unwrap();
+        if state.used < state.limit {
+            state.used += 1;
+            Ok(Async::Ready(Permit {
+                capacity: Arc::clone(&self.capacity),
+            }))
+        } else {
+            state.waiters.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// The permit to execute a job, released once dropped.
+pub(crate) struct Permit {
+    capacity: Arc<Capacity>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.capacity.state.lock().unwrap();
+        state.used -= 1;
+        for waiter in state.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
+}
+
+/// The settings of the scaling of a worker.
+#[derive(Clone, Debug)]
+pub(crate) struct Autoscaling {
+    pub(crate) scaler: Arc<Scaler>,
+    pub(crate) capacity: Arc<Capacity>,
+    pub(crate) min: u16,
+    pub(crate) max: u16,
+}
+
+/// Return a `Future` periodically retrieving the statistics of the given queues, exporting them
+/// and scaling the worker if enabled.
+///
+/// The returned `Future` never resolves, errors are logged.
+pub(crate) fn watch(
+    inspector: Arc<QueueInspector>,
+    queues: Vec<String>,
+    autoscaling: Option<Autoscaling>,
+    interval: Duration,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let task = future::loop_fn((), move |_| {
+        let tasks = queues
+            .iter()
+            .map(|queue| inspector.stats(queue))
+            .collect::<Vec<_>>();
+        let autoscaling = autoscaling.clone();
+        future::join_all(tasks)
+            .then(move |result| {
+                match result {
+                    Ok(stats) => observe(&stats, autoscaling.as_ref()),
+                    Err(e) => warn!("Couldn't retrieve the statistics of the queues: {}", e),
+                }
+                Delay::new(Instant::now() + interval)
+                    .map_err(|e| error!("Couldn't wait for the next statistics: {}", e))
+            })
+            .map(|_| future::Loop::Continue::<(), ()>(()))
+    });
+    Box::new(task)
+}
+
+/// Export the given statistics, and scale the worker according to them if enabled.
+fn observe(stats: &[QueueStats], autoscaling: Option<&Autoscaling>) {
+    for queue in stats {
+        debug!(
+            "Queue {} has {} pending jobs and {:?} consumers",
+            queue.name, queue.messages, queue.consumers
+        );
+        #[cfg(feature = "metrics")]
+        metrics::queue_stats(queue);
+    }
+    if let Some(autoscaling) = autoscaling {
+        let current = autoscaling.capacity.limit() as u16;
+        let wanted = autoscaling.scaler.scale(current, stats);
+        let wanted = cmp::min(cmp::max(wanted, autoscaling.min), autoscaling.max);
+        if wanted != current {
+            info!("Scaling worker from {} to {} concurrent jobs", current, wanted);
+            autoscaling.capacity.resize(usize::from(wanted));
+        }
+        #[cfg(feature = "metrics")]
+        metrics::concurrency(wanted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(messages: u64) -> QueueStats {
+        QueueStats {
+            name: "tests.scaling".into(),
+            messages,
+            consumers: None,
+        }
+    }
+
+    #[test]
+    fn backlog() {
+        let scaler = Backlog::new(10);
+        assert_eq!(scaler.scale(4, &[]), 0);
+        assert_eq!(scaler.scale(4, &[stats(1)]), 1);
+        assert_eq!(scaler.scale(4, &[stats(25), stats(10)]), 4);
+    }
+
+    #[test]
+    fn scaling() {
+        let autoscaling = Autoscaling {
+            scaler: Arc::new(Backlog::new(10)),
+            capacity: Arc::new(Capacity::new(2)),
+            min: 2,
+            max: 8,
+        };
+        observe(&[stats(55)], Some(&autoscaling));
+        assert_eq!(autoscaling.capacity.limit(), 6);
+        observe(&[stats(500)], Some(&autoscaling));
+        assert_eq!(autoscaling.capacity.limit(), 8);
+        observe(&[stats(0)], Some(&autoscaling));
+        assert_eq!(autoscaling.capacity.limit(), 2);
+    }
+
+    #[test]
+    fn capacity() {
+        let capacity = Arc::new(Capacity::new(1));
+        let first = Capacity::acquire(&capacity).wait().unwrap();
+        let mut second = Capacity::acquire(&capacity);
+        // `second` is polled from a task, as waiting for it would block.
+        let polled = future::lazy(|| Ok::<_, ()>(second.poll())).wait().unwrap();
+        assert!(polled.unwrap().is_not_ready());
+
+        capacity.resize(2);
+        let polled = future::lazy(|| Ok::<_, ()>(second.poll())).wait().unwrap();
+        assert!(polled.unwrap().is_ready());
+        drop(first);
+        assert_eq!(capacity.state.lock().unwrap().used, 0);
+    }
+}
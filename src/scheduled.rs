@@ -0,0 +1,242 @@
+//! Jobs scheduled to be published at a given date.
+//!
+//! Delayed jobs (see `Query::delay`) wait in the broker, which can't list nor cancel them, and
+//! delays are lost by brokers which don't persist them. Jobs sent with `Client::send_at` are
+//! stored in a `ScheduleStore` instead, until a `Dispatcher` publishes them once they are due. As
+//! long as the store is durable (e.g: `batch_postgres::Schedule`), scheduled jobs survive the
+//! restart of every producer and worker, and are published as soon as a dispatcher runs again
+//! if they became due in the meantime. Pending jobs are listed with `Client::scheduled` and
+//! canceled with `Client::cancel_scheduled`.
+//!
+//! A job whose publication couldn't be recorded in the store is published again by the next
+//! iteration of the dispatcher: handlers of scheduled jobs should be idempotent, see
+//! `Idempotency`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::{future, Future};
+use tokio_timer::Delay;
+use uuid::Uuid;
+
+use broker::Properties;
+use client::Client;
+use error::{Error, ErrorKind};
+
+/// The default number of jobs published by each iteration of a `Dispatcher`.
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// The default number of milliseconds a `Dispatcher` waits for once no job is due.
+const DEFAULT_POLL_INTERVAL: u64 = 1_000;
+
+/// A job stored in a `ScheduleStore`, waiting to be published.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledJob {
+    /// The date at which the job is published.
+    pub due: DateTime<Utc>,
+    /// The serialized job, encoded with the codec given by its properties.
+    pub payload: Vec<u8>,
+    /// The properties of the job.
+    pub properties: Properties,
+}
+
+/// Storage of the jobs waiting to be published by a `Dispatcher`.
+pub trait ScheduleStore: fmt::Debug + Send + Sync {
+    /// Store the given job until it is published or removed.
+    fn schedule(&self, job: &ScheduledJob) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Return the jobs waiting to be published, in the order they are due.
+    fn pending(&self) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send>;
+
+    /// Return at most `limit` jobs due at the given date, in the order they are due.
+    fn due(
+        &self,
+        now: DateTime<Utc>,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<ScheduledJob>, Error = Error> + Send>;
+
+    /// Remove the job with the given ID, once it was published or canceled.
+    ///
+    /// Returns whether the job was still waiting to be published.
+    fn remove(&self, id: Uuid) -> Box<Future<Item = bool, Error = Error> + Send>;
+}
+
+/// A task publishing the jobs of a `ScheduleStore` once they are due.
+///
+/// Jobs are published one at a time in the order they are due, then removed from the store. A
+/// single dispatcher should run for a given store, as concurrent dispatchers would publish the
+/// same jobs.
+#[derive(Debug)]
+pub struct Dispatcher {
+    store: Arc<ScheduleStore>,
+    client: Client,
+    batch_size: usize,
+    poll_interval: Duration,
+}
+
+impl Dispatcher {
+    /// Create a new `Dispatcher` publishing the jobs of the given store with the given client.
+    pub fn new<S>(store: S, client: Client) -> Self
+    where
+        S: ScheduleStore + 'static,
+    {
+        Dispatcher {
+            store: Arc::new(store),
+            client,
+            batch_size: DEFAULT_BATCH_SIZE,
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL),
+        }
+    }
+
+    /// Set the maximum number of due jobs fetched from the store at once.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Set the duration to wait before polling the store again once no job is due.
+    ///
+    /// Jobs are published up to this duration after they are due.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Publish the jobs that are due once, returning the number of jobs published.
+    ///
+    /// At most `batch_size` jobs are published. The returned `Future` fails on the first job
+    /// that couldn't be published or removed, leaving it and the following jobs in the store.
+    pub fn dispatch_once(&self) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let store = Arc::clone(&self.store);
+        let client = self.client.clone();
+        let task = self
+            .store
+            .due(Utc::now(), self.batch_size)
+            .and_then(move |jobs| {
+                future::loop_fn(
+                    (jobs.into_iter(), 0),
+                    move |(mut jobs, published)| -> Box<
+                        Future<Item = future::Loop<usize, _>, Error = Error> + Send,
+                    > {
+                        let job = match jobs.next() {
+                            Some(job) => job,
+                            None => return Box::new(future::ok(future::Loop::Break(published))),
+                        };
+                        let store = Arc::clone(&store);
+                        let id = job.properties.id;
+                        let task = client
+                            .send(&job.payload, &job.properties)
+                            .and_then(move |_| store.remove(id))
+                            .map(move |_| future::Loop::Continue((jobs, published + 1)));
+                        Box::new(task)
+                    },
+                )
+            });
+        Box::new(task)
+    }
+
+    /// Return a `Future` publishing the jobs of the store once they are due, until it is
+    /// dropped.
+    ///
+    /// The store is polled again right away after a full batch, and after `poll_interval`
+    /// otherwise. Failures are logged and retried after `poll_interval`.
+    pub fn run(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = future::loop_fn(self, |dispatcher| {
+            let poll_interval = dispatcher.poll_interval;
+            let batch_size = dispatcher.batch_size;
+            let task = dispatcher.dispatch_once();
+            task.then(move |result| {
+                let wait = match result {
+                    Ok(published) => {
+                        if published > 0 {
+                            debug!("Published {} scheduled jobs", published);
+                        }
+                        published < batch_size
+                    }
+                    Err(e) => {
+                        error!("Couldn't publish the scheduled jobs: {}", e);
+                        true
+                    }
+                };
+                let delay: Box<Future<Item = (), Error = Error> + Send> = if wait {
+                    let task = Delay::new(Instant::now() + poll_interval)
+                        .map_err(|e| -> Error { ErrorKind::Timer(e).into() });
+                    Box::new(task)
+                } else {
+                    Box::new(future::ok(()))
+                };
+                delay
+            })
+            .map(move |_| future::Loop::Continue::<(), _>(dispatcher))
+        });
+        Box::new(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    use job::{Job, Priority};
+    use memory;
+    use topology::queue;
+
+    #[derive(Serialize, Deserialize)]
+    struct Remind {
+        user: u64,
+    }
+
+    impl Job for Remind {
+        fn name() -> &'static str {
+            "remind"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "tests.scheduled"
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let connection = memory::Connection::new(vec![queue("tests.scheduled")]);
+        let client = Client::new(connection.clone()).schedule_store(connection.clone());
+        let past = SystemTime::now() - Duration::from_secs(60);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let later = client.send_at(Remind { user: 1 }, future).wait().unwrap();
+        let due = client.send_at(Remind { user: 2 }, past).wait().unwrap();
+        let pending = client.scheduled().wait().unwrap();
+        let ids = pending
+            .iter()
+            .map(|job| job.properties.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![due, later]);
+        assert!(connection.is_empty("tests.scheduled"));
+
+        let dispatcher = Dispatcher::new(connection.clone(), client.clone());
+        assert_eq!(dispatcher.dispatch_once().wait().unwrap(), 1);
+        assert_eq!(dispatcher.dispatch_once().wait().unwrap(), 0);
+        assert_eq!(connection.len("tests.scheduled"), 1);
+        assert!(client.cancel_scheduled(later).wait().unwrap());
+        assert!(!client.cancel_scheduled(later).wait().unwrap());
+        assert!(client.scheduled().wait().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,628 @@
+//! Periodic jobs.
+//!
+//! The `Scheduler` is a long-running task publishing jobs according to cron expressions. Jobs can
+//! declare their schedule using the `cron` attribute, or be registered with an explicit
+//! expression using [`SchedulerBuilder::schedule`](struct.SchedulerBuilder.html#method.schedule).
+//!
+//! Cron expressions use the usual 5 fields (minute, hour, day of month, month, day of week), an
+//! optional leading seconds field is also accepted.
+//!
+//! # Timezones
+//!
+//! Schedules are evaluated in UTC, unless a timezone is given with the `timezone` attribute of
+//! the job, `SchedulerBuilder::schedule_in` or `SchedulerBuilder::timezone`. Expressions are then
+//! matched against the wall clock of this timezone: `0 9 * * MON` is published at 09:00 local
+//! time all year long. Across DST changes, an occurrence falling in the hour skipped when clocks
+//! go forward is published when the clocks read the same time in the previous offset (i.e: an
+//! occurrence at 02:30 is published at 03:30), and an occurrence in the hour repeated when clocks
+//! go back is only published once, the first time.
+//!
+//! # Jitter and splay
+//!
+//! Many jobs sharing a schedule are all published at the same instant, which puts a burst of
+//! load on the broker, the workers and whatever they call. Two options spread them over time:
+//!
+//! * the jitter (see `SchedulerBuilder::jitter` and the `jitter` attribute) delays each
+//!   occurrence by a pseudo-random duration up to the given maximum, drawn from the job and the
+//!   occurrence, so that it changes at every occurrence but every scheduler agrees on it.
+//! * the splay (see `SchedulerBuilder::splay`) delays every occurrence by a fixed duration up to
+//!   the given maximum, drawn from the job and the hostname, so that schedulers running on
+//!   different hosts publish at different times.
+//!
+//! # Running multiple schedulers
+//!
+//! By default, each occurrence of a schedule is published by every running scheduler. When
+//! running more than one instance (e.g: for availability), give them a shared `Lock`: each
+//! occurrence is then only published by the instance acquiring the lock for it.
+//!
+//! # Example
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate batch;
+//! extern crate futures;
+//! #[macro_use]
+//! extern crate lazy_static;
+//! #[macro_use]
+//! extern crate serde;
+//! extern crate tokio;
+//!
+//! use batch::scheduler::Scheduler;
+//! use batch::Client;
+//! use futures::Future;
+//!
+//! #[derive(Default, Serialize, Deserialize, Job)]
+//! #[job(routing_key = "maintenance", cron = "0 3 * * *")]
+//! struct PurgeExpiredSessions;
+//!
+//! #[derive(Serialize, Deserialize, Job)]
+//! #[job(routing_key = "reports")]
+//! struct SendReport {
+//!     kind: String,
+//! }
+//!
+//! fn main() {
+//!     let task = Client::builder()
+//!         .connection_url("amqp://localhost/%2f")
+//!         .build()
+//!         .and_then(|client| {
+//!             Scheduler::builder(client)
+//!                 .job::<PurgeExpiredSessions>()
+//!                 .schedule("0 9 * * MON", || SendReport { kind: "weekly".into() })
+//!                 .build()
+//!         })
+//!         .and_then(|scheduler| scheduler.run())
+//!         .map_err(|e| eprintln!("An error occured: {}", e));
+//!
+//! # if false {
+//!     tokio::run(task);
+//! # }
+//! }
+//! ```
+
+use std::cmp;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{self, DateTime, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use futures::{future, Future};
+use hostname;
+use tokio_timer::Delay;
+
+use client::Client;
+use error::{Error, ErrorKind, Result};
+use job::Job;
+use query;
+
+/// The number of seconds the lock of an occurrence is held for.
+const LOCK_DURATION: u64 = 60;
+
+/// The maximum number of seconds the scheduler sleeps between two checks of its schedules.
+const MAX_SLEEP: u64 = 60;
+
+/// A lock shared between `Scheduler` instances.
+///
+/// Before publishing an occurrence of a schedule, a scheduler tries to acquire the lock with a
+/// key identifying this occurrence. Implementations must ensure that for a given key, only one
+/// call to `acquire` resolves to `true` until the given duration elapsed.
+///
+/// Locks are also used by the `Client` to send unique jobs, see `Client::unique_lock`.
+pub trait Lock: fmt::Debug + Send + Sync {
+    /// Try to acquire the lock for the given key, returning whether it was acquired.
+    fn acquire(
+        &self,
+        key: &str,
+        duration: Duration,
+    ) -> Box<Future<Item = bool, Error = Error> + Send>;
+}
+
+/// Type of the functions publishing the job of a schedule.
+type PublishFn = Fn(&Client) -> Box<Future<Item = (), Error = Error> + Send> + Send + Sync;
+
+/// A job registered to a `Scheduler` and its schedule.
+struct Entry {
+    key: String,
+    schedule: Schedule,
+    timezone: Option<Tz>,
+    jitter: Option<Duration>,
+    splay: Duration,
+    publish: Box<PublishFn>,
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "Entry {{ key: {:?}, timezone: {:?} }}",
+            self.key,
+            self.timezone.unwrap_or(Tz::UTC)
+        )
+    }
+}
+
+impl Entry {
+    /// Return the occurrences of this entry's schedule along with the date they are due at, for
+    /// the occurrences due after the given date.
+    ///
+    /// The due date of an occurrence is shifted by the jitter and the splay of the entry.
+    fn occurrences<'a>(
+        &'a self,
+        after: DateTime<Utc>,
+    ) -> Box<Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> + 'a> {
+        let timezone = self.timezone.unwrap_or(Tz::UTC);
+        let max_offset = self.splay + self.jitter.unwrap_or_default();
+        let start = after
+            - chrono::Duration::from_std(max_offset).unwrap_or_else(|_| chrono::Duration::zero());
+        // The schedule is evaluated on the wall clock, as if local dates were UTC dates.
+        let local = Utc.from_utc_datetime(&start.with_timezone(&timezone).naive_local());
+        let occurrences = self
+            .schedule
+            .after(&local)
+            .map(move |local| resolve(timezone, &local.naive_utc()))
+            .filter(move |occurrence| *occurrence > start)
+            .map(move |occurrence| (occurrence, occurrence + self.offset(occurrence)))
+            .filter(move |&(_, due)| due > after);
+        Box::new(occurrences)
+    }
+
+    /// Return the duration by which the given occurrence is delayed.
+    fn offset(&self, occurrence: DateTime<Utc>) -> chrono::Duration {
+        let jitter = self.jitter.map_or(Duration::default(), |max| {
+            let timestamp = occurrence.timestamp().to_string();
+            spread(&[self.key.as_bytes(), timestamp.as_bytes()], max)
+        });
+        chrono::Duration::from_std(self.splay + jitter).unwrap_or_else(|_| chrono::Duration::zero())
+    }
+}
+
+/// Return the date at which the clocks of the given timezone read the given local date.
+///
+/// Ambiguous dates resolve to their first occurrence, and dates skipped by the clocks resolve
+/// using the offset in effect before the transition.
+fn resolve(timezone: Tz, local: &NaiveDateTime) -> DateTime<Utc> {
+    match timezone.from_local_datetime(local).earliest() {
+        Some(date) => date.with_timezone(&Utc),
+        None => {
+            // Transitions are never less than a day apart.
+            let before = *local - chrono::Duration::days(1);
+            let offset = timezone.offset_from_utc_datetime(&before).fix();
+            let utc = *local - chrono::Duration::seconds(i64::from(offset.local_minus_utc()));
+            Utc.from_utc_datetime(&utc)
+        }
+    }
+}
+
+/// Return a duration between zero and `max`, drawn from the given values.
+///
+/// Every scheduler must draw the same duration, whatever its platform and the version of Rust it
+/// was built with: the values are hashed with FNV-1a, whose specification is fixed, unlike the
+/// hasher of the standard library.
+fn spread(values: &[&[u8]], max: Duration) -> Duration {
+    let max = max.as_secs() * 1_000 + u64::from(max.subsec_nanos() / 1_000_000);
+    if max == 0 {
+        return Duration::default();
+    }
+    Duration::from_millis(fnv1a(values) % (max + 1))
+}
+
+/// Return the 64 bits FNV-1a hash of the given values, each one followed by a `0xff` byte.
+fn fnv1a(values: &[&[u8]]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    values
+        .iter()
+        .flat_map(|value| value.iter().chain(&[0xff]))
+        .fold(OFFSET_BASIS, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
+}
+
+/// Parse the name of a timezone of the IANA database.
+fn parse_timezone(timezone: &str) -> Result<Tz> {
+    timezone
+        .parse::<Tz>()
+        .map_err(|_| ErrorKind::InvalidCron(format!("unknown timezone {:?}", timezone)).into())
+}
+
+/// Parse a cron expression, accepting both the 5 fields and the 6 fields (with seconds) syntax.
+fn parse(expression: &str) -> Result<Schedule> {
+    let fields = expression.split_whitespace().count();
+    let normalized = if fields == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&normalized)
+        .map_err(|e| ErrorKind::InvalidCron(format!("{:?}: {}", expression, e)).into())
+}
+
+/// A builder to ease the construction of `Scheduler` instances.
+///
+/// See [`Scheduler::builder`](struct.Scheduler.html#method.builder).
+#[derive(Debug)]
+pub struct SchedulerBuilder {
+    client: Client,
+    entries: Vec<Result<Entry>>,
+    lock: Option<Arc<Lock>>,
+    timezone: Option<Tz>,
+    jitter: Option<Duration>,
+    splay: Option<Duration>,
+}
+
+impl SchedulerBuilder {
+    /// Register a job declaring its schedule with the `cron` attribute.
+    ///
+    /// The schedule is evaluated in the timezone given by the `timezone` attribute and delayed
+    /// by the `jitter` attribute, if any. The published jobs are created using the job's
+    /// `Default` implementation.
+    pub fn job<T>(self) -> Self
+    where
+        T: Job + Default + Send + 'static,
+    {
+        match T::cron() {
+            Some(expression) => self.entry(expression, T::timezone(), T::jitter(), T::default),
+            None => {
+                let error = ErrorKind::InvalidCron(format!("job {:?} has no schedule", T::name()));
+                self.push(Err(error.into()))
+            }
+        }
+    }
+
+    /// Register a job to be published according to the given cron expression.
+    ///
+    /// Occurrences are delayed by the `jitter` attribute of the job, if any. The given function
+    /// is called to create the job each time it is published.
+    pub fn schedule<T, F>(self, expression: &str, factory: F) -> Self
+    where
+        T: Job + Send + 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        self.entry(expression, None, T::jitter(), factory)
+    }
+
+    /// Register a job to be published according to the given cron expression, evaluated in the
+    /// given timezone (e.g: `"Europe/Paris"`).
+    ///
+    /// Occurrences are delayed by the `jitter` attribute of the job, if any. The given function
+    /// is called to create the job each time it is published.
+    pub fn schedule_in<T, F>(self, expression: &str, timezone: &str, factory: F) -> Self
+    where
+        T: Job + Send + 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        self.entry(expression, Some(timezone), T::jitter(), factory)
+    }
+
+    fn entry<T, F>(
+        self,
+        expression: &str,
+        timezone: Option<&str>,
+        jitter: Option<Duration>,
+        factory: F,
+    ) -> Self
+    where
+        T: Job + Send + 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        let timezone = match timezone {
+            Some(timezone) => parse_timezone(timezone).map(Some),
+            None => Ok(None),
+        };
+        let entry = timezone.and_then(|timezone| {
+            parse(expression).map(|schedule| Entry {
+                key: format!("{}:{}", T::name(), expression),
+                schedule,
+                timezone,
+                jitter,
+                splay: Duration::default(),
+                publish: Box::new(move |client| query::job(factory()).send(client)),
+            })
+        });
+        self.push(entry)
+    }
+
+    /// Set the timezone the schedules are evaluated in when they don't give one.
+    ///
+    /// Defaults to UTC. An unknown timezone makes `build` fail.
+    pub fn timezone(mut self, timezone: &str) -> Self {
+        match parse_timezone(timezone) {
+            Ok(timezone) => {
+                self.timezone = Some(timezone);
+                self
+            }
+            Err(e) => self.push(Err(e)),
+        }
+    }
+
+    /// Set the maximum duration by which each occurrence of the schedules is delayed when they
+    /// don't give one.
+    ///
+    /// The delay of each occurrence is drawn from the job and the date of the occurrence: it
+    /// differs from one occurrence to the next, but is the same for every scheduler.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Set the maximum duration by which every occurrence of the schedules is delayed on this
+    /// host.
+    ///
+    /// The delay is drawn from the job and the hostname: it is the same for every occurrence,
+    /// but differs from one host to another.
+    pub fn splay(mut self, splay: Duration) -> Self {
+        self.splay = Some(splay);
+        self
+    }
+
+    /// Set the lock used to coordinate multiple `Scheduler` instances.
+    pub fn lock<L>(mut self, lock: L) -> Self
+    where
+        L: Lock + 'static,
+    {
+        self.lock = Some(Arc::new(lock));
+        self
+    }
+
+    fn push(mut self, entry: Result<Entry>) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// Create a new `Scheduler` instance from this builder data.
+    ///
+    /// Fails if one of the registered cron expressions is invalid.
+    pub fn build(self) -> Result<Scheduler> {
+        let mut entries = self.entries.into_iter().collect::<Result<Vec<_>>>()?;
+        let hostname = hostname::get_hostname().unwrap_or_default();
+        for entry in &mut entries {
+            entry.timezone = entry.timezone.or(self.timezone);
+            entry.jitter = entry.jitter.or(self.jitter);
+            if let Some(splay) = self.splay {
+                entry.splay = spread(&[hostname.as_bytes(), entry.key.as_bytes()], splay);
+            }
+        }
+        Ok(Scheduler {
+            client: self.client,
+            entries: Arc::new(entries),
+            lock: self.lock,
+        })
+    }
+}
+
+/// Long-running task publishing jobs on schedule.
+#[derive(Debug)]
+pub struct Scheduler {
+    client: Client,
+    entries: Arc<Vec<Entry>>,
+    lock: Option<Arc<Lock>>,
+}
+
+impl Scheduler {
+    /// Create a new `SchedulerBuilder` publishing jobs using the given client.
+    pub fn builder(client: Client) -> SchedulerBuilder {
+        SchedulerBuilder {
+            client,
+            entries: Vec::new(),
+            lock: None,
+            timezone: None,
+            jitter: None,
+            splay: None,
+        }
+    }
+
+    /// Publish jobs on schedule, until an error occurs in the timer.
+    ///
+    /// Errors occurring while publishing a job are logged and don't stop the scheduler. This
+    /// function requires a Tokio timer to be available, which is the case when running on the
+    /// default runtime.
+    pub fn run(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let task = future::loop_fn((self, Utc::now()), |(scheduler, last)| {
+            let now = Utc::now();
+            let publish = scheduler.publish_due(last, now);
+            let next = scheduler.next_occurrence(now);
+            let max_sleep = Duration::from_secs(MAX_SLEEP);
+            let sleep = next
+                .and_then(|next| next.signed_duration_since(Utc::now()).to_std().ok())
+                .map_or(max_sleep, |sleep| cmp::min(sleep, max_sleep));
+            trace!("Scheduler sleeping for {:?}", sleep);
+            publish
+                .and_then(move |_| {
+                    Delay::new(Instant::now() + sleep).map_err(|e| ErrorKind::Timer(e).into())
+                })
+                .map(move |_| future::Loop::Continue((scheduler, now)))
+        });
+        Box::new(task)
+    }
+
+    /// Return the date of the next occurrence of any schedule after the given date.
+    fn next_occurrence(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.occurrences(after).next().map(|(_, due)| due))
+            .min()
+    }
+
+    /// Publish the jobs whose schedule had an occurrence due in `(last, now]`.
+    ///
+    /// Only the last occurrence of each schedule is published, so that a scheduler waking up
+    /// late doesn't publish a burst of jobs.
+    fn publish_due(
+        &self,
+        last: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let mut tasks = Vec::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let occurrence = entry
+                .occurrences(last)
+                .take_while(|&(occurrence, _)| occurrence <= now)
+                .filter(|&(_, due)| due <= now)
+                .map(|(occurrence, _)| occurrence)
+                .last();
+            let occurrence = match occurrence {
+                Some(occurrence) => occurrence,
+                None => continue,
+            };
+            let key = format!("batch:cron:{}:{}", entry.key, occurrence.timestamp());
+            let acquire: Box<Future<Item = bool, Error = Error> + Send> = match self.lock {
+                Some(ref lock) => lock.acquire(&key, Duration::from_secs(LOCK_DURATION)),
+                None => Box::new(future::ok(true)),
+            };
+            let client = self.client.clone();
+            let entries = Arc::clone(&self.entries);
+            let task = acquire
+                .and_then(move |acquired| -> Box<Future<Item = (), Error = Error> + Send> {
+                    if acquired {
+                        debug!("Publishing scheduled job {:?}", key);
+                        (entries[index].publish)(&client)
+                    } else {
+                        debug!("Scheduled job {:?} was published by another scheduler", key);
+                        Box::new(future::ok(()))
+                    }
+                })
+                .then(move |result| -> StdResult<(), Error> {
+                    if let Err(e) = result {
+                        error!("Couldn't publish scheduled job: {}", e);
+                    }
+                    Ok(())
+                });
+            tasks.push(task);
+        }
+        Box::new(future::join_all(tasks).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_cron_expressions() {
+        let schedule = parse("30 2 * * *").unwrap();
+        let after = Utc.ymd(2018, 5, 1).and_hms(12, 0, 0);
+        let next = schedule.after(&after).next().unwrap();
+        assert_eq!(next, Utc.ymd(2018, 5, 2).and_hms(2, 30, 0));
+
+        let schedule = parse("15 30 2 * * *").unwrap();
+        let next = schedule.after(&after).next().unwrap();
+        assert_eq!(next, Utc.ymd(2018, 5, 2).and_hms(2, 30, 15));
+
+        assert!(parse("not a cron expression").is_err());
+    }
+
+    fn entry(expression: &str, timezone: &str) -> Entry {
+        Entry {
+            key: expression.to_string(),
+            schedule: parse(expression).unwrap(),
+            timezone: Some(parse_timezone(timezone).unwrap()),
+            jitter: None,
+            splay: Duration::default(),
+            publish: Box::new(|_| Box::new(future::ok(()))),
+        }
+    }
+
+    fn occurrences(entry: &Entry, after: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        entry
+            .occurrences(after)
+            .take(3)
+            .map(|(_, due)| due)
+            .collect()
+    }
+
+    #[test]
+    fn timezones() {
+        assert!(parse_timezone("Europe/Pariss").is_err());
+        let entry = entry("0 9 * * MON", "Europe/Paris");
+        // Mondays before and after the switch to summer time.
+        let after = Utc.ymd(2018, 3, 18).and_hms(12, 0, 0);
+        assert_eq!(
+            occurrences(&entry, after),
+            vec![
+                Utc.ymd(2018, 3, 19).and_hms(8, 0, 0),
+                Utc.ymd(2018, 3, 26).and_hms(7, 0, 0),
+                Utc.ymd(2018, 4, 2).and_hms(7, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn daylight_saving_time() {
+        let entry = entry("30 2 * * *", "Europe/Paris");
+        // 02:30 doesn't exist on March 25th, and is published at 03:30.
+        let after = Utc.ymd(2018, 3, 24).and_hms(12, 0, 0);
+        assert_eq!(
+            occurrences(&entry, after),
+            vec![
+                Utc.ymd(2018, 3, 25).and_hms(1, 30, 0),
+                Utc.ymd(2018, 3, 26).and_hms(0, 30, 0),
+                Utc.ymd(2018, 3, 27).and_hms(0, 30, 0),
+            ]
+        );
+        // 02:30 happens twice on October 28th, and is published once.
+        let after = Utc.ymd(2018, 10, 27).and_hms(12, 0, 0);
+        assert_eq!(
+            occurrences(&entry, after),
+            vec![
+                Utc.ymd(2018, 10, 28).and_hms(0, 30, 0),
+                Utc.ymd(2018, 10, 29).and_hms(1, 30, 0),
+                Utc.ymd(2018, 10, 30).and_hms(1, 30, 0),
+            ]
+        );
+        let after = Utc.ymd(2018, 10, 28).and_hms(1, 15, 0);
+        assert_eq!(
+            occurrences(&entry, after)[0],
+            Utc.ymd(2018, 10, 29).and_hms(1, 30, 0)
+        );
+    }
+
+    #[test]
+    fn jitter_and_splay() {
+        let mut entry = entry("0 9 * * *", "UTC");
+        entry.jitter = Some(Duration::from_secs(60));
+        entry.splay = Duration::from_secs(30);
+        let after = Utc.ymd(2018, 5, 1).and_hms(12, 0, 0);
+        let dues = entry.occurrences(after).take(10).collect::<Vec<_>>();
+        for &(occurrence, due) in &dues {
+            let offset = due.signed_duration_since(occurrence);
+            assert!(offset >= chrono::Duration::seconds(30));
+            assert!(offset <= chrono::Duration::seconds(90));
+        }
+        let offsets = dues
+            .iter()
+            .map(|&(occurrence, due)| due - occurrence)
+            .collect::<Vec<_>>();
+        assert!(offsets.iter().any(|offset| *offset != offsets[0]));
+        // Every scheduler agrees on the date an occurrence is due at.
+        assert_eq!(entry.occurrences(after).take(10).collect::<Vec<_>>(), dues);
+        // An occurrence whose delay isn't elapsed yet is still due.
+        let (occurrence, due) = dues[0];
+        let late = entry.occurrences(occurrence).next().unwrap();
+        assert_eq!(late, (occurrence, due));
+    }
+
+    #[test]
+    fn stable_spread() {
+        // FNV-1a of "a", from the reference test vectors, followed by the separator.
+        let mut expected = 0xaf63_dc4c_8601_ec8c_u64;
+        expected = (expected ^ 0xff).wrapping_mul(0x0100_0000_01b3);
+        assert_eq!(fnv1a(&[b"a"]), expected);
+        assert_eq!(
+            fnv1a(&[b"reports", b"1525176000"]),
+            4_460_577_856_990_582_033
+        );
+        let jitter = spread(&[b"reports", b"1525176000"], Duration::from_secs(60));
+        assert_eq!(jitter, Duration::from_millis(7_611));
+        let splay = spread(&[b"worker-1", b"reports"], Duration::from_secs(30));
+        assert_eq!(splay, Duration::from_millis(2_398));
+        assert_eq!(
+            spread(&[b"reports"], Duration::default()),
+            Duration::default()
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Signatures of job payloads.
+
+use std::fmt;
+#[cfg(feature = "signing")]
+use std::result::Result as StdResult;
+
+#[cfg(feature = "signing")]
+use ring::{constant_time, digest, hmac};
+
+use broker::Properties;
+use error::Result;
+
+/// The header holding the signature of a job.
+pub const SIGNATURE_HEADER: &str = "x-batch-signature";
+
+/// A hook signing the jobs published by a client, and verifying their signature before they
+/// are executed.
+///
+/// Signing is enabled on the client with `Client::signer`, and on the worker with
+/// `WorkerBuilder::signer`. The signature covers the ID, the name and the payload (as sent to
+/// the broker, i.e: once compressed and encrypted) of the job, and is sent along with it in the
+/// `x-batch-signature` header. Workers verifying signatures move the jobs that aren't signed or
+/// whose signature is invalid to their dead-letter queue instead of executing them.
+///
+/// An implementation based on HMAC-SHA256 is enabled by the `signing` feature, see `Hmac`.
+pub trait Signer: fmt::Debug + Send + Sync {
+    /// Return the signature of the given message.
+    fn sign(&self, message: &[u8]) -> Result<String>;
+
+    /// Returns true if the given signature of the given message is valid.
+    fn verify(&self, message: &[u8], signature: &str) -> bool;
+}
+
+/// A `Signer` using HMAC-SHA256 with a shared secret, supporting secret rotation.
+///
+/// Jobs are signed with the current secret, and signatures made with any secret known to the
+/// signer are valid. To rotate secrets, deploy workers knowing the new secret first, then make
+/// it the current secret of the clients, and remove the old secret once the jobs signed with it
+/// were consumed.
+///
+/// # Example
+///
+/// ```
+/// use batch::{memory, queue, Client, Hmac};
+///
+/// let signer = Hmac::new(b"current secret")
+///     .secret(b"previous secret");
+/// let connection = memory::Connection::new(vec![queue("payments")]);
+/// let client = Client::new(connection)
+///     .signer(signer);
+/// ```
+#[cfg(feature = "signing")]
+pub struct Hmac {
+    keys: Vec<hmac::SigningKey>,
+}
+
+#[cfg(feature = "signing")]
+impl fmt::Debug for Hmac {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "Hmac {{ secrets: {} }}", self.keys.len())
+    }
+}
+
+#[cfg(feature = "signing")]
+impl Hmac {
+    /// Create a new `Hmac` signing jobs with the given secret.
+    pub fn new(secret: &[u8]) -> Self {
+        Hmac {
+            keys: vec![hmac::SigningKey::new(&digest::SHA256, secret)],
+        }
+    }
+
+    /// Add a secret used to verify the signatures made with it, but not to sign new jobs.
+    /// Chainable.
+    pub fn secret(mut self, secret: &[u8]) -> Self {
+        self.keys
+            .push(hmac::SigningKey::new(&digest::SHA256, secret));
+        self
+    }
+}
+
+#[cfg(feature = "signing")]
+fn hex(signature: &hmac::Signature) -> String {
+    signature
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(feature = "signing")]
+impl Signer for Hmac {
+    fn sign(&self, message: &[u8]) -> Result<String> {
+        Ok(hex(&hmac::sign(&self.keys[0], message)))
+    }
+
+    fn verify(&self, message: &[u8], signature: &str) -> bool {
+        self.keys.iter().any(|key| {
+            let expected = hex(&hmac::sign(key, message));
+            constant_time::verify_slices_are_equal(expected.as_bytes(), signature.as_bytes())
+                .is_ok()
+        })
+    }
+}
+
+/// Return the message covered by the signature of the given job.
+fn message(properties: &Properties, payload: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}\n{}\n", properties.id, properties.task).into_bytes();
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Sign the given job, if a signer is set.
+pub(crate) fn sign(
+    signer: Option<&Signer>,
+    properties: &mut Properties,
+    payload: &[u8],
+) -> Result<()> {
+    if let Some(signer) = signer {
+        let signature = signer.sign(&message(properties, payload))?;
+        properties
+            .headers
+            .insert(SIGNATURE_HEADER.into(), signature);
+    }
+    Ok(())
+}
+
+/// Sign the given job published by a worker (e.g: the next job of a chain), if any.
+pub(crate) fn signed(
+    signer: Option<&Signer>,
+    job: Option<(Vec<u8>, Properties)>,
+) -> Result<Option<(Vec<u8>, Properties)>> {
+    match job {
+        Some((payload, mut properties)) => {
+            sign(signer, &mut properties, &payload)?;
+            Ok(Some((payload, properties)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns true if the given job is signed and its signature is valid.
+pub(crate) fn verify(signer: &Signer, properties: &Properties, payload: &[u8]) -> bool {
+    match properties.headers.get(SIGNATURE_HEADER) {
+        Some(signature) => signer.verify(&message(properties, payload), signature),
+        None => false,
+    }
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verification() {
+        let payload = b"{\"amount\":42}";
+        let mut properties = Properties::named("charge", "payments", "payments");
+        let previous = Hmac::new(b"previous");
+        assert!(!verify(&previous, &properties, payload));
+
+        sign(Some(&previous), &mut properties, payload).unwrap();
+        assert!(verify(&previous, &properties, payload));
+        assert!(!verify(&previous, &properties, b"{\"amount\":4200}"));
+        let current = Hmac::new(b"current").secret(b"previous");
+        assert!(verify(&current, &properties, payload));
+        assert!(!verify(&Hmac::new(b"current"), &properties, payload));
+
+        properties.task = "refund".into();
+        assert!(!verify(&current, &properties, payload));
+    }
+}
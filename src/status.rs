@@ -0,0 +1,191 @@
+//! Abstraction over the storage of job statuses.
+//!
+//! When a `StatusStore` is given to the `Client` and the `Worker`, the status of each job is
+//! recorded as it goes through the system, and can be queried using `Client::status`.
+
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use futures::{future, Future};
+use uuid::Uuid;
+
+use crash::Panic;
+use error::Error;
+use job::Status;
+use progress::ProgressReport;
+
+/// The current status of a job, and the dates of its state transitions.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StatusReport {
+    /// The unique ID of the job.
+    pub id: Uuid,
+    /// The current status of the job.
+    pub status: Status,
+    /// When the job was last sent to the broker.
+    pub pending_at: Option<DateTime<Utc>>,
+    /// When the job last started executing.
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the job completed, successfully or not.
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The progress last reported by the job's handler, see `Progress`.
+    #[serde(default)]
+    pub progress: Option<ProgressReport>,
+    /// The last panic of the job's handler, if it panicked, see `Panic`.
+    #[serde(default)]
+    pub panic: Option<Panic>,
+}
+
+impl StatusReport {
+    /// Create a new `StatusReport` for a job that was just sent.
+    pub fn new(id: Uuid) -> Self {
+        StatusReport {
+            id,
+            status: Status::Pending,
+            pending_at: None,
+            started_at: None,
+            finished_at: None,
+            progress: None,
+            panic: None,
+        }
+    }
+
+    /// Record a transition of the job to the given status, at the given date.
+    pub fn apply(&mut self, status: Status, at: DateTime<Utc>) {
+        match status {
+            Status::Pending => {
+                self.pending_at = Some(at);
+                self.finished_at = None;
+                self.progress = None;
+            }
+            Status::Started => self.started_at = Some(at),
+            Status::Success | Status::Failed(_) | Status::Revoked => self.finished_at = Some(at),
+        }
+        self.status = status;
+    }
+}
+
+/// A storage for the status of jobs.
+pub trait StatusStore: fmt::Debug + Send + Sync {
+    /// Return whether the given job was revoked, according to the given store, if any.
+///
+/// Like `record`, the returned `Future` never fails: errors are logged and the job is considered
+/// not revoked.
+pub(crate) fn revoked(
+    store: Option<&Arc<StatusStore>>,
+    id: Uuid,
+) -> Box<Future<Item = bool, Error = Error> + Send> {
+    let store = match store {
+        Some(store) => store,
+        None => return Box::new(future::ok(false)),
+    };
+    let task = store
+        .fetch(id)
+        .map(|report| report.map_or(false, |report| report.status == Status::Revoked))
+        .or_else(move |e| {
+            warn!("[{}] Couldn't check whether job was revoked: {}", id, e);
+            Ok(false)
+        });
+    Box::new(task)
+}
+
+/// Record a transition of the given job to the given status, at the given date.
+    ///
+    /// Returns a `Future` that completes once the status is stored.
+    fn update(
+        &self,
+        id: Uuid,
+        status: Status,
+        at: DateTime<Utc>,
+    ) -> Box<Future<Item = (), Error = Error> + Send>;
+
+    /// Fetch the status of the given job.
+    ///
+    /// Returns a `Future` resolving to `None` if the job is unknown to this store.
+    fn fetch(&self, id: Uuid) -> Box<Future<Item = Option<StatusReport>, Error = Error> + Send>;
+
+    /// Record the progress reported by the given job's handler.
+    ///
+    /// The default implementation ignores progress reports.
+    fn progress(
+        &self,
+        id: Uuid,
+        progress: &ProgressReport,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        let _ = (id, progress);
+        Box::new(future::ok(()))
+    }
+
+    /// Record the panic of the given job's handler.
+    ///
+    /// The default implementation ignores panics.
+    fn panicked(&self, id: Uuid, panic: &Panic) -> Box<Future<Item = (), Error = Error> + Send> {
+        let _ = (id, panic);
+        Box::new(future::ok(()))
+    }
+}
+
+/// Record a transition of the given job to the given status in the given store, if any.
+///
+/// Statuses are tracked on a best-effort basis: the returned `Future` never fails, errors are
+/// logged instead.
+pub(crate) fn record(
+    store: Option<&Arc<StatusStore>>,
+    id: Uuid,
+    status: Status,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let store = match store {
+        Some(store) => store,
+        None => return Box::new(future::ok(())),
+    };
+    let task = store.update(id, status, Utc::now()).or_else(move |e| {
+        warn!("[{}] Couldn't record status of job: {}", id, e);
+        Ok(())
+    });
+    Box::new(task)
+}
+
+/// Record the panic of the given job's handler in the given store, if any.
+///
+/// Like `record`, the returned `Future` never fails: errors are logged instead.
+pub(crate) fn panicked(
+    store: Option<&Arc<StatusStore>>,
+    id: Uuid,
+    panic: Option<&Panic>,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let (store, panic) = match (store, panic) {
+        (Some(store), Some(panic)) => (store, panic),
+        _ => return Box::new(future::ok(())),
+    };
+    let task = store.panicked(id, panic).or_else(move |e| {
+        warn!("[{}] Couldn't record panic of job: {}", id, e);
+        Ok(())
+    });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use job::Failure;
+
+    #[test]
+    fn apply_transitions() {
+        let mut report = StatusReport::new(Uuid::new_v4());
+        let sent = Utc.timestamp(1_000, 0);
+        let started = Utc.timestamp(1_010, 0);
+        let failed = Utc.timestamp(1_020, 0);
+        let retried = Utc.timestamp(1_030, 0);
+        report.apply(Status::Pending, sent);
+        report.apply(Status::Started, started);
+        report.apply(Status::Failed(Failure::Timeout), failed);
+        assert_eq!(report.status, Status::Failed(Failure::Timeout));
+        assert_eq!(report.finished_at, Some(failed));
+        report.apply(Status::Pending, retried);
+        assert_eq!(report.status, Status::Pending);
+        assert_eq!(report.pending_at, Some(retried));
+        assert_eq!(report.started_at, Some(started));
+        assert_eq!(report.finished_at, None);
+    }
+}
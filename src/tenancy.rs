@@ -0,0 +1,137 @@
+//! Routing of the jobs of several tenants to queues of their own.
+//!
+//! A `Client` scoped to a tenant with `Client::for_tenant` publishes its jobs with the name of
+//! the tenant appended to their routing key (e.g: `emails.acme`), to a queue named after this
+//! routing key that it declares the first time it sends a job to it. Workers subscribed to the
+//! tenants of a queue with `WorkerBuilder::tenants` consume from the queues of every tenant,
+//! taking jobs from them in turn: a tenant with a large backlog can't delay the jobs of others.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future};
+
+use dead_letter::DEAD_LETTER_SUFFIX;
+use error::Error;
+use inspect::QueueInspector;
+use topology::{self, Queue};
+
+/// The header holding the tenant a job was sent for, see `Client::for_tenant`.
+pub const TENANT_HEADER: &str = "x-batch-tenant";
+
+/// Return the routing key of the jobs of the given tenant, which is also the name of its queue.
+pub(crate) fn routing_key(routing_key: &str, tenant: &str) -> String {
+    format!("{}.{}", routing_key, tenant)
+}
+
+/// Return the queue receiving the jobs published with the given tenant routing key.
+///
+/// Jobs published to the default exchange are routed by the name of the queue, which can't be
+/// bound explicitly.
+pub(crate) fn queue(exchange: &str, routing_key: &str) -> Queue {
+    let builder = topology::queue(routing_key);
+    let builder = if exchange.is_empty() {
+        builder
+    } else {
+        builder.bind(exchange, routing_key)
+    };
+    builder.build()
+}
+
+/// Return whether the given queue holds the jobs of a tenant of the given base queue.
+pub(crate) fn is_tenant_queue(base: &str, name: &str) -> bool {
+    name.len() > base.len() + 1
+        && name.starts_with(base)
+        && name[base.len()..].starts_with('.')
+        && !name.ends_with(DEAD_LETTER_SUFFIX)
+}
+
+/// The tenant queues already declared by a `Client` and its tenant-scoped copies.
+#[derive(Debug, Default)]
+pub(crate) struct Declared {
+    queues: Mutex<HashSet<String>>,
+}
+
+impl Declared {
+    /// Return the queues among the given ones that weren't declared yet.
+    pub(crate) fn missing(&self, queues: Vec<Queue>) -> Vec<Queue> {
+        let declared = self.queues.lock().unwrap();
+        let mut missing: Vec<Queue> = Vec::new();
+        for queue in queues {
+            if !declared.contains(queue.name()) && !missing.contains(&queue) {
+                missing.push(queue);
+            }
+        }
+        missing
+    }
+
+    /// Remember that the given queues were declared.
+    pub(crate) fn insert(&self, queues: &[Queue]) {
+        let mut declared = self.queues.lock().unwrap();
+        declared.extend(queues.iter().map(|queue| queue.name().to_string()));
+    }
+}
+
+/// Return a `Future` resolving to the queues of the tenants of the given base queues, found
+/// through the given inspector.
+///
+/// Only the tenants known when the worker starts are consumed from. Failing to list the queues
+/// of the broker isn't fatal: the worker then only consumes from the base queues.
+pub(crate) fn discover(
+    inspector: Option<Arc<QueueInspector>>,
+    bases: Vec<String>,
+) -> Box<Future<Item = Vec<Queue>, Error = Error> + Send> {
+    if bases.is_empty() {
+        return Box::new(future::ok(Vec::new()));
+    }
+    let inspector = match inspector {
+        Some(inspector) => inspector,
+        None => {
+            warn!("The broker can't list its queues, the queues of tenants aren't consumed");
+            return Box::new(future::ok(Vec::new()));
+        }
+    };
+    let task = inspector
+        .discover()
+        .then(move |result| -> Result<_, Error> {
+            let names = result.unwrap_or_else(|e| {
+                warn!("Couldn't list the queues of tenants: {}", e);
+                Vec::new()
+            });
+            let queues = names
+                .into_iter()
+                .filter(|name| bases.iter().any(|base| is_tenant_queue(base, name)))
+                .inspect(|name| debug!("Consuming from tenant queue {}", name))
+                .map(|name| topology::queue(&name).build())
+                .collect();
+            Ok(queues)
+        });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_queues() {
+        assert_eq!(routing_key("emails", "acme"), "emails.acme");
+        assert!(is_tenant_queue("emails", "emails.acme"));
+        assert!(!is_tenant_queue("emails", "emails"));
+        assert!(!is_tenant_queue("emails", "emails."));
+        assert!(!is_tenant_queue("emails", "emailsacme"));
+        assert!(!is_tenant_queue("emails", "emails.acme.dead"));
+        assert!(!is_tenant_queue("emails", "invoices.acme"));
+    }
+
+    #[test]
+    fn declared() {
+        let declared = Declared::default();
+        let queues = vec![queue("batch", "emails.acme"), queue("", "emails.acme")];
+        let missing = declared.missing(queues.clone());
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].bindings().len(), 1);
+        declared.insert(&missing);
+        assert!(declared.missing(queues).is_empty());
+    }
+}
@@ -0,0 +1,659 @@
+//! Helpers to test job handlers.
+//!
+//! A `TestWorker` executes jobs synchronously on the current thread, without a broker nor a
+//! background reactor, and tells whether the `Worker` would have acknowledged the job, retried it
+//! or dead-lettered it. The jobs sent by the handlers through the `TestWorker`'s `Client`, or
+//! enqueued with `Context::enqueue`, are captured instead of being published, so that tests can
+//! assert on them.
+//!
+//! # Example
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate batch;
+//! extern crate failure;
+//! extern crate futures;
+//! #[macro_use]
+//! extern crate lazy_static;
+//! # #[macro_use]
+//! # extern crate serde;
+//! #
+//! use batch::testing::TestWorker;
+//! use batch::{job, Client, Context, Perform};
+//! use futures::Future;
+//!
+//! #[derive(Serialize, Deserialize, Job)]
+//! #[job(routing_key = "users")]
+//! struct SignUp {
+//!     email: String,
+//! }
+//!
+//! #[derive(Serialize, Deserialize, Job)]
+//! #[job(routing_key = "emails")]
+//! struct SendWelcomeEmail {
+//!     to: String,
+//! }
+//!
+//! impl Perform for SignUp {
+//!     type Context = Client;
+//!     type Output = ();
+//!     type Error = batch::Error;
+//!     type Future = Box<Future<Item = (), Error = batch::Error> + Send>;
+//!
+//!     fn perform(&self, ctx: Context<Self::Context>) -> Self::Future {
+//!         let email = SendWelcomeEmail { to: self.email.clone() };
+//!         job(email).send(&ctx)
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let worker = TestWorker::with_client(|client| client);
+//! let execution = worker.perform(SignUp { email: "ferris@example.com".into() });
+//! assert!(execution.is_success());
+//! let sent = worker.enqueued_jobs::<SendWelcomeEmail>().unwrap();
+//! assert_eq!(sent[0].to, "ferris@example.com");
+//! # }
+//! ```
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, stream, Future, IntoFuture};
+
+use broker::{Broker, Deliveries, Properties};
+use client::Client;
+use context::Context;
+use enqueue::Enqueue;
+use error::{Error, Result};
+use extensions::Extensions;
+use job::{Failure, FailureInfo, Job, Perform};
+use reschedule::{Reschedule, Rescheduling};
+use retry::{FailurePolicy, RetryStrategy};
+
+/// A `Broker` capturing the jobs published through it.
+#[derive(Clone, Debug, Default)]
+struct Outbox {
+    jobs: Arc<Mutex<Vec<Enqueued>>>,
+}
+
+impl Broker for Outbox {
+    fn publish(
+        &self,
+        payload: &[u8],
+        properties: &Properties,
+    ) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.jobs.lock().unwrap().push(Enqueued {
+            payload: payload.to_vec(),
+            properties: properties.clone(),
+        });
+        Box::new(future::ok(()))
+    }
+
+    fn consume(&self, _prefetch: u16) -> Box<Future<Item = Deliveries, Error = Error> + Send> {
+        Box::new(future::ok(Box::new(stream::empty()) as Deliveries))
+    }
+}
+
+/// A job sent through the `Client` of a `TestWorker`.
+#[derive(Clone, Debug)]
+pub struct Enqueued {
+    payload: Vec<u8>,
+    properties: Properties,
+}
+
+impl Enqueued {
+    /// Return the properties of this job.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Return the payload of this job, as published.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Return true if this job is an instance of the given `Job` type.
+    pub fn is<T>(&self) -> bool
+    where
+        T: Job,
+    {
+        self.properties.task == T::name()
+    }
+
+    /// Decode the payload of this job.
+    pub fn decode<T>(&self) -> Result<T>
+    where
+        T: Job,
+    {
+        match self.properties.compression {
+            Some(compression) => {
+                let payload = compression.decompress(&self.payload)?;
+                self.properties.codec.decode(&payload)
+            }
+            None => self.properties.codec.decode(&self.payload),
+        }
+    }
+}
+
+/// The outcome of the execution of a job by a `TestWorker`.
+#[derive(Debug)]
+pub struct Execution<T> {
+    result: StdResult<T, ::failure::Error>,
+    retry: Option<Properties>,
+    rescheduled: Option<Enqueued>,
+}
+
+impl<T> Execution<T> {
+    /// Return true if the job succeeded, i.e. it would have been acknowledged.
+    pub fn is_success(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// Return the output of the job, if it succeeded.
+    pub fn output(&self) -> Option<&T> {
+        self.result.as_ref().ok()
+    }
+
+    /// Return the error the job failed with, if any.
+    pub fn error(&self) -> Option<&::failure::Error> {
+        self.result.as_ref().err()
+    }
+
+    /// Return true if the job failed and would have been published again.
+    pub fn is_retried(&self) -> bool {
+        self.retry.is_some()
+    }
+
+    /// Return true if the job failed and exhausted its retries, i.e. it would have been
+    /// dead-lettered.
+    pub fn is_dead_lettered(&self) -> bool {
+        self.result.is_err() && self.retry.is_none() && self.rescheduled.is_none()
+    }
+
+    /// Return the properties the job would have been published again with, if it was retried.
+    ///
+    /// These properties account for the retry (see `Properties::retries`), and carry the delay
+    /// given by the job's `RetryStrategy`.
+    pub fn retry(&self) -> Option<&Properties> {
+        self.retry.as_ref()
+    }
+
+    /// Return the job its handler rescheduled, if it called `Context::retry_in` or
+    /// `Context::retry_with`.
+    ///
+    /// The job would have been published again as returned, instead of being acknowledged or
+    /// retried, whatever its handler returned.
+    pub fn rescheduled(&self) -> Option<&Enqueued> {
+        self.rescheduled.as_ref()
+    }
+
+    /// Return the output of the job, or the error it failed with.
+    pub fn into_result(self) -> StdResult<T, ::failure::Error> {
+        self.result
+    }
+}
+
+/// A worker executing jobs synchronously, meant to be used in tests.
+///
+/// See the [module documentation](index.html).
+pub struct TestWorker<Ctx> {
+    context: Ctx,
+    extensions: Extensions,
+    outbox: Outbox,
+}
+
+impl<Ctx> fmt::Debug for TestWorker<Ctx>
+where
+    Ctx: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "TestWorker {{ context: {:?} extensions: {:?} enqueued: {:?} }}",
+            self.context,
+            self.extensions,
+            self.outbox.jobs.lock().unwrap().len()
+        )
+    }
+}
+
+impl<Ctx> TestWorker<Ctx>
+where
+    Ctx: Clone,
+{
+    /// Create a new `TestWorker` giving the given context to the jobs it executes.
+    pub fn new(context: Ctx) -> Self {
+        TestWorker {
+            context,
+            extensions: Extensions::new(),
+            outbox: Outbox::default(),
+        }
+    }
+
+    /// Create a new `TestWorker` whose context is built from its `Client`.
+    ///
+    /// This allows handlers that send jobs through a `Client` held by their context to be tested,
+    /// see `TestWorker::enqueued`.
+    pub fn with_client<F>(context: F) -> Self
+    where
+        F: FnOnce(Client) -> Ctx,
+    {
+        let outbox = Outbox::default();
+        TestWorker {
+            context: context(Client::new(outbox.clone())),
+            extensions: Extensions::new(),
+            outbox,
+        }
+    }
+
+    /// Make the given value available to the jobs executed by this worker, see
+    /// `WorkerBuilder::provide`.
+    pub fn provide<T>(mut self, value: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        self.extensions.insert(value);
+        self
+    }
+
+    /// Return a `Client` whose jobs are captured by this worker, see `TestWorker::enqueued`.
+    pub fn client(&self) -> Client {
+        Client::new(self.outbox.clone())
+    }
+
+    /// Execute the given job, as if it was just sent.
+    pub fn perform<T>(&self, job: T) -> Execution<T::Output>
+    where
+        T: Job + Perform<Context = Ctx>,
+    {
+        self.perform_with(job, Properties::new::<T>())
+    }
+
+    /// Execute the given job with the given properties.
+    ///
+    /// This allows testing how a job behaves when it's retried (see `Properties::retries`), or
+    /// how it uses the headers it was sent with. The job is encoded then decoded with the codec
+    /// of the given properties, like it would be when sent to a `Worker`. The hooks of the job
+    /// (see `Perform::on_success`) are called once it completed.
+    pub fn perform_with<T>(&self, job: T, properties: Properties) -> Execution<T::Output>
+    where
+        T: Job + Perform<Context = Ctx>,
+    {
+        let codec = properties.codec;
+        let job: T = match codec
+            .encode(&job)
+            .and_then(|payload| codec.decode(&payload))
+        {
+            Ok(job) => job,
+            Err(e) => return failed(properties, retry::<T>(), e.into()),
+        };
+        let reschedule = Reschedule::new();
+        let enqueue = Enqueue::new();
+        let mut ctx = Context::new(self.context.clone(), properties.clone());
+        ctx.provide(self.extensions.clone());
+        ctx.reschedule_with(reschedule.clone());
+        ctx.enqueue_with(enqueue.clone());
+        let hooks = ctx.detach();
+        let mut execution = match job.perform(ctx).into_future().wait() {
+            Ok(output) => {
+                // The jobs enqueued by the handler are only sent once it succeeded.
+                let client = self.client();
+                for entry in enqueue.entries() {
+                    let _ = client.send(&entry.payload, &entry.properties).wait();
+                }
+                job.on_success(&output, hooks);
+                Execution {
+                    result: Ok(output),
+                    retry: None,
+                    rescheduled: None,
+                }
+            }
+            Err(e) => {
+                let execution = failed(properties.clone(), retry::<T>(), e.into());
+                let failure = FailureInfo {
+                    failure: Failure::Error,
+                    error: execution.error().map(|e| e.to_string()).unwrap_or_default(),
+                    panic: None,
+                };
+                job.on_failure(&failure, hooks.clone());
+                if execution.is_dead_lettered() {
+                    job.on_retries_exhausted(&failure, hooks);
+                }
+                execution
+            }
+        };
+        if let Some(rescheduling) = reschedule.requested() {
+            match rescheduled(&job, properties, rescheduling) {
+                Ok(rescheduled) => {
+                    execution.retry = None;
+                    execution.rescheduled = Some(rescheduled);
+                }
+                Err(e) => execution.result = Err(e.into()),
+            }
+        }
+        execution
+    }
+
+    /// Return the jobs sent through the `Client` of this worker, in the order they were sent.
+    pub fn enqueued(&self) -> Vec<Enqueued> {
+        self.outbox.jobs.lock().unwrap().clone()
+    }
+
+    /// Decode the jobs of the given type sent through the `Client` of this worker.
+    pub fn enqueued_jobs<T>(&self) -> Result<Vec<T>>
+    where
+        T: Job,
+    {
+        self.outbox
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|enqueued| enqueued.is::<T>())
+            .map(|enqueued| enqueued.decode())
+            .collect()
+    }
+
+    /// Forget the jobs sent through the `Client` of this worker so far.
+    pub fn clear(&self) {
+        self.outbox.jobs.lock().unwrap().clear();
+    }
+}
+
+/// Return how jobs of type `T` are retried, the default policy of a `Worker` being `Retry`.
+fn retry<T>() -> (u32, RetryStrategy, FailurePolicy)
+where
+    T: Job,
+{
+    (
+        T::retries(),
+        T::retry_strategy(),
+        T::failure_policy().unwrap_or_default(),
+    )
+}
+
+/// Return the outcome of a job that failed with the given error, retrying it like a `Worker`
+/// would.
+fn failed<T>(
+    mut properties: Properties,
+    (max_retries, strategy, policy): (u32, RetryStrategy, FailurePolicy),
+    error: ::failure::Error,
+) -> Execution<T> {
+    let retry = match policy {
+        FailurePolicy::Retry
+            if properties.retries < properties.max_retries.unwrap_or(max_retries) =>
+        {
+            properties.retries += 1;
+            properties.delay = strategy.delay(properties.retries);
+            Some(properties)
+        }
+        // Requeued jobs are delivered again as is.
+        FailurePolicy::Requeue => Some(properties),
+        _ => None,
+    };
+    Execution {
+        result: Err(error),
+        retry,
+        rescheduled: None,
+    }
+}
+
+/// Return the given job as it would be published again once rescheduled by its handler.
+fn rescheduled<T>(
+    job: &T,
+    mut properties: Properties,
+    rescheduling: Rescheduling,
+) -> Result<Enqueued>
+where
+    T: Job,
+{
+    properties.delay = rescheduling.delay;
+    let payload = match rescheduling.payload {
+        Some((payload, version)) => {
+            properties.version = Some(version);
+            payload
+        }
+        None => properties.codec.encode(job)?,
+    };
+    properties.compression = None;
+    Ok(Enqueued {
+        payload,
+        properties,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use failure;
+
+    use job::Priority;
+    use query::job;
+
+    #[derive(Serialize, Deserialize)]
+    struct Charge {
+        amount: u64,
+    }
+
+    impl Job for Charge {
+        fn name() -> &'static str {
+            "charge"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "payments"
+        }
+
+        fn retries() -> u32 {
+            1
+        }
+
+        fn retry_strategy() -> RetryStrategy {
+            RetryStrategy::Fixed(Duration::from_secs(30))
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    impl Perform for Charge {
+        type Context = Client;
+        type Output = u64;
+        type Error = failure::Error;
+        type Future = Box<Future<Item = u64, Error = failure::Error> + Send>;
+
+        fn perform(&self, ctx: Context<Client>) -> Self::Future {
+            if self.amount == 0 {
+                return Box::new(future::err(failure::err_msg("nothing to charge")));
+            }
+            let amount = self.amount;
+            let receipt = job(Charge { amount: 0 })
+                .routing_key("receipts")
+                .send(&ctx)
+                .map(move |_| amount)
+                .map_err(failure::Error::from);
+            Box::new(receipt)
+        }
+
+        fn on_success(&self, output: &u64, ctx: Context<()>) {
+            record(&ctx, format!("success {}", output));
+        }
+
+        fn on_failure(&self, failure: &FailureInfo, ctx: Context<()>) {
+            record(&ctx, format!("failure {}", failure.error));
+        }
+
+        fn on_retries_exhausted(&self, _failure: &FailureInfo, ctx: Context<()>) {
+            record(&ctx, "exhausted".into());
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Export {
+        page: u32,
+    }
+
+    impl Job for Export {
+        fn name() -> &'static str {
+            "export"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "exports"
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn retry_strategy() -> RetryStrategy {
+            RetryStrategy::default()
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    impl Perform for Export {
+        type Context = ();
+        type Output = ();
+        type Error = failure::Error;
+        type Future = StdResult<(), failure::Error>;
+
+        fn perform(&self, ctx: Context<()>) -> Self::Future {
+            ctx.enqueue(Charge {
+                amount: u64::from(self.page),
+            })?;
+            if self.page == 3 {
+                return Ok(());
+            }
+            if self.page == 0 {
+                ctx.retry_with(&Charge { amount: 0 })?;
+            }
+            ctx.retry_with(&Export {
+                page: self.page + 1,
+            })?;
+            ctx.retry_in(Duration::from_secs(1));
+            Err(failure::err_msg("rate limited"))
+        }
+    }
+
+    /// The calls to the hooks of the jobs.
+    #[derive(Default)]
+    struct Hooks(Mutex<Vec<String>>);
+
+    fn record(ctx: &Context<()>, call: String) {
+        if let Ok(hooks) = ctx.get::<Hooks>() {
+            hooks.0.lock().unwrap().push(call);
+        }
+    }
+
+    #[test]
+    fn success() {
+        let worker = TestWorker::with_client(|client| client);
+        let execution = worker.perform(Charge { amount: 42 });
+        assert!(execution.is_success());
+        assert_eq!(execution.output(), Some(&42));
+        assert!(!execution.is_retried());
+
+        let enqueued = worker.enqueued();
+        assert_eq!(enqueued.len(), 1);
+        assert!(enqueued[0].is::<Charge>());
+        assert_eq!(enqueued[0].properties().routing_key, "receipts");
+        let charges = worker.enqueued_jobs::<Charge>().unwrap();
+        assert_eq!(charges[0].amount, 0);
+
+        worker.clear();
+        assert!(worker.enqueued().is_empty());
+    }
+
+    #[test]
+    fn retries() {
+        let worker = TestWorker::with_client(|client| client);
+        let execution = worker.perform(Charge { amount: 0 });
+        assert!(!execution.is_success());
+        assert!(execution.is_retried());
+        let retry = execution.retry().unwrap().clone();
+        assert_eq!(retry.retries, 1);
+        assert_eq!(retry.delay, Some(Duration::from_secs(30)));
+
+        let execution = worker.perform_with(Charge { amount: 0 }, retry);
+        assert!(execution.is_dead_lettered());
+        assert_eq!(execution.error().unwrap().to_string(), "nothing to charge");
+        assert!(worker.enqueued().is_empty());
+    }
+
+    #[test]
+    fn hooks() {
+        let worker = TestWorker::with_client(|client| client).provide(Hooks::default());
+        worker.perform(Charge { amount: 42 });
+        let retry = worker.perform(Charge { amount: 0 }).retry().unwrap().clone();
+        worker.perform_with(Charge { amount: 0 }, retry);
+        let hooks = worker.extensions.get::<Hooks>().unwrap();
+        assert_eq!(
+            *hooks.0.lock().unwrap(),
+            vec![
+                "success 42",
+                "failure nothing to charge",
+                "failure nothing to charge",
+                "exhausted",
+            ]
+        );
+    }
+
+    #[test]
+    fn follow_ups() {
+        let worker = TestWorker::new(());
+        let execution = worker.perform(Export { page: 1 });
+        assert!(!execution.is_retried());
+        assert!(!execution.is_dead_lettered());
+        let rescheduled = execution.rescheduled().unwrap();
+        assert_eq!(rescheduled.decode::<Export>().unwrap().page, 2);
+        assert_eq!(rescheduled.properties().retries, 0);
+        assert_eq!(rescheduled.properties().delay, Some(Duration::from_secs(1)));
+
+        // The jobs enqueued by a failed handler are discarded.
+        assert!(worker.enqueued().is_empty());
+
+        let execution = worker.perform(Export { page: 3 });
+        assert!(execution.is_success());
+        assert!(execution.rescheduled().is_none());
+        let charges = worker.enqueued_jobs::<Charge>().unwrap();
+        assert_eq!(charges.len(), 1);
+        assert_eq!(charges[0].amount, 3);
+    }
+
+    #[test]
+    fn mismatched_follow_up() {
+        let worker = TestWorker::new(());
+        let execution = worker.perform(Export { page: 0 });
+        assert!(execution.rescheduled().is_none());
+        let error = execution.error().unwrap().downcast_ref::<Error>().unwrap();
+        assert!(error.is_mismatched_job());
+        assert_eq!(
+            error.to_string(),
+            "The job export can't be replaced by a charge job"
+        );
+    }
+}
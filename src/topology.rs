@@ -0,0 +1,1670 @@
+//! The exchanges and queues jobs are routed through.
+//!
+//! The topology follows the AMQP model used by `RabbitMQ`: jobs are published to an exchange
+//! with a routing key, and delivered to the queues bound to it. Other brokers map it to their own
+//! concepts (see `Broker::declare`), options and arguments they don't support being ignored.
+//!
+//! # Migrations
+//!
+//! `RabbitMQ` refuses to declare an exchange or a queue that already exists with different
+//! settings, e.g: adding `x-max-priority` to an existing queue makes the workers fail to start.
+//! `plan` compares the topology declared by the code against the live one (see
+//! `Management::topology`), listing the exchanges, queues and bindings to create, and those
+//! that differ or aren't declared anymore. Only creations are applied by `Plan::apply`: changing
+//! the settings of a queue requires deleting it along with its jobs, which is left to operators.
+//!
+//! ```
+//! use batch::topology::{plan, Topology};
+//! use batch::{exchange, queue};
+//!
+//! let declared = Topology::new()
+//!     .exchanges(vec![exchange("batch.example")])
+//!     .queues(vec![queue("emails").enable_priorities().bind("batch.example", "emails")]);
+//! // The live topology would usually come from `Management::topology`.
+//! let live = Topology::new()
+//!     .exchanges(vec![exchange("batch.example")])
+//!     .queues(vec![queue("emails").bind("batch.example", "emails")]);
+//! for change in plan(&declared, &live).changes() {
+//!     println!("{}", change);
+//! }
+//! ```
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::result::Result as StdResult;
+use std::time::Duration;
+
+use amq_protocol::types::{AMQPValue, FieldTable};
+use futures::{future, Future};
+
+use broker::Broker;
+use dead_letter::dead_letter_queue;
+use error::Error;
+
+/// A binding from a queue to an exchange, or from an exchange to an exchange.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Binding {
+    exchange: String,
+    routing_key: String,
+}
+
+impl Binding {
+    /// Return the exchange routing jobs through this binding.
+    pub fn exchange(&self) -> &str {
+        &self.exchange
+    }
+
+    /// Return the routing key, or the routing key pattern, of this binding.
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+}
+
+/// The kind of an exchange, deciding how it routes jobs to the queues bound to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExchangeKind {
+    /// Jobs are routed to the queues bound with their exact routing key.
+    Direct,
+    /// Jobs are routed to the queues bound with a pattern matching their routing key: in a
+    /// pattern, `*` matches exactly one dot-separated word and `#` matches zero or more words.
+    Topic,
+    /// Jobs are routed to every queue bound to the exchange, whatever their routing key.
+    Fanout,
+    /// Jobs are routed according to their headers, rather than their routing key.
+    Headers,
+}
+
+impl ExchangeKind {
+    /// Return the name of this kind, as used when declaring an exchange.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ExchangeKind::Direct => "direct",
+            ExchangeKind::Topic => "topic",
+            ExchangeKind::Fanout => "fanout",
+            ExchangeKind::Headers => "headers",
+        }
+    }
+}
+
+impl Default for ExchangeKind {
+    fn default() -> Self {
+        ExchangeKind::Direct
+    }
+}
+
+/// The options used when declaring an `Exchange`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExchangeOptions {
+    /// Only check that the exchange exists, rather than creating it.
+    pub passive: bool,
+    /// Keep the exchange when the broker restarts.
+    pub durable: bool,
+    /// Delete the exchange once no queue is bound to it anymore.
+    pub auto_delete: bool,
+    /// Only allow publishing to the exchange from other exchanges.
+    pub internal: bool,
+    /// Don't wait for the broker to confirm the declaration.
+    pub nowait: bool,
+    /// The access ticket, deprecated by AMQP 0.9.1.
+    pub ticket: u16,
+}
+
+/// The options used when declaring a `Queue`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueOptions {
+    /// Only check that the queue exists, rather than creating it.
+    pub passive: bool,
+    /// Keep the queue when the broker restarts.
+    pub durable: bool,
+    /// Only allow the connection declaring the queue to use it, deleting it once closed.
+    pub exclusive: bool,
+    /// Delete the queue once its last consumer is gone.
+    pub auto_delete: bool,
+    /// Don't wait for the broker to confirm the declaration.
+    pub nowait: bool,
+    /// The access ticket, deprecated by AMQP 0.9.1.
+    pub ticket: u16,
+}
+
+/// An exchange jobs are published to, routing them to the queues bound to it.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    name: String,
+    kind: String,
+    bindings: BTreeSet<Binding>,
+    options: ExchangeOptions,
+    arguments: FieldTable,
+}
+
+impl Default for Exchange {
+    fn default() -> Exchange {
+        Exchange {
+            name: "".into(),
+            kind: "direct".into(),
+            bindings: BTreeSet::new(),
+            options: ExchangeOptions::default(),
+            arguments: FieldTable::new(),
+        }
+    }
+}
+
+impl cmp::PartialEq for Exchange {
+    fn eq(&self, other: &Exchange) -> bool {
+        self.name == other.name
+    }
+}
+
+impl cmp::Eq for Exchange {}
+
+impl cmp::PartialOrd for Exchange {
+    fn partial_cmp(&self, other: &Exchange) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for Exchange {
+    fn cmp(&self, other: &Exchange) -> cmp::Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+impl Exchange {
+    /// Create a new `ExchangeBuilder` instance from the desired exchange name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example");
+    /// ```
+    pub fn builder(name: &str) -> ExchangeBuilder {
+        ExchangeBuilder::new(name)
+    }
+
+    /// Return the name of this `Exchange`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the kind of this `Exchange`.
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Return the bindings associated to this `Exchange`.
+    pub(crate) fn bindings(&self) -> &BTreeSet<Binding> {
+        &self.bindings
+    }
+
+    /// Return the options of this `Exchange`.
+    pub fn options(&self) -> &ExchangeOptions {
+        &self.options
+    }
+
+    /// Return the arguments of this `Exchange`.
+    pub fn arguments(&self) -> &FieldTable {
+        &self.arguments
+    }
+
+    /// Return this `Exchange` as provisioned out of band: it is only declared passively, to check
+    /// that it exists, without binding it.
+    pub(crate) fn provisioned(mut self) -> Self {
+        self.options.passive = true;
+        self.bindings.clear();
+        self
+    }
+
+    /// Set the kind of this `Exchange`, e.g: one reported by the broker but unknown to
+    /// `ExchangeKind`.
+    pub(crate) fn with_kind(mut self, kind: &str) -> Self {
+        self.kind = kind.into();
+        self
+    }
+}
+
+/// A builder for `Exchange`.
+///
+/// See [`Exchange::builder`](struct.Exchange.html#method.builder).
+#[derive(Debug)]
+pub struct ExchangeBuilder {
+    name: String,
+    kind: ExchangeKind,
+    bindings: BTreeSet<Binding>,
+    options: ExchangeOptions,
+    arguments: FieldTable,
+}
+
+impl ExchangeBuilder {
+    /// Create a new `ExchangeBuilder` instance from the desired exchange name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example");
+    /// ```
+    fn new(name: &str) -> ExchangeBuilder {
+        ExchangeBuilder {
+            name: name.into(),
+            kind: ExchangeKind::default(),
+            bindings: BTreeSet::new(),
+            options: ExchangeOptions::default(),
+            arguments: FieldTable::new(),
+        }
+    }
+
+    /// Set the kind of this exchange. Chainable.
+    ///
+    /// Defaults to `ExchangeKind::Direct`. Queues bound to a topic exchange can use wildcard
+    /// patterns as routing keys, e.g: `emails.#`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{exchange, queue, ExchangeKind};
+    ///
+    /// let exchange = exchange("batch.example").kind(ExchangeKind::Topic);
+    /// let queue = queue("emails").bind("batch.example", "emails.#");
+    /// ```
+    pub fn kind(mut self, kind: ExchangeKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Set the exchange receiving the jobs this exchange can't route to any queue. Chainable.
+    ///
+    /// Without an alternate exchange, jobs published with a routing key matching no binding
+    /// are dropped by `RabbitMQ`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::{exchange, ExchangeKind};
+    ///
+    /// let unrouted = exchange("batch.unrouted").kind(ExchangeKind::Fanout);
+    /// let exchange = exchange("batch.example").alternate_exchange("batch.unrouted");
+    /// ```
+    pub fn alternate_exchange(mut self, name: &str) -> Self {
+        self.arguments.insert(
+            "alternate-exchange".to_string(),
+            AMQPValue::LongString(name.into()),
+        );
+        self
+    }
+
+    /// Binds this exchange to another exchange via a routing key.
+    ///
+    /// All of the messages posted to this exchange associated to the given routing key
+    /// are automatically sent to the given exchange.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example")
+    ///     .bind("batch.messaging", "hello-world");
+    /// ```
+    pub fn bind(mut self, exchange: &str, routing_key: &str) -> Self {
+        self.bindings.insert(Binding {
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+        });
+        self
+    }
+
+    /// Return a reference the declare options for this exchange.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example");
+    /// {
+    ///     let options = builder.options();
+    ///     println!("Options: {:?}", options);
+    /// }
+    /// ```
+    pub fn options(&self) -> &ExchangeOptions {
+        &self.options
+    }
+
+    /// Return a mutable reference to the declare options for this exchange.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example");
+    /// {
+    ///     let options = builder.options_mut();
+    ///     options.durable = true;
+    ///     println!("Options: {:?}", options);
+    /// }
+    /// ```
+    pub fn options_mut(&mut self) -> &mut ExchangeOptions {
+        &mut self.options
+    }
+
+    /// Return a reference to the exchange arguments.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let builder = Exchange::builder("batch.example");
+    /// {
+    ///     let arguments = builder.arguments();
+    ///     println!("Arguments: {:?}", arguments);
+    /// }
+    /// ```
+    pub fn arguments(&self) -> &FieldTable {
+        &self.arguments
+    }
+
+    /// Return a mutable reference to the exchange arguments.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate batch;
+    /// extern crate amq_protocol;
+    ///
+    /// use amq_protocol::types::AMQPValue;
+    /// use batch::Exchange;
+    ///
+    /// # fn main() {
+    /// let mut builder = Exchange::builder("batch.example");
+    /// {
+    ///     let arguments = builder.arguments_mut();
+    ///     arguments.insert("x-custom-argument".to_string(), AMQPValue::Boolean(true));
+    ///     println!("Arguments: {:?}", arguments);
+    /// }
+    /// # }
+    /// ```
+    pub fn arguments_mut(&mut self) -> &mut FieldTable {
+        &mut self.arguments
+    }
+
+    /// Set the durable option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example")
+    ///     .durable(true);
+    /// ```
+    pub fn durable(mut self, durable: bool) -> Self {
+        self.options.durable = durable;
+        self
+    }
+
+    /// Set the auto_delete option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example")
+    ///     .auto_delete(true);
+    /// ```
+    pub fn auto_delete(mut self, auto_delete: bool) -> Self {
+        self.options.auto_delete = auto_delete;
+        self
+    }
+
+    /// Set the nowait option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example")
+    ///     .nowait(true);
+    /// ```
+    pub fn nowait(mut self, nowait: bool) -> Self {
+        self.options.nowait = nowait;
+        self
+    }
+
+    /// Set the internal option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example")
+    ///     .internal(true);
+    /// ```
+    pub fn internal(mut self, internal: bool) -> Self {
+        self.options.internal = internal;
+        self
+    }
+
+    /// Set the passive option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example")
+    ///     .passive(true);
+    /// ```
+    pub fn passive(mut self, passive: bool) -> Self {
+        self.options.passive = passive;
+        self
+    }
+
+    /// Set the ticket option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Exchange;
+    ///
+    /// let mut builder = Exchange::builder("batch.example")
+    ///     .ticket(2);
+    /// ```
+    pub fn ticket(mut self, ticket: u16) -> Self {
+        self.options.ticket = ticket;
+        self
+    }
+
+    /// Build a new `Exchange` instance from this builder data.
+    pub(crate) fn build(self) -> Exchange {
+        Exchange {
+            name: self.name,
+            kind: self.kind.as_str().into(),
+            bindings: self.bindings,
+            options: self.options,
+            arguments: self.arguments,
+        }
+    }
+}
+
+/// Shorthand to create a new `ExchangeBuilder` instance.
+pub fn exchange(name: &str) -> ExchangeBuilder {
+    ExchangeBuilder::new(name)
+}
+
+/// A queue jobs are consumed from.
+#[derive(Clone, Debug)]
+pub struct Queue {
+    name: String,
+    bindings: BTreeSet<Binding>,
+    options: QueueOptions,
+    arguments: FieldTable,
+    dead_letter: bool,
+    provisioned: bool,
+}
+
+impl cmp::PartialEq for Queue {
+    fn eq(&self, other: &Queue) -> bool {
+        self.name == other.name
+    }
+}
+
+impl cmp::Eq for Queue {}
+
+impl cmp::PartialOrd for Queue {
+    fn partial_cmp(&self, other: &Queue) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for Queue {
+    fn cmp(&self, other: &Queue) -> cmp::Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+impl Queue {
+    /// Create a new `QueueBuilder` from the desired queue name.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let queue = Queue::builder("video-transcoding");
+    /// ```
+    pub fn builder(name: &str) -> QueueBuilder {
+        QueueBuilder::new(name)
+    }
+
+    /// Return the name of this `Queue`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the bindings associated to this `Queue`.
+    pub(crate) fn bindings(&self) -> &BTreeSet<Binding> {
+        &self.bindings
+    }
+
+    /// Return whether jobs published with the given routing key to the given exchange, of the
+    /// given kind, are delivered to this `Queue`.
+    ///
+    /// Headers exchanges route jobs according to arguments given when binding queues, which
+    /// aren't supported yet: any queue bound to them is considered to receive all of their jobs.
+    pub(crate) fn routes(&self, exchange: &str, kind: &str, routing_key: &str) -> bool {
+        if exchange.is_empty() && self.name == routing_key {
+            return true;
+        }
+        self.bindings
+            .iter()
+            .filter(|b| b.exchange() == exchange)
+            .any(|b| match kind {
+                "fanout" | "headers" => true,
+                "topic" => topic_matches(b.routing_key(), routing_key),
+                _ => b.routing_key() == routing_key,
+            })
+    }
+
+    /// Return whether the jobs of this `Queue` are delivered according to their priority, see
+    /// `QueueBuilder::enable_priorities`.
+    pub(crate) fn is_prioritized(&self) -> bool {
+        self.arguments.contains_key("x-max-priority")
+    }
+
+    /// Return the options used when declaring this `Queue`.
+    pub fn options(&self) -> &QueueOptions {
+        &self.options
+    }
+
+    /// Return the arguments used when declaring this `Queue`.
+    pub fn arguments(&self) -> &FieldTable {
+        &self.arguments
+    }
+
+    /// Return the name of the dead-letter queue associated to this `Queue`, if enabled.
+    pub fn dead_letter_queue(&self) -> Option<String> {
+        if self.dead_letter {
+            Some(dead_letter_queue(&self.name))
+        } else {
+            None
+        }
+    }
+
+    /// Return this `Queue` as provisioned out of band: it and its dead-letter queue are only
+    /// declared passively, to check that they exist, without binding them.
+    pub(crate) fn provisioned(mut self) -> Self {
+        self.options.passive = true;
+        self.bindings.clear();
+        self.provisioned = true;
+        self
+    }
+
+    /// Return whether this `Queue` is provisioned out of band, see `Queue::provisioned`.
+    pub(crate) fn is_provisioned(&self) -> bool {
+        self.provisioned
+    }
+}
+
+/// A builder for `Queue`.
+///
+/// See [`Queue::builder`](struct.Queue.html#method.builder).
+#[derive(Debug)]
+pub struct QueueBuilder {
+    name: String,
+    bindings: BTreeSet<Binding>,
+    options: QueueOptions,
+    arguments: FieldTable,
+    dead_letter: bool,
+}
+
+impl QueueBuilder {
+    fn new(name: &str) -> QueueBuilder {
+        QueueBuilder {
+            name: name.into(),
+            bindings: BTreeSet::new(),
+            options: QueueOptions::default(),
+            arguments: FieldTable::new(),
+            dead_letter: true,
+        }
+    }
+
+    /// Return a reference the declare options for this queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let builder = Queue::builder("video-transcoding");
+    /// {
+    ///     let options = builder.options();
+    ///     println!("Options: {:?}", options);
+    /// }
+    /// ```
+    pub fn options(&self) -> &QueueOptions {
+        &self.options
+    }
+
+    /// Return a mutable reference the declare options for this queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding");
+    /// {
+    ///     let options = builder.options_mut();
+    ///     options.auto_delete = true;
+    ///     println!("Options: {:?}", options);
+    /// }
+    /// ```
+    pub fn options_mut(&mut self) -> &mut QueueOptions {
+        &mut self.options
+    }
+
+    /// Return a reference to the queue arguments.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let builder = Queue::builder("video-transcoding");
+    /// {
+    ///     let arguments = builder.arguments();
+    ///     println!("Arguments: {:?}", arguments);
+    /// }
+    /// ```
+    pub fn arguments(&self) -> &FieldTable {
+        &self.arguments
+    }
+
+    /// Return a mutable reference to the queue arguments.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate batch;
+    /// extern crate amq_protocol;
+    ///
+    /// use amq_protocol::types::AMQPValue;
+    /// use batch::Queue;
+    ///
+    /// # fn main() {
+    /// let mut builder = Queue::builder("video-transcoding");
+    /// {
+    ///     let arguments = builder.arguments_mut();
+    ///     arguments.insert("x-custom-argument".to_string(), AMQPValue::Boolean(true));
+    ///     println!("Arguments: {:?}", arguments);
+    /// }
+    /// # }
+    /// ```
+    pub fn arguments_mut(&mut self) -> &mut FieldTable {
+        &mut self.arguments
+    }
+
+    /// Bind this queue to an exchange via a routing key.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .bind("movies", "transcoding")
+    ///     .bind("series", "transcoding")
+    ///     .bind("anime", "transcoding");
+    /// ```
+    pub fn bind(mut self, exchange: &str, routing_key: &str) -> Self {
+        self.bindings.insert(Binding {
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+        });
+        self
+    }
+
+    /// Set the durability. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .durable(true);
+    /// ```
+    pub fn durable(mut self, durable: bool) -> Self {
+        self.options.durable = durable;
+        self
+    }
+
+    /// Set the auto_delete option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .auto_delete(true);
+    /// ```
+    pub fn auto_delete(mut self, auto_delete: bool) -> Self {
+        self.options.auto_delete = auto_delete;
+        self
+    }
+
+    /// Set the nowait option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .nowait(true);
+    /// ```
+    pub fn nowait(mut self, nowait: bool) -> Self {
+        self.options.nowait = nowait;
+        self
+    }
+
+    /// Set the exclusive option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .exclusive(true);
+    /// ```
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.options.exclusive = exclusive;
+        self
+    }
+
+    /// Set the passive option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .passive(true);
+    /// ```
+    pub fn passive(mut self, passive: bool) -> Self {
+        self.options.passive = passive;
+        self
+    }
+
+    /// Set the ticket option. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .ticket(2);
+    /// ```
+    pub fn ticket(mut self, ticket: u16) -> Self {
+        self.options.ticket = ticket;
+        self
+    }
+
+    /// Enable priorities on this queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .enable_priorities();
+    /// ```
+    pub fn enable_priorities(mut self) -> Self {
+        self.arguments
+            .insert("x-max-priority".to_string(), AMQPValue::ShortShortUInt(4));
+        self
+    }
+
+    /// Declare this queue as a quorum queue, replicated across the nodes of the cluster.
+    /// Chainable.
+    ///
+    /// Quorum queues are always durable. They don't support priorities nor lazy mode.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .quorum();
+    /// ```
+    pub fn quorum(mut self) -> Self {
+        self.options.durable = true;
+        self.arguments.insert(
+            "x-queue-type".to_string(),
+            AMQPValue::LongString("quorum".into()),
+        );
+        self
+    }
+
+    /// Set the maximum number of jobs waiting in this queue. Chainable.
+    ///
+    /// Once the limit is reached, the oldest jobs are dropped, or dead-lettered if a
+    /// dead-letter exchange was given (see `dead_letter_exchange`).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .max_length(10_000);
+    /// ```
+    pub fn max_length(mut self, length: u64) -> Self {
+        self.arguments.insert(
+            "x-max-length".to_string(),
+            AMQPValue::LongLongInt(length as i64),
+        );
+        self
+    }
+
+    /// Set how long jobs can wait in this queue before they expire. Chainable.
+    ///
+    /// Expired jobs are dropped, or dead-lettered if a dead-letter exchange was given (see
+    /// `dead_letter_exchange`).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .message_ttl(Duration::from_secs(24 * 60 * 60));
+    /// ```
+    pub fn message_ttl(mut self, ttl: Duration) -> Self {
+        let millis = ttl.as_secs() * 1000 + u64::from(ttl.subsec_millis());
+        self.arguments.insert(
+            "x-message-ttl".to_string(),
+            AMQPValue::LongLongInt(millis as i64),
+        );
+        self
+    }
+
+    /// Set the maximum priority of the jobs of this queue, enabling priorities. Chainable.
+    ///
+    /// Jobs only use priorities up to 4 (see `Priority`), higher values only cost memory.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .max_priority(4);
+    /// ```
+    pub fn max_priority(mut self, priority: u8) -> Self {
+        self.arguments.insert(
+            "x-max-priority".to_string(),
+            AMQPValue::ShortShortUInt(priority),
+        );
+        self
+    }
+
+    /// Set the exchange, and optionally the routing key, `RabbitMQ` dead-letters the jobs of
+    /// this queue to. Chainable.
+    ///
+    /// Jobs are dead-lettered by the broker when they expire, overflow the queue, or are
+    /// rejected without being requeued. This is unrelated to the dead-letter queue where the
+    /// worker moves the jobs exhausting their retries (see `dead_letter`).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .dead_letter_exchange("batch.expired", Some("video-transcoding"));
+    /// ```
+    pub fn dead_letter_exchange(mut self, exchange: &str, routing_key: Option<&str>) -> Self {
+        self.arguments.insert(
+            "x-dead-letter-exchange".to_string(),
+            AMQPValue::LongString(exchange.into()),
+        );
+        if let Some(routing_key) = routing_key {
+            self.arguments.insert(
+                "x-dead-letter-routing-key".to_string(),
+                AMQPValue::LongString(routing_key.into()),
+            );
+        }
+        self
+    }
+
+    /// Enable the lazy mode of this queue, keeping its jobs on disk rather than in memory.
+    /// Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// Queue::builder("video-transcoding")
+    ///     .lazy();
+    /// ```
+    pub fn lazy(mut self) -> Self {
+        self.arguments.insert(
+            "x-queue-mode".to_string(),
+            AMQPValue::LongString("lazy".into()),
+        );
+        self
+    }
+
+    /// Enable or disable the dead-letter queue associated to this queue. Chainable.
+    ///
+    /// When enabled (the default), a durable queue named after this one with a `.dead` suffix is
+    /// declared alongside it, and the jobs consumed from this queue that exhaust their retries are
+    /// moved to it. See `DeadLetterConsumer`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::Queue;
+    ///
+    /// let mut builder = Queue::builder("video-transcoding")
+    ///     .dead_letter(false);
+    /// ```
+    pub fn dead_letter(mut self, enabled: bool) -> Self {
+        self.dead_letter = enabled;
+        self
+    }
+
+    /// Create a new `Queue` instance from this builder data.
+    pub(crate) fn build(self) -> Queue {
+        Queue {
+            name: self.name,
+            bindings: self.bindings,
+            options: self.options,
+            arguments: self.arguments,
+            dead_letter: self.dead_letter,
+            provisioned: false,
+        }
+    }
+}
+
+/// Shorthand to create a new `QueueBuilder` instance.
+pub fn queue(name: &str) -> QueueBuilder {
+    QueueBuilder::new(name)
+}
+
+/// The exchanges and queues of a virtual host, see `plan`.
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    pub(crate) exchanges: Vec<Exchange>,
+    pub(crate) queues: Vec<Queue>,
+}
+
+impl Topology {
+    /// Create a new, empty, `Topology`.
+    pub fn new() -> Self {
+        Topology::default()
+    }
+
+    /// Add exchanges to this `Topology`. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::exchange;
+    /// use batch::topology::Topology;
+    ///
+    /// let topology = Topology::new()
+    ///     .exchanges(vec![exchange("batch.example")]);
+    /// ```
+    pub fn exchanges<EIter>(mut self, exchanges: EIter) -> Self
+    where
+        EIter: IntoIterator<Item = ExchangeBuilder>,
+    {
+        self.exchanges
+            .extend(exchanges.into_iter().map(|e| e.build()));
+        self
+    }
+
+    /// Add queues to this `Topology`. Chainable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use batch::queue;
+    /// use batch::topology::Topology;
+    ///
+    /// let topology = Topology::new()
+    ///     .queues(vec![queue("hello-world").bind("batch.example", "hello-world")]);
+    /// ```
+    pub fn queues<QIter>(mut self, queues: QIter) -> Self
+    where
+        QIter: IntoIterator<Item = QueueBuilder>,
+    {
+        self.queues.extend(queues.into_iter().map(|q| q.build()));
+        self
+    }
+}
+
+/// A setting of an exchange or a queue whose declared value differs from the live one.
+///
+/// Arguments are named after their key, e.g: `x-max-priority`, and have no value when they are
+/// missing on one side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    /// The name of the setting.
+    pub setting: String,
+    /// The value declared by the code.
+    pub declared: Option<String>,
+    /// The value of the entity on the broker.
+    pub live: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        let none = "<none>".to_string();
+        write!(
+            f,
+            "{}: {} (live: {})",
+            self.setting,
+            self.declared.as_ref().unwrap_or(&none),
+            self.live.as_ref().unwrap_or(&none)
+        )
+    }
+}
+
+/// A difference between the declared and the live topologies, see `plan`.
+#[derive(Clone, Debug)]
+pub enum Change {
+    /// An exchange declared by the code that doesn't exist on the broker.
+    MissingExchange(Exchange),
+    /// An exchange existing on the broker that isn't declared by the code.
+    ExtraExchange(String),
+    /// An exchange existing on the broker with other settings than the declared ones.
+    MismatchedExchange {
+        /// The name of the exchange.
+        name: String,
+        /// The settings differing between the declared and the live exchange.
+        differences: Vec<Difference>,
+    },
+    /// A queue declared by the code that doesn't exist on the broker.
+    MissingQueue(Queue),
+    /// A queue existing on the broker that isn't declared by the code.
+    ExtraQueue(String),
+    /// A queue existing on the broker with other settings than the declared ones.
+    MismatchedQueue {
+        /// The name of the queue.
+        name: String,
+        /// The settings differing between the declared and the live queue.
+        differences: Vec<Difference>,
+    },
+    /// A binding declared by the code that doesn't exist on the broker.
+    MissingBinding {
+        /// The queue or exchange receiving the jobs.
+        destination: String,
+        /// The exchange routing the jobs.
+        exchange: String,
+        /// The routing key, or the routing key pattern, of the binding.
+        routing_key: String,
+    },
+    /// A binding existing on the broker that isn't declared by the code.
+    ExtraBinding {
+        /// The queue or exchange receiving the jobs.
+        destination: String,
+        /// The exchange routing the jobs.
+        exchange: String,
+        /// The routing key, or the routing key pattern, of the binding.
+        routing_key: String,
+    },
+}
+
+impl Change {
+    /// Return whether `Plan::apply` can apply this change.
+    ///
+    /// Only missing entities are created, unless they are declared passively, the other changes
+    /// requiring to delete entities along with the jobs they hold.
+    pub fn is_safe(&self) -> bool {
+        match *self {
+            Change::MissingExchange(ref exchange) => !exchange.options().passive,
+            Change::MissingQueue(ref queue) => !queue.options().passive,
+            Change::MissingBinding { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        match *self {
+            Change::MissingExchange(ref exchange) => {
+                write!(f, "missing exchange {}", exchange.name())
+            }
+            Change::ExtraExchange(ref name) => write!(f, "extra exchange {}", name),
+            Change::MismatchedExchange {
+                ref name,
+                ref differences,
+            } => write!(f, "mismatched exchange {}: {}", name, join(differences)),
+            Change::MissingQueue(ref queue) => write!(f, "missing queue {}", queue.name()),
+            Change::ExtraQueue(ref name) => write!(f, "extra queue {}", name),
+            Change::MismatchedQueue {
+                ref name,
+                ref differences,
+            } => write!(f, "mismatched queue {}: {}", name, join(differences)),
+            Change::MissingBinding {
+                ref destination,
+                ref exchange,
+                ref routing_key,
+            } => write!(
+                f,
+                "missing binding from {} to {} ({})",
+                exchange, destination, routing_key
+            ),
+            Change::ExtraBinding {
+                ref destination,
+                ref exchange,
+                ref routing_key,
+            } => write!(
+                f,
+                "extra binding from {} to {} ({})",
+                exchange, destination, routing_key
+            ),
+        }
+    }
+}
+
+/// Join the given differences in a single line.
+fn join(differences: &[Difference]) -> String {
+    differences
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The changes needed to migrate a live topology to a declared one, see `plan`.
+#[derive(Clone, Debug, Default)]
+pub struct Plan {
+    declared: Topology,
+    changes: Vec<Change>,
+}
+
+impl Plan {
+    /// Return the changes of this `Plan`, exchanges first, then queues and bindings.
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    /// Return whether the live topology matches the declared one.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Return whether all the changes of this `Plan` can be applied.
+    pub fn is_safe(&self) -> bool {
+        self.changes.iter().all(Change::is_safe)
+    }
+
+    /// Apply the safe changes of this `Plan` with the given broker, see `Change::is_safe`.
+    ///
+    /// Missing bindings are created by declaring the exchange or queue holding them again, which
+    /// is skipped if it, or its dead-letter queue, has mismatched settings.
+    pub fn apply<B>(&self, broker: &B) -> Box<Future<Item = (), Error = Error> + Send>
+    where
+        B: Broker + ?Sized,
+    {
+        let mut mismatched = BTreeSet::new();
+        let mut exchanges = BTreeSet::new();
+        let mut queues = BTreeSet::new();
+        for change in &self.changes {
+            match *change {
+                Change::MismatchedExchange { ref name, .. }
+                | Change::MismatchedQueue { ref name, .. } => {
+                    mismatched.insert(name.as_str());
+                }
+                Change::MissingExchange(ref exchange) if change.is_safe() => {
+                    exchanges.insert(exchange.name());
+                }
+                Change::MissingQueue(ref queue) if change.is_safe() => {
+                    queues.insert(queue.name());
+                }
+                Change::MissingBinding {
+                    ref destination,
+                    ref exchange,
+                    ..
+                } => {
+                    exchanges.insert(exchange.as_str());
+                    exchanges.insert(destination.as_str());
+                    queues.insert(destination.as_str());
+                }
+                _ => (),
+            }
+        }
+        let exchanges = self
+            .declared
+            .exchanges
+            .iter()
+            .filter(|e| exchanges.contains(e.name()) && !mismatched.contains(e.name()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let queues = self
+            .declared
+            .queues
+            .iter()
+            .filter(|q| queues.contains(q.name()) && !mismatched.contains(q.name()))
+            .filter(|q| match q.dead_letter_queue() {
+                Some(ref name) => !mismatched.contains(name.as_str()),
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if exchanges.is_empty() && queues.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        broker.declare(&exchanges, &queues)
+    }
+}
+
+/// Compare the topology declared by the code with the live topology of the broker.
+///
+/// The dead-letter queues of the declared queues are declared implicitly. The live entities
+/// managed by the broker (`amq.*`), the delay queues and the exclusive queues, which belong to
+/// the connection that declared them, are ignored.
+///
+/// # Example
+///
+/// ```
+/// use batch::topology::{plan, Change, Topology};
+/// use batch::{exchange, queue};
+///
+/// let declared = Topology::new()
+///     .exchanges(vec![exchange("batch.example")])
+///     .queues(vec![queue("emails").enable_priorities().bind("batch.example", "emails")]);
+/// let live = Topology::new()
+///     .exchanges(vec![exchange("batch.example")])
+///     .queues(vec![queue("emails").bind("batch.example", "emails")]);
+///
+/// let plan = plan(&declared, &live);
+/// assert!(!plan.is_safe());
+/// match plan.changes()[0] {
+///     Change::MismatchedQueue { ref name, .. } => assert_eq!(name, "emails"),
+///     ref change => panic!("unexpected change: {}", change),
+/// }
+/// ```
+pub fn plan(declared: &Topology, live: &Topology) -> Plan {
+    let mut expanded = declared.clone();
+    for queue in &declared.queues {
+        if let Some(name) = queue.dead_letter_queue() {
+            if !declared.queues.iter().any(|q| q.name() == name) {
+                expanded
+                    .queues
+                    .push(self::queue(&name).durable(true).dead_letter(false).build());
+            }
+        }
+    }
+    let mut changes = Vec::new();
+
+    let live_exchanges = live
+        .exchanges
+        .iter()
+        .filter(|e| !e.name().is_empty() && !e.name().starts_with("amq."))
+        .map(|e| (e.name(), e))
+        .collect::<BTreeMap<_, _>>();
+    for exchange in &expanded.exchanges {
+        match live_exchanges.get(exchange.name()) {
+            None => changes.push(Change::MissingExchange(exchange.clone())),
+            Some(existing) => {
+                let mut differences = Vec::new();
+                compare(&mut differences, "type", exchange.kind(), existing.kind());
+                compare_options(
+                    &mut differences,
+                    &[
+                        ("durable", exchange.options.durable, existing.options.durable),
+                        (
+                            "auto_delete",
+                            exchange.options.auto_delete,
+                            existing.options.auto_delete,
+                        ),
+                        ("internal", exchange.options.internal, existing.options.internal),
+                    ],
+                );
+                compare_arguments(&mut differences, &exchange.arguments, &existing.arguments);
+                if !differences.is_empty() && !exchange.options.passive {
+                    changes.push(Change::MismatchedExchange {
+                        name: exchange.name().into(),
+                        differences,
+                    });
+                }
+            }
+        }
+    }
+    for name in live_exchanges.keys() {
+        if !expanded.exchanges.iter().any(|e| e.name() == *name) {
+            changes.push(Change::ExtraExchange(name.to_string()));
+        }
+    }
+
+    let live_queues = live
+        .queues
+        .iter()
+        .filter(|q| {
+            !q.name().starts_with("amq.")
+                && !q.name().starts_with("batch.delay.")
+                && !q.options.exclusive
+        })
+        .map(|q| (q.name(), q))
+        .collect::<BTreeMap<_, _>>();
+    for queue in &expanded.queues {
+        match live_queues.get(queue.name()) {
+            None => changes.push(Change::MissingQueue(queue.clone())),
+            Some(existing) => {
+                let mut differences = Vec::new();
+                compare_options(
+                    &mut differences,
+                    &[
+                        ("durable", queue.options.durable, existing.options.durable),
+                        (
+                            "auto_delete",
+                            queue.options.auto_delete,
+                            existing.options.auto_delete,
+                        ),
+                    ],
+                );
+                compare_arguments(&mut differences, &queue.arguments, &existing.arguments);
+                if !differences.is_empty() && !queue.options.passive {
+                    changes.push(Change::MismatchedQueue {
+                        name: queue.name().into(),
+                        differences,
+                    });
+                }
+            }
+        }
+    }
+    for name in live_queues.keys() {
+        if !expanded.queues.iter().any(|q| q.name() == *name) {
+            changes.push(Change::ExtraQueue(name.to_string()));
+        }
+    }
+
+    let declared_bindings = bindings(&expanded);
+    let live_bindings = bindings(live);
+    for &(exchange, destination, routing_key) in &declared_bindings {
+        if !live_bindings.contains(&(exchange, destination, routing_key)) {
+            changes.push(Change::MissingBinding {
+                destination: destination.into(),
+                exchange: exchange.into(),
+                routing_key: routing_key.into(),
+            });
+        }
+    }
+    for &(exchange, destination, routing_key) in &live_bindings {
+        let managed = live_exchanges.contains_key(destination)
+            || live_queues.contains_key(destination);
+        if managed && !declared_bindings.contains(&(exchange, destination, routing_key)) {
+            changes.push(Change::ExtraBinding {
+                destination: destination.into(),
+                exchange: exchange.into(),
+                routing_key: routing_key.into(),
+            });
+        }
+    }
+
+    Plan {
+        declared: expanded,
+        changes,
+    }
+}
+
+/// Return the bindings of the given topology as `(exchange, destination, routing_key)` tuples,
+/// leaving out the bindings from the default exchange.
+///
+/// The bindings of an exchange route its jobs to other exchanges, while the bindings of a queue
+/// route the jobs of other exchanges to it.
+fn bindings(topology: &Topology) -> BTreeSet<(&str, &str, &str)> {
+    let exchanges = topology.exchanges.iter().flat_map(|e| {
+        e.bindings()
+            .iter()
+            .map(move |b| (e.name(), b.exchange(), b.routing_key()))
+    });
+    let queues = topology.queues.iter().flat_map(|q| {
+        q.bindings()
+            .iter()
+            .map(move |b| (b.exchange(), q.name(), b.routing_key()))
+    });
+    exchanges
+        .chain(queues)
+        .filter(|&(exchange, _, _)| !exchange.is_empty())
+        .collect()
+}
+
+/// Record a difference if the given declared and live values differ.
+fn compare(differences: &mut Vec<Difference>, setting: &str, declared: &str, live: &str) {
+    if declared != live {
+        differences.push(Difference {
+            setting: setting.into(),
+            declared: Some(declared.into()),
+            live: Some(live.into()),
+        });
+    }
+}
+
+/// Record a difference for each of the given options whose declared and live values differ.
+fn compare_options(differences: &mut Vec<Difference>, options: &[(&str, bool, bool)]) {
+    for &(setting, declared, live) in options {
+        compare(
+            differences,
+            setting,
+            &declared.to_string(),
+            &live.to_string(),
+        );
+    }
+}
+
+/// Record a difference for each argument whose declared and live values differ.
+///
+/// Values are compared according to their representation, as the management API doesn't report
+/// the type of the arguments, e.g: `x-max-priority` is declared as an unsigned byte but reported
+/// as a number.
+fn compare_arguments(differences: &mut Vec<Difference>, declared: &FieldTable, live: &FieldTable) {
+    let keys = declared.keys().chain(live.keys()).collect::<BTreeSet<_>>();
+    for key in keys {
+        let declared = declared.get(key).map(represent);
+        let live = live.get(key).map(represent);
+        if declared != live {
+            differences.push(Difference {
+                setting: key.clone(),
+                declared,
+                live,
+            });
+        }
+    }
+}
+
+/// Return the representation of the given argument value, regardless of its type.
+fn represent(value: &AMQPValue) -> String {
+    match *value {
+        AMQPValue::Boolean(value) => value.to_string(),
+        AMQPValue::ShortShortInt(value) => value.to_string(),
+        AMQPValue::ShortShortUInt(value) => value.to_string(),
+        AMQPValue::ShortInt(value) => value.to_string(),
+        AMQPValue::ShortUInt(value) => value.to_string(),
+        AMQPValue::LongInt(value) => value.to_string(),
+        AMQPValue::LongUInt(value) => value.to_string(),
+        AMQPValue::LongLongInt(value) => value.to_string(),
+        AMQPValue::Timestamp(value) => value.to_string(),
+        AMQPValue::LongString(ref value) => value.clone(),
+        ref value => format!("{:?}", value),
+    }
+}
+
+/// Return whether the given routing key matches the given topic binding pattern.
+fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches(pattern: &[&str], key: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => key.is_empty(),
+            Some((&"#", rest)) => (0..=key.len()).any(|skipped| matches(rest, &key[skipped..])),
+            Some((&word, rest)) => match key.split_first() {
+                Some((&first, remaining)) => {
+                    (word == "*" || word == first) && matches(rest, remaining)
+                }
+                None => false,
+            },
+        }
+    }
+    let pattern = pattern.split('.').collect::<Vec<_>>();
+    let key = routing_key.split('.').collect::<Vec<_>>();
+    matches(&pattern, &key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_patterns() {
+        assert!(topic_matches("emails", "emails"));
+        assert!(!topic_matches("emails", "emails.welcome"));
+        assert!(topic_matches("emails.*", "emails.welcome"));
+        assert!(!topic_matches("emails.*", "emails"));
+        assert!(!topic_matches("emails.*", "emails.welcome.fr"));
+        assert!(topic_matches("emails.#", "emails"));
+        assert!(topic_matches("emails.#", "emails.welcome.fr"));
+        assert!(topic_matches("#.fr", "emails.welcome.fr"));
+        assert!(topic_matches("*.welcome.#", "emails.welcome"));
+        assert!(!topic_matches("*.welcome.#", "welcome"));
+        assert!(topic_matches("#", "anything.at.all"));
+    }
+
+    #[test]
+    fn routes() {
+        let queue = queue("emails")
+            .bind("batch.direct", "emails.*")
+            .bind("batch.topic", "emails.#")
+            .bind("batch.fanout", "")
+            .build();
+        assert!(queue.routes("", "direct", "emails"));
+        assert!(!queue.routes("batch.direct", "direct", "emails.welcome"));
+        assert!(queue.routes("batch.direct", "direct", "emails.*"));
+        assert!(queue.routes("batch.topic", "topic", "emails.welcome"));
+        assert!(!queue.routes("batch.topic", "topic", "invoices"));
+        assert!(queue.routes("batch.fanout", "fanout", "invoices"));
+        assert!(!queue.routes("batch.other", "fanout", "emails"));
+    }
+
+    #[test]
+    fn is_prioritized() {
+        assert!(!queue("emails").build().is_prioritized());
+        assert!(queue("emails").enable_priorities().build().is_prioritized());
+        assert!(queue("emails").max_priority(10).build().is_prioritized());
+    }
+
+    #[test]
+    fn provisioned() {
+        let exchange = exchange("batch.example")
+            .bind("batch.upstream", "emails")
+            .build()
+            .provisioned();
+        assert!(exchange.options().passive);
+        assert!(exchange.bindings().is_empty());
+        let queue = queue("emails")
+            .bind("batch.example", "emails")
+            .build()
+            .provisioned();
+        assert!(queue.options().passive);
+        assert!(queue.bindings().is_empty());
+        assert!(queue.is_provisioned());
+        assert_eq!(queue.dead_letter_queue(), Some("emails.dead".into()));
+    }
+
+    #[test]
+    fn plan_changes() {
+        let declared = Topology::new()
+            .exchanges(vec![
+                exchange("batch.example").durable(true),
+                exchange("batch.new").kind(ExchangeKind::Topic),
+            ])
+            .queues(vec![
+                queue("emails")
+                    .durable(true)
+                    .max_priority(4)
+                    .bind("batch.example", "emails"),
+                queue("invoices").bind("batch.new", "invoices.#"),
+            ]);
+        let mut live = Topology::new()
+            .exchanges(vec![
+                exchange("").durable(true),
+                exchange("amq.direct").durable(true),
+                exchange("batch.example").durable(true),
+            ])
+            .queues(vec![
+                queue("emails")
+                    .durable(true)
+                    .dead_letter(false)
+                    .bind("", "emails")
+                    .bind("batch.example", "emails")
+                    .bind("batch.example", "newsletters"),
+                queue("emails.dead").durable(true).dead_letter(false),
+                queue("legacy").dead_letter(false),
+                queue("amq.gen-1").exclusive(true).dead_letter(false),
+            ]);
+        live.queues[0]
+            .arguments
+            .insert("x-max-priority".into(), AMQPValue::LongLongInt(4));
+
+        let plan = plan(&declared, &live);
+        let changes = plan.changes()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                "missing exchange batch.new",
+                "missing queue invoices",
+                "missing queue invoices.dead",
+                "extra queue legacy",
+                "missing binding from batch.new to invoices (invoices.#)",
+                "extra binding from batch.example to emails (newsletters)",
+            ]
+        );
+        assert!(!plan.is_safe());
+
+        live.queues[0]
+            .arguments
+            .insert("x-max-priority".into(), AMQPValue::LongLongInt(10));
+        live.queues[0].options.durable = false;
+        let plan = super::plan(&declared, &live);
+        match plan.changes()[1] {
+            Change::MismatchedQueue {
+                ref name,
+                ref differences,
+            } => {
+                assert_eq!(name, "emails");
+                assert_eq!(
+                    differences
+                        .iter()
+                        .map(|d| d.to_string())
+                        .collect::<Vec<_>>(),
+                    vec!["durable: true (live: false)", "x-max-priority: 4 (live: 10)"]
+                );
+            }
+            ref change => panic!("unexpected change: {}", change),
+        }
+    }
+}
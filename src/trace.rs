@@ -0,0 +1,190 @@
+//! Propagation of distributed tracing contexts through jobs.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use rand;
+
+use broker::Properties;
+
+/// The name of the header carrying the trace context of a job, as defined by the W3C Trace
+/// Context specification.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// A W3C trace context, identifying a span of a distributed trace.
+///
+/// The context of the span that sent a job is carried by its `traceparent` header (see
+/// `Query::trace`), and the execution of the job is a child of this span (see
+/// `Context::trace_context`). This lets a tracing system show the execution of a job as part of
+/// the trace of the code that enqueued it.
+///
+/// # Example
+///
+/// ```
+/// use batch::TraceContext;
+///
+/// let parent = TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+///     .unwrap();
+/// let child = parent.child();
+/// assert_eq!(child.trace_id(), parent.trace_id());
+/// assert_ne!(child.span_id(), parent.span_id());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    sampled: bool,
+}
+
+impl TraceContext {
+    /// Create the context of the root span of a new, sampled, trace.
+    pub fn new() -> Self {
+        let trace_id = u128::from(rand::random::<u64>()) << 64 | u128::from(rand::random::<u64>());
+        TraceContext {
+            trace_id,
+            span_id: rand::random(),
+            sampled: true,
+        }
+    }
+
+    /// Create the context of a new span, child of this one.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: rand::random(),
+            ..*self
+        }
+    }
+
+    /// Parse the value of a `traceparent` header.
+    ///
+    /// Returns `None` if the given value isn't a valid version 0 header.
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        if version != "00" || parts.next().is_some() {
+            return None;
+        }
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let span_id = u64::from_str_radix(span_id, 16).ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 0x01 != 0,
+        })
+    }
+
+    /// Return the trace context carried by the `traceparent` header of a job, if any.
+    pub fn extract(properties: &Properties) -> Option<Self> {
+        properties
+            .headers
+            .get(TRACEPARENT)
+            .and_then(|header| TraceContext::parse(header))
+    }
+
+    /// Set the `traceparent` header of a job to this context.
+    pub fn inject(&self, properties: &mut Properties) {
+        properties
+            .headers
+            .insert(TRACEPARENT.into(), self.to_string());
+    }
+
+    /// Return the ID of the trace this span belongs to.
+    pub fn trace_id(&self) -> u128 {
+        self.trace_id
+    }
+
+    /// Return the ID of this span.
+    pub fn span_id(&self) -> u64 {
+        self.span_id
+    }
+
+    /// Return whether this trace is recorded.
+    pub fn is_sampled(&self) -> bool {
+        self.sampled
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        TraceContext::new()
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+}
+
+/// Create a `tracing` span named after the given step of the processing of the given job.
+///
+/// The span records the job's name and ID, and the trace context it was sent with, if any.
+#[cfg(feature = "tracing-spans")]
+macro_rules! job_span {
+    ($name:expr, $properties:expr) => {{
+        let properties: &::broker::Properties = $properties;
+        let parent = ::trace::TraceContext::extract(properties);
+        ::tracing::info_span!(
+            $name,
+            job = %properties.task,
+            id = %properties.id,
+            trace_id = %parent.map(|p| format!("{:032x}", p.trace_id())).unwrap_or_default(),
+            parent_span_id = %parent.map(|p| format!("{:016x}", p.span_id())).unwrap_or_default()
+        )
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let header = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let context = TraceContext::parse(header).unwrap();
+        assert_eq!(context.trace_id(), 0x0af7651916cd43dd8448eb211c80319c);
+        assert_eq!(context.span_id(), 0xb7ad6b7169203331);
+        assert!(context.is_sampled());
+        assert_eq!(context.to_string(), header);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(TraceContext::parse(""), None);
+        assert_eq!(
+            TraceContext::parse("01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse("00-00000000000000000000000000000000-b7ad6b7169203331-01"),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b71-01"),
+            None
+        );
+    }
+
+    #[test]
+    fn child() {
+        let parent = TraceContext::new();
+        let child = parent.child();
+        assert_eq!(child.trace_id(), parent.trace_id());
+        assert_eq!(child.is_sampled(), parent.is_sampled());
+        assert_ne!(child.span_id(), parent.span_id());
+    }
+}
@@ -0,0 +1,205 @@
+//! Weighted consumption of the jobs received from several queues.
+
+use std::collections::{HashMap, VecDeque};
+
+use futures::{Async, Poll, Stream};
+
+use broker::{Deliveries, Delivery};
+use error::Error;
+
+/// A stream of deliveries, ordering the jobs received from several queues according to the
+/// weights of the queues.
+///
+/// The jobs received from the broker are buffered per queue as soon as they are available, and
+/// handed to the worker using a smooth weighted round-robin: a queue with a weight of 4 gets 4
+/// jobs executed for every job of a queue with a weight of 1, as long as both have jobs waiting.
+/// When `strict` is set, jobs are always taken from the queue with the highest weight holding
+/// any. Queues without a weight have a weight of 1.
+pub(crate) struct Weighted {
+    stream: Deliveries,
+    done: bool,
+    weights: HashMap<String, u32>,
+    strict: bool,
+    lanes: Vec<Lane>,
+}
+
+/// The jobs received from a queue, waiting to be handed to the worker.
+struct Lane {
+    queue: String,
+    weight: i64,
+    current: i64,
+    deliveries: VecDeque<Box<Delivery>>,
+}
+
+impl Weighted {
+    pub(crate) fn new(stream: Deliveries, weights: HashMap<String, u32>, strict: bool) -> Self {
+        Weighted {
+            stream,
+            done: false,
+            weights,
+            strict,
+            lanes: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, delivery: Box<Delivery>) {
+        let queue = delivery.queue().unwrap_or("").to_string();
+        if let Some(lane) = self.lanes.iter_mut().find(|lane| lane.queue == queue) {
+            lane.deliveries.push_back(delivery);
+            return;
+        }
+        let weight = self.weights.get(&queue).map_or(1, |&weight| weight.max(1));
+        let mut deliveries = VecDeque::new();
+        deliveries.push_back(delivery);
+        self.lanes.push(Lane {
+            queue,
+            weight: i64::from(weight),
+            current: 0,
+            deliveries,
+        });
+    }
+
+    fn pop(&mut self) -> Option<Box<Delivery>> {
+        let index = if self.strict {
+            self.lanes
+                .iter()
+                .enumerate()
+                .filter(|&(_, lane)| !lane.deliveries.is_empty())
+                .max_by_key(|&(_, lane)| lane.weight)
+                .map(|(index, _)| index)
+        } else {
+            let mut total = 0;
+            for lane in self.lanes.iter_mut().filter(|l| !l.deliveries.is_empty()) {
+                lane.current += lane.weight;
+                total += lane.weight;
+            }
+            let index = self.lanes
+                .iter()
+                .enumerate()
+                .filter(|&(_, lane)| !lane.deliveries.is_empty())
+                .max_by_key(|&(_, lane)| lane.current)
+                .map(|(index, _)| index);
+            if let Some(index) = index {
+                self.lanes[index].current -= total;
+            }
+            index
+        };
+        index.and_then(|index| self.lanes[index].deliveries.pop_front())
+    }
+}
+
+impl Stream for Weighted {
+    type Item = Box<Delivery>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done {
+            match self.stream.poll()? {
+                Async::Ready(Some(delivery)) => self.push(delivery),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => break,
+            }
+        }
+        match self.pop() {
+            Some(delivery) => Ok(Async::Ready(Some(delivery))),
+            None if self.done => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, stream, Future};
+
+    use broker::Properties;
+    use job::{Job, Priority};
+
+    #[derive(Debug)]
+    struct Queued(&'static str, Properties);
+
+    impl Delivery for Queued {
+        fn properties(&self) -> &Properties {
+            &self.1
+        }
+
+        fn payload(&self) -> &[u8] {
+            &[]
+        }
+
+        fn queue(&self) -> Option<&str> {
+            Some(self.0)
+        }
+
+        fn ack(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+
+        fn reject(self: Box<Self>) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Noop;
+
+    impl Job for Noop {
+        fn name() -> &'static str {
+            "noop"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "noop"
+        }
+
+        fn timeout() -> Option<::std::time::Duration> {
+            None
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    fn order(strict: bool) -> Vec<&'static str> {
+        let deliveries = (0..5)
+            .flat_map(|_| vec!["critical", "default"])
+            .map(|queue| -> Box<Delivery> { Box::new(Queued(queue, Properties::new::<Noop>())) })
+            .collect::<Vec<_>>();
+        let mut weights = HashMap::new();
+        weights.insert("critical".to_string(), 3);
+        let stream = Box::new(stream::iter_ok(deliveries));
+        Weighted::new(stream, weights, strict)
+            .wait()
+            .map(|delivery| match delivery.unwrap().queue().unwrap() {
+                "critical" => "c",
+                _ => "d",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn weighted() {
+        assert_eq!(
+            order(false),
+            vec!["c", "d", "c", "c", "c", "d", "c", "d", "d", "d"]
+        );
+    }
+
+    #[test]
+    fn strict() {
+        assert_eq!(
+            order(true),
+            vec!["c", "c", "c", "c", "c", "d", "d", "d", "d", "d"]
+        );
+    }
+}
@@ -0,0 +1,835 @@
+//! Workflows made of multiple jobs.
+//!
+//! A `Chain` executes jobs one after the other: each job is only published once the previous one
+//! succeeded. If a job of the chain fails for good, the jobs following it are never published.
+//!
+//! The jobs following the one being executed are serialized in its `batch-chain` header: when a
+//! job succeeds, the worker publishes the next job of the chain, along with the rest of the chain.
+//! The value returned by a job can be injected into the payload of the next one, see
+//! `Chain::then_with_result`.
+//!
+//! The serialized value returned by a job is also carried by the `batch-parent-result` header of
+//! the next job, and handed to its handler by `Context::parent_result`. Values larger than the
+//! limit set with `WorkerBuilder::parent_result_limit` are stored in the worker's `BlobStore`
+//! instead (see `WorkerBuilder::blob_store`), and the next job only carries their key in its
+//! `batch-parent-result-blob` header. The stored value is deleted once the next job succeeded.
+//! Without a blob store, values exceeding the limit aren't handed to the next job.
+//!
+//! A `Group` executes jobs in parallel, and a `Chord` executes a callback job once all the jobs
+//! of a group succeeded. The members of a chord carry its ID, size and callback in their
+//! `batch-chord` header: when one of them succeeds, the worker records it in a `Barrier`, and
+//! the member completing the chord publishes the callback.
+//!
+//! Finally, a job can depend on jobs sent earlier, possibly by other services, given their IDs
+//! (see `Client::send_after_jobs`). The IDs are carried by its `batch-depends-on` header: the
+//! worker receiving the job checks the status of its dependencies in its `StatusStore`, and
+//! publishes the job again a few seconds later until all of them succeeded.
+//!
+//! # Example
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate batch;
+//! # extern crate futures;
+//! # #[macro_use]
+//! # extern crate lazy_static;
+//! # #[macro_use]
+//! # extern crate serde;
+//! # extern crate tokio;
+//! #
+//! use batch::Client;
+//! use futures::Future;
+//!
+//! #[derive(Serialize, Deserialize, Job)]
+//! #[job(routing_key = "videos")]
+//! struct Transcode {
+//!     video: u64,
+//! }
+//!
+//! #[derive(Serialize, Deserialize, Job)]
+//! #[job(routing_key = "emails")]
+//! struct NotifyUploader {
+//!     video: u64,
+//! }
+//!
+//! # fn main() {
+//! let task = Client::builder()
+//!     .connection_url("amqp://localhost/%2f")
+//!     .build()
+//!     .and_then(|client| {
+//!         chain![Transcode { video: 42 }, NotifyUploader { video: 42 }].send(&client)
+//!     })
+//!     .map_err(|e| eprintln!("An error occured: {}", e));
+//!
+//! # if false {
+//! tokio::run(task);
+//! # }
+//! # }
+//! ```
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::{future, Future};
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+use blob::BlobStore;
+use broker::Properties;
+use client::Client;
+use codec::Codec;
+use error::{Error, ErrorKind, Result};
+use job::{Job, Status};
+use status::StatusStore;
+
+/// The name of the header carrying the jobs following a job in its chain.
+pub const CHAIN_HEADER: &str = "batch-chain";
+
+/// The name of the header carrying the chord a job is a member of.
+pub const CHORD_HEADER: &str = "batch-chord";
+
+/// The name of the header carrying the comma-separated IDs of the jobs a job depends on.
+pub const DEPENDENCIES_HEADER: &str = "batch-depends-on";
+
+/// The name of the header carrying the serialized value returned by the previous job of a chain.
+pub const RESULT_HEADER: &str = "batch-parent-result";
+
+/// The name of the header carrying the key of the value returned by the previous job of a chain,
+/// when it was stored in a `BlobStore`.
+pub const RESULT_BLOB_HEADER: &str = "batch-parent-result-blob";
+
+/// The default maximum size in bytes of the value carried by the `batch-parent-result` header.
+pub const DEFAULT_RESULT_LIMIT: usize = 64 * 1024;
+
+/// A job of a chain, waiting for the previous jobs to succeed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Step {
+    properties: Properties,
+    payload: Vec<u8>,
+    #[serde(default)]
+    result_field: Option<String>,
+}
+
+/// A sequence of jobs, each executed once the previous one succeeded.
+///
+/// See the module documentation, and the `chain!` macro.
+pub struct Chain {
+    steps: Vec<Step>,
+    error: Option<Error>,
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        let tasks = self.steps
+            .iter()
+            .map(|step| step.properties.task.as_str())
+            .collect::<Vec<_>>();
+        write!(f, "Chain {{ jobs: {:?} }}", tasks)
+    }
+}
+
+impl Chain {
+    /// Create a new, empty, `Chain`.
+    pub fn new() -> Self {
+        Chain {
+            steps: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Append the given job to this chain.
+    pub fn then<T>(self, job: T) -> Self
+    where
+        T: Job,
+    {
+        self.push(job, None)
+    }
+
+    /// Append the given job to this chain, injecting the value returned by the previous job in
+    /// the given field of its payload.
+    ///
+    /// The job must be serialized as a JSON object, and the given field must be deserializable
+    /// from the value returned by the previous job.
+    pub fn then_with_result<T>(self, job: T, field: &str) -> Self
+    where
+        T: Job,
+    {
+        self.push(job, Some(field.into()))
+    }
+
+    fn push<T>(mut self, job: T, result_field: Option<String>) -> Self
+    where
+        T: Job,
+    {
+        if self.error.is_some() {
+            return self;
+        }
+        match step(job, result_field) {
+            Ok(step) => self.steps.push(step),
+            Err(e) => self.error = Some(e),
+        }
+        self
+    }
+
+    /// Send the first job of this chain using the given client.
+    ///
+    /// Returns a `Future` that completes once the first job is sent to the broker.
+    pub fn send(self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+        if let Some(e) = self.error {
+            return Box::new(future::err(e));
+        }
+        let mut steps = self.steps.into_iter();
+        let mut first = match steps.next() {
+            Some(first) => first,
+            None => return Box::new(future::ok(())),
+        };
+        if first.result_field.is_some() {
+            let message = "the first job of a chain can't receive a result".to_string();
+            return Box::new(future::err(ErrorKind::InvalidWorkflow(message).into()));
+        }
+        if let Err(e) = attach(&mut first.properties, steps.collect()) {
+            return Box::new(future::err(e));
+        }
+        client.send(&first.payload, &first.properties)
+    }
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Chain::new()
+    }
+}
+
+/// A set of jobs executed in parallel.
+///
+/// See the `group!` macro, and `chord` to execute a job once all the jobs of a group succeeded.
+pub struct Group {
+    steps: Vec<Step>,
+    error: Option<Error>,
+}
+
+impl fmt::Debug for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        let tasks = self.steps
+            .iter()
+            .map(|step| step.properties.task.as_str())
+            .collect::<Vec<_>>();
+        write!(f, "Group {{ jobs: {:?} }}", tasks)
+    }
+}
+
+impl Group {
+    /// Create a new, empty, `Group`.
+    pub fn new() -> Self {
+        Group {
+            steps: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Add the given job to this group.
+    pub fn add<T>(mut self, job: T) -> Self
+    where
+        T: Job,
+    {
+        if self.error.is_some() {
+            return self;
+        }
+        match step(job, None) {
+            Ok(step) => self.steps.push(step),
+            Err(e) => self.error = Some(e),
+        }
+        self
+    }
+
+    /// Return the number of jobs in this group.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Return whether this group has no job.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Send every job of this group using the given client.
+    ///
+    /// Returns a `Future` that completes once every job is sent to the broker.
+    pub fn send(self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+        if let Some(e) = self.error {
+            return Box::new(future::err(e));
+        }
+        let tasks = self.steps
+            .iter()
+            .map(|step| client.send(&step.payload, &step.properties))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks).map(|_| ()))
+    }
+}
+
+impl Default for Group {
+    fn default() -> Self {
+        Group::new()
+    }
+}
+
+/// A group of jobs, and a callback job executed once all of them succeeded.
+///
+/// See `chord`.
+#[derive(Debug)]
+pub struct Chord {
+    group: Group,
+    callback: Result<Step>,
+}
+
+/// Create a `Chord`, executing `callback` once all the jobs of `group` succeeded.
+///
+/// The workers executing the jobs of the group must be given a `Barrier` (see
+/// `WorkerBuilder::barrier`), recording which members of the chord succeeded. If a member of the
+/// group fails for good, the callback is never executed.
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate batch;
+/// # #[macro_use]
+/// # extern crate lazy_static;
+/// # #[macro_use]
+/// # extern crate serde;
+/// #
+/// use batch::workflow::chord;
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job(routing_key = "reports")]
+/// struct CountWords {
+///     chapter: u32,
+/// }
+///
+/// #[derive(Serialize, Deserialize, Job)]
+/// #[job(routing_key = "reports")]
+/// struct PublishReport;
+///
+/// # fn main() {
+/// let chord = chord(
+///     group![CountWords { chapter: 1 }, CountWords { chapter: 2 }],
+///     PublishReport,
+/// );
+/// # }
+/// ```
+pub fn chord<T>(group: Group, callback: T) -> Chord
+where
+    T: Job,
+{
+    Chord {
+        group,
+        callback: step(callback, None),
+    }
+}
+
+impl Chord {
+    /// Send every job of the group of this chord using the given client.
+    ///
+    /// Returns a `Future` that completes once every job of the group is sent to the broker. If
+    /// the group is empty, the callback is sent right away.
+    pub fn send(self, client: &Client) -> Box<Future<Item = (), Error = Error> + Send> {
+        let callback = match self.callback {
+            Ok(callback) => callback,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        if let Some(e) = self.group.error {
+            return Box::new(future::err(e));
+        }
+        if self.group.steps.is_empty() {
+            return client.send(&callback.payload, &callback.properties);
+        }
+        let membership = Membership {
+            id: Uuid::new_v4(),
+            size: self.group.steps.len(),
+            callback,
+        };
+        let serialized = match serde_json::to_string(&membership) {
+            Ok(serialized) => serialized,
+            Err(e) => return Box::new(future::err(ErrorKind::Serialization(e).into())),
+        };
+        let tasks = self.group
+            .steps
+            .into_iter()
+            .map(|mut step| {
+                step.properties
+                    .headers
+                    .insert(CHORD_HEADER.into(), serialized.clone());
+                client.send(&step.payload, &step.properties)
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(tasks).map(|_| ()))
+    }
+}
+
+/// The chord a job is a member of, carried by its `batch-chord` header.
+#[derive(Debug, Serialize, Deserialize)]
+struct Membership {
+    id: Uuid,
+    size: usize,
+    callback: Step,
+}
+
+/// A synchronization store recording which members of chords succeeded.
+///
+/// Implementations must be shared by all the workers executing the members of a chord, see
+/// `WorkerBuilder::barrier`. `memory::Connection` and `batch_redis::Connection` implement this
+/// trait.
+pub trait Barrier: fmt::Debug + Send + Sync {
+    /// Record that the given member of the given chord succeeded.
+    ///
+    /// Returns a `Future` resolving to the number of distinct members of the chord that succeeded
+    /// so far, including the given one. Recording the same member twice must not change the
+    /// number of members that succeeded.
+    fn arrive(
+        &self,
+        chord: Uuid,
+        member: Uuid,
+    ) -> Box<Future<Item = usize, Error = Error> + Send>;
+}
+
+/// Create a `Chain` of the given jobs, executed in the given order.
+///
+/// See the `workflow` module documentation.
+#[macro_export]
+macro_rules! chain {
+    ($($job:expr),+ $(,)*) => {
+        $crate::workflow::Chain::new()$(.then($job))+
+    };
+}
+
+/// Create a `Group` of the given jobs, executed in parallel.
+///
+/// See the `workflow` module documentation.
+#[macro_export]
+macro_rules! group {
+    ($($job:expr),+ $(,)*) => {
+        $crate::workflow::Group::new()$(.add($job))+
+    };
+}
+
+/// Serialize the given job into a step of a workflow.
+fn step<T>(job: T, result_field: Option<String>) -> Result<Step>
+where
+    T: Job,
+{
+    let properties = Properties::new::<T>();
+    if result_field.is_some() && properties.codec != Codec::Json {
+        let message = format!("{} must use the JSON codec to receive a result", T::name());
+        return Err(ErrorKind::InvalidWorkflow(message).into());
+    }
+    let payload = properties.codec.encode(&job)?;
+    Ok(Step {
+        properties,
+        payload,
+        result_field,
+    })
+}
+
+/// Serialize the given steps into the chain header of the given properties.
+fn attach(properties: &mut Properties, steps: Vec<Step>) -> Result<()> {
+    if steps.is_empty() {
+        properties.headers.remove(CHAIN_HEADER);
+        return Ok(());
+    }
+    let serialized = serde_json::to_string(&steps).map_err(ErrorKind::Serialization)?;
+    properties.headers.insert(CHAIN_HEADER.into(), serialized);
+    Ok(())
+}
+
+/// Return the job following the given one in its chain, if any, given the output of its
+/// successful execution.
+pub(crate) fn next(
+    properties: &Properties,
+    output: &[u8],
+) -> Result<Option<(Vec<u8>, Properties)>> {
+    let header = match properties.headers.get(CHAIN_HEADER) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let steps: Vec<Step> = serde_json::from_str(header).map_err(ErrorKind::Deserialization)?;
+    let mut steps = steps.into_iter();
+    let mut next = match steps.next() {
+        Some(next) => next,
+        None => return Ok(None),
+    };
+    if let Some(ref field) = next.result_field {
+        let mut payload: Value =
+            serde_json::from_slice(&next.payload).map_err(ErrorKind::Deserialization)?;
+        let result: Value = serde_json::from_slice(output).map_err(ErrorKind::Deserialization)?;
+        match payload.as_object_mut() {
+            Some(object) => {
+                object.insert(field.clone(), result);
+            }
+            None => {
+                let message = format!("{} isn't serialized as an object", next.properties.task);
+                return Err(ErrorKind::InvalidWorkflow(message).into());
+            }
+        }
+        next.payload = serde_json::to_vec(&payload).map_err(ErrorKind::Serialization)?;
+    }
+    next.properties.enqueued_at = Some(Utc::now());
+    next.properties.origin = properties.origin.clone();
+    attach(&mut next.properties, steps.collect())?;
+    // Outputs are serialized as JSON, which is always valid UTF-8.
+    match String::from_utf8(output.to_vec()) {
+        Ok(result) => {
+            next.properties.headers.insert(RESULT_HEADER.into(), result);
+        }
+        Err(_) => warn!(
+            "[{}] The output of the job isn't valid UTF-8",
+            properties.id
+        ),
+    }
+    Ok(Some((next.payload, next.properties)))
+}
+
+/// Move the value carried by the given job to the given store if it exceeds `limit` bytes.
+///
+/// Without a store, the value is dropped, and the job receives no result.
+pub(crate) fn check_in(
+    store: Option<&Arc<BlobStore>>,
+    limit: usize,
+    next: Option<(Vec<u8>, Properties)>,
+) -> Box<Future<Item = Option<(Vec<u8>, Properties)>, Error = Error> + Send> {
+    let (payload, mut properties) = match next {
+        Some(next) => next,
+        None => return Box::new(future::ok(None)),
+    };
+    let size = properties
+        .headers
+        .get(RESULT_HEADER)
+        .map_or(0, |result| result.len());
+    if size <= limit {
+        return Box::new(future::ok(Some((payload, properties))));
+    }
+    let result = properties.headers.remove(RESULT_HEADER).unwrap_or_default();
+    let store = match store {
+        Some(store) => store,
+        None => {
+            warn!(
+                "[{}] The result of {} bytes handed to the job exceeds {} bytes and no blob store \
+                 was configured, it is dropped",
+                properties.id, size, limit
+            );
+            return Box::new(future::ok(Some((payload, properties))));
+        }
+    };
+    let key = format!("result-{}", properties.id);
+    properties
+        .headers
+        .insert(RESULT_BLOB_HEADER.into(), key.clone());
+    let task = store
+        .put(&key, result.into_bytes())
+        .map(move |_| Some((payload, properties)));
+    Box::new(task)
+}
+
+/// Delete the value handed to the given job from the given store, if it was stored there.
+///
+/// Failures are logged, as the job already succeeded.
+pub(crate) fn check_out(
+    store: Option<&Arc<BlobStore>>,
+    properties: &Properties,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let (store, key) = match (store, properties.headers.get(RESULT_BLOB_HEADER)) {
+        (Some(store), Some(key)) => (store, key.clone()),
+        _ => return Box::new(future::ok(())),
+    };
+    let task = store.delete(&key).then(move |result| {
+        if let Err(e) = result {
+            warn!("Couldn't delete the result stored under {}: {}", key, e);
+        }
+        Ok(())
+    });
+    Box::new(task)
+}
+
+/// Return the serialized value returned by the previous job of the chain of the given job, if
+/// any, fetching it from the given store if it was stored there.
+pub(crate) fn parent_result(
+    store: Option<&Arc<BlobStore>>,
+    properties: &Properties,
+) -> Box<Future<Item = Option<Vec<u8>>, Error = Error> + Send> {
+    if let Some(result) = properties.headers.get(RESULT_HEADER) {
+        return Box::new(future::ok(Some(result.clone().into_bytes())));
+    }
+    let key = match properties.headers.get(RESULT_BLOB_HEADER) {
+        Some(key) => key,
+        None => return Box::new(future::ok(None)),
+    };
+    match store {
+        Some(store) => Box::new(store.get(key).map(Some)),
+        None => Box::new(future::err(ErrorKind::NoBlobStore.into())),
+    }
+}
+
+/// Record the success of the given job in the chord it is a member of, if any.
+///
+/// Returns a `Future` resolving to the callback of the chord if this job completed it.
+pub(crate) fn arrive(
+    barrier: Option<Arc<Barrier>>,
+    properties: &Properties,
+) -> Box<Future<Item = Option<(Vec<u8>, Properties)>, Error = Error> + Send> {
+    let membership: Membership = match properties.headers.get(CHORD_HEADER) {
+        Some(header) => match serde_json::from_str(header) {
+            Ok(membership) => membership,
+            Err(e) => return Box::new(future::err(ErrorKind::Deserialization(e).into())),
+        },
+        None => return Box::new(future::ok(None)),
+    };
+    let barrier = match barrier {
+        Some(barrier) => barrier,
+        None => return Box::new(future::err(ErrorKind::NoBarrier.into())),
+    };
+    let origin = properties.origin.clone();
+    let task = barrier
+        .arrive(membership.id, properties.id)
+        .map(move |arrived| {
+            if arrived != membership.size {
+                return None;
+            }
+            let mut callback = membership.callback;
+            callback.properties.enqueued_at = Some(Utc::now());
+            callback.properties.origin = origin;
+            Some((callback.payload, callback.properties))
+        });
+    Box::new(task)
+}
+
+/// The state of the dependencies of a job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Dependencies {
+    /// All the dependencies succeeded, or the job has none.
+    Succeeded,
+    /// Some dependencies didn't succeed yet.
+    Pending,
+    /// The given dependency failed or was revoked, the job will never be ready.
+    Failed(Uuid),
+}
+
+/// Serialize the given IDs into the dependencies header of the given properties.
+pub(crate) fn depend_on(properties: &mut Properties, ids: &[Uuid]) {
+    if ids.is_empty() {
+        return;
+    }
+    let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    properties
+        .headers
+        .insert(DEPENDENCIES_HEADER.into(), ids.join(","));
+}
+
+/// Check the state of the dependencies of the given job in the given store.
+///
+/// Unknown dependencies are considered pending, as their status may not be recorded yet.
+pub(crate) fn dependencies(
+    statuses: Option<&Arc<StatusStore>>,
+    properties: &Properties,
+) -> Box<Future<Item = Dependencies, Error = Error> + Send> {
+    let header = match properties.headers.get(DEPENDENCIES_HEADER) {
+        Some(header) => header,
+        None => return Box::new(future::ok(Dependencies::Succeeded)),
+    };
+    let statuses = match statuses {
+        Some(statuses) => statuses,
+        None => return Box::new(future::err(ErrorKind::NoStatusStore.into())),
+    };
+    let ids = header
+        .split(',')
+        .map(|id| id.trim().parse::<Uuid>())
+        .collect::<StdResult<Vec<_>, _>>();
+    let ids = match ids {
+        Ok(ids) => ids,
+        Err(e) => {
+            let message = format!("invalid dependency in {:?}: {}", header, e);
+            return Box::new(future::err(ErrorKind::InvalidWorkflow(message).into()));
+        }
+    };
+    let tasks = ids.iter().map(|&id| statuses.fetch(id)).collect::<Vec<_>>();
+    let task = future::join_all(tasks).map(move |reports| {
+        let mut state = Dependencies::Succeeded;
+        for (id, report) in ids.into_iter().zip(reports) {
+            match report.map(|report| report.status) {
+                Some(Status::Success) => (),
+                Some(Status::Failed(_)) | Some(Status::Revoked) => {
+                    return Dependencies::Failed(id)
+                }
+                _ => state = Dependencies::Pending,
+            }
+        }
+        state
+    });
+    Box::new(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use job::{Failure, Priority};
+    use memory;
+
+    #[derive(Serialize, Deserialize)]
+    struct Resize {
+        image: u64,
+    }
+
+    impl Job for Resize {
+        fn name() -> &'static str {
+            "resize"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "images"
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Publish {
+        image: u64,
+        #[serde(default)]
+        url: Option<String>,
+    }
+
+    impl Job for Publish {
+        fn name() -> &'static str {
+            "publish"
+        }
+
+        fn exchange() -> &'static str {
+            ""
+        }
+
+        fn routing_key() -> &'static str {
+            "images"
+        }
+
+        fn retries() -> u32 {
+            0
+        }
+
+        fn timeout() -> Option<Duration> {
+            None
+        }
+
+        fn priority() -> Priority {
+            Priority::Normal
+        }
+    }
+
+    #[test]
+    fn advance() {
+        let chain = Chain::new()
+            .then(Resize { image: 1 })
+            .then_with_result(Publish { image: 1, url: None }, "url")
+            .then(Resize { image: 2 });
+        let mut steps = chain.steps.into_iter();
+        let mut first = steps.next().unwrap();
+        attach(&mut first.properties, steps.collect()).unwrap();
+
+        let (payload, properties) = next(&first.properties, b"\"https://example.com/1.png\"")
+            .unwrap()
+            .unwrap();
+        assert_eq!(properties.task, "publish");
+        let publish: Publish = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(publish.url, Some("https://example.com/1.png".into()));
+
+        assert_eq!(
+            properties.headers.get(RESULT_HEADER).unwrap(),
+            "\"https://example.com/1.png\""
+        );
+
+        let (_, properties) = next(&properties, b"null").unwrap().unwrap();
+        assert_eq!(properties.task, "resize");
+        assert!(!properties.headers.contains_key(CHAIN_HEADER));
+        assert!(next(&properties, b"null").unwrap().is_none());
+    }
+
+    #[test]
+    fn hand_over_results() {
+        let store: Arc<BlobStore> = Arc::new(memory::Connection::new(Vec::new()));
+        let chain = Chain::new()
+            .then(Resize { image: 1 })
+            .then(Resize { image: 2 });
+        let mut steps = chain.steps.into_iter();
+        let mut first = steps.next().unwrap();
+        attach(&mut first.properties, steps.collect()).unwrap();
+        let hand_over = |store: Option<&Arc<BlobStore>>, output: &[u8]| {
+            let next = next(&first.properties, output).unwrap();
+            check_in(store, 64, next).wait().unwrap().unwrap().1
+        };
+
+        let properties = hand_over(Some(&store), b"42");
+        assert!(!properties.headers.contains_key(RESULT_BLOB_HEADER));
+        let result = parent_result(None, &properties).wait().unwrap();
+        assert_eq!(result, Some(b"42".to_vec()));
+
+        // Large results are stored in the blob store until the job succeeded.
+        let large = serde_json::to_vec(&"x".repeat(100)).unwrap();
+        let properties = hand_over(Some(&store), &large);
+        assert!(!properties.headers.contains_key(RESULT_HEADER));
+        let result = parent_result(Some(&store), &properties).wait().unwrap();
+        assert_eq!(result, Some(large.clone()));
+        let error = parent_result(None, &properties).wait().unwrap_err();
+        assert!(error.is_no_blob_store());
+        check_out(Some(&store), &properties).wait().unwrap();
+        assert!(parent_result(Some(&store), &properties).wait().is_err());
+
+        // Without a blob store, they are dropped.
+        let properties = hand_over(None, &large);
+        assert_eq!(parent_result(None, &properties).wait().unwrap(), None);
+    }
+
+    #[test]
+    fn check_dependencies() {
+        let statuses: Arc<StatusStore> = Arc::new(memory::Connection::new(Vec::new()));
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut properties = Properties::new::<Resize>();
+        let check = |properties: &Properties| dependencies(Some(&statuses), properties).wait();
+        assert_eq!(check(&properties).unwrap(), Dependencies::Succeeded);
+
+        depend_on(&mut properties, &[first, second]);
+        assert_eq!(check(&properties).unwrap(), Dependencies::Pending);
+        assert!(dependencies(None, &properties).wait().unwrap_err().is_no_status_store());
+
+        let now = Utc::now();
+        statuses.update(first, Status::Success, now).wait().unwrap();
+        statuses.update(second, Status::Started, now).wait().unwrap();
+        assert_eq!(check(&properties).unwrap(), Dependencies::Pending);
+        statuses.update(second, Status::Success, now).wait().unwrap();
+        assert_eq!(check(&properties).unwrap(), Dependencies::Succeeded);
+        statuses
+            .update(first, Status::Failed(Failure::Error), now)
+            .wait()
+            .unwrap();
+        assert_eq!(check(&properties).unwrap(), Dependencies::Failed(first));
+
+        properties
+            .headers
+            .insert(DEPENDENCIES_HEADER.into(), "not-an-id".into());
+        assert!(check(&properties).unwrap_err().is_invalid_workflow());
+    }
+}